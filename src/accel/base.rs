@@ -1,8 +1,39 @@
+use std::sync::Arc;
+
+use ::cpu::boot::LongModeBootState;
 use ::cpu::exits::VcpuExit;
 use ::memory::MmapMemorySlot;
 
 pub trait Accelerator {
-    fn init_vcpu(&mut self);
+    /// Creates a new vCPU. The first vCPU is the bootstrap processor,
+    /// the other ones are left waiting for a SIPI.
+    fn create_vcpu(&mut self, vcpu_index: usize) -> Box<Vcpu>;
     fn memory_region_add(&self, mem: &MmapMemorySlot);
-    fn vcpu_run(&mut self, vcpu_index: usize) -> VcpuExit;
+    fn irq_chip(&self) -> Arc<IrqChip>;
+    fn vm_state(&self) -> Arc<VmState>;
+}
+
+/// The interrupt controller, shared by the emulated devices.
+pub trait IrqChip: Send + Sync {
+    /// Sets the level of the given GSI.
+    fn set_irq_line(&self, irq: u32, active: bool);
+}
+
+/// The VM wide state kept by the accelerator, such as the in-kernel
+/// interrupt controllers and the guest clock.
+pub trait VmState: Send + Sync {
+    fn save_state(&self) -> Vec<u8>;
+    fn restore_state(&self, data: &[u8]);
+}
+
+/// A virtual CPU, which may be moved to its own host thread.
+pub trait Vcpu: Send {
+    fn index(&self) -> usize;
+    fn run(&mut self) -> VcpuExit;
+    /// Puts the vCPU in 64-bit mode, skipping the firmware.
+    fn init_long_mode(&mut self, state: &LongModeBootState);
+    /// Saves the registers and the LAPIC state. Must be called from the
+    /// thread running the vCPU, while it's out of the guest.
+    fn save_state(&mut self) -> Vec<u8>;
+    fn restore_state(&mut self, data: &[u8]);
 }
@@ -1,20 +1,29 @@
 extern crate clap;
+extern crate libc;
 extern crate libkvm;
 extern crate std;
 
+use std::sync::Arc;
+
 use libkvm::linux::kvm_bindings::*;
 use libkvm::system::*;
 use libkvm::vcpu::VirtualCPU;
 use libkvm::vm::VirtualMachine;
 
+use ::cpu::boot::LongModeBootState;
 use ::cpu::exits::VcpuExit;
+use ::layout::KVM_IDENTITY_MAP_ADDR;
 use ::memory::MmapMemorySlot;
-use super::base::Accelerator;
+use ::snapshot::state::{StateReader, StateWriter};
+use super::base::{Accelerator, IrqChip, Vcpu, VmState};
+
+const IRQCHIPS: [u32; 3] = [KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+                            KVM_IRQCHIP_IOAPIC];
 
 pub struct KVMAccelerator {
     kvm: KVMSystem,
-    vm: VirtualMachine,
-    vcpus: Vec<VirtualCPU>
+    vm: Arc<VirtualMachine>,
+    vcpu_count: usize,
 }
 
 impl KVMAccelerator {
@@ -28,8 +37,8 @@ impl KVMAccelerator {
 
         let accel = KVMAccelerator {
             kvm: kvm,
-            vm: vm,
-            vcpus: Vec::new()
+            vm: Arc::new(vm),
+            vcpu_count: 0,
         };
 
         accel.init_vm();
@@ -38,16 +47,19 @@ impl KVMAccelerator {
     }
 
     fn init_vm(&self) {
-        let identity_base = 0xfeffc000;
-        let tss_addr = identity_base + 0x1000;
+        let tss_addr = KVM_IDENTITY_MAP_ADDR + 0x1000;
 
-        self.vm.set_identity_map_addr(identity_base).unwrap();
+        self.vm.set_identity_map_addr(KVM_IDENTITY_MAP_ADDR).unwrap();
 
         println!("Setting TSS address: {:x}", tss_addr);
         self.vm.set_tss_address(tss_addr as u32).unwrap();
+
+        // The in-kernel LAPIC is required in order to park the APs
+        // until the BSP wakes them up using INIT-SIPI-SIPI.
+        self.vm.create_irqchip().unwrap();
     }
 
-    fn setup_cpuid(&self, vcpu: &VirtualCPU) {
+    fn setup_cpuid(&self, vcpu: &VirtualCPU, vcpu_index: usize) {
         let mut kvm_cpuid_entries = self.kvm.get_supported_cpuid().unwrap();
 
         let i = kvm_cpuid_entries
@@ -71,11 +83,20 @@ impl KVMAccelerator {
             .unwrap();
 
         kvm_cpuid_entries[i].ecx |= ::cpu::constants::CPUID_EXT_HYPERVISOR;
+        // Initial APIC ID, matching the one used by the in-kernel LAPIC.
+        kvm_cpuid_entries[i].ebx &= 0x00ffffff;
+        kvm_cpuid_entries[i].ebx |= (vcpu_index as u32) << 24;
+
+        for entry in kvm_cpuid_entries.iter_mut().filter(|r| r.function == 0xb) {
+            // x2APIC ID
+            entry.edx = vcpu_index as u32;
+        }
 
         vcpu.set_cpuid(&kvm_cpuid_entries).unwrap();
     }
 
-    fn setup_msrs(&self, vcpu: &VirtualCPU) {
+    /// Resets the MSRs, returning their indexes.
+    fn setup_msrs(&self, vcpu: &VirtualCPU) -> Vec<u32> {
         let msr_list = self.kvm.get_msr_index_list().unwrap();
         let ignored_msrs = [0x40000020, 0x40000022, 0x40000023];
 
@@ -89,6 +110,8 @@ impl KVMAccelerator {
             .collect::<Vec<_>>();
 
         vcpu.set_msrs(&msr_entries).unwrap();
+
+        msr_entries.iter().map(|entry| entry.index).collect()
     }
 
     fn init_regs(&self, vcpu: &VirtualCPU) {
@@ -133,23 +156,123 @@ impl KVMAccelerator {
 
         vcpu.set_kvm_regs(&regs).unwrap();
     }
+
+    fn init_ap_state(&self, vcpu: &VirtualCPU) {
+        let mp_state = kvm_mp_state {
+            mp_state: KVM_MP_STATE_INIT_RECEIVED,
+        };
+        vcpu.set_mp_state(&mp_state).unwrap();
+    }
 }
 
 impl Accelerator for KVMAccelerator {
-    fn init_vcpu(&mut self) {
+    fn create_vcpu(&mut self, vcpu_index: usize) -> Box<Vcpu> {
+        assert_eq!(vcpu_index, self.vcpu_count);
+
         let vcpu = self.vm.create_vcpu().unwrap();
 
-        self.setup_cpuid(&vcpu);
-        self.setup_msrs(&vcpu);
-        self.init_regs(&vcpu);
+        self.setup_cpuid(&vcpu, vcpu_index);
+        let msr_indexes = self.setup_msrs(&vcpu);
+        if vcpu_index == 0 {
+            self.init_regs(&vcpu);
+        } else {
+            self.init_ap_state(&vcpu);
+        }
+
+        self.vcpu_count += 1;
+
+        Box::new(KVMVcpu {
+            index: vcpu_index,
+            vcpu: vcpu,
+            msr_indexes: msr_indexes,
+        })
+    }
+
+    fn memory_region_add(&self, mem: &MmapMemorySlot) {
+        self.vm.set_user_memory_region(mem).unwrap();
+    }
+
+    fn irq_chip(&self) -> Arc<IrqChip> {
+        Arc::new(KVMIrqChip {
+            vm: self.vm.clone(),
+        })
+    }
+
+    fn vm_state(&self) -> Arc<VmState> {
+        Arc::new(KVMVmState {
+            vm: self.vm.clone(),
+        })
+    }
+}
+
+pub struct KVMIrqChip {
+    vm: Arc<VirtualMachine>,
+}
+
+// KVM_IRQ_LINE may be issued concurrently from any thread.
+unsafe impl Send for KVMIrqChip {}
+unsafe impl Sync for KVMIrqChip {}
+
+impl IrqChip for KVMIrqChip {
+    fn set_irq_line(&self, irq: u32, active: bool) {
+        self.vm.set_irq_line(irq, active).unwrap();
+    }
+}
+
+pub struct KVMVmState {
+    vm: Arc<VirtualMachine>,
+}
+
+unsafe impl Send for KVMVmState {}
+unsafe impl Sync for KVMVmState {}
+
+impl VmState for KVMVmState {
+    fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        for chip_id in IRQCHIPS.iter() {
+            state.write_pod(&self.vm.get_irqchip(*chip_id).unwrap());
+        }
+        state.write_pod(&self.vm.get_clock().unwrap());
+        state.into_bytes()
+    }
+
+    fn restore_state(&self, data: &[u8]) {
+        let mut state = StateReader::new(data);
+        for _ in IRQCHIPS.iter() {
+            let irqchip: kvm_irqchip = state.read_pod();
+            self.vm.set_irqchip(&irqchip).unwrap();
+        }
+        let mut clock: kvm_clock_data = state.read_pod();
+        // KVM_SET_CLOCK doesn't accept the flags reported by KVM_GET_CLOCK.
+        clock.flags = 0;
+        self.vm.set_clock(&clock).unwrap();
+    }
+}
+
+pub struct KVMVcpu {
+    index: usize,
+    vcpu: VirtualCPU,
+    // The MSRs saved along with the vCPU state.
+    msr_indexes: Vec<u32>,
+}
+
+// Each vCPU is only used by the thread that runs it.
+unsafe impl Send for KVMVcpu {}
 
-        self.vcpus.push(vcpu);
+impl Vcpu for KVMVcpu {
+    fn index(&self) -> usize {
+        self.index
     }
 
-    fn vcpu_run(&mut self, vcpu_index: usize) -> VcpuExit {
-        let ref mut vcpu = self.vcpus[vcpu_index];
+    fn run(&mut self) -> VcpuExit {
+        let ref mut vcpu = self.vcpu;
 
-        vcpu.run().unwrap();
+        if let Err(e) = vcpu.run() {
+            if e.raw_os_error() == Some(libc::EINTR) {
+                return VcpuExit::Interrupted;
+            }
+            panic!("KVM_RUN failed: {}", e);
+        }
         let kvm_run = vcpu.kvm_run_mut();
         match kvm_run.exit_reason {
             KVM_EXIT_HLT => {
@@ -187,6 +310,9 @@ impl Accelerator for KVMAccelerator {
             KVM_EXIT_SHUTDOWN => {
                 VcpuExit::Shutdown
             }
+            KVM_EXIT_INTR => {
+                VcpuExit::Interrupted
+            }
             KVM_EXIT_INTERNAL_ERROR => {
                 unsafe {
                     let suberr = kvm_run.__bindgen_anon_1.internal.suberror;
@@ -205,7 +331,120 @@ impl Accelerator for KVMAccelerator {
         }
     }
 
-    fn memory_region_add(&self, mem: &MmapMemorySlot) {
-        self.vm.set_user_memory_region(mem).unwrap();
+    fn init_long_mode(&mut self, state: &LongModeBootState) {
+        const X86_CR0_PE: u64 = 1 << 0;
+        const X86_CR0_PG: u64 = 1 << 31;
+        const X86_CR4_PAE: u64 = 1 << 5;
+        const EFER_LME: u64 = 1 << 8;
+        const EFER_LMA: u64 = 1 << 10;
+
+        let vcpu = &self.vcpu;
+        let mut sregs = vcpu.get_kvm_sregs().unwrap();
+
+        let code_seg = kvm_segment {
+            base: 0,
+            limit: 0xffffffff,
+            selector: state.code_selector,
+            present: 1,
+            type_: 11,
+            dpl: 0,
+            db: 0,
+            s: 1,
+            l: 1,
+            g: 1,
+            ..Default::default()
+        };
+        let data_seg = kvm_segment {
+            selector: state.data_selector,
+            type_: 3,
+            db: 1,
+            l: 0,
+            ..code_seg
+        };
+
+        sregs.cs = code_seg;
+        sregs.ds = data_seg;
+        sregs.es = data_seg;
+        sregs.fs = data_seg;
+        sregs.gs = data_seg;
+        sregs.ss = data_seg;
+
+        sregs.gdt.base = state.gdt_addr;
+        sregs.gdt.limit = state.gdt_limit;
+
+        sregs.cr3 = state.page_table_addr;
+        sregs.cr4 |= X86_CR4_PAE;
+        sregs.cr0 |= X86_CR0_PE | X86_CR0_PG;
+        sregs.efer |= EFER_LME | EFER_LMA;
+
+        vcpu.set_kvm_sregs(&sregs).unwrap();
+
+        let mut regs = vcpu.get_kvm_regs().unwrap();
+        regs.rip = state.entry_point;
+        regs.rsi = state.boot_params_addr;
+        regs.rflags = 0x2;
+
+        vcpu.set_kvm_regs(&regs).unwrap();
+    }
+
+    fn save_state(&mut self) -> Vec<u8> {
+        // Let KVM complete the last I/O instruction without entering the
+        // guest, otherwise the registers don't reflect it yet.
+        self.vcpu.kvm_run_mut().immediate_exit = 1;
+        let _ = self.vcpu.run();
+        self.vcpu.kvm_run_mut().immediate_exit = 0;
+
+        let vcpu = &self.vcpu;
+        let mut msrs = self.msr_indexes.iter()
+            .map(|index| kvm_msr_entry {
+                index: *index,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        vcpu.get_msrs(&mut msrs).unwrap();
+
+        let mut state = StateWriter::new();
+        state.write_pod(&vcpu.get_kvm_regs().unwrap());
+        state.write_pod(&vcpu.get_kvm_sregs().unwrap());
+        state.write_pod(&vcpu.get_fpu().unwrap());
+        state.write_pod(&vcpu.get_xsave().unwrap());
+        state.write_pod(&vcpu.get_xcrs().unwrap());
+        state.write_pod(&vcpu.get_lapic().unwrap());
+        state.write_u32(msrs.len() as u32);
+        for msr in &msrs {
+            state.write_u32(msr.index);
+            state.write_u64(msr.data);
+        }
+        state.write_pod(&vcpu.get_vcpu_events().unwrap());
+        state.write_pod(&vcpu.get_mp_state().unwrap());
+        state.into_bytes()
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        let vcpu = &self.vcpu;
+        let mut state = StateReader::new(data);
+
+        let regs: kvm_regs = state.read_pod();
+        let sregs: kvm_sregs = state.read_pod();
+        // The APIC base and the control registers come first.
+        vcpu.set_kvm_sregs(&sregs).unwrap();
+        vcpu.set_kvm_regs(&regs).unwrap();
+        vcpu.set_fpu(&state.read_pod()).unwrap();
+        vcpu.set_xsave(&state.read_pod()).unwrap();
+        vcpu.set_xcrs(&state.read_pod()).unwrap();
+        vcpu.set_lapic(&state.read_pod()).unwrap();
+
+        let msr_count = state.read_u32();
+        let msrs = (0..msr_count)
+            .map(|_| kvm_msr_entry {
+                index: state.read_u32(),
+                data: state.read_u64(),
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        vcpu.set_msrs(&msrs).unwrap();
+
+        vcpu.set_vcpu_events(&state.read_pod()).unwrap();
+        vcpu.set_mp_state(&state.read_pod()).unwrap();
     }
 }
@@ -1,7 +1,7 @@
 mod kvm;
 mod base;
 
-use self::base::Accelerator;
+pub use self::base::{Accelerator, IrqChip, Vcpu, VmState};
 use self::kvm::KVMAccelerator;
 
 pub fn new() -> Box<Accelerator> {
@@ -0,0 +1,339 @@
+//! Encoding of the AML objects used by the DSDT and the SSDT. Each function
+//! returns the encoded bytes, which can be nested into the scopes and
+//! devices.
+
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const DUAL_NAME_PREFIX: u8 = 0x2e;
+const MULTI_NAME_PREFIX: u8 = 0x2f;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const DEVICE_OP: u8 = 0x82;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+
+// Resource descriptor tags.
+const IRQ_NO_FLAGS_TAG: u8 = 0x22;
+const IO_TAG: u8 = 0x47;
+const END_TAG: u8 = 0x79;
+const MEMORY32_FIXED_TAG: u8 = 0x86;
+const DWORD_ADDRESS_SPACE_TAG: u8 = 0x87;
+const WORD_ADDRESS_SPACE_TAG: u8 = 0x88;
+
+// Address space resource types.
+const RESOURCE_TYPE_MEMORY: u8 = 0;
+const RESOURCE_TYPE_IO: u8 = 1;
+const RESOURCE_TYPE_BUS_NUMBER: u8 = 2;
+// Produced windows with fixed minimum and maximum addresses.
+const ADDRESS_SPACE_PRODUCER_FLAGS: u8 = 0x0c;
+// I/O windows decoding the whole range.
+const IO_ENTIRE_RANGE: u8 = 0x03;
+const MEMORY_READ_WRITE: u8 = 0x01;
+
+/// Encodes the package length, which covers its own bytes as well.
+fn pkg_length(len: usize) -> Vec<u8> {
+    if len + 1 < 0x40 {
+        return vec![(len + 1) as u8];
+    }
+
+    let count = if len + 2 < 0x1000 {
+        2
+    } else if len + 3 < 0x10_0000 {
+        3
+    } else {
+        4
+    };
+    let total = len + count;
+    // The first byte holds the count of the following bytes and the low
+    // nibble of the length.
+    let mut bytes = vec![((count - 1) << 6) as u8 | (total & 0xf) as u8];
+    for i in 0..count - 1 {
+        bytes.push((total >> (4 + 8 * i)) as u8);
+    }
+    bytes
+}
+
+/// Prepends the opcode and the package length to the contents.
+fn package_object(opcode: &[u8], contents: Vec<u8>) -> Vec<u8> {
+    let mut bytes = opcode.to_vec();
+    bytes.extend(pkg_length(contents.len()));
+    bytes.extend(contents);
+    bytes
+}
+
+fn name_seg(seg: &str) -> Vec<u8> {
+    if seg.is_empty() || seg.len() > 4 {
+        panic!("Invalid AML name segment: {}", seg);
+    }
+    let mut bytes = seg.as_bytes().to_vec();
+    bytes.resize(4, b'_');
+    bytes
+}
+
+/// Encodes a path such as "\_SB.PCI0" or "^COM1".
+pub fn name_string(path: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut path = path;
+    if path.starts_with('\\') {
+        bytes.push(ROOT_CHAR);
+        path = &path[1..];
+    }
+    while path.starts_with('^') {
+        bytes.push(PARENT_PREFIX_CHAR);
+        path = &path[1..];
+    }
+
+    let segs = path.split('.').collect::<Vec<_>>();
+    match segs.len() {
+        1 => (),
+        2 => bytes.push(DUAL_NAME_PREFIX),
+        count => {
+            bytes.push(MULTI_NAME_PREFIX);
+            bytes.push(count as u8);
+        },
+    }
+    for seg in segs {
+        bytes.extend(name_seg(seg));
+    }
+    bytes
+}
+
+/// Encodes an integer using the smallest possible representation.
+pub fn integer(value: u64) -> Vec<u8> {
+    let mut bytes = match value {
+        0 => return vec![ZERO_OP],
+        1 => return vec![ONE_OP],
+        0x2..=0xff => vec![BYTE_PREFIX, 0],
+        0x100..=0xffff => vec![WORD_PREFIX, 0, 0],
+        0x1_0000..=0xffff_ffff => vec![DWORD_PREFIX, 0, 0, 0, 0],
+        _ => vec![QWORD_PREFIX, 0, 0, 0, 0, 0, 0, 0, 0],
+    };
+    let len = bytes.len();
+    LittleEndian::write_uint(&mut bytes[1..], value, len - 1);
+    bytes
+}
+
+/// Encodes an integer as a DWORD whatever its value, leaving room for the
+/// table loader to patch it.
+pub fn dword(value: u32) -> Vec<u8> {
+    let mut bytes = vec![DWORD_PREFIX, 0, 0, 0, 0];
+    LittleEndian::write_u32(&mut bytes[1..], value);
+    bytes
+}
+
+pub fn string(value: &str) -> Vec<u8> {
+    let mut bytes = vec![STRING_PREFIX];
+    bytes.extend_from_slice(value.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+/// Encodes a PNP ID such as "PNP0A03" as a compressed EISA ID.
+pub fn eisa_id(id: &str) -> Vec<u8> {
+    let chars = id.as_bytes();
+    if chars.len() != 7 {
+        panic!("Invalid EISA ID: {}", id);
+    }
+    let product = u16::from_str_radix(&id[3..], 16).unwrap_or_else(
+        |_| panic!("Invalid EISA ID: {}", id));
+    let value = (chars[0] as u32 - 0x40) << 26 |
+                (chars[1] as u32 - 0x40) << 21 |
+                (chars[2] as u32 - 0x40) << 16 |
+                product as u32;
+    // The ID is stored in big-endian order.
+    integer(value.swap_bytes() as u64)
+}
+
+pub fn name(path: &str, value: &[u8]) -> Vec<u8> {
+    let mut bytes = vec![NAME_OP];
+    bytes.extend(name_string(path));
+    bytes.extend_from_slice(value);
+    bytes
+}
+
+pub fn scope(path: &str, children: &[Vec<u8>]) -> Vec<u8> {
+    let mut contents = name_string(path);
+    contents.extend(children.concat());
+    package_object(&[SCOPE_OP], contents)
+}
+
+pub fn device(path: &str, children: &[Vec<u8>]) -> Vec<u8> {
+    let mut contents = name_string(path);
+    contents.extend(children.concat());
+    package_object(&[EXT_OP_PREFIX, DEVICE_OP], contents)
+}
+
+pub fn package(elements: &[Vec<u8>]) -> Vec<u8> {
+    if elements.len() > 0xff {
+        panic!("Too many AML package elements: {}", elements.len());
+    }
+    let mut contents = vec![elements.len() as u8];
+    contents.extend(elements.concat());
+    package_object(&[PACKAGE_OP], contents)
+}
+
+fn buffer(data: &[u8]) -> Vec<u8> {
+    let mut contents = integer(data.len() as u64);
+    contents.extend_from_slice(data);
+    package_object(&[BUFFER_OP], contents)
+}
+
+/// Builds a buffer out of the given resource descriptors.
+pub fn resource_template(descriptors: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = descriptors.concat();
+    // A zero checksum means that the resources are valid.
+    data.extend_from_slice(&[END_TAG, 0]);
+    buffer(&data)
+}
+
+/// An I/O port range with 16-bit address decoding.
+pub fn io(min: u16, max: u16, align: u8, len: u8) -> Vec<u8> {
+    let mut bytes = vec![IO_TAG, 1, 0, 0, 0, 0, align, len];
+    LittleEndian::write_u16(&mut bytes[2..4], min);
+    LittleEndian::write_u16(&mut bytes[4..6], max);
+    bytes
+}
+
+/// An edge triggered, active high ISA interrupt.
+pub fn irq_no_flags(irq: u8) -> Vec<u8> {
+    let mut bytes = vec![IRQ_NO_FLAGS_TAG, 0, 0];
+    LittleEndian::write_u16(&mut bytes[1..], 1 << irq);
+    bytes
+}
+
+pub fn memory32_fixed(base: u32, len: u32, writable: bool) -> Vec<u8> {
+    let mut bytes = vec![MEMORY32_FIXED_TAG, 9, 0, writable as u8];
+    bytes.extend_from_slice(&[0; 8]);
+    LittleEndian::write_u32(&mut bytes[4..8], base);
+    LittleEndian::write_u32(&mut bytes[8..12], len);
+    bytes
+}
+
+fn word_address_space(resource_type: u8, type_flags: u8,
+                      min: u16, max: u16) -> Vec<u8> {
+    let mut bytes = vec![WORD_ADDRESS_SPACE_TAG, 13, 0, resource_type,
+                         ADDRESS_SPACE_PRODUCER_FLAGS, type_flags];
+    bytes.extend_from_slice(&[0; 10]);
+    // Granularity and translation offset are left as zero.
+    LittleEndian::write_u16(&mut bytes[8..10], min);
+    LittleEndian::write_u16(&mut bytes[10..12], max);
+    LittleEndian::write_u16(&mut bytes[14..16], max - min + 1);
+    bytes
+}
+
+/// A range of bus numbers decoded by a host bridge.
+pub fn word_bus_number(min: u16, max: u16) -> Vec<u8> {
+    word_address_space(RESOURCE_TYPE_BUS_NUMBER, 0, min, max)
+}
+
+/// An I/O port window forwarded by a host bridge.
+pub fn word_io(min: u16, max: u16) -> Vec<u8> {
+    word_address_space(RESOURCE_TYPE_IO, IO_ENTIRE_RANGE, min, max)
+}
+
+/// A non-cacheable MMIO window forwarded by a host bridge.
+pub fn dword_memory(min: u32, max: u32) -> Vec<u8> {
+    let mut bytes = vec![DWORD_ADDRESS_SPACE_TAG, 23, 0, RESOURCE_TYPE_MEMORY,
+                         ADDRESS_SPACE_PRODUCER_FLAGS, MEMORY_READ_WRITE];
+    bytes.extend_from_slice(&[0; 20]);
+    LittleEndian::write_u32(&mut bytes[10..14], min);
+    LittleEndian::write_u32(&mut bytes[14..18], max);
+    LittleEndian::write_u32(&mut bytes[22..26], max - min + 1);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_lengths() {
+        assert_eq!(pkg_length(0), vec![0x01]);
+        assert_eq!(pkg_length(0x3e), vec![0x3f]);
+        // Two bytes, the length including them being 0x41.
+        assert_eq!(pkg_length(0x3f), vec![0x41, 0x04]);
+        assert_eq!(pkg_length(0xffd), vec![0x4f, 0xff]);
+        assert_eq!(pkg_length(0xffe), vec![0x81, 0x00, 0x01]);
+        assert_eq!(pkg_length(0x10_0000), vec![0xc4, 0x00, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(integer(0), vec![ZERO_OP]);
+        assert_eq!(integer(1), vec![ONE_OP]);
+        assert_eq!(integer(0x12), vec![BYTE_PREFIX, 0x12]);
+        assert_eq!(integer(0x1234), vec![WORD_PREFIX, 0x34, 0x12]);
+        assert_eq!(integer(0x1234_5678), vec![DWORD_PREFIX, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(integer(1 << 32), vec![QWORD_PREFIX, 0, 0, 0, 0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn dwords() {
+        assert_eq!(dword(0), vec![DWORD_PREFIX, 0, 0, 0, 0]);
+        assert_eq!(dword(40), vec![DWORD_PREFIX, 40, 0, 0, 0]);
+    }
+
+    #[test]
+    fn name_strings() {
+        assert_eq!(name_string("COM1"), b"COM1".to_vec());
+        assert_eq!(name_string("_SB"), b"_SB_".to_vec());
+        assert_eq!(name_string("\\_SB.PCI0"), b"\\\x2e_SB_PCI0".to_vec());
+        assert_eq!(name_string("^^S1.S2.S3"), b"^^\x2f\x03S1__S2__S3__".to_vec());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid AML name segment")]
+    fn long_name_segment() {
+        name_string("\\_SB.TOOLONG");
+    }
+
+    #[test]
+    fn eisa_ids() {
+        assert_eq!(eisa_id("PNP0A03"), vec![DWORD_PREFIX, 0x41, 0xd0, 0x0a, 0x03]);
+        assert_eq!(eisa_id("PNP0501"), vec![DWORD_PREFIX, 0x41, 0xd0, 0x05, 0x01]);
+    }
+
+    #[test]
+    fn objects() {
+        assert_eq!(string("COM"), vec![STRING_PREFIX, b'C', b'O', b'M', 0]);
+        assert_eq!(name("_UID", &integer(0)), b"\x08_UID\x00".to_vec());
+        assert_eq!(package(&[integer(0), integer(1)]),
+                   vec![PACKAGE_OP, 0x04, 0x02, ZERO_OP, ONE_OP]);
+        assert_eq!(scope("\\_SB", &[name("_UID", &integer(0))]),
+                   b"\x10\x0c\\_SB_\x08_UID\x00".to_vec());
+        assert_eq!(device("COM1", &[name("_UID", &integer(0))]),
+                   b"\x5b\x82\x0bCOM1\x08_UID\x00".to_vec());
+    }
+
+    #[test]
+    fn resources() {
+        assert_eq!(irq_no_flags(4), vec![IRQ_NO_FLAGS_TAG, 0x10, 0x00]);
+        let io_port = io(0x3f8, 0x3f8, 1, 8);
+        assert_eq!(io_port, vec![IO_TAG, 1, 0xf8, 0x03, 0xf8, 0x03, 1, 8]);
+        assert_eq!(resource_template(&[io_port.clone()]),
+                   [&[BUFFER_OP, 0x0d, BYTE_PREFIX, 10][..], &io_port,
+                    &[END_TAG, 0]].concat());
+
+        let bus = word_bus_number(0, 0xff);
+        assert_eq!(bus.len(), 3 + bus[1] as usize);
+        assert_eq!(&bus[8..16], &[0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        let memory = dword_memory(0x8000_0000, 0xfebf_ffff);
+        assert_eq!(memory.len(), 3 + memory[1] as usize);
+        assert_eq!(LittleEndian::read_u32(&memory[22..]), 0x7ec0_0000);
+        assert_eq!(memory32_fixed(0xfed0_0000, 0x400, true),
+                   vec![MEMORY32_FIXED_TAG, 9, 0, 1, 0x00, 0x00, 0xd0, 0xfe,
+                        0x00, 0x04, 0x00, 0x00]);
+    }
+}
@@ -0,0 +1,148 @@
+//! Differentiated system description table, describing the PCI host
+//! bridge, the legacy devices and the processors.
+
+use ::devices::acpi_pm::PM_IO_SIZE;
+use ::devices::fw_cfg::{FW_CFG_IO_BASE, FW_CFG_IO_SIZE, FW_CFG_MMIO_SIZE};
+use super::aml::*;
+use super::tables::Sdt;
+use super::AcpiConfig;
+
+// Interrupt pins of each slot, as used by _PRT.
+const PCI_PINS: usize = 4;
+const PCI_SLOTS: u8 = 32;
+
+// End of the 32-bit PCI MMIO window, where the IOAPIC starts.
+const PCI_MMIO_END: u64 = 0xfec0_0000;
+const PCI_MMIO_ALIGNMENT: u64 = 256 << 20;
+
+/// Returns the MMIO ranges left for the PCI BARs below 4GB, as
+/// (first, last) address pairs.
+fn pci_mmio_windows(config: &AcpiConfig) -> Vec<(u64, u64)> {
+    let start = (config.ram_end + PCI_MMIO_ALIGNMENT - 1) &
+                !(PCI_MMIO_ALIGNMENT - 1);
+    let mut windows = Vec::new();
+    match config.ecam {
+        Some((ecam_base, ecam_size)) => {
+            if start < ecam_base {
+                windows.push((start, ecam_base - 1));
+            }
+            windows.push((start.max(ecam_base + ecam_size), PCI_MMIO_END - 1));
+        },
+        None => windows.push((start, PCI_MMIO_END - 1)),
+    }
+    windows.into_iter().filter(|&(first, last)| first < last).collect()
+}
+
+fn pci_host_bridge(config: &AcpiConfig) -> Vec<u8> {
+    let mut children = Vec::new();
+    if config.ecam.is_some() {
+        children.push(name("_HID", &eisa_id("PNP0A08")));
+        children.push(name("_CID", &eisa_id("PNP0A03")));
+    } else {
+        children.push(name("_HID", &eisa_id("PNP0A03")));
+    }
+    children.push(name("_ADR", &integer(0)));
+    children.push(name("_SEG", &integer(0)));
+    children.push(name("_UID", &integer(0)));
+    children.push(name("_BBN", &integer(0)));
+
+    let mut resources = vec![
+        word_bus_number(0, 0xff),
+        io(0xcf8, 0xcf8, 1, 8),
+        word_io(0, 0xcf7),
+        word_io(0xd00, 0xffff),
+    ];
+    for (first, last) in pci_mmio_windows(config) {
+        resources.push(dword_memory(first as u32, last as u32));
+    }
+    children.push(name("_CRS", &resource_template(&resources)));
+
+    // Legacy interrupts, as wired by the PCI root.
+    let mut routes = Vec::new();
+    for slot in 0..PCI_SLOTS {
+        for pin in 0..PCI_PINS {
+            let irq = config.pci_irqs[(slot as usize + pin) % config.pci_irqs.len()];
+            routes.push(package(&[
+                integer((slot as u64) << 16 | 0xffff),
+                integer(pin as u64),
+                integer(0),
+                integer(irq as u64),
+            ]));
+        }
+    }
+    children.push(name("_PRT", &package(&routes)));
+
+    for slot in &config.pci_slots {
+        children.push(device(&format!("S{:02X}", slot), &[
+            name("_ADR", &integer((*slot as u64) << 16)),
+        ]));
+    }
+
+    device("PCI0", &children)
+}
+
+/// Reserves the I/O ports and MMIO ranges used by the chipset, so that the
+/// OS doesn't assign them to PCI devices.
+fn motherboard_resources(config: &AcpiConfig) -> Vec<u8> {
+    let mut resources = vec![
+        io(config.pm_base, config.pm_base, 1, PM_IO_SIZE as u8),
+    ];
+    if let Some((ecam_base, ecam_size)) = config.ecam {
+        resources.push(memory32_fixed(ecam_base as u32, ecam_size as u32, false));
+    }
+    device("MRES", &[
+        name("_HID", &eisa_id("PNP0C02")),
+        name("_UID", &integer(0)),
+        name("_CRS", &resource_template(&resources)),
+    ])
+}
+
+fn serial_port(index: usize, base: u64, irq: u32) -> Vec<u8> {
+    device(&format!("COM{}", index + 1), &[
+        name("_HID", &eisa_id("PNP0501")),
+        name("_UID", &integer(index as u64 + 1)),
+        name("_CRS", &resource_template(&[
+            io(base as u16, base as u16, 1, 8),
+            irq_no_flags(irq as u8),
+        ])),
+    ])
+}
+
+fn fw_cfg(config: &AcpiConfig) -> Vec<u8> {
+    let resource = match config.fw_cfg_mmio {
+        Some(addr) => memory32_fixed(addr as u32, FW_CFG_MMIO_SIZE as u32, true),
+        None => io(FW_CFG_IO_BASE as u16, FW_CFG_IO_BASE as u16, 1,
+                   FW_CFG_IO_SIZE as u8),
+    };
+    device("FWCF", &[
+        name("_HID", &string("QEMU0002")),
+        name("_STA", &integer(0xb)),
+        name("_CRS", &resource_template(&[resource])),
+    ])
+}
+
+fn processor(index: usize) -> Vec<u8> {
+    device(&format!("C{:03X}", index), &[
+        name("_HID", &string("ACPI0007")),
+        name("_UID", &integer(index as u64)),
+    ])
+}
+
+pub fn dsdt(config: &AcpiConfig) -> Vec<u8> {
+    let mut children = vec![
+        pci_host_bridge(config),
+        motherboard_resources(config),
+        fw_cfg(config),
+    ];
+    for (i, &(base, irq)) in config.serial_ports.iter().enumerate() {
+        children.push(serial_port(i, base, irq));
+    }
+    for i in 0..config.vcpu_count {
+        children.push(processor(i));
+    }
+
+    // Revision 2 enables 64-bit integers.
+    let mut table = Sdt::new(b"DSDT", 2);
+    table.append(&scope("\\_SB", &children));
+    table.into_bytes()
+}
@@ -0,0 +1,192 @@
+//! The "etc/table-loader" script, telling the firmware how to place the
+//! ACPI tables in guest memory and how to patch the pointers and
+//! checksums once their addresses are known.
+
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use ::devices::fw_cfg::FWCfgDev;
+use ::devices::fw_cfg::defs::FW_CFG_MAX_FILE_PATH;
+
+const COMMAND_SIZE: usize = 128;
+const FILE_NAME_SIZE: usize = FW_CFG_MAX_FILE_PATH as usize;
+
+const COMMAND_ALLOCATE: u32 = 1;
+const COMMAND_ADD_POINTER: u32 = 2;
+const COMMAND_ADD_CHECKSUM: u32 = 3;
+const COMMAND_WRITE_POINTER: u32 = 4;
+
+/// Where the firmware allocates a file.
+#[derive(Copy, Clone)]
+pub enum Zone {
+    /// Anywhere below 4GB.
+    High = 1,
+    /// The 0xe0000-0xfffff BIOS area, scanned by the OSes for the RSDP.
+    FSeg = 2,
+}
+
+enum Command {
+    Allocate { file: String, align: u32, zone: Zone },
+    /// Adds the address of `src_file` to the `size` bytes integer found at
+    /// `offset` in `dest_file`.
+    AddPointer { dest_file: String, src_file: String, offset: u32, size: u8 },
+    /// Adjusts the byte at `offset` so that the given range sums up to zero.
+    AddChecksum { file: String, offset: u32, start: u32, length: u32 },
+    /// Writes the address of `src_file` plus `src_offset` back to the
+    /// writable fw_cfg file `dest_file`, letting insula know where the
+    /// firmware placed it.
+    WritePointer { dest_file: String, src_file: String, dest_offset: u32,
+                   src_offset: u32, size: u8 },
+}
+
+/// A file placed in guest memory.
+pub struct LoadedFile {
+    pub name: String,
+    pub address: u64,
+    pub data: Vec<u8>,
+}
+
+pub struct TableLoader {
+    commands: Vec<Command>,
+}
+
+fn write_file_name(buf: &mut [u8], name: &str) {
+    if name.len() >= FILE_NAME_SIZE {
+        panic!("fw_cfg file name too long: {}", name);
+    }
+    buf[..name.len()].copy_from_slice(name.as_bytes());
+}
+
+fn find_file<'a>(files: &'a mut [LoadedFile], name: &str) -> &'a mut LoadedFile {
+    files.iter_mut()
+         .find(|f| f.name == name)
+         .unwrap_or_else(|| panic!("Table loader file not loaded: {}", name))
+}
+
+impl TableLoader {
+    pub fn new() -> Self {
+        TableLoader {
+            commands: Vec::new(),
+        }
+    }
+
+    pub fn allocate(&mut self, file: &str, align: u32, zone: Zone) {
+        self.commands.push(Command::Allocate {
+            file: file.to_string(),
+            align: align,
+            zone: zone,
+        });
+    }
+
+    pub fn add_pointer(&mut self, dest_file: &str, src_file: &str,
+                       offset: usize, size: u8) {
+        self.commands.push(Command::AddPointer {
+            dest_file: dest_file.to_string(),
+            src_file: src_file.to_string(),
+            offset: offset as u32,
+            size: size,
+        });
+    }
+
+    pub fn add_checksum(&mut self, file: &str, offset: usize,
+                        start: usize, length: usize) {
+        self.commands.push(Command::AddChecksum {
+            file: file.to_string(),
+            offset: offset as u32,
+            start: start as u32,
+            length: length as u32,
+        });
+    }
+
+    pub fn write_pointer(&mut self, dest_file: &str, src_file: &str,
+                         dest_offset: usize, src_offset: usize, size: u8) {
+        self.commands.push(Command::WritePointer {
+            dest_file: dest_file.to_string(),
+            src_file: src_file.to_string(),
+            dest_offset: dest_offset as u32,
+            src_offset: src_offset as u32,
+            size: size,
+        });
+    }
+
+    /// Serializes the script in the format expected by SeaBIOS and OVMF.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0u8; self.commands.len() * COMMAND_SIZE];
+        for (command, buf) in self.commands.iter()
+                                  .zip(bytes.chunks_mut(COMMAND_SIZE)) {
+            let (opcode, args) = buf.split_at_mut(4);
+            match *command {
+                Command::Allocate { ref file, align, zone } => {
+                    LittleEndian::write_u32(opcode, COMMAND_ALLOCATE);
+                    write_file_name(args, file);
+                    LittleEndian::write_u32(&mut args[FILE_NAME_SIZE..], align);
+                    args[FILE_NAME_SIZE + 4] = zone as u8;
+                },
+                Command::AddPointer { ref dest_file, ref src_file, offset, size } => {
+                    LittleEndian::write_u32(opcode, COMMAND_ADD_POINTER);
+                    write_file_name(args, dest_file);
+                    write_file_name(&mut args[FILE_NAME_SIZE..], src_file);
+                    LittleEndian::write_u32(&mut args[FILE_NAME_SIZE * 2..], offset);
+                    args[FILE_NAME_SIZE * 2 + 4] = size;
+                },
+                Command::AddChecksum { ref file, offset, start, length } => {
+                    LittleEndian::write_u32(opcode, COMMAND_ADD_CHECKSUM);
+                    write_file_name(args, file);
+                    let args = &mut args[FILE_NAME_SIZE..];
+                    LittleEndian::write_u32(&mut args[0..4], offset);
+                    LittleEndian::write_u32(&mut args[4..8], start);
+                    LittleEndian::write_u32(&mut args[8..12], length);
+                },
+                Command::WritePointer { ref dest_file, ref src_file, dest_offset,
+                                        src_offset, size } => {
+                    LittleEndian::write_u32(opcode, COMMAND_WRITE_POINTER);
+                    write_file_name(args, dest_file);
+                    write_file_name(&mut args[FILE_NAME_SIZE..], src_file);
+                    let args = &mut args[FILE_NAME_SIZE * 2..];
+                    LittleEndian::write_u32(&mut args[0..4], dest_offset);
+                    LittleEndian::write_u32(&mut args[4..8], src_offset);
+                    args[8] = size;
+                },
+            }
+        }
+        bytes
+    }
+
+    /// Patches the pointers and checksums of files which were already
+    /// placed by the caller, acting as the firmware would. Used when the
+    /// kernel is booted directly.
+    pub fn link(&self, files: &mut [LoadedFile], fw_cfg: &mut FWCfgDev) {
+        for command in &self.commands {
+            match *command {
+                Command::Allocate { .. } => (),
+                Command::AddPointer { ref dest_file, ref src_file, offset, size } => {
+                    let src_address = find_file(files, src_file).address;
+                    let dest = find_file(files, dest_file);
+                    let field = &mut dest.data[offset as usize..
+                                               offset as usize + size as usize];
+                    let value = LittleEndian::read_uint(field, size as usize);
+                    LittleEndian::write_uint(field, value + src_address,
+                                             size as usize);
+                },
+                Command::AddChecksum { ref file, offset, start, length } => {
+                    let file = find_file(files, file);
+                    let sum = file.data[start as usize..(start + length) as usize]
+                        .iter()
+                        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+                    let checksum = &mut file.data[offset as usize];
+                    *checksum = checksum.wrapping_sub(sum);
+                },
+                Command::WritePointer { ref dest_file, ref src_file, dest_offset,
+                                        src_offset, size } => {
+                    let address = find_file(files, src_file).address +
+                                  src_offset as u64;
+                    let mut value = [0u8; 8];
+                    LittleEndian::write_u64(&mut value, address);
+                    fw_cfg.write_file(dest_file, dest_offset,
+                                      &value[..size as usize]);
+                },
+            }
+        }
+    }
+}
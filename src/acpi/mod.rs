@@ -0,0 +1,201 @@
+//! ACPI tables describing the machine, published to the firmware through
+//! fw_cfg or placed in guest memory when the kernel is booted directly.
+//!
+//! There is no HPET table, as no HPET is modelled: the guests keep time
+//! with the local APIC timers and the kvmclock, calibrated against the PM
+//! timer. Adding one would need a timer device raising interrupts through
+//! the IOAPIC, with its own snapshot state.
+
+mod aml;
+mod dsdt;
+mod loader;
+mod tables;
+mod vmgenid;
+
+use ::devices::acpi_pm::{PM1_CONTROL, PM1_STATUS, PM_TIMER};
+use ::devices::fw_cfg::FWCfgDev;
+use ::memory::GuestMemory;
+use self::loader::{LoadedFile, TableLoader, Zone};
+use self::tables::*;
+
+const TABLES_FILE: &str = "etc/acpi/tables";
+const RSDP_FILE: &str = "etc/acpi/rsdp";
+const LOADER_FILE: &str = "etc/table-loader";
+
+// The FACS has to be 64 bytes aligned.
+const TABLES_ALIGNMENT: usize = 64;
+const RSDP_ALIGNMENT: usize = 16;
+
+// Where the tables are placed for direct kernel boot, in the BIOS area
+// scanned by the OSes for the RSDP. The SMBIOS tables use the upper half.
+const BIOS_AREA_START: u64 = 0xe_0000;
+const BIOS_AREA_END: u64 = 0xf_0000;
+
+/// The machine configuration described by the tables.
+pub struct AcpiConfig {
+    pub vcpu_count: usize,
+    /// End of the RAM below 4GB.
+    pub ram_end: u64,
+    /// Base I/O port of the ACPI PM registers.
+    pub pm_base: u16,
+    pub sci_irq: u8,
+    /// The PCIe ECAM window, if any.
+    pub ecam: Option<(u64, u64)>,
+    /// Device numbers used on the root PCI bus.
+    pub pci_slots: Vec<u8>,
+    /// Legacy interrupts shared by the PCI devices.
+    pub pci_irqs: Vec<u32>,
+    /// Base I/O port and interrupt of each serial port.
+    pub serial_ports: Vec<(u64, u32)>,
+    /// Address of the fw_cfg registers, unless they use I/O ports.
+    pub fw_cfg_mmio: Option<u64>,
+    /// Whether to expose a VM generation ID.
+    pub vmgenid: bool,
+}
+
+pub struct AcpiTables {
+    tables: Vec<u8>,
+    rsdp: Vec<u8>,
+    loader: TableLoader,
+    vmgenid: Option<[u8; 16]>,
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+/// Appends a table to the blob, returning its offset.
+fn add_table(loader: &mut TableLoader, tables: &mut Vec<u8>,
+             table: Vec<u8>) -> usize {
+    let offset = tables.len();
+    loader.add_checksum(TABLES_FILE, offset + SDT_CHECKSUM_OFFSET,
+                        offset, table.len());
+    tables.extend(table);
+    offset
+}
+
+impl AcpiTables {
+    pub fn new(config: &AcpiConfig) -> Self {
+        let mut loader = TableLoader::new();
+        loader.allocate(TABLES_FILE, TABLES_ALIGNMENT as u32, Zone::High);
+
+        let mut tables = facs();
+        let dsdt_offset = add_table(&mut loader, &mut tables,
+                                    dsdt::dsdt(config));
+
+        let pm = PmBlock {
+            pm1_event: config.pm_base + PM1_STATUS as u16,
+            pm1_control: config.pm_base + PM1_CONTROL as u16,
+            pm_timer: config.pm_base + PM_TIMER as u16,
+            sci_irq: config.sci_irq,
+        };
+        let fadt_offset = tables.len();
+        let fadt = fadt(&pm, 0, dsdt_offset as u32);
+        // The pointers have to be patched before computing the checksum.
+        for &(offset, size) in &[(FADT_FIRMWARE_CTRL_OFFSET, 4),
+                                 (FADT_DSDT_OFFSET, 4),
+                                 (FADT_X_DSDT_OFFSET, 8)] {
+            loader.add_pointer(TABLES_FILE, TABLES_FILE, fadt_offset + offset,
+                               size);
+        }
+        add_table(&mut loader, &mut tables, fadt);
+
+        let mut table_offsets = vec![fadt_offset];
+        table_offsets.push(add_table(
+            &mut loader, &mut tables,
+            madt(config.vcpu_count, &config.pci_irqs, config.sci_irq)));
+        if let Some((ecam_base, ecam_size)) = config.ecam {
+            table_offsets.push(add_table(&mut loader, &mut tables,
+                                         mcfg(ecam_base, ecam_size)));
+        }
+        if config.vmgenid {
+            loader.allocate(vmgenid::GUID_FILE, vmgenid::GUID_FILE_SIZE as u32,
+                            Zone::High);
+            let (ssdt, addr_offset) = vmgenid::ssdt();
+            loader.add_pointer(TABLES_FILE, vmgenid::GUID_FILE,
+                               tables.len() + addr_offset, 4);
+            table_offsets.push(add_table(&mut loader, &mut tables, ssdt));
+            loader.write_pointer(vmgenid::ADDR_FILE, vmgenid::GUID_FILE, 0,
+                                 vmgenid::GUID_OFFSET, 8);
+        }
+
+        let xsdt_offset = tables.len();
+        for i in 0..table_offsets.len() {
+            loader.add_pointer(TABLES_FILE, TABLES_FILE,
+                               xsdt_offset + SDT_HEADER_SIZE + i * 8, 8);
+        }
+        let xsdt = xsdt(&table_offsets.iter()
+                                      .map(|offset| *offset as u64)
+                                      .collect::<Vec<_>>());
+        add_table(&mut loader, &mut tables, xsdt);
+
+        loader.allocate(RSDP_FILE, RSDP_ALIGNMENT as u32, Zone::FSeg);
+        loader.add_pointer(RSDP_FILE, TABLES_FILE, RSDP_XSDT_OFFSET, 8);
+        loader.add_checksum(RSDP_FILE, RSDP_CHECKSUM_OFFSET, 0, RSDP_V1_SIZE);
+        loader.add_checksum(RSDP_FILE, RSDP_EXT_CHECKSUM_OFFSET, 0, RSDP_SIZE);
+
+        AcpiTables {
+            tables: tables,
+            rsdp: rsdp(xsdt_offset as u64),
+            loader: loader,
+            vmgenid: if config.vmgenid {
+                Some(vmgenid::random_guid())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Lets the firmware install the tables.
+    pub fn add_to_fw_cfg(&self, fw_cfg: &mut FWCfgDev, mem: &GuestMemory) {
+        fw_cfg.add_file(TABLES_FILE, &self.tables, self.tables.len() as u32);
+        fw_cfg.add_file(RSDP_FILE, &self.rsdp, self.rsdp.len() as u32);
+        if let Some(ref guid) = self.vmgenid {
+            vmgenid::add_to_fw_cfg(guid, fw_cfg, mem);
+        }
+        let script = self.loader.to_bytes();
+        fw_cfg.add_file(LOADER_FILE, &script, script.len() as u32);
+    }
+
+    /// Places the tables in guest memory, for direct kernel boot.
+    pub fn install(&self, mem: &GuestMemory, fw_cfg: &mut FWCfgDev) {
+        let tables_address = BIOS_AREA_START +
+                             align_up(self.rsdp.len(), TABLES_ALIGNMENT) as u64;
+        let mut end = tables_address + self.tables.len() as u64;
+        let guid_address = align_up(end as usize,
+                                    vmgenid::GUID_FILE_SIZE) as u64;
+        if self.vmgenid.is_some() {
+            end = guid_address + vmgenid::GUID_FILE_SIZE as u64;
+        }
+        if end > BIOS_AREA_END {
+            panic!("The ACPI tables don't fit the BIOS area.");
+        }
+
+        let mut files = vec![
+            LoadedFile {
+                name: RSDP_FILE.to_string(),
+                address: BIOS_AREA_START,
+                data: self.rsdp.clone(),
+            },
+            LoadedFile {
+                name: TABLES_FILE.to_string(),
+                address: tables_address,
+                data: self.tables.clone(),
+            },
+        ];
+        if let Some(ref guid) = self.vmgenid {
+            files.push(LoadedFile {
+                name: vmgenid::GUID_FILE.to_string(),
+                address: guid_address,
+                data: vmgenid::guid_file(guid),
+            });
+        }
+        self.loader.link(&mut files, fw_cfg);
+
+        for file in files {
+            if !mem.write(file.address, &file.data) {
+                panic!("Cannot write the ACPI tables at {:x}.", file.address);
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Fixed layout ACPI tables. Pointers to other tables hold offsets within
+//! the tables blob, the table loader turns them into addresses.
+
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+const OEM_ID: &[u8; 6] = b"INSULA";
+const OEM_TABLE_ID_PREFIX: &[u8; 4] = b"ISLA";
+const OEM_REVISION: u32 = 1;
+const CREATOR_ID: &[u8; 4] = b"ISLA";
+const CREATOR_REVISION: u32 = 1;
+
+pub const SDT_HEADER_SIZE: usize = 36;
+/// Offset of the checksum within the system description table header.
+pub const SDT_CHECKSUM_OFFSET: usize = 9;
+
+pub const RSDP_SIZE: usize = 36;
+pub const RSDP_CHECKSUM_OFFSET: usize = 8;
+/// The ACPI 1.0 checksum only covers the first 20 bytes.
+pub const RSDP_V1_SIZE: usize = 20;
+pub const RSDP_EXT_CHECKSUM_OFFSET: usize = 32;
+pub const RSDP_XSDT_OFFSET: usize = 24;
+
+pub const FACS_SIZE: usize = 64;
+
+// FADT fields holding table offsets.
+pub const FADT_FIRMWARE_CTRL_OFFSET: usize = 36;
+pub const FADT_DSDT_OFFSET: usize = 40;
+pub const FADT_X_DSDT_OFFSET: usize = 140;
+const FADT_SIZE: usize = 268;
+const FADT_REVISION: u8 = 5;
+
+// FADT flags.
+const FADT_WBINVD: u32 = 1 << 0;
+const FADT_PROC_C1: u32 = 1 << 2;
+const FADT_PWR_BUTTON: u32 = 1 << 4;
+const FADT_SLP_BUTTON: u32 = 1 << 5;
+
+// IA-PC boot architecture flags.
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+const BOOT_ARCH_CMOS_RTC_NOT_PRESENT: u16 = 1 << 5;
+
+// Generic address structure address spaces.
+const GAS_SYSTEM_IO: u8 = 1;
+const GAS_ACCESS_WORD: u8 = 2;
+const GAS_ACCESS_DWORD: u8 = 3;
+
+// MADT entry types.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_IO_APIC: u8 = 1;
+const MADT_INTERRUPT_OVERRIDE: u8 = 2;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_PCAT_COMPAT: u32 = 1;
+const MADT_LOCAL_APIC_ENABLED: u32 = 1;
+/// Active high, level triggered interrupts.
+const MPS_INTI_LEVEL_HIGH: u16 = 0xd;
+/// The NMI applies to all the processors.
+const ALL_PROCESSORS: u8 = 0xff;
+
+pub const LOCAL_APIC_ADDRESS: u32 = 0xfee0_0000;
+pub const IO_APIC_ADDRESS: u32 = 0xfec0_0000;
+
+/// Power management registers exposed through the FADT.
+pub struct PmBlock {
+    pub pm1_event: u16,
+    pub pm1_control: u16,
+    pub pm_timer: u16,
+    pub sci_irq: u8,
+}
+
+/// A system description table, built by appending fields after the header.
+/// The checksum is left for the table loader to compute.
+pub struct Sdt {
+    data: Vec<u8>,
+}
+
+impl Sdt {
+    pub fn new(signature: &[u8; 4], revision: u8) -> Self {
+        let mut data = vec![0u8; SDT_HEADER_SIZE];
+        data[0..4].copy_from_slice(signature);
+        data[8] = revision;
+        data[10..16].copy_from_slice(OEM_ID);
+        data[16..20].copy_from_slice(OEM_TABLE_ID_PREFIX);
+        data[20..24].copy_from_slice(signature);
+        LittleEndian::write_u32(&mut data[24..28], OEM_REVISION);
+        data[28..32].copy_from_slice(CREATOR_ID);
+        LittleEndian::write_u32(&mut data[32..36], CREATOR_REVISION);
+        Sdt {
+            data: data,
+        }
+    }
+
+    pub fn append(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn append_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn append_u16(&mut self, value: u16) {
+        let mut bytes = [0u8; 2];
+        LittleEndian::write_u16(&mut bytes, value);
+        self.append(&bytes);
+    }
+
+    pub fn append_u32(&mut self, value: u32) {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, value);
+        self.append(&bytes);
+    }
+
+    pub fn append_u64(&mut self, value: u64) {
+        let mut bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bytes, value);
+        self.append(&bytes);
+    }
+
+    /// Appends a generic address structure.
+    fn append_gas(&mut self, space_id: u8, bit_width: u8, access_size: u8,
+                  address: u64) {
+        self.append(&[space_id, bit_width, 0, access_size]);
+        self.append_u64(address);
+    }
+
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        let len = self.data.len() as u32;
+        LittleEndian::write_u32(&mut self.data[4..8], len);
+        self.data
+    }
+}
+
+/// Root system description pointer, pointing to the XSDT.
+pub fn rsdp(xsdt_offset: u64) -> Vec<u8> {
+    let mut data = vec![0u8; RSDP_SIZE];
+    data[0..8].copy_from_slice(b"RSD PTR ");
+    data[9..15].copy_from_slice(OEM_ID);
+    // ACPI 2.0 or later.
+    data[15] = 2;
+    LittleEndian::write_u32(&mut data[20..24], RSDP_SIZE as u32);
+    LittleEndian::write_u64(&mut data[RSDP_XSDT_OFFSET..], xsdt_offset);
+    data
+}
+
+/// Extended system description table, listing the given tables.
+pub fn xsdt(table_offsets: &[u64]) -> Vec<u8> {
+    let mut table = Sdt::new(b"XSDT", 1);
+    for offset in table_offsets {
+        table.append_u64(*offset);
+    }
+    table.into_bytes()
+}
+
+/// Firmware ACPI control structure, holding the waking vector and the
+/// global lock.
+pub fn facs() -> Vec<u8> {
+    let mut data = vec![0u8; FACS_SIZE];
+    data[0..4].copy_from_slice(b"FACS");
+    LittleEndian::write_u32(&mut data[4..8], FACS_SIZE as u32);
+    data[32] = 2;
+    data
+}
+
+/// Fixed ACPI description table. SMI_CMD is left as zero, meaning that
+/// the hardware is always in ACPI mode. The SCI is declared for the OS to
+/// install its handler, but never raised: there are no GPE blocks, the
+/// buttons aren't fixed features and the PM timer overflow isn't signaled.
+pub fn fadt(pm: &PmBlock, facs_offset: u32, dsdt_offset: u32) -> Vec<u8> {
+    let mut table = Sdt::new(b"FACP", FADT_REVISION);
+    table.append_u32(facs_offset);
+    table.append_u32(dsdt_offset);
+    // Reserved, preferred PM profile (unspecified).
+    table.append(&[0, 0]);
+    table.append_u16(pm.sci_irq as u16);
+    // SMI_CMD, ACPI_ENABLE, ACPI_DISABLE, S4BIOS_REQ and PSTATE_CNT.
+    table.append_u32(0);
+    table.append(&[0; 4]);
+    table.append_u32(pm.pm1_event as u32);
+    table.append_u32(0);
+    table.append_u32(pm.pm1_control as u32);
+    table.append_u32(0);
+    // PM2 control block.
+    table.append_u32(0);
+    table.append_u32(pm.pm_timer as u32);
+    // GPE0 and GPE1 blocks.
+    table.append_u32(0);
+    table.append_u32(0);
+    // PM1 event, PM1 control, PM2 control and PM timer lengths.
+    table.append(&[4, 2, 0, 4]);
+    // GPE block lengths, GPE1 base and CST_CNT.
+    table.append(&[0; 4]);
+    // No C2 and C3 states.
+    table.append_u16(0x0fff);
+    table.append_u16(0x0fff);
+    // Flush size and stride, duty cycle, RTC alarms and century.
+    table.append(&[0; 9]);
+    table.append_u16(BOOT_ARCH_LEGACY_DEVICES | BOOT_ARCH_VGA_NOT_PRESENT |
+                     BOOT_ARCH_CMOS_RTC_NOT_PRESENT);
+    table.append_u8(0);
+    table.append_u32(FADT_WBINVD | FADT_PROC_C1 | FADT_PWR_BUTTON |
+                     FADT_SLP_BUTTON);
+    // No reset register.
+    table.append(&[0; 12]);
+    table.append_u8(0);
+    // ARM boot flags and minor version.
+    table.append(&[0; 3]);
+    // X_FIRMWARE_CTRL must be zero when FIRMWARE_CTRL is used.
+    table.append_u64(0);
+    table.append_u64(dsdt_offset as u64);
+    table.append_gas(GAS_SYSTEM_IO, 32, GAS_ACCESS_WORD, pm.pm1_event as u64);
+    table.append(&[0; 12]);
+    table.append_gas(GAS_SYSTEM_IO, 16, GAS_ACCESS_WORD, pm.pm1_control as u64);
+    table.append(&[0; 12]);
+    // PM2 control block.
+    table.append(&[0; 12]);
+    table.append_gas(GAS_SYSTEM_IO, 32, GAS_ACCESS_DWORD, pm.pm_timer as u64);
+    // GPE blocks, sleep control and status registers.
+    table.append(&[0; 48]);
+
+    let data = table.into_bytes();
+    assert_eq!(data.len(), FADT_SIZE);
+    data
+}
+
+/// Multiple APIC description table, listing the local APICs and the
+/// IOAPIC along with the ISA interrupt overrides.
+pub fn madt(vcpu_count: usize, pci_irqs: &[u32], sci_irq: u8) -> Vec<u8> {
+    let mut table = Sdt::new(b"APIC", 3);
+    table.append_u32(LOCAL_APIC_ADDRESS);
+    table.append_u32(MADT_PCAT_COMPAT);
+
+    for i in 0..vcpu_count {
+        // The processor UIDs match the APIC IDs.
+        table.append(&[MADT_LOCAL_APIC, 8, i as u8, i as u8]);
+        table.append_u32(MADT_LOCAL_APIC_ENABLED);
+    }
+
+    table.append(&[MADT_IO_APIC, 12, 0, 0]);
+    table.append_u32(IO_APIC_ADDRESS);
+    table.append_u32(0);
+
+    // The PIT is wired to the IOAPIC pin 2.
+    table.append(&[MADT_INTERRUPT_OVERRIDE, 10, 0, 0]);
+    table.append_u32(2);
+    table.append_u16(0);
+
+    let mut level_irqs = pci_irqs.to_vec();
+    if !level_irqs.contains(&(sci_irq as u32)) {
+        level_irqs.push(sci_irq as u32);
+    }
+    for irq in level_irqs {
+        table.append(&[MADT_INTERRUPT_OVERRIDE, 10, 0, irq as u8]);
+        table.append_u32(irq);
+        table.append_u16(MPS_INTI_LEVEL_HIGH);
+    }
+
+    // LINT1 is wired to NMI.
+    table.append(&[MADT_LOCAL_APIC_NMI, 6, ALL_PROCESSORS]);
+    table.append_u16(0);
+    table.append_u8(1);
+
+    table.into_bytes()
+}
+
+/// PCI express memory mapped configuration table.
+pub fn mcfg(ecam_base: u64, ecam_size: u64) -> Vec<u8> {
+    let mut table = Sdt::new(b"MCFG", 1);
+    table.append_u64(0);
+    table.append_u64(ecam_base);
+    // Segment group, first and last bus numbers.
+    table.append_u16(0);
+    table.append_u8(0);
+    table.append_u8(((ecam_size >> 20) - 1) as u8);
+    table.append_u32(0);
+    table.into_bytes()
+}
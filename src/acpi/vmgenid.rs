@@ -0,0 +1,118 @@
+//! The VM generation ID, a GUID which changes whenever the VM is restored
+//! from a snapshot, telling the guests to reseed their random number
+//! generators.
+//!
+//! The firmware allocates the GUID file and reports its address through
+//! the writable "etc/vmgenid_addr" file, which is how the new GUID is put
+//! in place after a restore. The guests aren't notified, as the SCI is
+//! never raised: they see the change the next time they read the GUID.
+
+extern crate byteorder;
+
+use std::fs::File;
+use std::io::Read;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use ::devices::fw_cfg::{FWCfgDev, FWCfgWriteCallback};
+use ::memory::GuestMemory;
+use super::aml::*;
+use super::tables::Sdt;
+
+pub const GUID_FILE: &str = "etc/vmgenid_guid";
+pub const ADDR_FILE: &str = "etc/vmgenid_addr";
+
+// The GUID gets a page of its own, at the offset used by QEMU.
+pub const GUID_FILE_SIZE: usize = 4096;
+pub const GUID_OFFSET: usize = 40;
+const GUID_SIZE: usize = 16;
+const ADDR_SIZE: usize = 8;
+
+// Size of the high DWORD of the ADDR package and of its prefix, found
+// after the low DWORD.
+const ADDR_HIGH_SIZE: usize = 5;
+
+pub fn random_guid() -> [u8; GUID_SIZE] {
+    let mut guid = [0u8; GUID_SIZE];
+    File::open("/dev/urandom")
+        .and_then(|mut file| file.read_exact(&mut guid))
+        .expect("Cannot generate the VM generation ID.");
+    // Version 4, variant 1.
+    guid[7] = (guid[7] & 0x0f) | 0x40;
+    guid[8] = (guid[8] & 0x3f) | 0x80;
+    guid
+}
+
+pub fn guid_file(guid: &[u8; GUID_SIZE]) -> Vec<u8> {
+    let mut data = vec![0u8; GUID_FILE_SIZE];
+    data[GUID_OFFSET..GUID_OFFSET + GUID_SIZE].copy_from_slice(guid);
+    data
+}
+
+/// Secondary system description table holding the VGEN device. Returns
+/// the table and the offset of the low DWORD of the GUID address, which
+/// holds `GUID_OFFSET` for the table loader to add the file address to.
+pub fn ssdt() -> (Vec<u8>, usize) {
+    let vgen = device("VGEN", &[
+        name("_HID", &string("VM_GEN_COUNTER")),
+        name("_CID", &string("VM_Gen_Counter")),
+        name("_DDN", &string("VM_Gen_Counter")),
+        // Kept last, so that the address is at the end of the table.
+        name("ADDR", &package(&[dword(GUID_OFFSET as u32), dword(0)])),
+    ]);
+
+    let mut table = Sdt::new(b"SSDT", 2);
+    table.append(&scope("\\_SB", &[vgen]));
+    let table = table.into_bytes();
+    let addr_offset = table.len() - ADDR_HIGH_SIZE - 4;
+    (table, addr_offset)
+}
+
+/// Adds the GUID file and the address file written back by the firmware.
+/// The GUID is copied to guest memory whenever the address is written,
+/// including when the fw_cfg state is restored.
+pub fn add_to_fw_cfg(guid: &[u8; GUID_SIZE], fw_cfg: &mut FWCfgDev,
+                     mem: &GuestMemory) {
+    let data = guid_file(guid);
+    fw_cfg.add_file(GUID_FILE, &data, data.len() as u32);
+
+    let guid = *guid;
+    let mem = mem.clone();
+    let write_cb: FWCfgWriteCallback = Box::new(move |buf, offset, len| {
+        // Ignore the partial writes, the address is only valid once
+        // complete.
+        if offset + len != ADDR_SIZE {
+            return;
+        }
+        let address = LittleEndian::read_u64(buf);
+        if address != 0 && !mem.write(address, &guid) {
+            println!("Invalid VM generation ID address: {:x}", address);
+        }
+    });
+    fw_cfg.add_file_callback(ADDR_FILE, &[0u8; ADDR_SIZE], ADDR_SIZE as u32,
+                             None, Some(write_cb), true);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_at_end_of_ssdt() {
+        let (table, addr_offset) = ssdt();
+        assert_eq!(table[addr_offset - 1], 0x0c);
+        assert_eq!(LittleEndian::read_u32(&table[addr_offset..]),
+                   GUID_OFFSET as u32);
+        assert_eq!(table[addr_offset + 4], 0x0c);
+        assert_eq!(addr_offset + 4 + ADDR_HIGH_SIZE, table.len());
+    }
+
+    #[test]
+    fn guid_in_file() {
+        let guid = random_guid();
+        assert_eq!(guid[7] >> 4, 4);
+        let data = guid_file(&guid);
+        assert_eq!(data.len(), GUID_FILE_SIZE);
+        assert_eq!(&data[GUID_OFFSET..GUID_OFFSET + GUID_SIZE], &guid[..]);
+    }
+}
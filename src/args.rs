@@ -8,13 +8,203 @@ pub fn parse_args<'a>() -> &'a ArgMatches<'a>{
              .long("fw")
              .help("Firmware path.")
              .takes_value(true)
-             .required(true))
+             .required_unless("kernel"))
         .arg(Arg::with_name("memory_mb")
              .long("memory_mb")
              .help("The amount of RAM memory, in MB.")
              .takes_value(true)
              .required(false)
-             .default_value("128"));
+             .default_value("128"))
+        .arg(Arg::with_name("memory_backend")
+             .long("memory-backend")
+             .help("The host memory backing the guest RAM: \
+                    [backend=anon|hugetlb|memfd|file][,path=<path>]\
+                    [,pagesize=2M|1G][,prealloc-threads=<count>]\
+                    [,mlock=on|off][,host-nodes=<node>[:<node>...]] \
+                    (pagesize is only accepted by hugetlb and memfd)")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("machine")
+             .long("machine")
+             .help("The machine type.")
+             .takes_value(true)
+             .possible_values(&["pc", "q35"])
+             .required(false)
+             .default_value("pc"))
+        .arg(Arg::with_name("pcie_root_ports")
+             .long("pcie-root-ports")
+             .help("The number of PCIe root ports. Only used by the q35 \
+                    machine type.")
+             .takes_value(true)
+             .required(false)
+             .default_value("0"))
+        .arg(Arg::with_name("smbios")
+             .long("smbios")
+             .help("Overrides the SMBIOS system information: \
+                    [manufacturer=<name>][,product=<name>]\
+                    [,version=<version>][,serial=<serial>][,uuid=<uuid>]\
+                    [,oem-string=<string>]...")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("fw_cfg_mmio")
+             .long("fw-cfg-mmio")
+             .help("Maps fw_cfg at the given MMIO address instead of the \
+                    I/O port 0x510.")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("no_acpi")
+             .long("no-acpi")
+             .help("Doesn't expose the ACPI tables to the guest.")
+             .required(false))
+        .arg(Arg::with_name("vmgenid")
+             .long("vmgenid")
+             .help("Exposes a VM generation ID, changed when restoring a snapshot.")
+             .required(false))
+        .arg(Arg::with_name("smp")
+             .long("smp")
+             .help("The number of vCPUs.")
+             .takes_value(true)
+             .required(false)
+             .default_value("1"))
+        .arg(Arg::with_name("kernel")
+             .long("kernel")
+             .help("Linux bzImage path. Unless a firmware is specified, \
+                    the kernel will be booted directly. Otherwise it is \
+                    loaded by the firmware, which requires OVMF: SeaBIOS \
+                    needs the linuxboot option ROM, which isn't provided.")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("initrd")
+             .long("initrd")
+             .help("Initial ramdisk path.")
+             .takes_value(true)
+             .required(false)
+             .requires("kernel"))
+        .arg(Arg::with_name("cmdline")
+             .long("cmdline")
+             .alias("append")
+             .help("The kernel command line.")
+             .takes_value(true)
+             .required(false)
+             .requires("kernel"))
+        .arg(Arg::with_name("drive")
+             .long("drive")
+             .help("Adds a virtio disk: file=<path>[,format=raw|qcow2]\
+                    [,readonly=on|off][,serial=<serial>]. Images are used \
+                    as raw unless a format is specified.")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .required(false))
+        .arg(Arg::with_name("netdev")
+             .long("netdev")
+             .help("Adds a virtio NIC: tap,ifname=<name>[,queues=<n>] or \
+                    unix,path=<path>[,type=dgram|seqpacket][,peer=<path>]\
+                    [,server=on|off] or user[,hostfwd=tcp|udp:[<hostaddr>]:\
+                    <hostport>-[<guestaddr>]:<guestport>]...\
+                    [,loopback=on|off] (on maps 10.0.2.2 to the host \
+                    loopback). All accept \
+                    [,mac=<addr>][,pcap=<path>].")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .required(false))
+        .arg(Arg::with_name("console-port")
+             .long("console-port")
+             .help("Adds a port to the virtio console: chardev=<spec>\
+                    [,name=<name>][,console=on|off]. The chardev spec \
+                    accepts the same values as --serial.")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .required(false))
+        .arg(Arg::with_name("rng")
+             .long("rng")
+             .help("Adds a virtio entropy device, optionally rate limited: \
+                    [max-bytes=<n>][,period=<ms>]. The period defaults \
+                    to 1000ms.")
+             .takes_value(true)
+             .min_values(0)
+             .required(false))
+        .arg(Arg::with_name("vsock")
+             .long("vsock")
+             .help("Adds a virtio socket device bridged to host unix \
+                    sockets: cid=<guest cid>,path=<path>. Guest \
+                    connections to port P go to <path>_P, host clients \
+                    connect to <path> and send \"CONNECT <port>\\n\".")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("share")
+             .long("share")
+             .help("Shares a host directory through virtio-9p: \
+                    path=<path>,tag=<tag>[,readonly=on|off][,trusted=on|off]\
+                    [,uid-map=<guest uid>:<host uid>]\
+                    [,gid-map=<guest gid>:<host gid>]. The guest mounts \
+                    it with \"mount -t 9p -o trans=virtio,version=9p2000.L \
+                    <tag> <dir>\". The guest can only create regular files, \
+                    FIFOs and sockets, without set-user-ID or set-group-ID \
+                    bits unless trusted=on.")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .required(false))
+        .arg(Arg::with_name("balloon")
+             .long("balloon")
+             .help("Adds a virtio memory balloon, driven through the \
+                    control socket.")
+             .required(false))
+        .arg(Arg::with_name("control")
+             .long("control")
+             .help("Unix socket path of the control interface, accepting \
+                    one command per line. Use \"help\" to list the \
+                    commands. The \"snapshot\" command fails when \
+                    --console-port, --vsock or --share is used, as the \
+                    state of these devices isn't saved.")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("restore")
+             .long("restore")
+             .help("Resumes the VM from a snapshot, taken using the \
+                    \"snapshot\" control command. The other arguments must \
+                    match the ones used when the snapshot was taken.")
+             .takes_value(true)
+             .required(false))
+        .arg(Arg::with_name("restore_ram")
+             .long("restore-ram")
+             .help("How the guest RAM of a snapshot is restored: copied \
+                    upfront (copy), mapped copy-on-write (map), or read on \
+                    first access using userfaultfd (uffd). The lazy modes \
+                    share the unmodified pages between the VMs restored \
+                    from the same snapshot.")
+             .takes_value(true)
+             .required(false)
+             .default_value("copy"))
+        .arg(Arg::with_name("serial")
+             .long("serial")
+             .help("Adds a serial port, using the next free COM port. \
+                    Accepted values: stdio, file:<path>, unix:<path>, \
+                    pty, ringbuf[:<size>], none. The ring buffers are \
+                    printed on exit and returned by the \"ringbuf <name>\" \
+                    control command, e.g. \"ringbuf serial0\".")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .max_values(4)
+             .required(false))
+        .arg(Arg::with_name("debugcon")
+             .long("debugcon")
+             .help("The backend used by the debug console (port 0x402). \
+                    Accepts the same values as --serial.")
+             .takes_value(true)
+             .required(false)
+             .default_value("stdio"))
+        .arg(Arg::with_name("postcode")
+             .long("postcode")
+             .help("The backend used for the POST codes (port 0x80). \
+                    Accepts the same values as --serial.")
+             .takes_value(true)
+             .required(false)
+             .default_value("stdio"));
 
     unsafe {
         INSULA_ARGS = Some(insula_app.get_matches());
@@ -0,0 +1,124 @@
+//! Disk images used by the guest storage devices.
+
+mod qcow2;
+mod raw;
+
+use std::fs::OpenOptions;
+use std::io;
+
+use ::utils::options::{parse_bool, parse_key_values};
+
+/// A disk image, addressed using guest offsets.
+pub trait BlockBackend: Send {
+    /// The disk size, in bytes.
+    fn size(&self) -> u64;
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    /// Lets the backend release the given range. This is only a hint,
+    /// the range contents are undefined afterwards.
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()>;
+    /// Zeroes the given range, releasing the underlying storage if
+    /// `unmap` is set.
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool)
+        -> io::Result<()>;
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    Raw,
+    Qcow2,
+}
+
+impl ImageFormat {
+    fn from_str(format: &str) -> Option<ImageFormat> {
+        match format {
+            "raw" => Some(ImageFormat::Raw),
+            "qcow2" => Some(ImageFormat::Qcow2),
+            _ => None,
+        }
+    }
+}
+
+/// A disk attached through the --drive option.
+pub struct DriveConfig {
+    pub path: String,
+    pub read_only: bool,
+    /// Raw unless specified. The format is never probed: the guest could
+    /// write a qcow2 header referring to host files on a raw disk.
+    pub format: ImageFormat,
+    /// Reported to the guest as the disk serial number.
+    pub serial: String,
+}
+
+impl DriveConfig {
+    /// Parses a "file=<path>[,format=raw|qcow2][,readonly=on|off]
+    /// [,serial=<serial>]" spec.
+    pub fn parse(spec: &str) -> Self {
+        let mut path = None;
+        let mut read_only = false;
+        let mut format = ImageFormat::Raw;
+        let mut serial = String::new();
+
+        for (key, value) in parse_key_values(spec) {
+            match key.as_str() {
+                "file" => path = Some(value),
+                "readonly" => read_only = parse_bool(&key, &value),
+                "format" => format = ImageFormat::from_str(&value)
+                    .unwrap_or_else(|| panic!("Unsupported image format: {}",
+                                              value)),
+                "serial" => serial = value,
+                _ => panic!("Unknown drive option: {}", key),
+            }
+        }
+
+        DriveConfig {
+            path: path.unwrap_or_else(
+                || panic!("Missing drive path: {}", spec)),
+            read_only: read_only,
+            format: format,
+            serial: serial,
+        }
+    }
+}
+
+/// Opens a disk image.
+pub fn open(path: &str, format: ImageFormat, read_only: bool)
+        -> io::Result<Box<BlockBackend>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .open(path)?;
+
+    match format {
+        ImageFormat::Raw => Ok(Box::new(raw::RawFile::new(file)?)),
+        ImageFormat::Qcow2 => Ok(Box::new(
+            qcow2::QcowFile::new(file, path, read_only)?)),
+    }
+}
+
+/// Opens the disk image of a drive, panicking on failure.
+pub fn open_drive(drive: &DriveConfig) -> Box<BlockBackend> {
+    match open(&drive.path, drive.format, drive.read_only) {
+        Ok(backend) => backend,
+        Err(e) => panic!("Could not open disk image {}: {}", drive.path, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drives_are_raw_unless_specified() {
+        let drive = DriveConfig::parse("file=disk.img,serial=abc");
+        assert_eq!(drive.path, "disk.img");
+        assert_eq!(drive.format, ImageFormat::Raw);
+        assert_eq!(drive.serial, "abc");
+        assert!(!drive.read_only);
+
+        let drive = DriveConfig::parse("file=disk.qcow2,format=qcow2,readonly=on");
+        assert_eq!(drive.format, ImageFormat::Qcow2);
+        assert!(drive.read_only);
+    }
+}
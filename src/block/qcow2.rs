@@ -0,0 +1,1009 @@
+extern crate byteorder;
+extern crate flate2;
+
+use self::byteorder::{BigEndian, ByteOrder};
+use self::flate2::read::DeflateDecoder;
+
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+
+use super::{BlockBackend, ImageFormat};
+
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+const REFCOUNT_TABLE_OFFSET_OFFSET: u64 = 48;
+const AUTOCLEAR_FEATURES_OFFSET: u64 = 88;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+const MAX_BACKING_FILE_SIZE: u32 = 1023;
+// Limits the memory used by the L1 and refcount tables.
+const MAX_TABLE_ENTRIES: u64 = 32 << 20;
+const MAX_BACKING_CHAIN_LENGTH: usize = 16;
+
+// Incompatible feature bits.
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+const INCOMPAT_CORRUPT: u64 = 1 << 1;
+const INCOMPAT_SUPPORTED: u64 = INCOMPAT_DIRTY | INCOMPAT_CORRUPT;
+
+// Header extensions.
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+const L1_ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_ENTRY_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+// The cluster is only referenced once, it may be written in place.
+const CLUSTER_COPIED: u64 = 1 << 63;
+const CLUSTER_COMPRESSED: u64 = 1 << 62;
+// Version 3 only.
+const CLUSTER_ZERO: u64 = 1 << 0;
+
+const COMPRESSED_SECTOR_SIZE: u64 = 512;
+
+const L2_CACHE_SIZE: usize = 64;
+
+fn is_qcow2(magic: &[u8]) -> bool {
+    magic.len() >= 4 && BigEndian::read_u32(magic) == QCOW_MAGIC
+}
+
+fn invalid_image(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("qcow2: {}", msg))
+}
+
+/// Reads at the given offset, zero filling the bytes beyond the end
+/// of the file.
+fn read_at_zero_fill(file: &File, buf: &mut [u8], offset: u64)
+        -> io::Result<()> {
+    let mut done = 0;
+    while done < buf.len() {
+        match file.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(count) => done += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    for byte in buf[done..].iter_mut() {
+        *byte = 0;
+    }
+    Ok(())
+}
+
+fn read_table(file: &File, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0u8; entries as usize * 8];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf.chunks(8).map(BigEndian::read_u64).collect())
+}
+
+fn write_u64_at(file: &File, value: u64, offset: u64) -> io::Result<()> {
+    let mut buf = [0u8; 8];
+    BigEndian::write_u64(&mut buf, value);
+    file.write_all_at(&buf, offset)
+}
+
+/// A qcow2 (version 2 or 3) image. Encryption, external data files and
+/// extended L2 entries are not supported, compressed clusters are
+/// read-only: writing to them allocates a new cluster.
+pub struct QcowFile {
+    file: File,
+    read_only: bool,
+    version: u32,
+    size: u64,
+    cluster_bits: u32,
+    cluster_size: u64,
+    l2_entries: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    refcount_bytes: u64,
+    // L2 tables by host offset, kept in sync with the image.
+    l2_cache: HashMap<u64, Vec<u64>>,
+    // The last decompressed cluster.
+    compressed_cache: Option<(u64, Vec<u8>)>,
+    backing: Option<Box<BlockBackend>>,
+    // New clusters are always appended to the image.
+    next_free_offset: u64,
+}
+
+impl QcowFile {
+    pub fn new(file: File, path: &str, read_only: bool) -> io::Result<Self> {
+        QcowFile::open(file, path, read_only, 0)
+    }
+
+    fn open(file: File, path: &str, read_only: bool, depth: usize)
+            -> io::Result<Self> {
+        let mut header = [0u8; V3_HEADER_SIZE];
+        file.read_exact_at(&mut header[..V2_HEADER_SIZE], 0)?;
+        if !is_qcow2(&header) {
+            return Err(invalid_image("invalid magic"));
+        }
+
+        let version = BigEndian::read_u32(&header[4..]);
+        let backing_file_offset = BigEndian::read_u64(&header[8..]);
+        let backing_file_size = BigEndian::read_u32(&header[16..]);
+        let cluster_bits = BigEndian::read_u32(&header[20..]);
+        let size = BigEndian::read_u64(&header[24..]);
+        let crypt_method = BigEndian::read_u32(&header[32..]);
+        let l1_size = BigEndian::read_u32(&header[36..]) as u64;
+        let l1_table_offset = BigEndian::read_u64(&header[40..]);
+        let refcount_table_offset = BigEndian::read_u64(&header[48..]);
+        let refcount_table_clusters = BigEndian::read_u32(&header[56..]) as u64;
+
+        let (incompatible_features, autoclear_features, refcount_order,
+             header_length) = match version {
+            2 => (0, 0, 4, V2_HEADER_SIZE as u64),
+            3 => {
+                file.read_exact_at(&mut header[V2_HEADER_SIZE..],
+                                   V2_HEADER_SIZE as u64)?;
+                (BigEndian::read_u64(&header[72..]),
+                 BigEndian::read_u64(&header[88..]),
+                 BigEndian::read_u32(&header[96..]),
+                 BigEndian::read_u32(&header[100..]) as u64)
+            },
+            _ => return Err(invalid_image(
+                &format!("unsupported version {}", version))),
+        };
+
+        if cluster_bits < MIN_CLUSTER_BITS || cluster_bits > MAX_CLUSTER_BITS {
+            return Err(invalid_image("invalid cluster size"));
+        }
+        if crypt_method != 0 {
+            return Err(invalid_image("encrypted images are not supported"));
+        }
+        if incompatible_features & !INCOMPAT_SUPPORTED != 0 {
+            return Err(invalid_image(&format!(
+                "unsupported incompatible features: {:x}",
+                incompatible_features & !INCOMPAT_SUPPORTED)));
+        }
+        if !read_only && incompatible_features & INCOMPAT_CORRUPT != 0 {
+            return Err(invalid_image(
+                "the image is marked as corrupt, it can only be used read-only"));
+        }
+        if !read_only && incompatible_features & INCOMPAT_DIRTY != 0 {
+            return Err(invalid_image(
+                "the refcounts need to be repaired (qemu-img check -r all)"));
+        }
+        // Only byte aligned refcounts can be updated.
+        if refcount_order > 6 || (!read_only && refcount_order < 3) {
+            return Err(invalid_image("unsupported refcount width"));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let l2_entries = cluster_size / 8;
+        let l1_needed = cluster_size.checked_mul(l2_entries)
+            .and_then(|l2_coverage| size.checked_add(l2_coverage - 1)
+                                        .map(|end| end / l2_coverage))
+            .ok_or_else(|| invalid_image("invalid image size"))?;
+        if l1_size < l1_needed || l1_size > MAX_TABLE_ENTRIES {
+            return Err(invalid_image("invalid L1 table size"));
+        }
+        let refcount_table_entries = refcount_table_clusters * cluster_size / 8;
+        if refcount_table_entries > MAX_TABLE_ENTRIES {
+            return Err(invalid_image("invalid refcount table size"));
+        }
+
+        let l1_table = read_table(&file, l1_table_offset, l1_size)?;
+        let refcount_table = read_table(&file, refcount_table_offset,
+                                        refcount_table_entries)?;
+
+        // The format of the backing file is never probed, as for the
+        // image itself.
+        let backing_format = QcowFile::read_backing_format(
+            &file, header_length, backing_file_offset, cluster_size)?;
+        let backing = if backing_file_offset != 0 {
+            if backing_file_size > MAX_BACKING_FILE_SIZE {
+                return Err(invalid_image("invalid backing file name"));
+            }
+            let mut name = vec![0u8; backing_file_size as usize];
+            file.read_exact_at(&mut name, backing_file_offset)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid_image("invalid backing file name"))?;
+            Some(QcowFile::open_backing(path, &name, backing_format, depth)?)
+        } else {
+            None
+        };
+
+        if !read_only && autoclear_features != 0 {
+            // Let other tools know that the extensions these bits refer to
+            // (e.g. bitmaps) weren't kept up to date.
+            write_u64_at(&file, 0, AUTOCLEAR_FEATURES_OFFSET)?;
+        }
+
+        let file_size = file.metadata()?.len();
+        let next_free_offset = (file_size + cluster_size - 1) & !(cluster_size - 1);
+
+        Ok(QcowFile {
+            file: file,
+            read_only: read_only,
+            version: version,
+            size: size,
+            cluster_bits: cluster_bits,
+            cluster_size: cluster_size,
+            l2_entries: l2_entries,
+            l1_table_offset: l1_table_offset,
+            l1_table: l1_table,
+            refcount_table_offset: refcount_table_offset,
+            refcount_table: refcount_table,
+            refcount_bytes: (1u64 << refcount_order) / 8,
+            l2_cache: HashMap::new(),
+            compressed_cache: None,
+            backing: backing,
+            next_free_offset: next_free_offset,
+        })
+    }
+
+    /// Parses the header extensions, looking for the backing file format.
+    fn read_backing_format(file: &File, header_length: u64,
+                           backing_file_offset: u64, cluster_size: u64)
+            -> io::Result<Option<ImageFormat>> {
+        // The extensions end before the backing file name, if any.
+        let end = if backing_file_offset != 0 {
+            cmp::min(backing_file_offset, cluster_size)
+        } else {
+            cluster_size
+        };
+
+        let mut offset = header_length;
+        while offset + 8 <= end {
+            let mut ext_header = [0u8; 8];
+            file.read_exact_at(&mut ext_header, offset)?;
+            let ext_type = BigEndian::read_u32(&ext_header[0..]);
+            let ext_len = BigEndian::read_u32(&ext_header[4..]) as u64;
+            offset += 8;
+
+            match ext_type {
+                HEADER_EXT_END => break,
+                HEADER_EXT_BACKING_FORMAT if ext_len <= 16 => {
+                    let mut name = vec![0u8; ext_len as usize];
+                    file.read_exact_at(&mut name, offset)?;
+                    return match String::from_utf8_lossy(&name).as_ref() {
+                        "raw" => Ok(Some(ImageFormat::Raw)),
+                        "qcow2" => Ok(Some(ImageFormat::Qcow2)),
+                        format => Err(invalid_image(&format!(
+                            "unsupported backing file format: {}", format))),
+                    };
+                },
+                _ => (),
+            }
+            // Extensions are padded to 8 bytes.
+            offset += (ext_len + 7) & !7;
+        }
+        Ok(None)
+    }
+
+    /// Opens the backing file, which must be in the directory of the image
+    /// or below it, so that images can't expose arbitrary host files.
+    fn open_backing(path: &str, name: &str, format: Option<ImageFormat>,
+                    depth: usize) -> io::Result<Box<BlockBackend>> {
+        if depth >= MAX_BACKING_CHAIN_LENGTH {
+            return Err(invalid_image("the backing chain is too long"));
+        }
+        let format = format.ok_or(invalid_image(
+            "the backing file format is not specified \
+             (qemu-img rebase -u -F <format>)"))?;
+
+        // Relative paths are based on the image location.
+        let image_dir = fs::canonicalize(path)?.parent().unwrap().to_path_buf();
+        let backing_path = fs::canonicalize(image_dir.join(name))?;
+        if !backing_path.starts_with(&image_dir) {
+            return Err(invalid_image(&format!(
+                "the backing file {} is outside of the image directory",
+                backing_path.display())));
+        }
+        let backing_path = backing_path.to_string_lossy().into_owned();
+
+        let file = File::open(&backing_path)?;
+        match format {
+            ImageFormat::Raw => Ok(Box::new(super::raw::RawFile::new(file)?)),
+            ImageFormat::Qcow2 => Ok(Box::new(
+                QcowFile::open(file, &backing_path, true, depth + 1)?)),
+        }
+    }
+
+    fn cluster_offset(&self, addr: u64) -> u64 {
+        addr & (self.cluster_size - 1)
+    }
+
+    fn l1_index(&self, addr: u64) -> usize {
+        (addr >> self.cluster_bits) as usize / self.l2_entries as usize
+    }
+
+    fn l2_index(&self, addr: u64) -> usize {
+        ((addr >> self.cluster_bits) & (self.l2_entries - 1)) as usize
+    }
+
+    fn load_l2_table(&mut self, l2_offset: u64) -> io::Result<()> {
+        if self.l2_cache.contains_key(&l2_offset) {
+            return Ok(());
+        }
+        if self.l2_cache.len() >= L2_CACHE_SIZE {
+            self.l2_cache.clear();
+        }
+        let table = read_table(&self.file, l2_offset, self.l2_entries)?;
+        self.l2_cache.insert(l2_offset, table);
+        Ok(())
+    }
+
+    /// Returns the L2 entry describing the given guest address, 0 if
+    /// not allocated.
+    fn l2_entry(&mut self, addr: u64) -> io::Result<u64> {
+        let l2_offset = self.l1_table[self.l1_index(addr)] & L1_ENTRY_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        self.load_l2_table(l2_offset)?;
+        Ok(self.l2_cache[&l2_offset][self.l2_index(addr)])
+    }
+
+    fn set_l2_entry(&mut self, l2_offset: u64, l2_index: usize, entry: u64)
+            -> io::Result<()> {
+        write_u64_at(&self.file, entry, l2_offset + l2_index as u64 * 8)?;
+        if let Some(table) = self.l2_cache.get_mut(&l2_offset) {
+            table[l2_index] = entry;
+        }
+        Ok(())
+    }
+
+    fn read_compressed(&mut self, entry: u64) -> io::Result<&[u8]> {
+        let cached = match self.compressed_cache {
+            Some((cached_entry, _)) => cached_entry == entry,
+            None => false,
+        };
+        if !cached {
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let host_offset = entry & ((1 << offset_bits) - 1);
+            let sectors = ((entry & !(CLUSTER_COPIED | CLUSTER_COMPRESSED)) >>
+                           offset_bits) + 1;
+            let len = sectors * COMPRESSED_SECTOR_SIZE -
+                      (host_offset & (COMPRESSED_SECTOR_SIZE - 1));
+
+            let mut compressed = vec![0u8; len as usize];
+            read_at_zero_fill(&self.file, &mut compressed, host_offset)?;
+            let mut data = vec![0u8; self.cluster_size as usize];
+            DeflateDecoder::new(&compressed[..]).read_exact(&mut data)
+                .map_err(|_| invalid_image("invalid compressed cluster"))?;
+            self.compressed_cache = Some((entry, data));
+        }
+
+        match self.compressed_cache {
+            Some((_, ref data)) => Ok(data),
+            None => unreachable!(),
+        }
+    }
+
+    fn read_backing(&mut self, buf: &mut [u8], addr: u64) -> io::Result<()> {
+        let backing_size = match self.backing {
+            Some(ref backing) => backing.size(),
+            None => 0,
+        };
+        // The backing file may be smaller than the image.
+        let count = cmp::min(buf.len() as u64,
+                             backing_size.saturating_sub(addr)) as usize;
+        if count > 0 {
+            self.backing.as_mut().unwrap().read_at(&mut buf[..count], addr)?;
+        }
+        for byte in buf[count..].iter_mut() {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    /// Reads within a single cluster.
+    fn read_cluster(&mut self, buf: &mut [u8], addr: u64) -> io::Result<()> {
+        let entry = self.l2_entry(addr)?;
+        let offset = self.cluster_offset(addr) as usize;
+
+        if entry & CLUSTER_COMPRESSED != 0 {
+            let data = self.read_compressed(entry)?;
+            buf.copy_from_slice(&data[offset..offset + buf.len()]);
+        } else if self.version >= 3 && entry & CLUSTER_ZERO != 0 {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+        } else if entry & L2_ENTRY_OFFSET_MASK == 0 {
+            self.read_backing(buf, addr)?;
+        } else {
+            read_at_zero_fill(&self.file, buf,
+                              (entry & L2_ENTRY_OFFSET_MASK) + offset as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the refcount table index covering the given host cluster,
+    /// along with the offset of its refcount in the refcount block.
+    fn refcount_index(&self, host_offset: u64) -> (usize, u64) {
+        let cluster = host_offset >> self.cluster_bits;
+        let refcounts_per_block = self.cluster_size / self.refcount_bytes;
+        ((cluster / refcounts_per_block) as usize,
+         (cluster % refcounts_per_block) * self.refcount_bytes)
+    }
+
+    /// Returns the location of the refcount of the given host cluster.
+    fn refcount_offset(&self, host_offset: u64) -> io::Result<Option<u64>> {
+        let (table_index, block_offset) = self.refcount_index(host_offset);
+        if table_index >= self.refcount_table.len() {
+            return Err(invalid_image("the refcount table is full"));
+        }
+
+        let block = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block == 0 {
+            return Ok(None);
+        }
+        Ok(Some(block + block_offset))
+    }
+
+    fn get_refcount(&self, refcount_offset: u64) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        let len = self.refcount_bytes as usize;
+        self.file.read_exact_at(&mut buf[..len], refcount_offset)?;
+        Ok(BigEndian::read_uint(&buf[..len], len))
+    }
+
+    fn set_refcount(&self, refcount_offset: u64, refcount: u64) -> io::Result<()> {
+        let mut buf = [0u8; 8];
+        let len = self.refcount_bytes as usize;
+        BigEndian::write_uint(&mut buf[..len], refcount, len);
+        self.file.write_all_at(&buf[..len], refcount_offset)
+    }
+
+    /// Drops a reference to the given host cluster.
+    fn release_cluster(&mut self, host_offset: u64) -> io::Result<()> {
+        if let Some(refcount_offset) = self.refcount_offset(host_offset)? {
+            let refcount = self.get_refcount(refcount_offset)?;
+            if refcount > 0 {
+                self.set_refcount(refcount_offset, refcount - 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the refcount table to the end of the image, making it hold at
+    /// least `min_entries` entries. The refcount blocks covering the new
+    /// table are allocated right after it.
+    fn grow_refcount_table(&mut self, min_entries: usize) -> io::Result<()> {
+        let entries_per_cluster = (self.cluster_size / 8) as usize;
+        let mut entries = cmp::max(min_entries, self.refcount_table.len() * 2);
+        let (table, table_offset, new_clusters) = loop {
+            entries = (entries + entries_per_cluster - 1) /
+                      entries_per_cluster * entries_per_cluster;
+            if entries as u64 > MAX_TABLE_ENTRIES {
+                return Err(invalid_image("the refcount table is full"));
+            }
+
+            let table_offset = self.next_free_offset;
+            let table_clusters = (entries / entries_per_cluster) as u64;
+            let mut table = self.refcount_table.clone();
+            table.resize(entries, 0);
+            let mut new_clusters = (0..table_clusters)
+                .map(|i| table_offset + (i << self.cluster_bits))
+                .collect::<Vec<_>>();
+            let mut end = table_offset + (table_clusters << self.cluster_bits);
+
+            // The new refcount blocks have to be covered as well.
+            let mut i = 0;
+            while i < new_clusters.len() {
+                let (table_index, _) = self.refcount_index(new_clusters[i]);
+                if table_index >= entries {
+                    break;
+                }
+                if table[table_index] == 0 {
+                    table[table_index] = end;
+                    new_clusters.push(end);
+                    end += self.cluster_size;
+                }
+                i += 1;
+            }
+            if i == new_clusters.len() {
+                break (table, table_offset, new_clusters);
+            }
+            entries *= 2;
+        };
+
+        let zeroes = vec![0u8; self.cluster_size as usize];
+        for &host_offset in &new_clusters {
+            self.file.write_all_at(&zeroes, host_offset)?;
+        }
+        for &host_offset in &new_clusters {
+            let (table_index, block_offset) = self.refcount_index(host_offset);
+            self.set_refcount(table[table_index] + block_offset, 1)?;
+        }
+        let mut buf = vec![0u8; table.len() * 8];
+        for (i, entry) in table.iter().enumerate() {
+            BigEndian::write_u64(&mut buf[i * 8..], *entry);
+        }
+        self.file.write_all_at(&buf, table_offset)?;
+        self.file.sync_data()?;
+
+        // Switch to the new table, the offset and cluster count are
+        // updated at once.
+        let mut header = [0u8; 12];
+        BigEndian::write_u64(&mut header[0..], table_offset);
+        BigEndian::write_u32(&mut header[8..],
+                             (buf.len() / self.cluster_size as usize) as u32);
+        self.file.write_all_at(&header, REFCOUNT_TABLE_OFFSET_OFFSET)?;
+
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = (self.refcount_table.len() / entries_per_cluster) as u64;
+        self.refcount_table = table;
+        self.refcount_table_offset = table_offset;
+        self.next_free_offset = new_clusters.last().unwrap() + self.cluster_size;
+        for i in 0..old_clusters {
+            self.release_cluster(old_offset + (i << self.cluster_bits))?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a zeroed cluster at the end of the image.
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let zeroes = vec![0u8; self.cluster_size as usize];
+        loop {
+            let host_offset = self.next_free_offset;
+            self.file.write_all_at(&zeroes, host_offset)?;
+            self.next_free_offset += self.cluster_size;
+
+            let (table_index, _) = self.refcount_index(host_offset);
+            if table_index >= self.refcount_table.len() {
+                self.grow_refcount_table(table_index + 1)?;
+            }
+            match self.refcount_offset(host_offset)? {
+                Some(refcount_offset) => {
+                    self.set_refcount(refcount_offset, 1)?;
+                    return Ok(host_offset);
+                },
+                None => {
+                    // Use this cluster as the refcount block covering it,
+                    // then try again.
+                    let refcounts_per_block = self.cluster_size /
+                                              self.refcount_bytes;
+                    let table_index = ((host_offset >> self.cluster_bits) /
+                                       refcounts_per_block) as usize;
+                    self.refcount_table[table_index] = host_offset;
+                    write_u64_at(&self.file, host_offset,
+                                 self.refcount_table_offset +
+                                     table_index as u64 * 8)?;
+                    let refcount_offset = self.refcount_offset(host_offset)?
+                                              .unwrap();
+                    self.set_refcount(refcount_offset, 1)?;
+                },
+            }
+        }
+    }
+
+    /// Returns the L2 table for the given guest address, allocating it
+    /// or copying it if shared with a snapshot.
+    fn l2_table_for_write(&mut self, addr: u64) -> io::Result<u64> {
+        let l1_index = self.l1_index(addr);
+        let l1_entry = self.l1_table[l1_index];
+        let l2_offset = l1_entry & L1_ENTRY_OFFSET_MASK;
+        if l2_offset != 0 && l1_entry & CLUSTER_COPIED != 0 {
+            return Ok(l2_offset);
+        }
+
+        let new_offset = self.allocate_cluster()?;
+        if l2_offset != 0 {
+            self.load_l2_table(l2_offset)?;
+            let mut buf = vec![0u8; self.cluster_size as usize];
+            for (i, entry) in self.l2_cache[&l2_offset].iter().enumerate() {
+                BigEndian::write_u64(&mut buf[i * 8..], *entry);
+            }
+            self.file.write_all_at(&buf, new_offset)?;
+            self.release_cluster(l2_offset)?;
+        }
+
+        let l1_entry = new_offset | CLUSTER_COPIED;
+        write_u64_at(&self.file, l1_entry,
+                     self.l1_table_offset + l1_index as u64 * 8)?;
+        self.l1_table[l1_index] = l1_entry;
+        Ok(new_offset)
+    }
+
+    /// Returns the host cluster that may be written for the given guest
+    /// address. `overwrite` is set when the whole cluster is written,
+    /// in which case the current contents don't have to be copied.
+    fn cluster_for_write(&mut self, addr: u64, overwrite: bool) -> io::Result<u64> {
+        let l2_offset = self.l2_table_for_write(addr)?;
+        self.load_l2_table(l2_offset)?;
+        let l2_index = self.l2_index(addr);
+        let entry = self.l2_cache[&l2_offset][l2_index];
+        let host_offset = entry & L2_ENTRY_OFFSET_MASK;
+
+        let compressed = entry & CLUSTER_COMPRESSED != 0;
+        let zero = self.version >= 3 && entry & CLUSTER_ZERO != 0;
+        if entry & CLUSTER_COPIED != 0 && !compressed && !zero && host_offset != 0 {
+            return Ok(host_offset);
+        }
+
+        let cluster_addr = addr & !(self.cluster_size - 1);
+        let mut data = vec![0u8; self.cluster_size as usize];
+        if !overwrite {
+            self.read_cluster(&mut data, cluster_addr)?;
+        }
+
+        let new_offset = self.allocate_cluster()?;
+        self.file.write_all_at(&data, new_offset)?;
+        self.set_l2_entry(l2_offset, l2_index, new_offset | CLUSTER_COPIED)?;
+
+        // Compressed clusters may share host clusters, they are leaked.
+        if !compressed && host_offset != 0 {
+            self.release_cluster(host_offset)?;
+        }
+        Ok(new_offset)
+    }
+
+    /// Makes the given guest cluster read as zeroes without allocating
+    /// it. Returns false if not possible for this image.
+    fn zero_cluster(&mut self, addr: u64) -> io::Result<bool> {
+        let new_entry = if self.version >= 3 {
+            CLUSTER_ZERO
+        } else if self.backing.is_none() {
+            if self.l1_table[self.l1_index(addr)] & L1_ENTRY_OFFSET_MASK == 0 {
+                return Ok(true);
+            }
+            0
+        } else {
+            return Ok(false);
+        };
+
+        let l2_offset = self.l2_table_for_write(addr)?;
+        self.load_l2_table(l2_offset)?;
+        let l2_index = self.l2_index(addr);
+        let entry = self.l2_cache[&l2_offset][l2_index];
+        self.set_l2_entry(l2_offset, l2_index, new_entry)?;
+
+        let host_offset = entry & L2_ENTRY_OFFSET_MASK;
+        if entry & CLUSTER_COMPRESSED == 0 && host_offset != 0 {
+            self.release_cluster(host_offset)?;
+        }
+        Ok(true)
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                      "read-only image"));
+        }
+        Ok(())
+    }
+
+    /// Splits a guest range into chunks that don't cross clusters.
+    fn cluster_chunks(&self, offset: u64, len: u64) -> Vec<(u64, u64)> {
+        let mut chunks = Vec::new();
+        let mut addr = offset;
+        while addr < offset + len {
+            let count = cmp::min(offset + len - addr,
+                                 self.cluster_size - self.cluster_offset(addr));
+            chunks.push((addr, count));
+            addr += count;
+        }
+        chunks
+    }
+}
+
+impl BlockBackend for QcowFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        for (addr, count) in self.cluster_chunks(offset, buf.len() as u64) {
+            let start = (addr - offset) as usize;
+            self.read_cluster(&mut buf[start..start + count as usize], addr)?;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check_writable()?;
+        for (addr, count) in self.cluster_chunks(offset, buf.len() as u64) {
+            let start = (addr - offset) as usize;
+            let host_offset = self.cluster_for_write(
+                addr, count == self.cluster_size)?;
+            self.file.write_all_at(&buf[start..start + count as usize],
+                                   host_offset + self.cluster_offset(addr))?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        // Partial clusters are left untouched.
+        for (addr, count) in self.cluster_chunks(offset, len) {
+            if count == self.cluster_size {
+                self.zero_cluster(addr)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, _unmap: bool)
+            -> io::Result<()> {
+        self.check_writable()?;
+        // Zeroed clusters are always unmapped.
+        for (addr, count) in self.cluster_chunks(offset, len) {
+            if count != self.cluster_size || !self.zero_cluster(addr)? {
+                let zeroes = vec![0u8; count as usize];
+                self.write_at(&zeroes, addr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use ::utils::test_dir::TestDir;
+
+    // Small clusters with 64-bit refcounts: the initial refcount table
+    // covers 2MB of image.
+    const CLUSTER_BITS: u32 = 9;
+    const CLUSTER_SIZE: u64 = 1 << CLUSTER_BITS;
+    const REFCOUNT_ORDER: u32 = 6;
+
+    fn write_file(path: &str, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    /// Creates an empty version 3 image, using one cluster for the header,
+    /// the refcount table and the refcount block each, followed by the L1
+    /// table.
+    fn create_image(path: &str, size: u64,
+                    backing: Option<(&str, Option<&str>)>) {
+        let l2_coverage = CLUSTER_SIZE * (CLUSTER_SIZE / 8);
+        let l1_size = (size + l2_coverage - 1) / l2_coverage;
+        let clusters = 3 + (l1_size * 8 + CLUSTER_SIZE - 1) / CLUSTER_SIZE;
+
+        let mut image = vec![0u8; (clusters * CLUSTER_SIZE) as usize];
+        BigEndian::write_u32(&mut image[0..], QCOW_MAGIC);
+        BigEndian::write_u32(&mut image[4..], 3);
+        BigEndian::write_u32(&mut image[20..], CLUSTER_BITS);
+        BigEndian::write_u64(&mut image[24..], size);
+        BigEndian::write_u32(&mut image[36..], l1_size as u32);
+        BigEndian::write_u64(&mut image[40..], 3 * CLUSTER_SIZE);
+        BigEndian::write_u64(&mut image[48..], CLUSTER_SIZE);
+        BigEndian::write_u32(&mut image[56..], 1);
+        BigEndian::write_u32(&mut image[96..], REFCOUNT_ORDER);
+        BigEndian::write_u32(&mut image[100..], V3_HEADER_SIZE as u32);
+
+        BigEndian::write_u64(&mut image[CLUSTER_SIZE as usize..],
+                             2 * CLUSTER_SIZE);
+        for i in 0..clusters as usize {
+            BigEndian::write_u64(
+                &mut image[2 * CLUSTER_SIZE as usize + i * 8..], 1);
+        }
+
+        if let Some((name, format)) = backing {
+            let mut offset = V3_HEADER_SIZE;
+            if let Some(format) = format {
+                BigEndian::write_u32(&mut image[offset..],
+                                     HEADER_EXT_BACKING_FORMAT);
+                BigEndian::write_u32(&mut image[offset + 4..],
+                                     format.len() as u32);
+                image[offset + 8..offset + 8 + format.len()]
+                    .copy_from_slice(format.as_bytes());
+                offset += 8 + ((format.len() + 7) & !7);
+            }
+            // The end of the extensions.
+            offset += 8;
+            image[offset..offset + name.len()].copy_from_slice(name.as_bytes());
+            BigEndian::write_u64(&mut image[8..], offset as u64);
+            BigEndian::write_u32(&mut image[16..], name.len() as u32);
+        }
+        write_file(path, &image);
+    }
+
+    fn open_image(path: &str) -> io::Result<QcowFile> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        QcowFile::new(file, path, false)
+    }
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed))
+                .collect()
+    }
+
+    fn refcount(image: &QcowFile, host_offset: u64) -> u64 {
+        match image.refcount_offset(host_offset).unwrap() {
+            Some(refcount_offset) => image.get_refcount(refcount_offset).unwrap(),
+            None => 0,
+        }
+    }
+
+    #[test]
+    fn unallocated_clusters_read_as_zeroes() {
+        let dir = TestDir::new("qcow2-unallocated");
+        create_image(&dir.file("disk.qcow2"), 1 << 20, None);
+        let mut image = open_image(&dir.file("disk.qcow2")).unwrap();
+
+        assert_eq!(image.size(), 1 << 20);
+        let mut buf = vec![0xffu8; 3000];
+        image.read_at(&mut buf, 1000).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn written_data_is_read_back() {
+        let dir = TestDir::new("qcow2-write");
+        let path = dir.file("disk.qcow2");
+        create_image(&path, 1 << 20, None);
+
+        // Crosses clusters and L2 tables.
+        let offset = 64 * CLUSTER_SIZE - 100;
+        let data = pattern(3000, 1);
+        {
+            let mut image = open_image(&path).unwrap();
+            image.write_at(&data, offset).unwrap();
+            let mut buf = vec![0u8; data.len()];
+            image.read_at(&mut buf, offset).unwrap();
+            assert_eq!(buf, data);
+        }
+
+        let mut image = open_image(&path).unwrap();
+        let mut buf = vec![0u8; data.len() + 200];
+        image.read_at(&mut buf, offset - 100).unwrap();
+        assert!(buf[..100].iter().all(|&b| b == 0));
+        assert_eq!(&buf[100..100 + data.len()], &data[..]);
+        assert!(buf[100 + data.len()..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn allocated_clusters_are_referenced_once() {
+        let dir = TestDir::new("qcow2-refcounts");
+        let path = dir.file("disk.qcow2");
+        create_image(&path, 1 << 20, None);
+        let mut image = open_image(&path).unwrap();
+
+        image.write_at(&pattern(100, 2), 5 * CLUSTER_SIZE).unwrap();
+        let l1_entry = image.l1_table[0];
+        assert!(l1_entry & CLUSTER_COPIED != 0);
+        let l2_entry = image.l2_entry(5 * CLUSTER_SIZE).unwrap();
+        assert!(l2_entry & CLUSTER_COPIED != 0);
+        assert_eq!(refcount(&image, l1_entry & L1_ENTRY_OFFSET_MASK), 1);
+        assert_eq!(refcount(&image, l2_entry & L2_ENTRY_OFFSET_MASK), 1);
+
+        // Clusters only referenced once are written in place.
+        let file_size = image.file.metadata().unwrap().len();
+        image.write_at(&pattern(100, 3), 5 * CLUSTER_SIZE).unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), file_size);
+        assert_eq!(image.l2_entry(5 * CLUSTER_SIZE).unwrap(), l2_entry);
+    }
+
+    #[test]
+    fn zeroed_clusters_are_released() {
+        let dir = TestDir::new("qcow2-zeroes");
+        let path = dir.file("disk.qcow2");
+        create_image(&path, 1 << 20, None);
+        let mut image = open_image(&path).unwrap();
+
+        image.write_at(&pattern(CLUSTER_SIZE as usize, 4), 0).unwrap();
+        let host_offset = image.l2_entry(0).unwrap() & L2_ENTRY_OFFSET_MASK;
+        image.write_zeroes(0, CLUSTER_SIZE, true).unwrap();
+
+        assert_eq!(image.l2_entry(0).unwrap(), CLUSTER_ZERO);
+        assert_eq!(refcount(&image, host_offset), 0);
+        let mut buf = vec![0xffu8; CLUSTER_SIZE as usize];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn refcount_table_grows() {
+        let dir = TestDir::new("qcow2-grow");
+        let path = dir.file("disk.qcow2");
+        let size = 4 << 20;
+        create_image(&path, size, None);
+        let data = pattern(size as usize, 5);
+        {
+            let mut image = open_image(&path).unwrap();
+            for offset in (0..size).step_by(CLUSTER_SIZE as usize) {
+                let start = offset as usize;
+                image.write_at(&data[start..start + CLUSTER_SIZE as usize],
+                               offset).unwrap();
+            }
+            assert!(image.refcount_table.len() > (CLUSTER_SIZE / 8) as usize);
+            assert!(image.refcount_table_offset != CLUSTER_SIZE);
+
+            // The old table was released, the data clusters and the new
+            // table are referenced.
+            assert_eq!(refcount(&image, CLUSTER_SIZE), 0);
+            assert_eq!(refcount(&image, image.refcount_table_offset), 1);
+            for offset in (0..size).step_by(CLUSTER_SIZE as usize) {
+                let entry = image.l2_entry(offset).unwrap();
+                assert_eq!(refcount(&image, entry & L2_ENTRY_OFFSET_MASK), 1);
+            }
+        }
+
+        let mut image = open_image(&path).unwrap();
+        assert!(image.refcount_table_offset != CLUSTER_SIZE);
+        let mut buf = vec![0u8; size as usize];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf == data);
+    }
+
+    #[test]
+    fn backing_file_is_read_and_copied_on_write() {
+        let dir = TestDir::new("qcow2-backing");
+        let base = pattern(4 * CLUSTER_SIZE as usize, 6);
+        write_file(&dir.file("base.raw"), &base);
+        let path = dir.file("overlay.qcow2");
+        create_image(&path, 1 << 20, Some(("base.raw", Some("raw"))));
+        let mut image = open_image(&path).unwrap();
+
+        let mut buf = vec![0u8; 2 * CLUSTER_SIZE as usize];
+        image.read_at(&mut buf, CLUSTER_SIZE).unwrap();
+        assert_eq!(&buf[..], &base[CLUSTER_SIZE as usize..3 * CLUSTER_SIZE as usize]);
+
+        // The rest of the cluster is copied from the backing file.
+        image.write_at(&[0xaa; 10], CLUSTER_SIZE + 10).unwrap();
+        let mut expected = base[CLUSTER_SIZE as usize..2 * CLUSTER_SIZE as usize]
+            .to_vec();
+        expected[10..20].copy_from_slice(&[0xaa; 10]);
+        let mut buf = vec![0u8; CLUSTER_SIZE as usize];
+        image.read_at(&mut buf, CLUSTER_SIZE).unwrap();
+        assert_eq!(buf, expected);
+
+        // Beyond the end of the backing file.
+        image.read_at(&mut buf, 8 * CLUSTER_SIZE).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn backing_file_outside_of_the_image_directory() {
+        let dir = TestDir::new("qcow2-backing-outside");
+        let other_dir = TestDir::new("qcow2-backing-outside-other");
+        write_file(&other_dir.file("base.raw"), &[0u8; 512]);
+
+        let path = dir.file("overlay.qcow2");
+        create_image(&path, 1 << 20,
+                     Some((&other_dir.file("base.raw"), Some("raw"))));
+        assert!(open_image(&path).is_err());
+
+        let name = format!("../{}/base.raw",
+                           other_dir.path.file_name().unwrap().to_str().unwrap());
+        create_image(&path, 1 << 20, Some((&name, Some("raw"))));
+        assert!(open_image(&path).is_err());
+    }
+
+    #[test]
+    fn backing_file_format_is_required() {
+        let dir = TestDir::new("qcow2-backing-format");
+        write_file(&dir.file("base.raw"), &[0u8; 512]);
+        let path = dir.file("overlay.qcow2");
+        create_image(&path, 1 << 20, Some(("base.raw", None)));
+        assert!(open_image(&path).is_err());
+    }
+
+    #[test]
+    fn invalid_headers_are_rejected() {
+        let dir = TestDir::new("qcow2-invalid");
+        let path = dir.file("disk.qcow2");
+        create_image(&path, 1 << 20, None);
+        let file = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut cluster_bits = [0u8; 4];
+        BigEndian::write_u32(&mut cluster_bits, MAX_CLUSTER_BITS + 1);
+        file.write_all_at(&cluster_bits, 20).unwrap();
+        assert!(open_image(&path).is_err());
+
+        create_image(&path, 1 << 20, None);
+        let mut size = [0u8; 8];
+        BigEndian::write_u64(&mut size, u64::max_value());
+        file.write_all_at(&size, 24).unwrap();
+        assert!(open_image(&path).is_err());
+
+        write_file(&path, &[0u8; 512]);
+        assert!(open_image(&path).is_err());
+    }
+}
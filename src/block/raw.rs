@@ -0,0 +1,82 @@
+extern crate libc;
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use super::BlockBackend;
+
+/// A raw disk image, mapped one to one to the guest disk.
+pub struct RawFile {
+    file: File,
+    size: u64,
+}
+
+impl RawFile {
+    pub fn new(file: File) -> io::Result<Self> {
+        let size = file.metadata()?.len();
+        Ok(RawFile {
+            file: file,
+            size: size,
+        })
+    }
+
+    fn fallocate(&self, mode: libc::c_int, offset: u64, len: u64)
+            -> io::Result<()> {
+        let ret = unsafe {
+            libc::fallocate64(self.file.as_raw_fd(), mode,
+                              offset as libc::off64_t, len as libc::off64_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl BlockBackend for RawFile {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn discard(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        self.fallocate(libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                       offset, len)
+    }
+
+    fn write_zeroes(&mut self, offset: u64, len: u64, unmap: bool)
+            -> io::Result<()> {
+        let mode = if unmap {
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE
+        } else {
+            libc::FALLOC_FL_ZERO_RANGE | libc::FALLOC_FL_KEEP_SIZE
+        };
+        if self.fallocate(mode, offset, len).is_ok() {
+            return Ok(());
+        }
+
+        // Not supported by the host filesystem.
+        let zeroes = vec![0u8; cmp::min(len, 1 << 20) as usize];
+        let mut done = 0;
+        while done < len {
+            let count = cmp::min(len - done, zeroes.len() as u64) as usize;
+            self.file.write_all_at(&zeroes[..count], offset + done)?;
+            done += count as u64;
+        }
+        Ok(())
+    }
+}
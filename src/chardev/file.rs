@@ -0,0 +1,29 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+
+use super::CharBackend;
+
+/// Appends guest output to a file.
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    pub fn new(path: &str) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .unwrap_or_else(|_| panic!("Cannot open \"{}\".", path));
+
+        FileBackend {
+            file: file,
+        }
+    }
+}
+
+impl CharBackend for FileBackend {
+    fn write(&mut self, data: &[u8]) {
+        let _ = self.file.write_all(data);
+    }
+}
@@ -0,0 +1,97 @@
+//! Host side endpoints for guest facing character devices.
+
+mod file;
+mod pty;
+mod ringbuf;
+mod socket;
+mod stdio;
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub use self::ringbuf::{add_control_commands, ring_buffers};
+
+/// Guest facing device, receiving data from the host.
+pub trait CharFrontend: Send {
+    /// Returns the number of bytes that were accepted.
+    fn receive(&mut self, data: &[u8]) -> usize;
+}
+
+/// Host side endpoint of a character device.
+#[allow(unused_variables)]
+pub trait CharBackend: Send {
+    /// Sends guest output to the host. Data that can't be
+    /// written is dropped, the guest can't do much about it anyway.
+    fn write(&mut self, data: &[u8]);
+    /// Starts forwarding host input to the given frontend. Output only
+    /// backends ignore this.
+    fn connect_input(&mut self, frontend: Arc<Mutex<CharFrontend>>) {}
+}
+
+/// Discards guest output.
+pub struct NullBackend {}
+
+impl CharBackend for NullBackend {
+    fn write(&mut self, _data: &[u8]) {}
+}
+
+/// Creates a character device backend based on a command line spec:
+/// stdio, file:<path>, unix:<path>, pty, ringbuf[:<size>] or none.
+/// `name` identifies the device when reporting the pty path or
+/// querying ring buffers.
+pub fn new(spec: &str, name: &str) -> Box<CharBackend> {
+    let (kind, arg) = match spec.find(':') {
+        Some(idx) => (&spec[..idx], Some(&spec[idx + 1..])),
+        None => (spec, None),
+    };
+
+    match (kind, arg) {
+        ("stdio", None) => Box::new(stdio::StdioBackend::new()),
+        ("file", Some(path)) => Box::new(file::FileBackend::new(path)),
+        ("unix", Some(path)) => Box::new(
+            socket::UnixSocketBackend::new(path, name)),
+        ("pty", None) => Box::new(pty::PtyBackend::new(name)),
+        ("ringbuf", size) => {
+            let size = size.map(|s| s.parse::<usize>().unwrap_or_else(
+                |_| panic!("Invalid ring buffer size: {}", s)));
+            Box::new(ringbuf::RingBufferBackend::new(name, size))
+        },
+        ("none", None) => Box::new(NullBackend {}),
+        _ => panic!("Invalid character device spec for {}: {}", name, spec),
+    }
+}
+
+/// Passes host input to the frontend, waiting for it to make room
+/// when needed.
+fn deliver_input(frontend: &Mutex<CharFrontend>, data: &[u8]) {
+    let mut pending = data;
+    while !pending.is_empty() {
+        let accepted = frontend.lock().unwrap().receive(pending);
+        pending = &pending[accepted..];
+        if !pending.is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+/// Spawns a thread forwarding the data read by `read_fn` to the frontend
+/// until it returns 0.
+fn spawn_input_thread<F>(name: &str, frontend: Arc<Mutex<CharFrontend>>,
+                         mut read_fn: F)
+    where F: FnMut(&mut [u8]) -> usize + Send + 'static
+{
+    thread::Builder::new()
+        .name(format!("{}-input", name))
+        .spawn(move || {
+            let mut buf = [0u8; 1024];
+            loop {
+                let count = read_fn(&mut buf);
+                if count == 0 {
+                    break;
+                }
+                deliver_input(&frontend, &buf[..count]);
+            }
+        })
+        .unwrap();
+}
@@ -0,0 +1,92 @@
+extern crate libc;
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ::utils::posix::os::errno;
+use super::{spawn_input_thread, CharBackend, CharFrontend};
+
+/// Allocates a pseudo terminal, printing the slave path so that users
+/// can attach to it (e.g. using screen or minicom).
+pub struct PtyBackend {
+    master: File,
+}
+
+impl PtyBackend {
+    pub fn new(name: &str) -> Self {
+        let (master, slave_path) = open_pty();
+        println!("{}: redirected to {}", name, slave_path);
+
+        PtyBackend {
+            master: master,
+        }
+    }
+}
+
+fn open_pty() -> (File, String) {
+    unsafe {
+        // Non-blocking, the guest must not wait for the slave to be
+        // drained.
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY |
+                                    libc::O_NONBLOCK);
+        if fd < 0 {
+            panic!("posix_openpt failed with: {}", errno());
+        }
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            panic!("Cannot unlock pty, error: {}", errno());
+        }
+
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) == 0 {
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(fd, libc::TCSANOW, &termios);
+        }
+
+        let slave_path = CStr::from_ptr(libc::ptsname(fd))
+            .to_string_lossy()
+            .into_owned();
+        (File::from_raw_fd(fd), slave_path)
+    }
+}
+
+fn wait_readable(file: &File) {
+    let mut pollfd = libc::pollfd {
+        fd: file.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe {
+        libc::poll(&mut pollfd, 1, -1);
+    }
+}
+
+impl CharBackend for PtyBackend {
+    fn write(&mut self, data: &[u8]) {
+        // Fails with EIO while nobody has the slave open, the output
+        // that doesn't fit is dropped while the slave isn't drained.
+        let _ = self.master.write_all(data);
+    }
+
+    fn connect_input(&mut self, frontend: Arc<Mutex<CharFrontend>>) {
+        let mut master = self.master.try_clone().unwrap();
+        spawn_input_thread("pty", frontend, move |buf| {
+            loop {
+                match master.read(buf) {
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                        wait_readable(&master);
+                    },
+                    Ok(0) | Err(_) => {
+                        // Wait for a client to open the slave.
+                        thread::sleep(Duration::from_millis(100));
+                    },
+                    Ok(count) => return count,
+                }
+            }
+        });
+    }
+}
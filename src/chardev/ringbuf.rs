@@ -0,0 +1,108 @@
+use std::ascii;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Once};
+
+use ::control::ControlServer;
+use super::CharBackend;
+
+const DEFAULT_RING_BUFFER_SIZE: usize = 64 << 10;
+
+/// Keeps the most recent guest output in memory.
+pub struct RingBuffer {
+    data: VecDeque<u8>,
+    size: usize,
+}
+
+impl RingBuffer {
+    fn new(size: usize) -> Self {
+        RingBuffer {
+            data: VecDeque::with_capacity(size),
+            size: size,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        // Only the tail is relevant if we got more than we can hold.
+        let data = &data[data.len().saturating_sub(self.size)..];
+        let overflow = (self.data.len() + data.len()).saturating_sub(
+            self.size);
+        self.data.drain(..overflow);
+        self.data.extend(data);
+    }
+
+    /// Returns the buffered data, without consuming it.
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.iter().cloned().collect()
+    }
+}
+
+type RingBufferMap = HashMap<String, Arc<Mutex<RingBuffer>>>;
+
+static mut RING_BUFFERS: Option<Mutex<RingBufferMap>> = None;
+static RING_BUFFERS_INIT: Once = Once::new();
+
+fn registry() -> &'static Mutex<RingBufferMap> {
+    unsafe {
+        RING_BUFFERS_INIT.call_once(|| {
+            RING_BUFFERS = Some(Mutex::new(HashMap::new()));
+        });
+        match RING_BUFFERS {
+            Some(ref buffers) => buffers,
+            None => unreachable!(),
+        }
+    }
+}
+
+/// Returns the ring buffers, by device name.
+pub fn ring_buffers() -> Vec<(String, Arc<Mutex<RingBuffer>>)> {
+    let mut buffers = registry().lock().unwrap()
+        .iter()
+        .map(|(name, buf)| (name.clone(), buf.clone()))
+        .collect::<Vec<_>>();
+    buffers.sort_by(|a, b| a.0.cmp(&b.0));
+    buffers
+}
+
+/// Adds the "ringbuf" command, returning the buffered output of a device
+/// on a single line, with the control characters escaped.
+pub fn add_control_commands(control: &mut ControlServer) {
+    control.add_command("ringbuf", "<name>", Box::new(|args| {
+        let name = match args {
+            [name] => name,
+            _ => return Err("usage: ringbuf <name>".to_string()),
+        };
+        let buffer = registry().lock().unwrap().get(*name).cloned()
+            .ok_or(format!("unknown ring buffer: {}", name))?;
+        let contents = buffer.lock().unwrap().contents();
+        let escaped = contents.iter()
+            .flat_map(|&b| ascii::escape_default(b))
+            .collect::<Vec<u8>>();
+        Ok(String::from_utf8(escaped).unwrap())
+    }));
+}
+
+pub struct RingBufferBackend {
+    buffer: Arc<Mutex<RingBuffer>>,
+}
+
+impl RingBufferBackend {
+    pub fn new(name: &str, size: Option<usize>) -> Self {
+        let size = size.unwrap_or(DEFAULT_RING_BUFFER_SIZE);
+        if size == 0 {
+            panic!("The ring buffer size must be greater than 0.");
+        }
+
+        let buffer = Arc::new(Mutex::new(RingBuffer::new(size)));
+        registry().lock().unwrap().insert(name.to_string(), buffer.clone());
+
+        RingBufferBackend {
+            buffer: buffer,
+        }
+    }
+}
+
+impl CharBackend for RingBufferBackend {
+    fn write(&mut self, data: &[u8]) {
+        self.buffer.lock().unwrap().push(data);
+    }
+}
@@ -0,0 +1,145 @@
+extern crate libc;
+
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::{deliver_input, CharBackend, CharFrontend};
+
+type SharedFrontend = Arc<Mutex<Option<Arc<Mutex<CharFrontend>>>>>;
+
+/// Listens on a unix socket, serving one client at a time. Guest output
+/// is dropped while no client is connected or while it isn't reading.
+pub struct UnixSocketBackend {
+    client: Arc<Mutex<Option<UnixStream>>>,
+    frontend: SharedFrontend,
+}
+
+impl UnixSocketBackend {
+    pub fn new(path: &str, name: &str) -> Self {
+        // Remove stale sockets left behind by previous runs.
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap_or_else(
+            |_| panic!("Cannot listen on \"{}\".", path));
+
+        let backend = UnixSocketBackend {
+            client: Arc::new(Mutex::new(None)),
+            frontend: Arc::new(Mutex::new(None)),
+        };
+
+        let client = backend.client.clone();
+        let frontend = backend.frontend.clone();
+        thread::Builder::new()
+            .name(format!("{}-unix", name))
+            .spawn(move || accept_loop(listener, client, frontend))
+            .unwrap();
+
+        backend
+    }
+}
+
+fn accept_loop(listener: UnixListener,
+               client: Arc<Mutex<Option<UnixStream>>>,
+               frontend: SharedFrontend) {
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                println!("Unix socket accept failed: {}", e);
+                continue;
+            },
+        };
+
+        *client.lock().unwrap() = stream.try_clone().ok();
+
+        // Serve the client until it disconnects.
+        let mut buf = [0u8; 1024];
+        loop {
+            let count = match stream.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(count) => count,
+            };
+
+            let frontend = frontend.lock().unwrap().clone();
+            if let Some(frontend) = frontend {
+                deliver_input(&frontend, &buf[..count]);
+            }
+        }
+
+        *client.lock().unwrap() = None;
+    }
+}
+
+/// Writes without blocking, as the stream is shared with the input
+/// thread. Returns false if the client is gone, the data that doesn't
+/// fit the socket buffer being dropped.
+fn send_nonblocking(stream: &UnixStream, data: &[u8]) -> bool {
+    let mut sent = 0;
+    while sent < data.len() {
+        let ret = unsafe {
+            libc::send(stream.as_raw_fd(),
+                       data[sent..].as_ptr() as *const libc::c_void,
+                       data.len() - sent,
+                       libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL)
+        };
+        if ret >= 0 {
+            sent += ret as usize;
+            continue;
+        }
+        match io::Error::last_os_error().kind() {
+            io::ErrorKind::Interrupted => continue,
+            io::ErrorKind::WouldBlock => return true,
+            _ => return false,
+        }
+    }
+    true
+}
+
+impl CharBackend for UnixSocketBackend {
+    fn write(&mut self, data: &[u8]) {
+        let mut client = self.client.lock().unwrap();
+        let failed = match *client {
+            Some(ref stream) => !send_nonblocking(stream, data),
+            None => false,
+        };
+        if failed {
+            *client = None;
+        }
+    }
+
+    fn connect_input(&mut self, frontend: Arc<Mutex<CharFrontend>>) {
+        *self.frontend.lock().unwrap() = Some(frontend);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::Duration;
+
+    #[test]
+    fn write_doesnt_block() {
+        let path = env::temp_dir().join(
+            format!("insula-chardev-{}.sock", process::id()));
+        let path = path.to_string_lossy().into_owned();
+        let mut backend = UnixSocketBackend::new(&path, "test");
+        let _stream = UnixStream::connect(&path).unwrap();
+        while backend.client.lock().unwrap().is_none() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // The client never reads, the output past the socket buffer is
+        // dropped.
+        let data = vec![0u8; 1 << 20];
+        for _ in 0..16 {
+            backend.write(&data);
+        }
+        assert!(backend.client.lock().unwrap().is_some());
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,91 @@
+extern crate libc;
+
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{spawn_input_thread, CharBackend, CharFrontend};
+
+// Only one frontend may consume stdin.
+static STDIN_CONNECTED: AtomicBool = AtomicBool::new(false);
+static RAW_MODE_SET: AtomicBool = AtomicBool::new(false);
+
+static mut SAVED_TERMIOS: Option<libc::termios> = None;
+
+pub struct StdioBackend {}
+
+impl StdioBackend {
+    pub fn new() -> Self {
+        StdioBackend {}
+    }
+}
+
+impl CharBackend for StdioBackend {
+    fn write(&mut self, data: &[u8]) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(data);
+        let _ = stdout.flush();
+    }
+
+    fn connect_input(&mut self, frontend: Arc<Mutex<CharFrontend>>) {
+        if STDIN_CONNECTED.swap(true, Ordering::SeqCst) {
+            println!("stdin is already used by another device.");
+            return;
+        }
+
+        set_raw_mode();
+        spawn_input_thread("stdio", frontend, |buf| {
+            match io::stdin().read(buf) {
+                Ok(count) => count,
+                Err(e) => {
+                    println!("stdin read error: {}", e);
+                    0
+                }
+            }
+        });
+    }
+}
+
+/// Puts the terminal in raw mode so that keystrokes are passed to the
+/// guest as they are typed. Signals are still handled, Ctrl-C stops insula.
+fn set_raw_mode() {
+    if unsafe { libc::isatty(libc::STDIN_FILENO) } == 0 ||
+            RAW_MODE_SET.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) != 0 {
+            return;
+        }
+        SAVED_TERMIOS = Some(termios);
+
+        libc::cfmakeraw(&mut termios);
+        termios.c_lflag |= libc::ISIG;
+        // Keep translating "\n" to "\r\n" for the host side messages.
+        termios.c_oflag |= libc::OPOST | libc::ONLCR;
+        libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+
+        libc::atexit(restore_terminal);
+        for signal in &[libc::SIGINT, libc::SIGTERM, libc::SIGHUP] {
+            libc::signal(
+                *signal,
+                restore_terminal_and_exit as extern "C" fn(libc::c_int)
+                    as libc::sighandler_t);
+        }
+    }
+}
+
+extern "C" fn restore_terminal() {
+    unsafe {
+        if let Some(ref termios) = SAVED_TERMIOS {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+        }
+    }
+}
+
+extern "C" fn restore_terminal_and_exit(signal: libc::c_int) {
+    restore_terminal();
+    unsafe { libc::_exit(128 + signal) };
+}
@@ -0,0 +1,110 @@
+//! Control interface, a unix socket accepting one command per line.
+//! Replies are single lines starting with "OK" or "ERROR".
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::thread;
+
+/// Runs a command given its arguments, returning its output.
+pub type CommandHandler = Box<Fn(&[&str]) -> Result<String, String> + Send + Sync>;
+
+struct Command {
+    name: String,
+    usage: String,
+    handler: CommandHandler,
+}
+
+pub struct ControlServer {
+    commands: Vec<Command>,
+}
+
+impl ControlServer {
+    pub fn new() -> Self {
+        ControlServer {
+            commands: Vec::new(),
+        }
+    }
+
+    /// `usage` describes the arguments, as listed by the help command.
+    pub fn add_command(&mut self, name: &str, usage: &str, handler: CommandHandler) {
+        self.commands.push(Command {
+            name: name.to_string(),
+            usage: usage.to_string(),
+            handler: handler,
+        });
+    }
+
+    /// Starts listening on the given path, serving each client in its own
+    /// thread.
+    pub fn start(self, path: &str) {
+        // Remove stale sockets left behind by previous runs.
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap_or_else(
+            |_| panic!("Cannot listen on \"{}\".", path));
+        let commands = Arc::new(self.commands);
+
+        thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            println!("Control socket accept failed: {}", e);
+                            continue;
+                        },
+                    };
+                    let commands = commands.clone();
+                    thread::Builder::new()
+                        .name("control-client".to_string())
+                        .spawn(move || serve_client(stream, &commands))
+                        .unwrap();
+                }
+            })
+            .unwrap();
+    }
+}
+
+fn run_command(commands: &[Command], line: &str) -> Result<String, String> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Err("empty command".to_string()),
+    };
+
+    if name == "help" {
+        let usage = commands.iter()
+            .map(|c| format!("{} {}", c.name, c.usage).trim().to_string())
+            .collect::<Vec<_>>();
+        return Ok(usage.join(", "));
+    }
+
+    match commands.iter().find(|c| c.name == name) {
+        Some(command) => (command.handler)(args),
+        None => Err(format!("unknown command: {}", name)),
+    }
+}
+
+fn serve_client(stream: UnixStream, commands: &[Command]) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        let reply = match run_command(commands, &line) {
+            Ok(ref output) if output.is_empty() => "OK\n".to_string(),
+            Ok(output) => format!("OK {}\n", output),
+            Err(error) => format!("ERROR {}\n", error),
+        };
+        if writer.write_all(reply.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
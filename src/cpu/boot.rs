@@ -0,0 +1,13 @@
+/// The vCPU state expected by the Linux 64-bit boot protocol:
+/// long mode with paging enabled, flat segments loaded from the given GDT,
+/// the zero page address in rsi.
+#[derive(Debug, Copy, Clone)]
+pub struct LongModeBootState {
+    pub entry_point: u64,
+    pub boot_params_addr: u64,
+    pub page_table_addr: u64,
+    pub gdt_addr: u64,
+    pub gdt_limit: u16,
+    pub code_selector: u16,
+    pub data_selector: u16,
+}
@@ -15,5 +15,7 @@ pub enum VcpuExit<'a> {
     /// A write instruction was run against the given MMIO address with the given data.
     MmioWrite(u64 /* address */, &'a [u8]),
     Hlt,
-    Shutdown
+    Shutdown,
+    /// The vCPU was kicked out of the guest by a signal.
+    Interrupted
 }
@@ -1,2 +1,3 @@
+pub mod boot;
 pub mod constants;
 pub mod exits;
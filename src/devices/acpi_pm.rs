@@ -0,0 +1,116 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::time::{Duration, Instant};
+
+use ::snapshot::state::{StateReader, StateWriter};
+use super::bus::{BusDevice, DeviceState};
+
+// Register offsets within the PM I/O block.
+pub const PM1_STATUS: u64 = 0x00;
+pub const PM1_ENABLE: u64 = 0x02;
+pub const PM1_CONTROL: u64 = 0x04;
+pub const PM_TIMER: u64 = 0x08;
+pub const PM_IO_SIZE: u64 = 0x80;
+
+/// PM I/O base of the pc machine type, as used by the PIIX4.
+pub const PC_PM_BASE: u64 = 0xb000;
+/// The system control interrupt. None of the events of the PM1 block are
+/// generated, so it is never raised.
+pub const SCI_IRQ: u32 = 9;
+
+// The ACPI PM timer runs at 3.579545 MHz.
+const PM_TIMER_FREQUENCY_HZ: u64 = 3_579_545;
+// The timer is 24 bits wide unless TMR_VAL_EXT is set in the FADT.
+const PM_TIMER_MASK: u32 = 0x00ff_ffff;
+
+/// ACPI power management registers: PM1 event and control blocks along
+/// with the PM timer.
+pub struct AcpiPmDevice {
+    pm1_status: u16,
+    pm1_enable: u16,
+    pm1_control: u16,
+    start: Instant,
+}
+
+impl AcpiPmDevice {
+    pub fn new() -> Self {
+        AcpiPmDevice {
+            pm1_status: 0,
+            pm1_enable: 0,
+            pm1_control: 0,
+            start: Instant::now(),
+        }
+    }
+
+    fn timer_value(&self) -> u32 {
+        let elapsed = self.start.elapsed();
+        let ticks = elapsed.as_secs() * PM_TIMER_FREQUENCY_HZ +
+                    elapsed.subsec_nanos() as u64 * PM_TIMER_FREQUENCY_HZ /
+                    1_000_000_000;
+        ticks as u32 & PM_TIMER_MASK
+    }
+
+    fn read_reg(&self, offset: u64) -> u32 {
+        match offset {
+            PM1_STATUS => self.pm1_status as u32 | (self.pm1_enable as u32) << 16,
+            PM1_CONTROL => self.pm1_control as u32,
+            PM_TIMER => self.timer_value(),
+            _ => 0,
+        }
+    }
+}
+
+impl BusDevice for AcpiPmDevice {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let reg_offset = offset & !3;
+        let value = self.read_reg(reg_offset) >> ((offset & 3) * 8);
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = value.checked_shr(i as u32 * 8).unwrap_or(0) as u8;
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let value = match data.len() {
+            1 => data[0] as u16,
+            2 | 4 => LittleEndian::read_u16(data),
+            _ => return,
+        };
+
+        if offset == PM1_STATUS && data.len() == 4 {
+            self.pm1_enable = LittleEndian::read_u16(&data[2..]);
+        }
+
+        match offset {
+            // Write one to clear.
+            PM1_STATUS => self.pm1_status &= !value,
+            PM1_ENABLE => self.pm1_enable = value,
+            PM1_CONTROL => self.pm1_control = value,
+            _ => (),
+        }
+    }
+}
+
+impl DeviceState for AcpiPmDevice {
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        let mut state = StateWriter::new();
+        state.write_u16(self.pm1_status);
+        state.write_u16(self.pm1_enable);
+        state.write_u16(self.pm1_control);
+        // The timer keeps counting from the saved value.
+        let elapsed = self.start.elapsed();
+        state.write_u64(elapsed.as_secs());
+        state.write_u32(elapsed.subsec_nanos());
+        Ok(state.into_bytes())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        let mut state = StateReader::new(data);
+        self.pm1_status = state.read_u16();
+        self.pm1_enable = state.read_u16();
+        self.pm1_control = state.read_u16();
+        let elapsed = Duration::new(state.read_u64(), state.read_u32());
+        self.start = Instant::now() - elapsed;
+    }
+}
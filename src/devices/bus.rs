@@ -9,7 +9,7 @@
 use std::cmp::{Ord, PartialOrd, PartialEq, Ordering};
 use std::collections::btree_map::BTreeMap;
 use std::result;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 /// Trait for devices that respond to reads or writes in an arbitrary address space.
 ///n offset
@@ -30,6 +30,19 @@ pub trait BusDevice: Send {
     fn config_register_read(&self, reg_idx: usize) -> u32 { 0 }
 }
 
+/// Trait for devices whose state is saved in snapshots.
+#[allow(unused_variables)]
+pub trait DeviceState: Send {
+    /// Serializes the device state, called while the vCPUs are paused.
+    /// Devices doing work in their own threads should stop it until
+    /// `resume` is called.
+    fn save_state(&mut self) -> result::Result<Vec<u8>, String>;
+    /// Loads the state returned by `save_state`, before the vCPUs start.
+    fn restore_state(&mut self, data: &[u8]);
+    /// Called once the snapshot was taken, before the vCPUs resume.
+    fn resume(&mut self) {}
+}
+
 #[derive(Debug)]
 pub enum Error {
     /// The insertion failed because the new device overlapped with an old device.
@@ -87,25 +100,28 @@ impl PartialOrd for BusRange {
 ///
 /// This doesn't have any restrictions on what kind of device or address space this applies to. The
 /// only restriction is that no two devices can overlap in this address space.
-#[derive(Clone)]
+///
+/// Devices may be added or removed while the bus is in use (e.g. when PCI BARs are reprogrammed),
+/// the device map lock is never held while calling into a device.
 pub struct Bus {
-    devices: BTreeMap<BusRange, Arc<Mutex<BusDevice>>>,
+    devices: RwLock<BTreeMap<BusRange, Arc<Mutex<BusDevice>>>>,
 }
 
 impl Bus {
     /// Constructs an a bus with an empty address space.
     pub fn new() -> Bus {
-        Bus { devices: BTreeMap::new() }
+        Bus { devices: RwLock::new(BTreeMap::new()) }
     }
 
-    fn first_before(&self, addr: u64) -> Option<(BusRange, &Mutex<BusDevice>)> {
-        let(range, dev) =  self.devices.range(..=BusRange {base:addr, len:1, full_addr: false})
-                                       .rev()
-                                       .next()?;
-        Some((*range, dev))
+    fn first_before(&self, addr: u64) -> Option<(BusRange, Arc<Mutex<BusDevice>>)> {
+        let devices = self.devices.read().unwrap();
+        let(range, dev) =  devices.range(..=BusRange {base:addr, len:1, full_addr: false})
+                                  .rev()
+                                  .next()?;
+        Some((*range, dev.clone()))
     }
 
-    fn get_device(&self, addr: u64) -> Option<(u64, &Mutex<BusDevice>)> {
+    fn get_device(&self, addr: u64) -> Option<(u64, Arc<Mutex<BusDevice>>)> {
         if let Some((range, dev)) = self.first_before(addr) {
             let offset = addr - range.base;
             if offset < range.len {
@@ -120,7 +136,7 @@ impl Bus {
     }
 
     /// Puts the given device at the given address space.
-    pub fn insert(&mut self, device: Arc<Mutex<BusDevice>>,
+    pub fn insert(&self, device: Arc<Mutex<BusDevice>>,
                   base: u64, len: u64, full_addr: bool)
         -> Result<()>
     {
@@ -128,12 +144,14 @@ impl Bus {
             return Err(Error::Overlap);
         }
 
+        let mut devices = self.devices.write().unwrap();
+
         // Reject all cases where the new device's range overlaps with an existing device.
-        if self.devices.iter().any(|(range, _dev)| range.overlaps(base, len)) {
+        if devices.iter().any(|(range, _dev)| range.overlaps(base, len)) {
             return Err(Error::Overlap);
         }
 
-        if self.devices
+        if devices
                .insert(BusRange{base, len, full_addr}, device)
                .is_some() {
             return Err(Error::Overlap);
@@ -142,6 +160,14 @@ impl Bus {
         Ok(())
     }
 
+    /// Removes the device whose range starts at `base`.
+    pub fn remove(&self, base: u64) -> Option<Arc<Mutex<BusDevice>>> {
+        self.devices
+            .write()
+            .unwrap()
+            .remove(&BusRange {base, len: 1, full_addr: false})
+    }
+
     /// Reads data from the device that owns the range containing `addr` and puts it into `data`.
     ///
     /// Returns true on success, otherwise `data` is untouched.
@@ -42,3 +42,17 @@ pub const FW_CFG_CTL_SIZE: u32 = 0x02;
 pub const FW_CFG_MAX_FILE_PATH: u32 = 56;
 
 pub const FW_CFG_FILE_SLOTS_DFLT: u32 = 0x20;
+
+/* FW_CFG_ID feature bits */
+pub const FW_CFG_VERSION: u32 = 0x01;
+pub const FW_CFG_VERSION_DMA: u32 = 0x02;
+
+/* FWCfgDmaAccess control bits */
+pub const FW_CFG_DMA_CTL_ERROR: u32 = 0x01;
+pub const FW_CFG_DMA_CTL_READ: u32 = 0x02;
+pub const FW_CFG_DMA_CTL_SKIP: u32 = 0x04;
+pub const FW_CFG_DMA_CTL_SELECT: u32 = 0x08;
+pub const FW_CFG_DMA_CTL_WRITE: u32 = 0x10;
+
+/* "QEMU CFG", read from the DMA address register */
+pub const FW_CFG_DMA_SIGNATURE: u64 = 0x51454d5520434647;
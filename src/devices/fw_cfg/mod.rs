@@ -4,15 +4,65 @@ extern crate std;
 pub mod defs;
 pub mod e820;
 
-use self::byteorder::{ByteOrder, LittleEndian};
+use self::byteorder::{BigEndian, ByteOrder, LittleEndian};
 
 use std::collections::HashMap;
-use std::cmp::min;
+use std::cmp::{max, min};
 use std::mem::size_of;
 
-use ::devices::bus::BusDevice;
+use ::devices::bus::{BusDevice, DeviceState};
 pub use self::defs::*;
 use ::ffi::*;
+use ::memory::GuestMemory;
+use ::snapshot::state::{StateReader, StateWriter};
+
+pub const FW_CFG_IO_BASE: u64 = 0x510;
+pub const FW_CFG_IO_SIZE: u64 = 0x0c;
+pub const FW_CFG_MMIO_SIZE: u64 = 0x18;
+
+/// How the registers are accessed by the guest.
+#[derive(Copy, Clone, PartialEq)]
+pub enum FWCfgInterface {
+    /// Little-endian selector at 0x0, data at 0x1 and DMA address at 0x4.
+    Io,
+    /// Data at 0x0, big-endian selector at 0x8 and DMA address at 0x10.
+    Mmio,
+}
+
+impl FWCfgInterface {
+    fn selector_offset(self) -> u64 {
+        match self {
+            FWCfgInterface::Io => 0,
+            FWCfgInterface::Mmio => 8,
+        }
+    }
+
+    fn data_offset(self) -> u64 {
+        match self {
+            FWCfgInterface::Io => 1,
+            FWCfgInterface::Mmio => 0,
+        }
+    }
+
+    fn dma_offset(self) -> u64 {
+        match self {
+            FWCfgInterface::Io => 4,
+            FWCfgInterface::Mmio => 0x10,
+        }
+    }
+}
+
+// Size of the FWCfgDmaAccess structure: control, length and address.
+const FW_CFG_DMA_ACCESS_SIZE: usize = 16;
+// DMA transfers are split into chunks of this size.
+const FW_CFG_DMA_CHUNK_SIZE: usize = 64 << 10;
+
+/// Invoked when the guest selects an entry, allowing its contents to be
+/// updated before being read.
+pub type FWCfgSelectCallback = Box<FnMut(&mut [u8]) + Send>;
+/// Invoked after the guest wrote to an entry, receiving the entry
+/// contents along with the offset and length of the written range.
+pub type FWCfgWriteCallback = Box<FnMut(&[u8], usize, usize) + Send>;
 
 #[allow(unused)]
 struct FWCfgEntry {
@@ -22,9 +72,14 @@ struct FWCfgEntry {
     // (e.g. the FwCfgFiles field).
     buf: Vec<u8>,
     data: *const u8,
+    // Callbacks and writable entries always own their data (buf).
+    allow_write: bool,
+    select_cb: Option<FWCfgSelectCallback>,
+    write_cb: Option<FWCfgWriteCallback>,
 }
 
-// Once initialized, those entries will be read-only.
+// Once initialized, only the writable entries change, through their
+// own buffer.
 unsafe impl Send for FWCfgEntry {}
 unsafe impl Send for FWCfgFilesWrapper {}
 
@@ -53,7 +108,7 @@ pub struct FWCfgFilesWrapper {
 impl FWCfgFilesWrapper {
     pub fn new(slots: u32) -> Self {
         let size = size_of::<FWCfgFiles>() +
-                   size_of::<u32>() * slots as usize;
+                   size_of::<FWCfgFile>() * slots as usize;
         let buf: Vec<u8> = vec![0; size];
         let files: &mut FWCfgFiles = unsafe {
             &mut *(buf.as_ptr() as *mut FWCfgFiles)
@@ -85,16 +140,23 @@ pub struct FWCfgDev {
     files_wrapper: FWCfgFilesWrapper,
     cur_entry: u16,
     cur_offset: u32,
+    interface: FWCfgInterface,
+    mem: GuestMemory,
+    // Written by the guest in two big-endian halves, high part first.
+    dma_address: u64,
 }
 
 impl FWCfgDev {
-    pub fn new()-> Self {
+    pub fn new(interface: FWCfgInterface, mem: GuestMemory)-> Self {
         let obj = FWCfgDev {
             file_slots: FW_CFG_FILE_SLOTS_DFLT as u16,
             entries: [HashMap::new(), HashMap::new()],
             files_wrapper: FWCfgFilesWrapper::new(FW_CFG_FILE_SLOTS_DFLT),
             cur_entry: 0,
             cur_offset: 0,
+            interface: interface,
+            mem: mem,
+            dma_address: 0,
         };
 
         obj.add_default_entries()
@@ -110,6 +172,7 @@ impl FWCfgDev {
         };
 
         self.add_bytes(FW_CFG_SIGNATURE, b"QEMU", 4);
+        self.add_i32(FW_CFG_ID, (FW_CFG_VERSION | FW_CFG_VERSION_DMA) as i32);
         self.add_bytes_no_copy(
             FW_CFG_FILE_DIR,
             f_buf_slice,
@@ -156,6 +219,9 @@ impl FWCfgDev {
                     len: len,
                     data: data_ptr,
                     buf: vec,
+                    allow_write: false,
+                    select_cb: None,
+                    write_cb: None,
                 }
             }
         };
@@ -169,6 +235,12 @@ impl FWCfgDev {
         self.add_bytes(key, &buf, size_of::<i16>() as u32);
     }
 
+    pub fn add_i32(&mut self, key: u32, data: i32) {
+        let mut buf = [0; size_of::<i32>()];
+        LittleEndian::write_i32(&mut buf, data);
+        self.add_bytes(key, &buf, size_of::<i32>() as u32);
+    }
+
     pub fn add_i64(&mut self, key: u32, data: i64) {
         let mut buf = [0; size_of::<i64>()];
         LittleEndian::write_i64(&mut buf, data);
@@ -176,6 +248,49 @@ impl FWCfgDev {
     }
 
     pub fn add_file(&mut self, filename: &str, data: &[u8], len: u32) {
+        self.add_file_entry(filename, data, len);
+    }
+
+    /// Adds a file which the guest may write to, if `allow_write` is set,
+    /// calling back on select and after writes.
+    pub fn add_file_callback(&mut self, filename: &str, data: &[u8], len: u32,
+                             select_cb: Option<FWCfgSelectCallback>,
+                             write_cb: Option<FWCfgWriteCallback>,
+                             allow_write: bool) {
+        let key = self.add_file_entry(filename, data, len);
+        let entry = self.entries[0].get_mut(&key).unwrap();
+        entry.allow_write = allow_write;
+        entry.select_cb = select_cb;
+        entry.write_cb = write_cb;
+    }
+
+    /// Writes to a file from the host side, as the guest would.
+    pub fn write_file(&mut self, filename: &str, offset: u32, data: &[u8]) {
+        let key = match self.find_file(filename) {
+            Some(key) => key,
+            None => panic!("No such fw cfg file: {}", filename),
+        };
+        self.select(key as usize);
+        self.cur_offset = offset;
+        if !self.write_data(data) {
+            panic!("Cannot write fw cfg file {} at {}.", filename, offset);
+        }
+    }
+
+    fn find_file(&self, filename: &str) -> Option<u32> {
+        let files = self.files_wrapper.to_files_vec();
+        let count = unsafe { u32::from_be((*self.files_wrapper.files).count) };
+        files[..count as usize].iter()
+            .find(|f| {
+                let len = f.name.iter().position(|c| *c == 0)
+                                .unwrap_or(f.name.len());
+                &f.name[..len] == filename.as_bytes()
+            })
+            .map(|f| u16::from_be(f.select) as u32)
+    }
+
+    /// Adds a file, returning its key.
+    fn add_file_entry(&mut self, filename: &str, data: &[u8], len: u32) -> u32 {
         // qemu sorts the files by filename, we can probably skip this.
 
         let fw_cfg_files = self.files_wrapper.files;
@@ -194,6 +309,7 @@ impl FWCfgDev {
 
         self.add_bytes(FW_CFG_FILE_FIRST + count, data, len);
         unsafe { (*fw_cfg_files).count = (count + 1).to_be() };
+        FW_CFG_FILE_FIRST + count
     }
 
     fn select(&mut self, key: usize) -> bool {
@@ -207,9 +323,48 @@ impl FWCfgDev {
             ret = true;
         }
 
+        let arch = FWCfgDev::get_arch(key);
+        let key = key as u32 & FW_CFG_ENTRY_MASK;
+        if let Some(entry) = self.entries[arch].get_mut(&key) {
+            if let Some(ref mut select_cb) = entry.select_cb {
+                select_cb(&mut entry.buf);
+            }
+        }
+
         ret
     }
 
+    /// Returns how many bytes can be written at the current offset.
+    fn writable_len(&self) -> usize {
+        let arch = FWCfgDev::get_arch(self.cur_entry as usize);
+        match self.get_cur_entry(arch) {
+            Some(entry) if entry.allow_write => {
+                entry.len.saturating_sub(self.cur_offset) as usize
+            },
+            _ => 0,
+        }
+    }
+
+    /// Writes to the current entry, returning false if it isn't writable
+    /// or if the data doesn't fit.
+    fn write_data(&mut self, data: &[u8]) -> bool {
+        if data.len() > self.writable_len() {
+            return false;
+        }
+
+        let arch = FWCfgDev::get_arch(self.cur_entry as usize);
+        let key = self.cur_entry as u32 & FW_CFG_ENTRY_MASK;
+        let offset = self.cur_offset as usize;
+        let entry = self.entries[arch].get_mut(&key).unwrap();
+        entry.buf[offset..offset + data.len()].copy_from_slice(data);
+        if let Some(ref mut write_cb) = entry.write_cb {
+            write_cb(&entry.buf, offset, data.len());
+        }
+
+        self.cur_offset += data.len() as u32;
+        true
+    }
+
     fn get_cur_entry(&self, arch: usize) -> Option<&FWCfgEntry> {
         match self.cur_entry as u32 {
             FW_CFG_INVALID => None,
@@ -217,29 +372,15 @@ impl FWCfgDev {
                     &(self.cur_entry as u32 & FW_CFG_ENTRY_MASK))
         }
     }
-}
-
-impl BusDevice for FWCfgDev {
-    fn write(&mut self, _offset: u64, data: &[u8]) {
-        match data.len() {
-            1 => println!("Ignoring fw cfg write."),
-            2 => {
-                self.select(LittleEndian::read_u16(data) as usize);
-                ()
-            },
-            _ => println!("Invalid fw cfg write length: {}", data.len())
-        };
-    }
 
-    fn read(&mut self, _offset: u64, data: &mut [u8]) {
+    /// Reads from the current entry, filling the rest of the buffer with
+    /// zeros. Returns the number of bytes read from the entry.
+    fn read_data(&mut self, data: &mut [u8]) -> usize {
         let arch = FWCfgDev::get_arch(self.cur_entry as usize);
         let read_len = data.len();
-        // TODO: clean this up, currently avoiding assigning a
-        // borrowed object.
         let cur_offset = self.cur_offset as usize;
 
-        println!("Read: {}.", read_len);
-        match self.get_cur_entry(arch) {
+        let bytes_read = match self.get_cur_entry(arch) {
             Some(entry) if self.cur_offset < entry.len as u32 => {
                 let entry_data = unsafe {
                     std::slice::from_raw_parts(
@@ -247,25 +388,363 @@ impl BusDevice for FWCfgDev {
                         entry.len as usize
                     )
                 };
-                println!("read: {:x} - {:x} -> {:x?} {}",
-                         self.cur_entry, cur_offset, entry.data, entry.len);
                 // Fill the buffer with data from the config entry,
                 // starting with the current offset.
                 let entry_read_len = min(
                     read_len, (entry.len - self.cur_offset) as usize);
                 data[..entry_read_len].clone_from_slice(
-                    &entry_data[cur_offset..cur_offset + read_len as usize]);
-                if entry_read_len < read_len {
-                    // Fill the rest with zeros.
-                    data[entry_read_len..read_len].clone_from_slice(
-                        &vec![0; entry_read_len - read_len]);
+                    &entry_data[cur_offset..cur_offset + entry_read_len]);
+                // Fill the rest with zeros.
+                for byte in data[entry_read_len..].iter_mut() {
+                    *byte = 0;
+                }
+                entry_read_len
+            },
+            _ => {
+                println!("No entry: {:x} - {:x}", self.cur_entry, cur_offset);
+                for byte in data.iter_mut() {
+                    *byte = 0;
                 }
+                0
             },
-            _ => println!("No entry: {:x} - {:x}",
-                          self.cur_entry, cur_offset),
+        };
+
+        self.cur_offset += bytes_read as u32;
+        bytes_read
+    }
+
+    fn skip_data(&mut self, len: u32) {
+        let arch = FWCfgDev::get_arch(self.cur_entry as usize);
+        let entry_len = self.get_cur_entry(arch).map_or(0, |e| e.len);
+        self.cur_offset = min(self.cur_offset.saturating_add(len),
+                              max(entry_len, self.cur_offset));
+    }
+
+    /// Copies the current entry to guest memory, returning false if the
+    /// destination isn't backed by RAM.
+    fn dma_read(&mut self, address: u64, len: u32) -> bool {
+        let mut buf = vec![0u8; min(len as usize, FW_CFG_DMA_CHUNK_SIZE)];
+        let mut done = 0;
+        while done < len as usize {
+            let chunk_len = min(len as usize - done, buf.len());
+            self.read_data(&mut buf[..chunk_len]);
+            if !self.mem.write(address + done as u64, &buf[..chunk_len]) {
+                return false;
+            }
+            done += chunk_len;
         }
+        true
+    }
 
-        self.cur_offset = cur_offset as u32;
+    /// Copies guest memory to the current entry.
+    fn dma_write(&mut self, address: u64, len: u32) -> bool {
+        if len as usize > self.writable_len() {
+            return false;
+        }
+        let mut buf = vec![0u8; len as usize];
+        self.mem.read(address, &mut buf) && self.write_data(&buf)
+    }
+
+    /// Processes the FWCfgDmaAccess structure found at the given address.
+    fn dma_transfer(&mut self, access_address: u64) {
+        let mut access = [0u8; FW_CFG_DMA_ACCESS_SIZE];
+        if !self.mem.read(access_address, &mut access) {
+            println!("Invalid fw cfg DMA access address: {:x}", access_address);
+            return;
+        }
+        let control = BigEndian::read_u32(&access[0..4]);
+        let len = BigEndian::read_u32(&access[4..8]);
+        let address = BigEndian::read_u64(&access[8..16]);
+
+        if control & FW_CFG_DMA_CTL_SELECT != 0 {
+            self.select((control >> 16) as usize);
+        }
+
+        let ok = if control & FW_CFG_DMA_CTL_READ != 0 {
+            self.dma_read(address, len)
+        } else if control & FW_CFG_DMA_CTL_WRITE != 0 {
+            self.dma_write(address, len)
+        } else {
+            if control & FW_CFG_DMA_CTL_SKIP != 0 {
+                self.skip_data(len);
+            }
+            true
+        };
+
+        // The control field is cleared once the transfer completes.
+        let mut status = [0u8; 4];
+        if !ok {
+            BigEndian::write_u32(&mut status, FW_CFG_DMA_CTL_ERROR);
+        }
+        self.mem.write(access_address, &status);
+    }
+
+    /// `offset` is relative to the DMA address register.
+    fn write_dma_address(&mut self, offset: u64, data: &[u8]) {
+        match (offset, data.len()) {
+            (0, 4) => {
+                self.dma_address = (BigEndian::read_u32(data) as u64) << 32;
+            },
+            // Writing the low half starts the transfer.
+            (4, 4) => {
+                let address = self.dma_address | BigEndian::read_u32(data) as u64;
+                self.dma_address = 0;
+                self.dma_transfer(address);
+            },
+            (0, 8) => {
+                self.dma_address = 0;
+                self.dma_transfer(BigEndian::read_u64(data));
+            },
+            _ => println!("Invalid fw cfg DMA write: {:x} - {}",
+                          offset, data.len()),
+        }
     }
 }
 
+impl BusDevice for FWCfgDev {
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let dma_offset = self.interface.dma_offset();
+        if offset == self.interface.selector_offset() && data.len() == 2 {
+            let key = match self.interface {
+                FWCfgInterface::Io => LittleEndian::read_u16(data),
+                FWCfgInterface::Mmio => BigEndian::read_u16(data),
+            };
+            self.select(key as usize);
+        } else if offset == self.interface.data_offset() {
+            // Data writes go through the write channel.
+            if self.cur_entry as u32 & FW_CFG_WRITE_CHANNEL == 0 ||
+                    !self.write_data(data) {
+                println!("Ignoring fw cfg write.");
+            }
+        } else if offset >= dma_offset {
+            self.write_dma_address(offset - dma_offset, data);
+        } else {
+            println!("Invalid fw cfg write: {:x} - {}", offset, data.len());
+        }
+    }
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let dma_offset = self.interface.dma_offset();
+        if offset >= dma_offset {
+            let mut signature = [0u8; 8];
+            BigEndian::write_u64(&mut signature, FW_CFG_DMA_SIGNATURE);
+            let start = (offset - dma_offset) as usize;
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = *signature.get(start + i).unwrap_or(&0);
+            }
+        } else if offset == self.interface.data_offset() {
+            // Wider accesses return the bytes in stream order.
+            self.read_data(data);
+        } else {
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+        }
+    }
+}
+
+impl DeviceState for FWCfgDev {
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        let mut state = StateWriter::new();
+        state.write_u16(self.cur_entry);
+        state.write_u32(self.cur_offset);
+        state.write_u64(self.dma_address);
+
+        // Only the entries owning their data may have changed.
+        let mut changing = Vec::new();
+        for (arch, entries) in self.entries.iter().enumerate() {
+            for (key, entry) in entries.iter() {
+                if entry.allow_write || entry.select_cb.is_some() {
+                    changing.push((arch, *key, &entry.buf));
+                }
+            }
+        }
+        changing.sort_by_key(|&(arch, key, _)| (arch, key));
+        state.write_u32(changing.len() as u32);
+        for (arch, key, buf) in changing {
+            state.write_u8(arch as u8);
+            state.write_u32(key);
+            state.write_bytes(buf);
+        }
+        Ok(state.into_bytes())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        let mut state = StateReader::new(data);
+        self.cur_entry = state.read_u16();
+        self.cur_offset = state.read_u32();
+        self.dma_address = state.read_u64();
+
+        for _ in 0..state.read_u32() {
+            let arch = state.read_u8() as usize;
+            let key = state.read_u32();
+            let buf = state.read_bytes();
+            let entry = self.entries.get_mut(arch)
+                .and_then(|entries| entries.get_mut(&key))
+                .filter(|entry| entry.buf.len() == buf.len())
+                .unwrap_or_else(|| panic!(
+                    "Invalid snapshot: unknown fw cfg entry {:x}.", key));
+            // The data pointer refers to the buffer, which is updated
+            // in place.
+            entry.buf.copy_from_slice(buf);
+            if entry.allow_write {
+                if let Some(ref mut write_cb) = entry.write_cb {
+                    write_cb(&entry.buf, 0, buf.len());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use ::memory::MmapMemorySlot;
+
+    const MEM_SIZE: usize = 1 << 20;
+    const ACCESS_ADDRESS: u64 = 0x1000;
+    const BUF_ADDRESS: u64 = 0x2000;
+
+    fn new_dev(slot: &MmapMemorySlot) -> FWCfgDev {
+        FWCfgDev::new(FWCfgInterface::Io, GuestMemory::new(&[slot]))
+    }
+
+    /// Runs a DMA transfer, returning the control field once complete.
+    fn dma(dev: &mut FWCfgDev, control: u32, len: u32, address: u64) -> u32 {
+        let mut access = [0u8; FW_CFG_DMA_ACCESS_SIZE];
+        BigEndian::write_u32(&mut access[0..4], control);
+        BigEndian::write_u32(&mut access[4..8], len);
+        BigEndian::write_u64(&mut access[8..16], address);
+        assert!(dev.mem.write(ACCESS_ADDRESS, &access));
+
+        let mut reg = [0u8; 8];
+        BigEndian::write_u64(&mut reg, ACCESS_ADDRESS);
+        let dma_offset = dev.interface.dma_offset();
+        dev.write(dma_offset, &reg);
+
+        let mut status = [0u8; 4];
+        assert!(dev.mem.read(ACCESS_ADDRESS, &mut status));
+        BigEndian::read_u32(&status)
+    }
+
+    fn select(key: u32) -> u32 {
+        key << 16 | FW_CFG_DMA_CTL_SELECT
+    }
+
+    #[test]
+    fn dma_signature() {
+        let slot = MmapMemorySlot::new(MEM_SIZE, 0, 0, 0);
+        let mut dev = new_dev(&slot);
+        let mut data = [0u8; 8];
+        dev.read(dev.interface.dma_offset(), &mut data);
+        assert_eq!(&data, b"QEMU CFG");
+    }
+
+    #[test]
+    fn dma_read_file() {
+        let slot = MmapMemorySlot::new(MEM_SIZE, 0, 0, 0);
+        let mut dev = new_dev(&slot);
+        dev.add_file("etc/test", b"0123456789", 10);
+        let key = dev.find_file("etc/test").unwrap();
+
+        // Reads past the end are filled with zeros.
+        assert_eq!(dma(&mut dev, select(key) | FW_CFG_DMA_CTL_READ, 4,
+                       BUF_ADDRESS), 0);
+        assert_eq!(dma(&mut dev, FW_CFG_DMA_CTL_SKIP, 2, 0), 0);
+        assert!(dev.mem.write(BUF_ADDRESS + 4, &[0xff; 8]));
+        assert_eq!(dma(&mut dev, FW_CFG_DMA_CTL_READ, 8, BUF_ADDRESS + 4), 0);
+
+        let mut data = [0u8; 12];
+        assert!(dev.mem.read(BUF_ADDRESS, &mut data));
+        assert_eq!(&data, b"01236789\0\0\0\0");
+    }
+
+    #[test]
+    fn dma_read_large_file() {
+        let slot = MmapMemorySlot::new(MEM_SIZE, 0, 0, 0);
+        let mut dev = new_dev(&slot);
+        let contents = (0..FW_CFG_DMA_CHUNK_SIZE * 2 + 3)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        dev.add_file("etc/large", &contents, contents.len() as u32);
+        let key = dev.find_file("etc/large").unwrap();
+
+        assert_eq!(dma(&mut dev, select(key) | FW_CFG_DMA_CTL_READ,
+                       contents.len() as u32, BUF_ADDRESS), 0);
+        let mut data = vec![0u8; contents.len()];
+        assert!(dev.mem.read(BUF_ADDRESS, &mut data));
+        assert!(data == contents);
+    }
+
+    #[test]
+    fn dma_invalid_addresses() {
+        let slot = MmapMemorySlot::new(MEM_SIZE, 0, 0, 0);
+        let mut dev = new_dev(&slot);
+        assert_eq!(dma(&mut dev, select(FW_CFG_SIGNATURE) | FW_CFG_DMA_CTL_READ,
+                       4, MEM_SIZE as u64 - 2), FW_CFG_DMA_CTL_ERROR);
+        assert_eq!(dma(&mut dev, select(FW_CFG_SIGNATURE) | FW_CFG_DMA_CTL_READ,
+                       4, u64::max_value() - 1), FW_CFG_DMA_CTL_ERROR);
+    }
+
+    #[test]
+    fn dma_write() {
+        let slot = MmapMemorySlot::new(MEM_SIZE, 0, 0, 0);
+        let mut dev = new_dev(&slot);
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let cb_written = written.clone();
+        dev.add_file_callback("etc/writable", &[0u8; 8], 8, None,
+                              Some(Box::new(move |buf, offset, len| {
+            cb_written.lock().unwrap().push((buf.to_vec(), offset, len));
+        })), true);
+        dev.add_file("etc/readonly", &[0u8; 8], 8);
+
+        // Read-only entries and writes past the end are refused.
+        let key = dev.find_file("etc/readonly").unwrap();
+        assert!(dev.mem.write(BUF_ADDRESS, b"abcdefghij"));
+        assert_eq!(dma(&mut dev, select(key) | FW_CFG_DMA_CTL_WRITE, 4,
+                       BUF_ADDRESS), FW_CFG_DMA_CTL_ERROR);
+        let key = dev.find_file("etc/writable").unwrap();
+        assert_eq!(dma(&mut dev, select(key) | FW_CFG_DMA_CTL_WRITE, 10,
+                       BUF_ADDRESS), FW_CFG_DMA_CTL_ERROR);
+        assert!(written.lock().unwrap().is_empty());
+
+        assert_eq!(dma(&mut dev, select(key) | FW_CFG_DMA_CTL_WRITE, 2,
+                       BUF_ADDRESS), 0);
+        assert_eq!(dma(&mut dev, FW_CFG_DMA_CTL_WRITE, 6, BUF_ADDRESS + 2), 0);
+        assert_eq!(*written.lock().unwrap(),
+                   vec![(b"ab\0\0\0\0\0\0".to_vec(), 0, 2),
+                        (b"abcdefgh".to_vec(), 2, 6)]);
+
+        // The written data can be read back.
+        assert_eq!(dma(&mut dev, select(key) | FW_CFG_DMA_CTL_READ, 8,
+                       BUF_ADDRESS + 0x100), 0);
+        let mut data = [0u8; 8];
+        assert!(dev.mem.read(BUF_ADDRESS + 0x100, &mut data));
+        assert_eq!(&data, b"abcdefgh");
+    }
+
+    #[test]
+    fn dma_address_halves() {
+        let slot = MmapMemorySlot::new(MEM_SIZE, 0, 0, 0);
+        let mut dev = new_dev(&slot);
+        let mut access = [0u8; FW_CFG_DMA_ACCESS_SIZE];
+        BigEndian::write_u32(&mut access[0..4],
+                             select(FW_CFG_SIGNATURE) | FW_CFG_DMA_CTL_READ);
+        BigEndian::write_u32(&mut access[4..8], 4);
+        BigEndian::write_u64(&mut access[8..16], BUF_ADDRESS);
+        assert!(dev.mem.write(ACCESS_ADDRESS, &access));
+
+        let dma_offset = dev.interface.dma_offset();
+        let mut half = [0u8; 4];
+        dev.write(dma_offset, &half);
+        BigEndian::write_u32(&mut half, ACCESS_ADDRESS as u32);
+        dev.write(dma_offset + 4, &half);
+
+        let mut data = [0u8; 8];
+        assert!(dev.mem.read(ACCESS_ADDRESS, &mut data[..4]));
+        assert_eq!(&data[..4], &[0u8; 4]);
+        assert!(dev.mem.read(BUF_ADDRESS, &mut data[..4]));
+        assert_eq!(&data[..4], b"QEMU");
+    }
+}
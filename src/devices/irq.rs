@@ -0,0 +1,49 @@
+use std::sync::{Arc, Mutex};
+
+use ::accel::IrqChip;
+
+/// A level triggered interrupt pin, which may be driven by several
+/// devices. It stays asserted as long as one of them asserts it.
+pub struct IrqPin {
+    chip: Arc<IrqChip>,
+    irq: u32,
+    // The level requested by each line driving the pin.
+    levels: Mutex<Vec<bool>>,
+}
+
+impl IrqPin {
+    pub fn new(chip: Arc<IrqChip>, irq: u32) -> Arc<Self> {
+        Arc::new(IrqPin {
+            chip: chip,
+            irq: irq,
+            levels: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Returns a new line driving the pin, for the next device using it.
+    pub fn new_line(pin: &Arc<IrqPin>) -> IrqLine {
+        let mut levels = pin.levels.lock().unwrap();
+        levels.push(false);
+        IrqLine {
+            pin: pin.clone(),
+            source: levels.len() - 1,
+        }
+    }
+}
+
+/// An interrupt line wired to a device.
+#[derive(Clone)]
+pub struct IrqLine {
+    pin: Arc<IrqPin>,
+    source: usize,
+}
+
+impl IrqLine {
+    pub fn set_level(&self, active: bool) {
+        let mut levels = self.pin.levels.lock().unwrap();
+        levels[self.source] = active;
+        // Keep the lock, so that the chip sees the levels in order.
+        self.pin.chip.set_irq_line(self.pin.irq,
+                                   levels.iter().any(|&level| level));
+    }
+}
@@ -1,4 +1,9 @@
+pub mod acpi_pm;
 pub mod bus;
 pub mod fw_cfg;
+pub mod irq;
+pub mod pci;
 pub mod post_code;
 pub mod qdbg;
+pub mod serial;
+pub mod virtio;
@@ -0,0 +1,319 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+// Includes the PCIe extended configuration space.
+pub const NUM_CONFIGURATION_REGISTERS: usize = 1024;
+pub const PCI_CONFIG_SPACE_SIZE: usize = 256;
+pub const NUM_BAR_REGS: usize = 6;
+
+const STATUS_REG: usize = 1;
+const STATUS_REG_CAPABILITIES_USED_MASK: u32 = 0x0010_0000;
+const BAR0_REG: usize = 4;
+const CAPABILITY_LIST_HEAD_OFFSET: usize = 0x34;
+const FIRST_CAPABILITY_OFFSET: usize = 0x40;
+const INTERRUPT_LINE_PIN_REG: usize = 15;
+
+const COMMAND_REG: usize = 1;
+const COMMAND_REG_IO_SPACE: u32 = 0x1;
+const COMMAND_REG_MEMORY_SPACE: u32 = 0x2;
+const COMMAND_REG_BUS_MASTER: u32 = 0x4;
+const COMMAND_REG_INTX_DISABLE: u32 = 0x400;
+const COMMAND_REG_WRITABLE_BITS: u32 = COMMAND_REG_IO_SPACE |
+                                       COMMAND_REG_MEMORY_SPACE |
+                                       COMMAND_REG_BUS_MASTER |
+                                       COMMAND_REG_INTX_DISABLE;
+
+const BAR_IO_ADDR_MASK: u32 = 0xffff_fffc;
+const BAR_IO_BIT: u32 = 0x1;
+const BAR_MEM_ADDR_MASK: u32 = 0xffff_fff0;
+const BAR_MEM_64BIT: u32 = 0x4;
+const BAR_MEM_PREFETCHABLE: u32 = 0x8;
+
+pub const HEADER_TYPE_DEVICE: u8 = 0x00;
+pub const HEADER_TYPE_BRIDGE: u8 = 0x01;
+pub const HEADER_TYPE_MULTIFUNCTION: u8 = 0x80;
+
+// PCI class codes.
+pub const PCI_CLASS_STORAGE: u8 = 0x01;
+pub const PCI_CLASS_NETWORK: u8 = 0x02;
+pub const PCI_CLASS_BRIDGE: u8 = 0x06;
+pub const PCI_CLASS_COMMUNICATION: u8 = 0x07;
+pub const PCI_CLASS_OTHER: u8 = 0xff;
+
+pub const PCI_SUBCLASS_BRIDGE_HOST: u8 = 0x00;
+pub const PCI_SUBCLASS_BRIDGE_ISA: u8 = 0x01;
+pub const PCI_SUBCLASS_BRIDGE_PCI: u8 = 0x04;
+
+// The devices only use 64-bit BARs so far, the other types are decoded for
+// completeness.
+#[allow(dead_code)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PciBarRegionType {
+    IoRegion,
+    Memory32BitRegion,
+    Memory64BitRegion,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PciInterruptPin {
+    IntA = 1,
+    IntB = 2,
+    IntC = 3,
+    IntD = 4,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PciBarConfiguration {
+    pub size: u64,
+    pub region_type: PciBarRegionType,
+    pub prefetchable: bool,
+}
+
+/// A BAR that's currently decoded by the device.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PciBarMapping {
+    pub bar_idx: usize,
+    pub addr: u64,
+    pub size: u64,
+    pub region_type: PciBarRegionType,
+}
+
+/// The PCI configuration space of a device, along with the bits that can be changed by the guest.
+pub struct PciConfiguration {
+    registers: [u32; NUM_CONFIGURATION_REGISTERS],
+    writable_bits: [u32; NUM_CONFIGURATION_REGISTERS],
+    bars: [Option<PciBarConfiguration>; NUM_BAR_REGS],
+    // Includes the upper halves of the 64-bit BARs.
+    bars_used: [bool; NUM_BAR_REGS],
+    // The offset and length of the last capability.
+    last_capability: Option<(usize, usize)>,
+}
+
+impl PciConfiguration {
+    pub fn new(vendor_id: u16, device_id: u16, class_code: u8, subclass: u8,
+               prog_if: u8, header_type: u8, subsystem_vendor_id: u16,
+               subsystem_id: u16, revision_id: u8) -> Self {
+        let mut registers = [0u32; NUM_CONFIGURATION_REGISTERS];
+        let mut writable_bits = [0u32; NUM_CONFIGURATION_REGISTERS];
+
+        registers[0] = (device_id as u32) << 16 | vendor_id as u32;
+        writable_bits[COMMAND_REG] = COMMAND_REG_WRITABLE_BITS;
+        registers[2] = (class_code as u32) << 24 | (subclass as u32) << 16 |
+                       (prog_if as u32) << 8 | revision_id as u32;
+        registers[3] = (header_type as u32) << 16;
+        if header_type & !HEADER_TYPE_MULTIFUNCTION == HEADER_TYPE_DEVICE {
+            registers[11] = (subsystem_id as u32) << 16 |
+                            subsystem_vendor_id as u32;
+        }
+        // The interrupt line may be set by the guest.
+        writable_bits[INTERRUPT_LINE_PIN_REG] = 0xff;
+
+        PciConfiguration {
+            registers: registers,
+            writable_bits: writable_bits,
+            bars: [None; NUM_BAR_REGS],
+            bars_used: [false; NUM_BAR_REGS],
+            last_capability: None,
+        }
+    }
+
+    /// The raw registers, saved in snapshots.
+    pub fn registers(&self) -> &[u32] {
+        &self.registers
+    }
+
+    /// Overwrites all the registers, including the read-only bits.
+    pub fn set_registers(&mut self, registers: &[u32]) {
+        self.registers.copy_from_slice(registers);
+    }
+
+    pub fn read_reg(&self, reg_idx: usize) -> u32 {
+        if reg_idx < NUM_CONFIGURATION_REGISTERS {
+            self.registers[reg_idx]
+        } else {
+            0xffff_ffff
+        }
+    }
+
+    /// Writes `data` at `offset` within the given register, preserving the read-only bits.
+    pub fn write_reg(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        let offset = offset as usize;
+        if reg_idx >= NUM_CONFIGURATION_REGISTERS || offset + data.len() > 4 {
+            return;
+        }
+
+        let (value, mask) = match data.len() {
+            1 => (data[0] as u32, 0xff),
+            2 => (LittleEndian::read_u16(data) as u32, 0xffff),
+            4 => (LittleEndian::read_u32(data), 0xffff_ffff),
+            _ => return,
+        };
+        let shift = offset * 8;
+        let mask = (mask << shift) & self.writable_bits[reg_idx];
+        let value = value << shift;
+
+        self.registers[reg_idx] = (self.registers[reg_idx] & !mask) | (value & mask);
+    }
+
+    /// Adds a BAR, returning its index. Sizes must be powers of two.
+    pub fn add_bar(&mut self, config: PciBarConfiguration) -> usize {
+        let bar_idx = self.bars_used.iter().position(|used| !used)
+                          .expect("No free PCI BARs.");
+        if !config.size.is_power_of_two() {
+            panic!("Invalid PCI BAR size: {}", config.size);
+        }
+        let reg_idx = BAR0_REG + bar_idx;
+
+        match config.region_type {
+            PciBarRegionType::IoRegion => {
+                let size = config.size.max(4);
+                self.registers[reg_idx] = BAR_IO_BIT;
+                self.writable_bits[reg_idx] = BAR_IO_ADDR_MASK &
+                                              !(size as u32 - 1);
+            },
+            PciBarRegionType::Memory32BitRegion => {
+                let size = config.size.max(16);
+                self.registers[reg_idx] = if config.prefetchable {
+                    BAR_MEM_PREFETCHABLE
+                } else {
+                    0
+                };
+                self.writable_bits[reg_idx] = BAR_MEM_ADDR_MASK &
+                                              !(size as u32 - 1);
+            },
+            PciBarRegionType::Memory64BitRegion => {
+                if bar_idx + 1 >= NUM_BAR_REGS {
+                    panic!("No room left for a 64-bit PCI BAR.");
+                }
+                let size = config.size.max(16);
+                let mask = !(size - 1);
+                self.registers[reg_idx] = BAR_MEM_64BIT | if config.prefetchable {
+                    BAR_MEM_PREFETCHABLE
+                } else {
+                    0
+                };
+                self.writable_bits[reg_idx] = BAR_MEM_ADDR_MASK & mask as u32;
+                self.writable_bits[reg_idx + 1] = (mask >> 32) as u32;
+                self.bars_used[bar_idx + 1] = true;
+            },
+        }
+
+        self.bars[bar_idx] = Some(config);
+        self.bars_used[bar_idx] = true;
+        bar_idx
+    }
+
+    /// Returns the address currently programmed in the given BAR.
+    pub fn get_bar_addr(&self, bar_idx: usize) -> u64 {
+        let reg_idx = BAR0_REG + bar_idx;
+        match self.bars[bar_idx] {
+            Some(PciBarConfiguration {
+                region_type: PciBarRegionType::IoRegion, ..
+            }) => (self.registers[reg_idx] & BAR_IO_ADDR_MASK) as u64,
+            Some(PciBarConfiguration {
+                region_type: PciBarRegionType::Memory32BitRegion, ..
+            }) => (self.registers[reg_idx] & BAR_MEM_ADDR_MASK) as u64,
+            Some(PciBarConfiguration {
+                region_type: PciBarRegionType::Memory64BitRegion, ..
+            }) => (self.registers[reg_idx] & BAR_MEM_ADDR_MASK) as u64 |
+                  (self.registers[reg_idx + 1] as u64) << 32,
+            None => 0,
+        }
+    }
+
+    /// Returns the BARs that are currently decoded, based on the command register.
+    pub fn bar_mappings(&self) -> Vec<PciBarMapping> {
+        let command = self.registers[COMMAND_REG];
+        let mut mappings = Vec::new();
+
+        for bar_idx in 0..NUM_BAR_REGS {
+            let bar = match self.bars[bar_idx] {
+                Some(bar) => bar,
+                None => continue,
+            };
+            let enabled = match bar.region_type {
+                PciBarRegionType::IoRegion =>
+                    command & COMMAND_REG_IO_SPACE != 0,
+                _ => command & COMMAND_REG_MEMORY_SPACE != 0,
+            };
+            let addr = self.get_bar_addr(bar_idx);
+            if enabled && addr != 0 {
+                mappings.push(PciBarMapping {
+                    bar_idx: bar_idx,
+                    addr: addr,
+                    size: bar.size,
+                    region_type: bar.region_type,
+                });
+            }
+        }
+        mappings
+    }
+
+    /// Returns the BAR containing `addr` along with the offset within the BAR.
+    pub fn bar_for_addr(&self, addr: u64) -> Option<(usize, u64)> {
+        self.bar_mappings()
+            .iter()
+            .find(|m| m.addr <= addr && addr < m.addr + m.size)
+            .map(|m| (m.bar_idx, addr - m.addr))
+    }
+
+    pub fn set_irq(&mut self, line: u8, pin: PciInterruptPin) {
+        let reg = &mut self.registers[INTERRUPT_LINE_PIN_REG];
+        *reg = (*reg & 0xffff_0000) | (pin as u32) << 8 | line as u32;
+    }
+
+    pub fn interrupt_pin(&self) -> Option<PciInterruptPin> {
+        match (self.registers[INTERRUPT_LINE_PIN_REG] >> 8) & 0xff {
+            1 => Some(PciInterruptPin::IntA),
+            2 => Some(PciInterruptPin::IntB),
+            3 => Some(PciInterruptPin::IntC),
+            4 => Some(PciInterruptPin::IntD),
+            _ => None,
+        }
+    }
+
+    pub fn set_interrupt_pin(&mut self, pin: PciInterruptPin) {
+        self.set_irq(0, pin);
+    }
+
+    /// Adds a capability, returning its offset in the configuration space.
+    /// `data` must start with the capability ID, the next pointer is filled in.
+    pub fn add_capability(&mut self, data: &[u8]) -> usize {
+        let offset = match self.last_capability {
+            Some((last, last_len)) => (last + last_len + 3) & !3,
+            None => FIRST_CAPABILITY_OFFSET,
+        };
+        if data.len() < 2 || offset + data.len() > PCI_CONFIG_SPACE_SIZE {
+            panic!("No room left for PCI capabilities.");
+        }
+
+        for (i, byte) in data.iter().enumerate() {
+            self.write_byte(offset + i, *byte);
+        }
+        // The next pointer.
+        self.write_byte(offset + 1, 0);
+        match self.last_capability {
+            Some((last, _)) => self.write_byte(last + 1, offset as u8),
+            None => {
+                self.write_byte(CAPABILITY_LIST_HEAD_OFFSET, offset as u8);
+                self.registers[STATUS_REG] |= STATUS_REG_CAPABILITIES_USED_MASK;
+            },
+        }
+        self.last_capability = Some((offset, data.len()));
+        offset
+    }
+
+    pub fn write_byte(&mut self, offset: usize, value: u8) {
+        let shift = (offset % 4) * 8;
+        let reg = &mut self.registers[offset / 4];
+        *reg = (*reg & !(0xff << shift)) | (value as u32) << shift;
+    }
+
+    /// Makes the given configuration space bytes writable by the guest.
+    pub fn set_writable(&mut self, offset: usize, len: usize) {
+        for i in offset..offset + len {
+            self.writable_bits[i / 4] |= 0xff << ((i % 4) * 8);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+use ::devices::bus::BusDevice;
+use ::devices::irq::IrqLine;
+use super::configuration::{PciConfiguration, PciInterruptPin};
+
+#[allow(unused_variables)]
+pub trait PciDevice: Send {
+    /// A short description, used in log messages.
+    fn debug_label(&self) -> String;
+    /// Wires the legacy interrupt pin of the device.
+    fn assign_irq(&mut self, irq: IrqLine, pin: PciInterruptPin) {}
+    fn config_registers(&self) -> &PciConfiguration;
+    fn config_registers_mut(&mut self) -> &mut PciConfiguration;
+    /// Reads from a BAR. `addr` is the guest address, as BARs are
+    /// inserted on the buses using full addresses.
+    fn read_bar(&mut self, addr: u64, data: &mut [u8]) {}
+    /// Writes to a BAR. `addr` is the guest address.
+    fn write_bar(&mut self, addr: u64, data: &[u8]) {}
+    /// Allows devices to react to configuration space changes.
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config_registers_mut().write_reg(reg_idx, offset, data);
+    }
+    fn read_config_register(&self, reg_idx: usize) -> u32 {
+        self.config_registers().read_reg(reg_idx)
+    }
+    /// Saves the device state, the configuration space being saved by the
+    /// PCI root. Returns None if the device doesn't support snapshots.
+    fn save_state(&mut self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+    /// Called after the configuration space was restored.
+    fn restore_state(&mut self, data: &[u8]) {}
+    /// Called once the snapshot was taken.
+    fn resume(&mut self) {}
+}
+
+impl<T: PciDevice> BusDevice for T {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        self.read_bar(offset, data)
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        self.write_bar(offset, data)
+    }
+
+    fn config_register_write(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.write_config_register(reg_idx, offset, data)
+    }
+
+    fn config_register_read(&self, reg_idx: usize) -> u32 {
+        self.read_config_register(reg_idx)
+    }
+}
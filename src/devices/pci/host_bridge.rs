@@ -0,0 +1,41 @@
+use super::configuration::*;
+use super::device::PciDevice;
+
+const VENDOR_ID_INTEL: u16 = 0x8086;
+const DEVICE_ID_INTEL_82441: u16 = 0x1237;
+
+/// i440FX compatible host bridge, only exposing its configuration space.
+pub struct I440fxHostBridge {
+    config: PciConfiguration,
+}
+
+impl I440fxHostBridge {
+    pub fn new() -> Self {
+        I440fxHostBridge {
+            config: PciConfiguration::new(
+                VENDOR_ID_INTEL,
+                DEVICE_ID_INTEL_82441,
+                PCI_CLASS_BRIDGE,
+                PCI_SUBCLASS_BRIDGE_HOST,
+                0,
+                HEADER_TYPE_DEVICE,
+                0,
+                0,
+                0x02),
+        }
+    }
+}
+
+impl PciDevice for I440fxHostBridge {
+    fn debug_label(&self) -> String {
+        "i440FX host bridge".to_string()
+    }
+
+    fn config_registers(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_registers_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+}
@@ -0,0 +1,8 @@
+pub mod configuration;
+pub mod device;
+pub mod host_bridge;
+pub mod q35;
+pub mod root;
+pub mod root_port;
+
+pub use self::root::{PciConfigIo, PciRoot};
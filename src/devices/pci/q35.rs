@@ -0,0 +1,299 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::sync::{Arc, Mutex};
+
+use ::devices::acpi_pm::{AcpiPmDevice, PM_IO_SIZE};
+use ::devices::bus::{Bus, BusDevice, DeviceState};
+use super::configuration::*;
+use super::device::PciDevice;
+use super::root::PciRoot;
+
+const VENDOR_ID_INTEL: u16 = 0x8086;
+const DEVICE_ID_INTEL_Q35_MCH: u16 = 0x29c0;
+const DEVICE_ID_INTEL_ICH9_LPC: u16 = 0x2918;
+
+pub const ICH9_LPC_DEV_NUM: u8 = 0x1f;
+
+// MCH configuration registers.
+const MCH_PCIEXBAR: usize = 0x60;
+const MCH_PCIEXBAR_ENABLE: u64 = 0x1;
+const MCH_PCIEXBAR_LENGTH_MASK: u64 = 0x6;
+const MCH_PCIEXBAR_BASE_MASK: u64 = 0xf_fc00_0000;
+const MCH_PAM0: usize = 0x90;
+const MCH_SMRAM_REGS_END: usize = 0xa0;
+
+/// Default MMCONFIG window, also used by OVMF and SeaBIOS.
+pub const PCIEXBAR_DEFAULT_BASE: u64 = 0xb000_0000;
+/// The ECAM window covers 256 buses, 1MB each.
+pub const PCIEXBAR_DEFAULT_SIZE: u64 = 256 << 20;
+
+// ICH9 LPC configuration registers.
+const ICH9_LPC_PMBASE: usize = 0x40;
+const ICH9_LPC_PMBASE_RTE: u32 = 0x1;
+const ICH9_LPC_PMBASE_MASK: u32 = 0xff80;
+const ICH9_LPC_ACPI_CTRL: usize = 0x44;
+const ICH9_LPC_ACPI_CTRL_ACPI_EN: u32 = 0x80;
+/// PM I/O base used until the firmware programs PMBASE, also used by OVMF
+/// and SeaBIOS.
+pub const ICH9_LPC_PMBASE_DEFAULT: u16 = 0x600;
+
+/// PCIe enhanced configuration access mechanism.
+pub struct PciEcam {
+    root: Arc<Mutex<PciRoot>>,
+}
+
+impl PciEcam {
+    pub fn new(root: Arc<Mutex<PciRoot>>) -> Self {
+        PciEcam {
+            root: root,
+        }
+    }
+
+    /// Returns the bus, device, function and register index.
+    fn decode_offset(offset: u64) -> (u8, u8, u8, usize) {
+        ((offset >> 20) as u8,
+         ((offset >> 15) & 0x1f) as u8,
+         ((offset >> 12) & 0x7) as u8,
+         ((offset & 0xfff) >> 2) as usize)
+    }
+}
+
+impl BusDevice for PciEcam {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let (bus, device, function, reg_idx) = PciEcam::decode_offset(offset);
+        let value = self.root.lock().unwrap()
+                        .config_read(bus, device, function, reg_idx) >>
+                    ((offset & 3) * 8);
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = value.checked_shr(i as u32 * 8).unwrap_or(0) as u8;
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        let (bus, device, function, reg_idx) = PciEcam::decode_offset(offset);
+        self.root.lock().unwrap().config_write(
+            bus, device, function, reg_idx, offset & 3, data);
+    }
+}
+
+/// Q35 memory controller hub. Handles the ECAM window placement
+/// through the PCIEXBAR register.
+pub struct Q35HostBridge {
+    config: PciConfiguration,
+    mmio_bus: Arc<Bus>,
+    ecam: Arc<Mutex<PciEcam>>,
+    // The currently mapped ECAM window, if any.
+    ecam_base: Option<u64>,
+}
+
+impl Q35HostBridge {
+    pub fn new(mmio_bus: Arc<Bus>, ecam: Arc<Mutex<PciEcam>>) -> Self {
+        let mut config = PciConfiguration::new(
+            VENDOR_ID_INTEL,
+            DEVICE_ID_INTEL_Q35_MCH,
+            PCI_CLASS_BRIDGE,
+            PCI_SUBCLASS_BRIDGE_HOST,
+            0,
+            HEADER_TYPE_DEVICE,
+            0,
+            0,
+            0);
+        config.set_writable(MCH_PCIEXBAR, 8);
+        // PAM and SMRAM registers. We don't model them, the firmware
+        // only expects to read back the programmed values.
+        config.set_writable(MCH_PAM0, MCH_SMRAM_REGS_END - MCH_PAM0);
+
+        let mut bridge = Q35HostBridge {
+            config: config,
+            mmio_bus: mmio_bus,
+            ecam: ecam,
+            ecam_base: None,
+        };
+
+        // Unlike the real hardware, the ECAM window is enabled by default,
+        // which allows booting kernels directly.
+        let mut pciexbar = [0u8; 8];
+        LittleEndian::write_u64(&mut pciexbar,
+                                PCIEXBAR_DEFAULT_BASE | MCH_PCIEXBAR_ENABLE);
+        bridge.config.write_reg(MCH_PCIEXBAR / 4, 0, &pciexbar[..4]);
+        bridge.config.write_reg(MCH_PCIEXBAR / 4 + 1, 0, &pciexbar[4..]);
+        bridge.update_ecam();
+
+        bridge
+    }
+
+    fn pciexbar(&self) -> u64 {
+        self.config.read_reg(MCH_PCIEXBAR / 4) as u64 |
+            (self.config.read_reg(MCH_PCIEXBAR / 4 + 1) as u64) << 32
+    }
+
+    fn update_ecam(&mut self) {
+        let pciexbar = self.pciexbar();
+        let size = match (pciexbar & MCH_PCIEXBAR_LENGTH_MASK) >> 1 {
+            0 => 256 << 20,
+            1 => 128 << 20,
+            2 => 64 << 20,
+            _ => 0,
+        };
+        // The reserved length encoding disables the window.
+        let new_base = if pciexbar & MCH_PCIEXBAR_ENABLE != 0 && size != 0 {
+            Some(pciexbar & MCH_PCIEXBAR_BASE_MASK & !(size - 1))
+        } else {
+            None
+        };
+
+        if new_base == self.ecam_base {
+            return;
+        }
+        if let Some(old_base) = self.ecam_base {
+            self.mmio_bus.remove(old_base);
+        }
+        self.ecam_base = None;
+
+        if let Some(base) = new_base {
+            match self.mmio_bus.insert(self.ecam.clone(), base, size, false) {
+                Ok(()) => self.ecam_base = Some(base),
+                Err(e) => println!("Cannot map the ECAM window at {:x}: {:?}",
+                                   base, e),
+            }
+        }
+    }
+}
+
+impl PciDevice for Q35HostBridge {
+    fn debug_label(&self) -> String {
+        "Q35 host bridge".to_string()
+    }
+
+    fn config_registers(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_registers_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config.write_reg(reg_idx, offset, data);
+        if reg_idx == MCH_PCIEXBAR / 4 || reg_idx == MCH_PCIEXBAR / 4 + 1 {
+            self.update_ecam();
+        }
+    }
+
+    fn restore_state(&mut self, _data: &[u8]) {
+        self.update_ecam();
+    }
+}
+
+/// ICH9 LPC bridge. Only the ACPI power management I/O block is modelled,
+/// which the firmware relies on for the PM timer.
+pub struct Ich9Lpc {
+    config: PciConfiguration,
+    io_bus: Arc<Bus>,
+    pm: Arc<Mutex<AcpiPmDevice>>,
+    pm_base: Option<u64>,
+}
+
+impl Ich9Lpc {
+    pub fn new(io_bus: Arc<Bus>) -> Self {
+        let mut config = PciConfiguration::new(
+            VENDOR_ID_INTEL,
+            DEVICE_ID_INTEL_ICH9_LPC,
+            PCI_CLASS_BRIDGE,
+            PCI_SUBCLASS_BRIDGE_ISA,
+            0,
+            HEADER_TYPE_MULTIFUNCTION,
+            0,
+            0,
+            0x02);
+        config.set_writable(ICH9_LPC_PMBASE, 2);
+        config.set_writable(ICH9_LPC_ACPI_CTRL, 1);
+        // The resource type indicator is hardwired to 1 (I/O space).
+        config.write_byte(ICH9_LPC_PMBASE,
+                          ICH9_LPC_PMBASE_DEFAULT as u8 | ICH9_LPC_PMBASE_RTE as u8);
+        config.write_byte(ICH9_LPC_PMBASE + 1, (ICH9_LPC_PMBASE_DEFAULT >> 8) as u8);
+        // ACPI is enabled from the start, as described by the ACPI tables.
+        config.write_byte(ICH9_LPC_ACPI_CTRL, ICH9_LPC_ACPI_CTRL_ACPI_EN as u8);
+
+        let mut lpc = Ich9Lpc {
+            config: config,
+            io_bus: io_bus,
+            pm: Arc::new(Mutex::new(AcpiPmDevice::new())),
+            pm_base: None,
+        };
+        lpc.update_pm_io();
+        lpc
+    }
+
+    fn update_pm_io(&mut self) {
+        let pm_base = self.config.read_reg(ICH9_LPC_PMBASE / 4) &
+                      ICH9_LPC_PMBASE_MASK;
+        let enabled = self.config.read_reg(ICH9_LPC_ACPI_CTRL / 4) &
+                      ICH9_LPC_ACPI_CTRL_ACPI_EN != 0;
+        let new_base = if enabled && pm_base != 0 {
+            Some(pm_base as u64)
+        } else {
+            None
+        };
+
+        if new_base == self.pm_base {
+            return;
+        }
+        if let Some(old_base) = self.pm_base {
+            self.io_bus.remove(old_base);
+        }
+        self.pm_base = None;
+
+        if let Some(base) = new_base {
+            match self.io_bus.insert(self.pm.clone(), base, PM_IO_SIZE, false) {
+                Ok(()) => self.pm_base = Some(base),
+                Err(e) => println!("Cannot map the ACPI PM registers at {:x}: {:?}",
+                                   base, e),
+            }
+        }
+    }
+}
+
+impl PciDevice for Ich9Lpc {
+    fn debug_label(&self) -> String {
+        "ICH9 LPC bridge".to_string()
+    }
+
+    fn config_registers(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_registers_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn write_config_register(&mut self, reg_idx: usize, offset: u64, data: &[u8]) {
+        self.config.write_reg(reg_idx, offset, data);
+        if reg_idx == ICH9_LPC_PMBASE / 4 || reg_idx == ICH9_LPC_ACPI_CTRL / 4 {
+            self.update_pm_io();
+        }
+    }
+
+    fn save_state(&mut self) -> Option<Vec<u8>> {
+        self.pm.lock().unwrap().save_state().ok()
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        self.pm.lock().unwrap().restore_state(data);
+        self.update_pm_io();
+    }
+}
+
+/// Sets up the Q35 host bridge, the ECAM window and the LPC bridge.
+pub fn init(root: &Arc<Mutex<PciRoot>>, io_bus: &Arc<Bus>,
+            mmio_bus: &Arc<Bus>) {
+    let ecam = Arc::new(Mutex::new(PciEcam::new(root.clone())));
+    let host_bridge = Q35HostBridge::new(mmio_bus.clone(), ecam);
+
+    let mut root = root.lock().unwrap();
+    root.add_device_at_slot(Arc::new(Mutex::new(host_bridge)), 0);
+    root.add_device_at_slot(Arc::new(Mutex::new(Ich9Lpc::new(io_bus.clone()))),
+                            ICH9_LPC_DEV_NUM);
+}
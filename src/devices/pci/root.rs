@@ -0,0 +1,389 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use ::accel::IrqChip;
+use ::devices::bus::{Bus, BusDevice, DeviceState};
+use ::devices::irq::IrqPin;
+use ::snapshot::state::{StateReader, StateWriter};
+use super::configuration::{PciBarMapping, PciBarRegionType};
+use super::device::PciDevice;
+use super::root_port::PciRootPort;
+
+pub const PCI_CONFIG_IO_BASE: u64 = 0xcf8;
+pub const PCI_CONFIG_IO_SIZE: u64 = 8;
+
+const MAX_DEVICES: usize = 32;
+
+// Legacy interrupts used by the PCI devices, assigned based on the
+// slot and interrupt pin.
+pub const PCI_IRQS: [u32; 4] = [5, 9, 10, 11];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PciLocation {
+    /// Attached to bus 0, using the given device number.
+    Root(u8),
+    /// The only device behind the root port with the given device number.
+    BehindPort(u8),
+}
+
+struct PciSlot {
+    location: PciLocation,
+    // The same device, used either for BAR accesses or for
+    // configuration space accesses.
+    bus_device: Arc<Mutex<BusDevice>>,
+    pci_device: Arc<Mutex<PciDevice>>,
+    mappings: Vec<PciBarMapping>,
+}
+
+/// The PCI hierarchy: bus 0 holds the host bridge and the devices attached
+/// to it, PCIe root ports may hold one device each on their secondary bus.
+/// Only single function devices are supported.
+pub struct PciRoot {
+    slots: Vec<PciSlot>,
+    root_ports: Vec<(u8, Arc<Mutex<PciRootPort>>)>,
+    io_bus: Arc<Bus>,
+    mmio_bus: Arc<Bus>,
+    irq_chip: Arc<IrqChip>,
+    // The legacy interrupts are shared by the devices using them.
+    irq_pins: BTreeMap<u32, Arc<IrqPin>>,
+}
+
+impl PciRoot {
+    pub fn new(io_bus: Arc<Bus>, mmio_bus: Arc<Bus>,
+               irq_chip: Arc<IrqChip>) -> Self {
+        PciRoot {
+            slots: Vec::new(),
+            root_ports: Vec::new(),
+            io_bus: io_bus,
+            mmio_bus: mmio_bus,
+            irq_chip: irq_chip,
+            irq_pins: BTreeMap::new(),
+        }
+    }
+
+    fn free_device_number(&self) -> u8 {
+        (0..MAX_DEVICES as u8)
+            .find(|dev| !self.slots.iter().any(
+                |s| s.location == PciLocation::Root(*dev)))
+            .expect("No free PCI slots.")
+    }
+
+    /// Attaches a device to the first free slot of bus 0, returning
+    /// the device number.
+    pub fn add_device<T: PciDevice + 'static>(&mut self, device: Arc<Mutex<T>>)
+        -> u8
+    {
+        let dev_num = self.free_device_number();
+        self.add_device_at(device, PciLocation::Root(dev_num));
+        dev_num
+    }
+
+    /// Attaches a device at the given device number of bus 0.
+    pub fn add_device_at_slot<T: PciDevice + 'static>(&mut self,
+                                                      device: Arc<Mutex<T>>,
+                                                      dev_num: u8) {
+        if self.slots.iter().any(|s| s.location == PciLocation::Root(dev_num)) {
+            panic!("PCI slot {:02x} is already used.", dev_num);
+        }
+        self.add_device_at(device, PciLocation::Root(dev_num));
+    }
+
+    /// Returns the device numbers used on bus 0.
+    pub fn root_device_numbers(&self) -> Vec<u8> {
+        let mut dev_nums = self.slots.iter()
+            .filter_map(|s| match s.location {
+                PciLocation::Root(dev_num) => Some(dev_num),
+                PciLocation::BehindPort(_) => None,
+            })
+            .collect::<Vec<_>>();
+        dev_nums.sort();
+        dev_nums
+    }
+
+    /// Adds a PCIe root port on bus 0, returning its device number.
+    pub fn add_root_port(&mut self) -> u8 {
+        let port_num = self.root_ports.len();
+        let port = Arc::new(Mutex::new(PciRootPort::new(port_num as u8)));
+        let dev_num = self.add_device(port.clone());
+        self.root_ports.push((dev_num, port));
+        dev_num
+    }
+
+    /// Returns the first root port that doesn't have a device attached.
+    pub fn free_root_port(&self) -> Option<u8> {
+        self.root_ports.iter()
+            .map(|&(dev_num, _)| dev_num)
+            .find(|dev_num| !self.slots.iter().any(
+                |s| s.location == PciLocation::BehindPort(*dev_num)))
+    }
+
+    /// Attaches a device behind the given root port.
+    pub fn add_device_to_port<T: PciDevice + 'static>(&mut self,
+                                                      device: Arc<Mutex<T>>,
+                                                      port_dev_num: u8) {
+        let location = PciLocation::BehindPort(port_dev_num);
+        if !self.root_ports.iter().any(|&(dev, _)| dev == port_dev_num) {
+            panic!("{:02x} is not a PCIe root port.", port_dev_num);
+        }
+        if self.slots.iter().any(|s| s.location == location) {
+            panic!("PCIe root port {:02x} is already used.", port_dev_num);
+        }
+        self.add_device_at(device, location);
+    }
+
+    fn add_device_at<T: PciDevice + 'static>(&mut self, device: Arc<Mutex<T>>,
+                                             location: PciLocation) {
+        {
+            let mut dev = device.lock().unwrap();
+            if let Some(pin) = dev.config_registers().interrupt_pin() {
+                // Devices behind root ports use the interrupts of
+                // the port, swizzled based on their device number (0).
+                let dev_num = match location {
+                    PciLocation::Root(dev_num) => dev_num,
+                    PciLocation::BehindPort(port_dev_num) => port_dev_num,
+                } as usize;
+                let irq = PCI_IRQS[(dev_num + pin as usize - 1) % PCI_IRQS.len()];
+                dev.config_registers_mut().set_irq(irq as u8, pin);
+                let irq_chip = &self.irq_chip;
+                let irq_pin = self.irq_pins.entry(irq).or_insert_with(
+                    || IrqPin::new(irq_chip.clone(), irq));
+                dev.assign_irq(IrqPin::new_line(irq_pin), pin);
+            }
+            match location {
+                PciLocation::Root(dev_num) => println!(
+                    "PCI device {:02x}.0: {}", dev_num, dev.debug_label()),
+                PciLocation::BehindPort(port_dev_num) => println!(
+                    "PCI device behind root port {:02x}.0: {}",
+                    port_dev_num, dev.debug_label()),
+            }
+        }
+
+        self.slots.push(PciSlot {
+            location: location,
+            bus_device: device.clone(),
+            pci_device: device,
+            mappings: Vec::new(),
+        });
+    }
+
+    fn find_slot(&self, bus: u8, device: u8, function: u8) -> Option<usize> {
+        if function != 0 {
+            return None;
+        }
+
+        let location = if bus == 0 {
+            PciLocation::Root(device)
+        } else {
+            // Devices behind root ports always use device number 0.
+            if device != 0 {
+                return None;
+            }
+            let port = self.root_ports.iter().find(|&&(_, ref port)| {
+                port.lock().unwrap().secondary_bus() == bus
+            })?;
+            PciLocation::BehindPort(port.0)
+        };
+
+        self.slots.iter().position(|s| s.location == location)
+    }
+
+    pub fn config_read(&self, bus: u8, device: u8, function: u8,
+                       reg_idx: usize) -> u32 {
+        match self.find_slot(bus, device, function) {
+            Some(idx) => self.slots[idx].bus_device.lock().unwrap()
+                             .config_register_read(reg_idx),
+            None => 0xffff_ffff,
+        }
+    }
+
+    pub fn config_write(&mut self, bus: u8, device: u8, function: u8,
+                        reg_idx: usize, offset: u64, data: &[u8]) {
+        let idx = match self.find_slot(bus, device, function) {
+            Some(idx) => idx,
+            None => return,
+        };
+        self.slots[idx].bus_device.lock().unwrap()
+            .config_register_write(reg_idx, offset, data);
+
+        // The guest may have reprogrammed the BARs or toggled the
+        // address decoding.
+        self.update_mappings(idx);
+    }
+
+    fn update_mappings(&mut self, idx: usize) {
+        let slot = &mut self.slots[idx];
+
+        let mappings = slot.pci_device.lock().unwrap()
+                           .config_registers().bar_mappings();
+        if mappings == slot.mappings {
+            return;
+        }
+
+        for mapping in slot.mappings.iter() {
+            if !mappings.contains(mapping) {
+                let bus = match mapping.region_type {
+                    PciBarRegionType::IoRegion => &self.io_bus,
+                    _ => &self.mmio_bus,
+                };
+                bus.remove(mapping.addr);
+            }
+        }
+
+        let mut active = Vec::new();
+        for mapping in mappings {
+            if slot.mappings.contains(&mapping) {
+                active.push(mapping);
+                continue;
+            }
+
+            let bus = match mapping.region_type {
+                PciBarRegionType::IoRegion => &self.io_bus,
+                _ => &self.mmio_bus,
+            };
+            match bus.insert(slot.bus_device.clone(), mapping.addr,
+                             mapping.size, true) {
+                Ok(()) => active.push(mapping),
+                Err(e) => println!(
+                    "Cannot map BAR {} of PCI device {:?} at {:x}: {:?}",
+                    mapping.bar_idx, slot.location, mapping.addr, e),
+            }
+        }
+        slot.mappings = active;
+    }
+}
+
+/// Saves the configuration space of each device along with the device state.
+/// The devices must be created in the same order when restoring.
+impl DeviceState for PciRoot {
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        let mut state = StateWriter::new();
+        state.write_u32(self.slots.len() as u32);
+        for slot in &self.slots {
+            let mut device = slot.pci_device.lock().unwrap();
+            let label = device.debug_label();
+            let device_state = device.save_state().ok_or(
+                format!("{} doesn't support snapshots", label))?;
+
+            state.write_str(&label);
+            let registers = device.config_registers().registers();
+            state.write_u32(registers.len() as u32);
+            for reg in registers {
+                state.write_u32(*reg);
+            }
+            state.write_bytes(&device_state);
+        }
+        Ok(state.into_bytes())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        let mut state = StateReader::new(data);
+        if state.read_u32() as usize != self.slots.len() {
+            panic!("Invalid snapshot: the PCI devices don't match.");
+        }
+        for idx in 0..self.slots.len() {
+            let pci_device = self.slots[idx].pci_device.clone();
+            let label = state.read_str();
+            if label != pci_device.lock().unwrap().debug_label() {
+                panic!("Invalid snapshot: unexpected PCI device: {}", label);
+            }
+
+            let registers = (0..state.read_u32())
+                .map(|_| state.read_u32())
+                .collect::<Vec<_>>();
+            pci_device.lock().unwrap().config_registers_mut()
+                .set_registers(&registers);
+            self.update_mappings(idx);
+
+            pci_device.lock().unwrap().restore_state(state.read_bytes());
+        }
+    }
+
+    fn resume(&mut self) {
+        for slot in &self.slots {
+            slot.pci_device.lock().unwrap().resume();
+        }
+    }
+}
+
+/// Configuration space access mechanism #1, using the 0xcf8 address
+/// register and the 0xcfc data register.
+pub struct PciConfigIo {
+    root: Arc<Mutex<PciRoot>>,
+    config_address: u32,
+}
+
+impl PciConfigIo {
+    pub fn new(root: Arc<Mutex<PciRoot>>) -> Self {
+        PciConfigIo {
+            root: root,
+            config_address: 0,
+        }
+    }
+
+    /// Returns the bus, device, function and register index, if enabled.
+    fn decode_address(&self) -> Option<(u8, u8, u8, usize)> {
+        if self.config_address & 0x8000_0000 == 0 {
+            return None;
+        }
+        Some(((self.config_address >> 16) as u8,
+              ((self.config_address >> 11) & 0x1f) as u8,
+              ((self.config_address >> 8) & 0x7) as u8,
+              ((self.config_address >> 2) & 0x3f) as usize))
+    }
+}
+
+impl BusDevice for PciConfigIo {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        let value = match offset {
+            0..=3 => self.config_address,
+            4..=7 => match self.decode_address() {
+                Some((bus, device, function, reg_idx)) =>
+                    self.root.lock().unwrap()
+                        .config_read(bus, device, function, reg_idx),
+                None => 0xffff_ffff,
+            },
+            _ => 0xffff_ffff,
+        };
+
+        // Handle partial register reads.
+        let shift = (offset % 4) * 8;
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (value.checked_shr(shift as u32 + i as u32 * 8)
+                          .unwrap_or(0)) as u8;
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        match offset {
+            // Only full register writes are accepted, this also leaves
+            // out the 0xcf9 reset control register.
+            0 if data.len() == 4 => {
+                self.config_address = LittleEndian::read_u32(data);
+            },
+            4..=7 => {
+                if let Some((bus, device, function, reg_idx)) =
+                        self.decode_address() {
+                    self.root.lock().unwrap().config_write(
+                        bus, device, function, reg_idx, offset - 4, data);
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl DeviceState for PciConfigIo {
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        let mut state = StateWriter::new();
+        state.write_u32(self.config_address);
+        Ok(state.into_bytes())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        self.config_address = StateReader::new(data).read_u32();
+    }
+}
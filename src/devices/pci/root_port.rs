@@ -0,0 +1,84 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use super::configuration::*;
+use super::device::PciDevice;
+
+const VENDOR_ID_REDHAT: u16 = 0x1b36;
+const DEVICE_ID_PCIE_ROOT_PORT: u16 = 0x000c;
+
+const BUS_NUMBERS_REG: usize = 6;
+
+const PCI_CAP_ID_EXP: u8 = 0x10;
+// Capability version 2, root port.
+const PCI_EXP_FLAGS: u16 = 0x2 | 0x4 << 4;
+const PCI_EXP_FLAGS_SLOT: u16 = 0x100;
+// 2.5GT/s, x1.
+const PCI_EXP_LINK_CAPS: u32 = 0x11;
+const PCI_EXP_LINK_STATUS: u16 = 0x11;
+const PCI_EXP_SLOT_CAPS_PHYSICAL_SLOT_SHIFT: u32 = 19;
+const PCI_EXP_CAP_SIZE: usize = 0x3c;
+
+/// A generic PCIe root port. It's a PCI-to-PCI bridge whose secondary
+/// bus holds at most one device.
+pub struct PciRootPort {
+    config: PciConfiguration,
+}
+
+impl PciRootPort {
+    pub fn new(port_num: u8) -> Self {
+        let mut config = PciConfiguration::new(
+            VENDOR_ID_REDHAT,
+            DEVICE_ID_PCIE_ROOT_PORT,
+            PCI_CLASS_BRIDGE,
+            PCI_SUBCLASS_BRIDGE_PCI,
+            0,
+            HEADER_TYPE_BRIDGE,
+            0,
+            0,
+            0);
+
+        // Bus numbers, I/O, memory and prefetchable memory windows,
+        // all managed by the guest.
+        config.set_writable(BUS_NUMBERS_REG * 4, 3);
+        config.set_writable(0x1c, 2);
+        config.set_writable(0x20, 16);
+        config.set_writable(0x30, 4);
+        // Bridge control.
+        config.set_writable(0x3e, 2);
+
+        let mut cap = [0u8; PCI_EXP_CAP_SIZE];
+        cap[0] = PCI_CAP_ID_EXP;
+        LittleEndian::write_u16(&mut cap[2..], PCI_EXP_FLAGS | PCI_EXP_FLAGS_SLOT);
+        LittleEndian::write_u32(&mut cap[0x0c..],
+                                PCI_EXP_LINK_CAPS | (port_num as u32) << 24);
+        LittleEndian::write_u16(&mut cap[0x12..], PCI_EXP_LINK_STATUS);
+        LittleEndian::write_u32(
+            &mut cap[0x14..],
+            (port_num as u32) << PCI_EXP_SLOT_CAPS_PHYSICAL_SLOT_SHIFT);
+        config.add_capability(&cap);
+
+        PciRootPort {
+            config: config,
+        }
+    }
+
+    pub fn secondary_bus(&self) -> u8 {
+        (self.config.read_reg(BUS_NUMBERS_REG) >> 8) as u8
+    }
+}
+
+impl PciDevice for PciRootPort {
+    fn debug_label(&self) -> String {
+        "PCIe root port".to_string()
+    }
+
+    fn config_registers(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_registers_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+}
@@ -1,15 +1,21 @@
+use ::chardev::CharBackend;
 use super::bus::BusDevice;
 
-pub struct PostCodeHandler {}
+pub struct PostCodeHandler {
+    backend: Box<CharBackend>,
+}
 
 impl PostCodeHandler {
-    pub fn new() -> Self {
-        PostCodeHandler {}
+    pub fn new(backend: Box<CharBackend>) -> Self {
+        PostCodeHandler {
+            backend: backend,
+        }
     }
 }
 
 impl BusDevice for PostCodeHandler {
     fn write(&mut self, _offset: u64, data: &[u8]) {
-        println!("POST: 0x{:x}", data[0]);
+        let msg = format!("POST: 0x{:x}\n", data[0]);
+        self.backend.write(msg.as_bytes());
     }
 }
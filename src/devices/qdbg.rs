@@ -1,18 +1,21 @@
-use std::io::{self, Write};
-
+use ::chardev::CharBackend;
 use super::bus::BusDevice;
 
-pub struct QemuDebugConsole {}
+pub struct QemuDebugConsole {
+    backend: Box<CharBackend>,
+}
 
 impl QemuDebugConsole {
-    pub fn new() -> Self {
-        QemuDebugConsole {}
+    pub fn new(backend: Box<CharBackend>) -> Self {
+        QemuDebugConsole {
+            backend: backend,
+        }
     }
 }
 
 impl BusDevice for QemuDebugConsole {
     fn write(&mut self, _offset: u64, data: &[u8]) {
-        io::stdout().write(data).unwrap();
+        self.backend.write(data);
     }
 
     fn read(&mut self, offset: u64, data: &mut [u8]) {
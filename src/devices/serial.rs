@@ -0,0 +1,400 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use ::chardev::{CharBackend, CharFrontend};
+use ::snapshot::state::{StateReader, StateWriter};
+use super::bus::{BusDevice, DeviceState};
+use super::irq::IrqLine;
+
+// Standard ISA serial ports: (I/O base, IRQ).
+pub const COM_PORTS: [(u64, u32); 4] = [
+    (0x3f8, 4),
+    (0x2f8, 3),
+    (0x3e8, 4),
+    (0x2e8, 3),
+];
+pub const COM_PORT_SIZE: u64 = 8;
+
+// Register offsets.
+const DATA: u64 = 0;            // RBR/THR, DLL when DLAB is set
+const IER: u64 = 1;             // DLM when DLAB is set
+const IIR_FCR: u64 = 2;         // IIR on read, FCR on write
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDA: u8 = 0x01;
+const IER_THRE: u8 = 0x02;
+const IER_RLS: u8 = 0x04;
+const IER_MSI: u8 = 0x08;
+const IER_MASK: u8 = 0x0f;
+
+const IIR_NO_INT: u8 = 0x01;
+const IIR_MSI: u8 = 0x00;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_RLS: u8 = 0x06;
+const IIR_CTI: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+const FCR_CLEAR_TX: u8 = 0x04;
+const FCR_TRIGGER_MASK: u8 = 0xc0;
+
+const LCR_DLAB: u8 = 0x80;
+
+const MCR_DTR: u8 = 0x01;
+const MCR_RTS: u8 = 0x02;
+const MCR_OUT1: u8 = 0x04;
+const MCR_OUT2: u8 = 0x08;
+const MCR_LOOP: u8 = 0x10;
+const MCR_MASK: u8 = 0x1f;
+
+const LSR_DR: u8 = 0x01;
+const LSR_OE: u8 = 0x02;
+const LSR_BI: u8 = 0x10;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+const LSR_ERRORS: u8 = LSR_OE | 0x04 | 0x08 | LSR_BI;
+
+const MSR_DCTS: u8 = 0x01;
+const MSR_DDSR: u8 = 0x02;
+const MSR_TERI: u8 = 0x04;
+const MSR_DDCD: u8 = 0x08;
+const MSR_CTS: u8 = 0x10;
+const MSR_DSR: u8 = 0x20;
+const MSR_RI: u8 = 0x40;
+const MSR_DCD: u8 = 0x80;
+const MSR_DELTA_MASK: u8 = 0x0f;
+
+const FIFO_SIZE: usize = 16;
+
+// 115200 baud.
+const DEFAULT_DIVISOR: u16 = 1;
+
+/// 16550A UART emulation.
+pub struct Serial {
+    divisor: u16,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    lsr: u8,
+    msr: u8,
+    scr: u8,
+    fcr: u8,
+    // Set when the transmitter holding register became empty, cleared
+    // when reading IIR or writing THR.
+    thr_ipending: bool,
+    rx_fifo: VecDeque<u8>,
+    irq: IrqLine,
+    irq_active: bool,
+    backend: Box<CharBackend>,
+}
+
+impl Serial {
+    pub fn new(irq: IrqLine, backend: Box<CharBackend>) -> Self {
+        Serial {
+            divisor: DEFAULT_DIVISOR,
+            ier: 0,
+            lcr: 0x03,  // 8N1
+            mcr: MCR_OUT2,
+            lsr: LSR_THRE | LSR_TEMT,
+            msr: MSR_CTS | MSR_DSR | MSR_DCD,
+            scr: 0,
+            fcr: 0,
+            thr_ipending: false,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            irq: irq,
+            irq_active: false,
+            backend: backend,
+        }
+    }
+
+    /// Starts receiving input from the backend.
+    pub fn connect_input(serial: &Arc<Mutex<Serial>>) {
+        let frontend = serial.clone();
+        serial.lock().unwrap().backend.connect_input(frontend);
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fcr & FCR_FIFO_ENABLE != 0
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() { FIFO_SIZE } else { 1 }
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match (self.fcr & FCR_TRIGGER_MASK) >> 6 {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn is_loopback(&self) -> bool {
+        self.mcr & MCR_LOOP != 0
+    }
+
+    /// Returns the currently pending interrupt, by priority.
+    fn pending_interrupt(&self) -> u8 {
+        if self.ier & IER_RLS != 0 && self.lsr & LSR_ERRORS != 0 {
+            IIR_RLS
+        } else if self.ier & IER_RDA != 0 && !self.rx_fifo.is_empty() {
+            // We don't model the character timeout, the data is reported
+            // as soon as it's available.
+            if self.rx_fifo.len() >= self.rx_trigger_level() {
+                IIR_RDA
+            } else {
+                IIR_CTI
+            }
+        } else if self.ier & IER_THRE != 0 && self.thr_ipending {
+            IIR_THRE
+        } else if self.ier & IER_MSI != 0 && self.msr & MSR_DELTA_MASK != 0 {
+            IIR_MSI
+        } else {
+            IIR_NO_INT
+        }
+    }
+
+    fn update_irq(&mut self) {
+        let active = self.pending_interrupt() != IIR_NO_INT;
+        if active != self.irq_active {
+            self.irq_active = active;
+            self.irq.set_level(active);
+        }
+    }
+
+    fn receive_byte(&mut self, byte: u8) {
+        if self.rx_fifo.len() >= self.rx_capacity() {
+            self.lsr |= LSR_OE;
+        } else {
+            self.rx_fifo.push_back(byte);
+        }
+        self.lsr |= LSR_DR;
+    }
+
+    fn transmit_byte(&mut self, byte: u8) {
+        if self.is_loopback() {
+            self.receive_byte(byte);
+        } else {
+            self.backend.write(&[byte]);
+        }
+
+        self.lsr |= LSR_THRE | LSR_TEMT;
+        self.thr_ipending = true;
+    }
+
+    fn loopback_msr(&self) -> u8 {
+        let mut msr = 0;
+        if self.mcr & MCR_RTS != 0 {
+            msr |= MSR_CTS;
+        }
+        if self.mcr & MCR_DTR != 0 {
+            msr |= MSR_DSR;
+        }
+        if self.mcr & MCR_OUT1 != 0 {
+            msr |= MSR_RI;
+        }
+        if self.mcr & MCR_OUT2 != 0 {
+            msr |= MSR_DCD;
+        }
+        msr
+    }
+
+    fn write_mcr(&mut self, value: u8) {
+        let old_status = self.msr & !MSR_DELTA_MASK;
+        self.mcr = value & MCR_MASK;
+
+        let new_status = if self.is_loopback() {
+            self.loopback_msr()
+        } else {
+            MSR_CTS | MSR_DSR | MSR_DCD
+        };
+
+        let changed = old_status ^ new_status;
+        let mut delta = self.msr & MSR_DELTA_MASK;
+        if changed & MSR_CTS != 0 {
+            delta |= MSR_DCTS;
+        }
+        if changed & MSR_DSR != 0 {
+            delta |= MSR_DDSR;
+        }
+        if changed & MSR_DCD != 0 {
+            delta |= MSR_DDCD;
+        }
+        // Trailing edge of the ring indicator.
+        if old_status & MSR_RI != 0 && new_status & MSR_RI == 0 {
+            delta |= MSR_TERI;
+        }
+        self.msr = new_status | delta;
+    }
+
+    fn write_fcr(&mut self, value: u8) {
+        if value & FCR_FIFO_ENABLE != self.fcr & FCR_FIFO_ENABLE {
+            // Toggling the FIFO mode resets both FIFOs.
+            self.rx_fifo.clear();
+        }
+        if value & FCR_CLEAR_RX != 0 {
+            self.rx_fifo.clear();
+            self.lsr &= !(LSR_DR | LSR_OE);
+        }
+        if value & FCR_CLEAR_TX != 0 {
+            self.lsr |= LSR_THRE | LSR_TEMT;
+        }
+        if self.rx_fifo.is_empty() {
+            self.lsr &= !LSR_DR;
+        }
+        self.fcr = value & (FCR_FIFO_ENABLE | FCR_TRIGGER_MASK);
+    }
+
+    fn read_iir(&mut self) -> u8 {
+        let mut iir = self.pending_interrupt();
+        if iir == IIR_THRE {
+            self.thr_ipending = false;
+        }
+        if self.fifo_enabled() {
+            iir |= IIR_FIFO_ENABLED;
+        }
+        iir
+    }
+
+    fn read_rbr(&mut self) -> u8 {
+        let byte = self.rx_fifo.pop_front().unwrap_or(0);
+        if self.rx_fifo.is_empty() {
+            self.lsr &= !LSR_DR;
+        }
+        byte
+    }
+}
+
+impl CharFrontend for Serial {
+    fn receive(&mut self, data: &[u8]) -> usize {
+        // While looping back, the serial port is disconnected from
+        // the outside world.
+        if self.is_loopback() {
+            return 0;
+        }
+
+        let count = data.len().min(
+            self.rx_capacity() - self.rx_fifo.len());
+        for byte in &data[..count] {
+            self.receive_byte(*byte);
+        }
+        self.update_irq();
+        count
+    }
+}
+
+impl BusDevice for Serial {
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            println!("Invalid serial write length: {}", data.len());
+            return;
+        }
+        let value = data[0];
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        match offset {
+            DATA if dlab => {
+                self.divisor = (self.divisor & 0xff00) | value as u16;
+            },
+            DATA => {
+                self.thr_ipending = false;
+                self.transmit_byte(value);
+            },
+            IER if dlab => {
+                self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8;
+            },
+            IER => {
+                let enabling_thre = value & IER_THRE != 0 &&
+                                    self.ier & IER_THRE == 0;
+                self.ier = value & IER_MASK;
+                // Enabling the THRE interrupt while the holding register
+                // is empty immediately triggers it.
+                if enabling_thre && self.lsr & LSR_THRE != 0 {
+                    self.thr_ipending = true;
+                }
+            },
+            IIR_FCR => self.write_fcr(value),
+            LCR => self.lcr = value,
+            MCR => self.write_mcr(value),
+            SCR => self.scr = value,
+            // LSR and MSR are read-only.
+            _ => (),
+        }
+
+        self.update_irq();
+    }
+
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if data.len() != 1 {
+            println!("Invalid serial read length: {}", data.len());
+            return;
+        }
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        data[0] = match offset {
+            DATA if dlab => self.divisor as u8,
+            DATA => self.read_rbr(),
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => self.read_iir(),
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let lsr = self.lsr;
+                // Error bits are cleared on read.
+                self.lsr &= !LSR_ERRORS;
+                lsr
+            },
+            MSR => {
+                let msr = self.msr;
+                self.msr &= !MSR_DELTA_MASK;
+                msr
+            },
+            SCR => self.scr,
+            _ => 0,
+        };
+
+        self.update_irq();
+    }
+}
+
+impl DeviceState for Serial {
+    fn save_state(&mut self) -> Result<Vec<u8>, String> {
+        let mut state = StateWriter::new();
+        state.write_u16(self.divisor);
+        for reg in &[self.ier, self.lcr, self.mcr, self.lsr, self.msr,
+                     self.scr, self.fcr] {
+            state.write_u8(*reg);
+        }
+        state.write_bool(self.thr_ipending);
+        state.write_bytes(&self.rx_fifo.iter().cloned().collect::<Vec<_>>());
+        state.write_bool(self.irq_active);
+        Ok(state.into_bytes())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        let mut state = StateReader::new(data);
+        self.divisor = state.read_u16();
+        self.ier = state.read_u8();
+        self.lcr = state.read_u8();
+        self.mcr = state.read_u8();
+        self.lsr = state.read_u8();
+        self.msr = state.read_u8();
+        self.scr = state.read_u8();
+        self.fcr = state.read_u8();
+        self.thr_ipending = state.read_bool();
+        self.rx_fifo = state.read_bytes().iter().cloned().collect();
+        self.irq_active = state.read_bool();
+        self.irq.set_level(self.irq_active);
+    }
+}
@@ -0,0 +1,427 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use ::control::ControlServer;
+use ::memory::GuestMemory;
+use ::snapshot::state::{StateReader, StateWriter};
+use super::*;
+
+const QUEUE_SIZE: u16 = 128;
+
+// Feature bits.
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
+const VIRTIO_BALLOON_F_PAGE_REPORTING: u32 = 5;
+
+// The balloon works with 4KB pages, whatever the guest page size.
+const BALLOON_PAGE_SHIFT: u32 = 12;
+const BALLOON_PAGE_SIZE: u64 = 1 << BALLOON_PAGE_SHIFT;
+
+// num_pages and actual
+const CONFIG_SIZE: usize = 8;
+const ACTUAL_OFFSET: u64 = 4;
+
+// The driver passes at most 256 page frame numbers at once.
+const MAX_PFNS_SIZE: usize = 256 * 4;
+
+// struct virtio_balloon_stat
+const STAT_SIZE: usize = 10;
+const MAX_STATS_SIZE: usize = STAT_SIZE * 64;
+const STAT_NAMES: [&str; 10] = [
+    "swap-in",
+    "swap-out",
+    "major-faults",
+    "minor-faults",
+    "free-memory",
+    "total-memory",
+    "available-memory",
+    "disk-caches",
+    "hugetlb-allocations",
+    "hugetlb-failures",
+];
+
+// How long to wait for the guest to refresh its statistics.
+const STATS_TIMEOUT_MS: u64 = 1000;
+
+struct StatsQueue {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    queue: Queue,
+    /// The buffer last filled by the driver, returned to ask for
+    /// updated statistics.
+    pending_head: Option<u16>,
+}
+
+struct BalloonState {
+    /// Target number of pages in the balloon.
+    num_pages: u32,
+    /// Number of pages in the balloon, as reported by the driver.
+    actual: u32,
+    stats: Vec<(u16, u64)>,
+    /// Incremented when the driver reports statistics.
+    stats_generation: u64,
+    stats_queue: Option<StatsQueue>,
+    /// Used to let the driver know about target changes.
+    interrupt: Option<Interrupt>,
+}
+
+/// State shared with the control interface.
+struct Shared {
+    state: Mutex<BalloonState>,
+    stats_cond: Condvar,
+}
+
+impl Shared {
+    fn set_target(&self, target_size: u64, ram_size: u64) -> Result<String, String> {
+        if target_size > ram_size {
+            return Err("the target exceeds the memory size".to_string());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.num_pages = ((ram_size - target_size) >> BALLOON_PAGE_SHIFT) as u32;
+        if let Some(ref interrupt) = state.interrupt {
+            interrupt.signal_config_changed();
+        }
+        Ok(String::new())
+    }
+
+    fn info(&self, ram_size: u64) -> String {
+        let state = self.state.lock().unwrap();
+        // The actual size is written by the guest.
+        let size = |pages: u32| {
+            ram_size.saturating_sub((pages as u64) << BALLOON_PAGE_SHIFT) >> 20
+        };
+        format!("target={} actual={}", size(state.num_pages), size(state.actual))
+    }
+
+    /// Asks the driver for updated statistics, returning the last ones if
+    /// it doesn't reply in time.
+    fn stats(&self) -> Result<String, String> {
+        let mut state = self.state.lock().unwrap();
+        let generation = state.stats_generation;
+
+        let requested = match state.stats_queue {
+            Some(ref mut stats_queue) => match stats_queue.pending_head.take() {
+                Some(head) => {
+                    stats_queue.queue.add_used(&stats_queue.mem, head, 0);
+                    stats_queue.interrupt.signal_used_queue();
+                    true
+                },
+                None => false,
+            },
+            None => return Err("statistics are not enabled".to_string()),
+        };
+        if requested {
+            state = self.stats_cond.wait_timeout(
+                state, Duration::from_millis(STATS_TIMEOUT_MS)).unwrap().0;
+            if state.stats_generation == generation {
+                println!("virtio-balloon: the guest did not update its statistics.");
+            }
+        }
+
+        let stats = state.stats.iter()
+            .filter_map(|&(tag, value)| STAT_NAMES.get(tag as usize)
+                        .map(|name| format!("{}={}", name, value)))
+            .collect::<Vec<_>>();
+        Ok(stats.join(" "))
+    }
+}
+
+struct Queues {
+    mem: GuestMemory,
+    inflate: Queue,
+    deflate: Queue,
+    reporting: Option<Queue>,
+}
+
+/// Virtio memory balloon, driven through the control interface. Pages
+/// given back by the guest, either by inflating the balloon or through
+/// free page reporting, are discarded.
+pub struct Balloon {
+    ram_size: u64,
+    shared: Arc<Shared>,
+    acked_features: u64,
+    queues: Option<Queues>,
+}
+
+impl Balloon {
+    pub fn new(ram_size: u64) -> Self {
+        Balloon {
+            ram_size: ram_size,
+            shared: Arc::new(Shared {
+                state: Mutex::new(BalloonState {
+                    num_pages: 0,
+                    actual: 0,
+                    stats: Vec::new(),
+                    stats_generation: 0,
+                    stats_queue: None,
+                    interrupt: None,
+                }),
+                stats_cond: Condvar::new(),
+            }),
+            acked_features: 0,
+            queues: None,
+        }
+    }
+
+    /// Adds the balloon, balloon-info and balloon-stats commands.
+    pub fn add_control_commands(&self, control: &mut ControlServer) {
+        let (shared, ram_size) = (self.shared.clone(), self.ram_size);
+        control.add_command("balloon", "<target MB>", Box::new(move |args| {
+            match args {
+                [target] => match target.parse::<u64>() {
+                    Ok(target) => shared.set_target(target << 20, ram_size),
+                    Err(_) => Err(format!("invalid target: {}", target)),
+                },
+                _ => Err("usage: balloon <target MB>".to_string()),
+            }
+        }));
+
+        let (shared, ram_size) = (self.shared.clone(), self.ram_size);
+        control.add_command("balloon-info", "", Box::new(move |_| {
+            Ok(shared.info(ram_size))
+        }));
+
+        let shared = self.shared.clone();
+        control.add_command("balloon-stats", "", Box::new(move |_| {
+            shared.stats()
+        }));
+    }
+
+    fn has_feature(&self, feature: u32) -> bool {
+        self.acked_features & (1 << feature) != 0
+    }
+
+    /// The queue indexes depend on the negotiated features.
+    fn stats_queue_index(&self) -> Option<usize> {
+        if self.has_feature(VIRTIO_BALLOON_F_STATS_VQ) { Some(2) } else { None }
+    }
+
+    fn reporting_queue_index(&self) -> Option<usize> {
+        if !self.has_feature(VIRTIO_BALLOON_F_PAGE_REPORTING) {
+            return None;
+        }
+        Some(if self.has_feature(VIRTIO_BALLOON_F_STATS_VQ) { 3 } else { 2 })
+    }
+
+    fn process_inflate(&mut self) {
+        let queues = match self.queues {
+            Some(ref mut queues) => queues,
+            None => return,
+        };
+
+        let mut pfns = Vec::new();
+        let mut used = false;
+        while let Some(chain) = queues.inflate.pop(&queues.mem) {
+            if let Some(data) = chain.read_all(&queues.mem, MAX_PFNS_SIZE) {
+                pfns.extend(data.chunks(4).filter(|c| c.len() == 4)
+                                .map(|c| LittleEndian::read_u32(c) as u64));
+            }
+            queues.inflate.add_used(&queues.mem, chain.head_index, 0);
+            used = true;
+        }
+
+        // Discard contiguous ranges at once.
+        pfns.sort();
+        pfns.dedup();
+        let mut i = 0;
+        while i < pfns.len() {
+            let mut count = 1;
+            while i + count < pfns.len() && pfns[i + count] == pfns[i] + count as u64 {
+                count += 1;
+            }
+            discard(&queues.mem, pfns[i] << BALLOON_PAGE_SHIFT,
+                    count as u64 * BALLOON_PAGE_SIZE);
+            i += count;
+        }
+
+        if used {
+            self.shared.state.lock().unwrap().interrupt.as_ref().unwrap()
+                .signal_used_queue();
+        }
+    }
+
+    fn process_deflate(&mut self) {
+        let queues = match self.queues {
+            Some(ref mut queues) => queues,
+            None => return,
+        };
+
+        // Deflated pages are faulted back in by the guest.
+        let mut used = false;
+        while let Some(chain) = queues.deflate.pop(&queues.mem) {
+            queues.deflate.add_used(&queues.mem, chain.head_index, 0);
+            used = true;
+        }
+        if used {
+            self.shared.state.lock().unwrap().interrupt.as_ref().unwrap()
+                .signal_used_queue();
+        }
+    }
+
+    fn process_reporting(&mut self) {
+        let queues = match self.queues {
+            Some(ref mut queues) => queues,
+            None => return,
+        };
+        let reporting = match queues.reporting {
+            Some(ref mut reporting) => reporting,
+            None => return,
+        };
+
+        let mut used = false;
+        while let Some(chain) = reporting.pop(&queues.mem) {
+            for desc in chain.descriptors.iter() {
+                discard(&queues.mem, desc.addr, desc.len as u64);
+            }
+            reporting.add_used(&queues.mem, chain.head_index, 0);
+            used = true;
+        }
+        if used {
+            self.shared.state.lock().unwrap().interrupt.as_ref().unwrap()
+                .signal_used_queue();
+        }
+    }
+
+    fn process_stats(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        let mut stats = Vec::new();
+        {
+            let stats_queue = match state.stats_queue {
+                Some(ref mut stats_queue) => stats_queue,
+                None => return,
+            };
+            while let Some(chain) = stats_queue.queue.pop(&stats_queue.mem) {
+                if let Some(head) = stats_queue.pending_head.take() {
+                    // Not expected, the driver uses a single buffer.
+                    stats_queue.queue.add_used(&stats_queue.mem, head, 0);
+                }
+                if let Some(data) = chain.read_all(&stats_queue.mem,
+                                                   MAX_STATS_SIZE) {
+                    stats = data.chunks(STAT_SIZE).filter(|c| c.len() == STAT_SIZE)
+                        .map(|c| (LittleEndian::read_u16(c),
+                                  LittleEndian::read_u64(&c[2..])))
+                        .collect();
+                }
+                stats_queue.pending_head = Some(chain.head_index);
+            }
+        }
+
+        state.stats = stats;
+        state.stats_generation += 1;
+        self.shared.stats_cond.notify_all();
+    }
+}
+
+/// Discards the backing of a guest range, ignoring the parts that are not
+/// page aligned and the ranges wrapping around.
+fn discard(mem: &GuestMemory, addr: u64, len: u64) {
+    let (start, end) = match (addr.checked_add(BALLOON_PAGE_SIZE - 1),
+                              addr.checked_add(len)) {
+        (Some(start), Some(end)) => (start & !(BALLOON_PAGE_SIZE - 1),
+                                     end & !(BALLOON_PAGE_SIZE - 1)),
+        _ => {
+            println!("virtio-balloon: invalid range {:x} - {:x}.", addr, len);
+            return;
+        },
+    };
+    if end > start && !mem.discard(start, (end - start) as usize) {
+        println!("virtio-balloon: invalid range {:x}-{:x}.", start, end);
+    }
+}
+
+impl VirtioDevice for Balloon {
+    fn debug_label(&self) -> String {
+        "virtio-balloon".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_BALLOON
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        // inflateq, deflateq, statsq and reporting_vq
+        vec![QUEUE_SIZE; 4]
+    }
+
+    fn features(&self) -> u64 {
+        1 << VIRTIO_F_VERSION_1 |
+        1 << VIRTIO_BALLOON_F_STATS_VQ |
+        1 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM |
+        1 << VIRTIO_BALLOON_F_PAGE_REPORTING
+    }
+
+    fn ack_features(&mut self, features: u64) {
+        self.acked_features = features;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let state = self.shared.state.lock().unwrap();
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u32(&mut config[0..], state.num_pages);
+        LittleEndian::write_u32(&mut config[4..], state.actual);
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        if offset == ACTUAL_OFFSET && data.len() == 4 {
+            self.shared.state.lock().unwrap().actual = LittleEndian::read_u32(data);
+        }
+    }
+
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                queues: Vec<Queue>) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.interrupt = Some(interrupt.clone());
+        state.stats_queue = self.stats_queue_index().map(|i| StatsQueue {
+            mem: mem.clone(),
+            interrupt: interrupt,
+            queue: queues[i].clone(),
+            pending_head: None,
+        });
+
+        self.queues = Some(Queues {
+            mem: mem,
+            inflate: queues[0].clone(),
+            deflate: queues[1].clone(),
+            reporting: self.reporting_queue_index().map(|i| queues[i].clone()),
+        });
+    }
+
+    fn queue_notify(&mut self, queue_index: usize) {
+        match queue_index {
+            0 => self.process_inflate(),
+            1 => self.process_deflate(),
+            i if Some(i) == self.stats_queue_index() => self.process_stats(),
+            i if Some(i) == self.reporting_queue_index() => self.process_reporting(),
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.actual = 0;
+        state.stats_queue = None;
+        state.interrupt = None;
+        self.queues = None;
+    }
+
+    fn save_state(&self) -> Option<Vec<u8>> {
+        let state = self.shared.state.lock().unwrap();
+        let mut writer = StateWriter::new();
+        writer.write_u32(state.num_pages);
+        writer.write_u32(state.actual);
+        Some(writer.into_bytes())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        let mut reader = StateReader::new(data);
+        let mut state = self.shared.state.lock().unwrap();
+        state.num_pages = reader.read_u32();
+        state.actual = reader.read_u32();
+    }
+}
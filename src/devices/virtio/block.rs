@@ -0,0 +1,282 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::cmp;
+use std::io;
+
+use ::block::BlockBackend;
+use ::memory::GuestMemory;
+use super::*;
+
+const QUEUE_SIZE: u16 = 256;
+
+const SECTOR_SHIFT: u8 = 9;
+const SECTOR_SIZE: u64 = 1 << SECTOR_SHIFT;
+
+// Feature bits.
+const VIRTIO_BLK_F_SIZE_MAX: u32 = 1;
+const VIRTIO_BLK_F_SEG_MAX: u32 = 2;
+const VIRTIO_BLK_F_RO: u32 = 5;
+const VIRTIO_BLK_F_BLK_SIZE: u32 = 6;
+const VIRTIO_BLK_F_FLUSH: u32 = 9;
+const VIRTIO_BLK_F_DISCARD: u32 = 13;
+const VIRTIO_BLK_F_WRITE_ZEROES: u32 = 14;
+
+// Request types.
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+const VIRTIO_BLK_T_DISCARD: u32 = 11;
+const VIRTIO_BLK_T_WRITE_ZEROES: u32 = 13;
+
+// Request status.
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const VIRTIO_BLK_ID_BYTES: usize = 20;
+const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: u32 = 0x1;
+
+// type, reserved, sector
+const REQUEST_HEADER_SIZE: usize = 16;
+// sector, num_sectors, flags
+const DISCARD_SEGMENT_SIZE: usize = 16;
+const MAX_DISCARD_SECTORS: u32 = u32::max_value() >> SECTOR_SHIFT;
+const MAX_DISCARD_SEGMENTS: u32 = 16;
+
+// The largest request accepted, as advertised through size_max and
+// seg_max: the header and status descriptors are not counted.
+const MAX_SEGMENT_SIZE: u32 = 64 << 10;
+const MAX_SEGMENTS: u32 = QUEUE_SIZE as u32 - 2;
+const MAX_DATA_SIZE: usize = (MAX_SEGMENT_SIZE * MAX_SEGMENTS) as usize;
+
+const CONFIG_SIZE: usize = 60;
+
+/// Virtio block device.
+pub struct Block {
+    disk: Box<BlockBackend>,
+    disk_size: u64,
+    read_only: bool,
+    serial: String,
+    config: [u8; CONFIG_SIZE],
+    state: Option<(GuestMemory, Interrupt, Queue)>,
+}
+
+impl Block {
+    pub fn new(disk: Box<BlockBackend>, read_only: bool, serial: &str) -> Self {
+        let disk_size = disk.size();
+        if disk_size % SECTOR_SIZE != 0 {
+            println!("The disk size is not a multiple of {} bytes, \
+                      the remainder will be ignored.", SECTOR_SIZE);
+        }
+
+        let mut config = [0u8; CONFIG_SIZE];
+        // capacity
+        LittleEndian::write_u64(&mut config[0..], disk_size >> SECTOR_SHIFT);
+        // size_max, seg_max
+        LittleEndian::write_u32(&mut config[8..], MAX_SEGMENT_SIZE);
+        LittleEndian::write_u32(&mut config[12..], MAX_SEGMENTS);
+        // blk_size
+        LittleEndian::write_u32(&mut config[20..], SECTOR_SIZE as u32);
+        // max_discard_sectors, max_discard_seg, discard_sector_alignment
+        LittleEndian::write_u32(&mut config[36..], MAX_DISCARD_SECTORS);
+        LittleEndian::write_u32(&mut config[40..], MAX_DISCARD_SEGMENTS);
+        LittleEndian::write_u32(&mut config[44..], 1);
+        // max_write_zeroes_sectors, max_write_zeroes_seg,
+        // write_zeroes_may_unmap
+        LittleEndian::write_u32(&mut config[48..], MAX_DISCARD_SECTORS);
+        LittleEndian::write_u32(&mut config[52..], MAX_DISCARD_SEGMENTS);
+        config[56] = 1;
+
+        Block {
+            disk: disk,
+            disk_size: disk_size & !(SECTOR_SIZE - 1),
+            read_only: read_only,
+            serial: serial.to_string(),
+            config: config,
+            state: None,
+        }
+    }
+
+    fn check_range(&self, sector: u64, len: u64) -> io::Result<u64> {
+        sector.checked_mul(SECTOR_SIZE)
+            .and_then(|offset| offset.checked_add(len)
+                                     .map(|end| (offset, end)))
+            .filter(|&(_, end)| end <= self.disk_size)
+            .map(|(offset, _)| offset)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "request beyond the end of the disk"))
+    }
+
+    /// Handles discard and write zeroes requests.
+    fn process_segments(&mut self, request_type: u32, payload: &[u8])
+            -> io::Result<()> {
+        if payload.len() % DISCARD_SEGMENT_SIZE != 0 ||
+                payload.len() / DISCARD_SEGMENT_SIZE >
+                    MAX_DISCARD_SEGMENTS as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "invalid discard segments"));
+        }
+
+        for segment in payload.chunks(DISCARD_SEGMENT_SIZE) {
+            let sector = LittleEndian::read_u64(&segment[0..]);
+            let num_sectors = LittleEndian::read_u32(&segment[8..]) as u64;
+            let flags = LittleEndian::read_u32(&segment[12..]);
+            let len = num_sectors << SECTOR_SHIFT;
+            let offset = self.check_range(sector, len)?;
+
+            if request_type == VIRTIO_BLK_T_DISCARD {
+                // Discarding is only a hint, failures are ignored.
+                let _ = self.disk.discard(offset, len);
+            } else {
+                self.disk.write_zeroes(
+                    offset, len, flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Executes a request, returning the data to be passed back to the
+    /// driver along with the request status.
+    fn execute(&mut self, request_type: u32, sector: u64, payload: &[u8],
+               data_len: usize) -> (Vec<u8>, u8) {
+        let result = match request_type {
+            VIRTIO_BLK_T_IN => {
+                let mut data = vec![0u8; data_len];
+                match self.check_range(sector, data_len as u64) {
+                    Ok(offset) => self.disk.read_at(&mut data, offset)
+                                      .map(|_| data),
+                    Err(e) => Err(e),
+                }
+            },
+            VIRTIO_BLK_T_OUT if !self.read_only => {
+                match self.check_range(sector, payload.len() as u64) {
+                    Ok(offset) => self.disk.write_at(payload, offset)
+                                      .map(|_| Vec::new()),
+                    Err(e) => Err(e),
+                }
+            },
+            VIRTIO_BLK_T_FLUSH => self.disk.flush().map(|_| Vec::new()),
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = vec![0u8; VIRTIO_BLK_ID_BYTES];
+                let serial = self.serial.as_bytes();
+                let count = cmp::min(serial.len(), VIRTIO_BLK_ID_BYTES);
+                id[..count].copy_from_slice(&serial[..count]);
+                Ok(id)
+            },
+            VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES if !self.read_only =>
+                self.process_segments(request_type, payload).map(|_| Vec::new()),
+            VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD | VIRTIO_BLK_T_WRITE_ZEROES =>
+                return (Vec::new(), VIRTIO_BLK_S_IOERR),
+            _ => return (Vec::new(), VIRTIO_BLK_S_UNSUPP),
+        };
+
+        match result {
+            Ok(data) => (data, VIRTIO_BLK_S_OK),
+            Err(e) => {
+                println!("virtio-blk request {} failed: {}", request_type, e);
+                (Vec::new(), VIRTIO_BLK_S_IOERR)
+            },
+        }
+    }
+
+    /// Returns the number of bytes written to the guest buffers.
+    fn process_request(&mut self, mem: &GuestMemory, chain: &DescriptorChain)
+            -> u32 {
+        let writable_len = chain.writable().iter()
+                                .map(|d| d.len as usize).sum::<usize>();
+        let readable = match chain.read_all(
+                mem, REQUEST_HEADER_SIZE + MAX_DATA_SIZE) {
+            Some(readable) => readable,
+            None => {
+                println!("Invalid virtio-blk request.");
+                return 0;
+            },
+        };
+        // The last writable byte holds the status.
+        if readable.len() < REQUEST_HEADER_SIZE || writable_len < 1 ||
+                writable_len - 1 > MAX_DATA_SIZE {
+            println!("Invalid virtio-blk request.");
+            return 0;
+        }
+
+        let request_type = LittleEndian::read_u32(&readable[0..]);
+        let sector = LittleEndian::read_u64(&readable[8..]);
+        let data_len = writable_len - 1;
+
+        let (mut data, status) = self.execute(
+            request_type, sector, &readable[REQUEST_HEADER_SIZE..], data_len);
+        // Place the status right after the buffers, as expected by the driver.
+        data.resize(data_len, 0);
+        data.push(status);
+        chain.write_all(mem, &data) as u32
+    }
+}
+
+impl VirtioDevice for Block {
+    fn debug_label(&self) -> String {
+        "virtio-blk".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_BLOCK
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = 1 << VIRTIO_F_VERSION_1 |
+                           1 << VIRTIO_BLK_F_SIZE_MAX |
+                           1 << VIRTIO_BLK_F_SEG_MAX |
+                           1 << VIRTIO_BLK_F_BLK_SIZE |
+                           1 << VIRTIO_BLK_F_FLUSH;
+        if self.read_only {
+            features |= 1 << VIRTIO_BLK_F_RO;
+        } else {
+            features |= 1 << VIRTIO_BLK_F_DISCARD |
+                        1 << VIRTIO_BLK_F_WRITE_ZEROES;
+        }
+        features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_bytes(&self.config, offset, data);
+    }
+
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                mut queues: Vec<Queue>) {
+        self.state = Some((mem, interrupt, queues.remove(0)));
+    }
+
+    fn queue_notify(&mut self, _queue_index: usize) {
+        let (mem, interrupt, mut queue) = match self.state.take() {
+            Some(state) => state,
+            None => return,
+        };
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(&mem) {
+            let len = self.process_request(&mem, &chain);
+            queue.add_used(&mem, chain.head_index, len);
+            used = true;
+        }
+        if used {
+            interrupt.signal_used_queue();
+        }
+
+        self.state = Some((mem, interrupt, queue));
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+    }
+
+    // The queues are all there is to save.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+}
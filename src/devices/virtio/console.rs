@@ -0,0 +1,396 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use ::chardev::{self, CharBackend, CharFrontend};
+use ::memory::GuestMemory;
+use ::utils::options::{parse_bool, parse_key_values};
+use super::*;
+
+const QUEUE_SIZE: u16 = 128;
+
+// Feature bits.
+const VIRTIO_CONSOLE_F_MULTIPORT: u32 = 1;
+const VIRTIO_CONSOLE_F_EMERG_WRITE: u32 = 2;
+
+// Control message events.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+// struct virtio_console_control
+const CONTROL_MSG_SIZE: usize = 8;
+
+// The largest output buffer accepted from the guest.
+const MAX_TX_SIZE: usize = 1 << 20;
+
+// cols, rows, max_nr_ports and emerg_wr.
+const CONFIG_SIZE: usize = 12;
+const EMERG_WR_OFFSET: u64 = 8;
+
+// The control queues sit between the queues of port 0 and port 1.
+const CONTROL_RX_QUEUE: usize = 2;
+const CONTROL_TX_QUEUE: usize = 3;
+
+/// A console port, as passed through the --console-port option.
+pub struct PortConfig {
+    pub chardev: String,
+    /// Exposed to the guest, typically as /dev/virtio-ports/<name>.
+    pub name: Option<String>,
+    /// Whether the guest should use the port as a console (hvc).
+    pub console: bool,
+}
+
+impl PortConfig {
+    /// Parses a chardev=<spec>[,name=<name>][,console=on|off] spec.
+    pub fn parse(spec: &str) -> Self {
+        let mut config = PortConfig {
+            chardev: String::new(),
+            name: None,
+            console: false,
+        };
+        for (key, value) in parse_key_values(spec) {
+            match key.as_str() {
+                "chardev" => config.chardev = value,
+                "name" => config.name = Some(value),
+                "console" => config.console = parse_bool(&key, &value),
+                _ => panic!("Unknown console port option: {}", key),
+            }
+        }
+        if config.chardev.is_empty() {
+            panic!("Missing console port chardev: {}", spec);
+        }
+        config
+    }
+}
+
+fn rx_queue_index(port: usize) -> usize {
+    if port == 0 { 0 } else { 2 + port * 2 }
+}
+
+fn tx_queue_index(port: usize) -> usize {
+    rx_queue_index(port) + 1
+}
+
+fn control_message(id: u32, event: u16, value: u16) -> Vec<u8> {
+    let mut msg = vec![0u8; CONTROL_MSG_SIZE];
+    LittleEndian::write_u32(&mut msg[0..], id);
+    LittleEndian::write_u16(&mut msg[4..], event);
+    LittleEndian::write_u16(&mut msg[6..], value);
+    msg
+}
+
+/// The receive side of the ports, shared with the backend input threads.
+struct RxState {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    queues: Vec<Queue>,
+    /// Whether a guest application has each port open. Input received
+    /// while a port is closed is dropped.
+    guest_open: Vec<bool>,
+}
+
+type SharedRxState = Arc<Mutex<Option<RxState>>>;
+
+/// Passes host input to the receive queue of a port.
+struct PortInput {
+    port: usize,
+    rx_state: SharedRxState,
+}
+
+impl CharFrontend for PortInput {
+    fn receive(&mut self, data: &[u8]) -> usize {
+        let mut rx_state = self.rx_state.lock().unwrap();
+        let state = match *rx_state {
+            Some(ref mut state) => state,
+            None => return data.len(),
+        };
+        // Without MULTIPORT, only the first port exists.
+        let queue = match state.queues.get_mut(self.port) {
+            Some(queue) if state.guest_open.get(self.port) == Some(&true) => {
+                queue
+            },
+            _ => return data.len(),
+        };
+
+        let mut accepted = 0;
+        while accepted < data.len() {
+            let chain = match queue.pop(&state.mem) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let written = chain.write_all(&state.mem, &data[accepted..]);
+            queue.add_used(&state.mem, chain.head_index, written as u32);
+            accepted += written;
+            if written == 0 {
+                break;
+            }
+        }
+        if accepted > 0 {
+            state.interrupt.signal_used_queue();
+        }
+        accepted
+    }
+}
+
+struct ControlState {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    tx_queues: Vec<Queue>,
+    /// Messages waiting for the driver to provide control buffers.
+    pending: VecDeque<Vec<u8>>,
+}
+
+struct Port {
+    config: PortConfig,
+    backend: Box<CharBackend>,
+}
+
+/// Virtio console with multiport support. Each port is bound to a
+/// character device backend, output is written synchronously and input
+/// is delivered by the backend threads.
+pub struct Console {
+    ports: Vec<Port>,
+    config: [u8; CONFIG_SIZE],
+    multiport: bool,
+    rx_state: SharedRxState,
+    state: Option<ControlState>,
+}
+
+impl Console {
+    pub fn new(port_configs: Vec<PortConfig>) -> Self {
+        let rx_state = Arc::new(Mutex::new(None));
+
+        let ports = port_configs.into_iter().enumerate().map(|(i, config)| {
+            let label = match config.name {
+                Some(ref name) => name.clone(),
+                None => format!("vport{}", i),
+            };
+            let mut backend = chardev::new(&config.chardev, &label);
+            backend.connect_input(Arc::new(Mutex::new(PortInput {
+                port: i,
+                rx_state: rx_state.clone(),
+            })));
+            Port {
+                config: config,
+                backend: backend,
+            }
+        }).collect::<Vec<_>>();
+
+        let mut config = [0u8; CONFIG_SIZE];
+        LittleEndian::write_u32(&mut config[4..], ports.len() as u32);
+
+        Console {
+            ports: ports,
+            config: config,
+            multiport: false,
+            rx_state: rx_state,
+            state: None,
+        }
+    }
+
+    fn process_tx(&mut self, port: usize) {
+        let state = match self.state {
+            Some(ref mut state) => state,
+            None => return,
+        };
+        let queue = &mut state.tx_queues[port];
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(&state.mem) {
+            if let Some(data) = chain.read_all(&state.mem, MAX_TX_SIZE) {
+                self.ports[port].backend.write(&data);
+            }
+            queue.add_used(&state.mem, chain.head_index, 0);
+            used = true;
+        }
+        if used {
+            state.interrupt.signal_used_queue();
+        }
+    }
+
+    fn send_control(&mut self, msg: Vec<u8>) {
+        if let Some(ref mut state) = self.state {
+            state.pending.push_back(msg);
+        }
+    }
+
+    fn flush_control(&mut self) {
+        let state = match self.state {
+            Some(ref mut state) => state,
+            None => return,
+        };
+
+        let mut used = false;
+        while !state.pending.is_empty() {
+            let chain = match state.rx_queue.pop(&state.mem) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let msg = state.pending.pop_front().unwrap();
+            let len = chain.write_all(&state.mem, &msg);
+            state.rx_queue.add_used(&state.mem, chain.head_index, len as u32);
+            used = true;
+        }
+        if used {
+            state.interrupt.signal_used_queue();
+        }
+    }
+
+    fn process_control(&mut self) {
+        let mut messages = Vec::new();
+        if let Some(ref mut state) = self.state {
+            let mut used = false;
+            while let Some(chain) = state.tx_queue.pop(&state.mem) {
+                if let Some(msg) = chain.read_all(&state.mem,
+                                                  CONTROL_MSG_SIZE) {
+                    messages.push(msg);
+                }
+                state.tx_queue.add_used(&state.mem, chain.head_index, 0);
+                used = true;
+            }
+            if used {
+                state.interrupt.signal_used_queue();
+            }
+        }
+
+        for msg in messages {
+            if msg.len() >= CONTROL_MSG_SIZE {
+                self.handle_control(LittleEndian::read_u32(&msg[0..]),
+                                    LittleEndian::read_u16(&msg[4..]),
+                                    LittleEndian::read_u16(&msg[6..]));
+            }
+        }
+        self.flush_control();
+    }
+
+    fn handle_control(&mut self, id: u32, event: u16, value: u16) {
+        let port = id as usize;
+        match event {
+            VIRTIO_CONSOLE_DEVICE_READY if value == 1 => {
+                for i in 0..self.ports.len() {
+                    self.send_control(control_message(
+                        i as u32, VIRTIO_CONSOLE_DEVICE_ADD, 1));
+                }
+            },
+            VIRTIO_CONSOLE_DEVICE_READY => {
+                println!("virtio-console: driver failed to initialize.");
+            },
+            VIRTIO_CONSOLE_PORT_READY if port < self.ports.len() => {
+                if value != 1 {
+                    println!("virtio-console: driver failed to add port {}.", port);
+                    return;
+                }
+                if self.ports[port].config.console {
+                    self.send_control(control_message(
+                        id, VIRTIO_CONSOLE_CONSOLE_PORT, 1));
+                }
+                if let Some(name) = self.ports[port].config.name.clone() {
+                    let mut msg = control_message(id, VIRTIO_CONSOLE_PORT_NAME, 1);
+                    msg.extend_from_slice(name.as_bytes());
+                    self.send_control(msg);
+                }
+                // The host side of the ports is always connected.
+                self.send_control(control_message(
+                    id, VIRTIO_CONSOLE_PORT_OPEN, 1));
+            },
+            VIRTIO_CONSOLE_PORT_OPEN if port < self.ports.len() => {
+                if let Some(ref mut state) = *self.rx_state.lock().unwrap() {
+                    if let Some(open) = state.guest_open.get_mut(port) {
+                        *open = value != 0;
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl VirtioDevice for Console {
+    fn debug_label(&self) -> String {
+        "virtio-console".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_CONSOLE
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        // Port 0 queues, control queues and the queues of the other ports.
+        vec![QUEUE_SIZE; (self.ports.len() + 1) * 2]
+    }
+
+    fn features(&self) -> u64 {
+        1 << VIRTIO_F_VERSION_1 |
+        1 << VIRTIO_CONSOLE_F_MULTIPORT |
+        1 << VIRTIO_CONSOLE_F_EMERG_WRITE
+    }
+
+    fn ack_features(&mut self, features: u64) {
+        self.multiport = features & (1 << VIRTIO_CONSOLE_F_MULTIPORT) != 0;
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_bytes(&self.config, offset, data);
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        // Emergency writes go to the first port, even before the driver
+        // is ready.
+        if offset == EMERG_WR_OFFSET && !data.is_empty() {
+            self.ports[0].backend.write(&data[..1]);
+        }
+    }
+
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                queues: Vec<Queue>) {
+        let ports = if self.multiport { self.ports.len() } else { 1 };
+        let rx_queues = (0..ports).map(|i| queues[rx_queue_index(i)].clone())
+                                  .collect();
+        let tx_queues = (0..ports).map(|i| queues[tx_queue_index(i)].clone())
+                                  .collect();
+
+        *self.rx_state.lock().unwrap() = Some(RxState {
+            mem: mem.clone(),
+            interrupt: interrupt.clone(),
+            queues: rx_queues,
+            // Without multiport, port 0 is a plain console.
+            guest_open: vec![!self.multiport; ports],
+        });
+        self.state = Some(ControlState {
+            mem: mem,
+            interrupt: interrupt,
+            rx_queue: queues[CONTROL_RX_QUEUE].clone(),
+            tx_queue: queues[CONTROL_TX_QUEUE].clone(),
+            tx_queues: tx_queues,
+            pending: VecDeque::new(),
+        });
+    }
+
+    fn queue_notify(&mut self, queue_index: usize) {
+        match queue_index {
+            CONTROL_RX_QUEUE => self.flush_control(),
+            CONTROL_TX_QUEUE => self.process_control(),
+            _ => {
+                let ports = self.state.as_ref().map_or(0, |s| s.tx_queues.len());
+                if let Some(port) = (0..ports).find(|&p| tx_queue_index(p) == queue_index) {
+                    self.process_tx(port);
+                }
+                // Input is delivered as soon as the backends receive it.
+            },
+        }
+    }
+
+    fn reset(&mut self) {
+        *self.rx_state.lock().unwrap() = None;
+        self.state = None;
+    }
+}
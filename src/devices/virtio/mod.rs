@@ -0,0 +1,135 @@
+//! Virtio 1.x devices, exposed through the PCI transport.
+
+pub mod balloon;
+pub mod block;
+pub mod console;
+pub mod net;
+pub mod p9;
+pub mod pci;
+pub mod queue;
+pub mod rng;
+pub mod vsock;
+
+use std::sync::{Arc, Mutex};
+
+use ::devices::irq::IrqLine;
+use ::memory::GuestMemory;
+
+pub use self::pci::VirtioPciDevice;
+pub use self::queue::{DescriptorChain, Queue};
+
+// Device types.
+pub const TYPE_NET: u32 = 1;
+pub const TYPE_BLOCK: u32 = 2;
+pub const TYPE_CONSOLE: u32 = 3;
+pub const TYPE_RNG: u32 = 4;
+pub const TYPE_BALLOON: u32 = 5;
+pub const TYPE_9P: u32 = 9;
+pub const TYPE_VSOCK: u32 = 19;
+
+// Device status bits.
+pub const STATUS_DRIVER_OK: u8 = 0x04;
+pub const STATUS_FEATURES_OK: u8 = 0x08;
+pub const STATUS_DEVICE_NEEDS_RESET: u8 = 0x40;
+
+pub const VIRTIO_F_VERSION_1: u32 = 32;
+
+// Interrupt status bits.
+pub const INTERRUPT_STATUS_USED_RING: usize = 0x1;
+pub const INTERRUPT_STATUS_CONFIG_CHANGED: usize = 0x2;
+
+/// The legacy interrupt of a virtio device, along with its ISR status.
+/// The status lock is held while changing the line level, so that the
+/// line always matches the status.
+#[derive(Clone)]
+pub struct Interrupt {
+    status: Arc<Mutex<usize>>,
+    irq: IrqLine,
+}
+
+impl Interrupt {
+    pub fn new(irq: IrqLine) -> Self {
+        Interrupt {
+            status: Arc::new(Mutex::new(0)),
+            irq: irq,
+        }
+    }
+
+    fn signal(&self, status: usize) {
+        let mut current = self.status.lock().unwrap();
+        *current |= status;
+        self.irq.set_level(true);
+    }
+
+    /// Lets the driver know that buffers were placed in a used ring.
+    pub fn signal_used_queue(&self) {
+        self.signal(INTERRUPT_STATUS_USED_RING);
+    }
+
+    /// Lets the driver know that the device configuration changed.
+    pub fn signal_config_changed(&self) {
+        self.signal(INTERRUPT_STATUS_CONFIG_CHANGED);
+    }
+
+    fn status(&self) -> u8 {
+        *self.status.lock().unwrap() as u8
+    }
+
+    /// Raises the interrupt again if the saved ISR status isn't clear.
+    fn restore_status(&self, status: u8) {
+        if status != 0 {
+            self.signal(status as usize);
+        }
+    }
+
+    /// Reading the ISR status acknowledges the interrupt.
+    pub fn read_and_clear_status(&self) -> u8 {
+        let mut status = self.status.lock().unwrap();
+        let value = *status;
+        *status = 0;
+        self.irq.set_level(false);
+        value as u8
+    }
+}
+
+/// A virtio device, independent of the transport.
+#[allow(unused_variables)]
+pub trait VirtioDevice: Send {
+    /// A short description, used in log messages.
+    fn debug_label(&self) -> String;
+    fn device_type(&self) -> u32;
+    /// The maximum size of each queue.
+    fn queue_max_sizes(&self) -> Vec<u16>;
+    /// The feature bits offered by the device.
+    fn features(&self) -> u64 {
+        1 << VIRTIO_F_VERSION_1
+    }
+    /// The features accepted by the driver, a subset of the offered ones.
+    fn ack_features(&mut self, features: u64) {}
+    fn read_config(&self, offset: u64, data: &mut [u8]) {}
+    fn write_config(&mut self, offset: u64, data: &[u8]) {}
+    /// Called once the driver is ready, handing over the queues.
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                queues: Vec<Queue>);
+    /// Called when the driver makes new buffers available.
+    fn queue_notify(&mut self, queue_index: usize) {}
+    /// Called when the driver resets the device. Devices should stop
+    /// using the queues.
+    fn reset(&mut self) {}
+    /// Saves the device state besides the queues, the device being reset
+    /// right after. Returns None if the device doesn't support snapshots.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        None
+    }
+    /// Restores the state returned by `save_state`, once activated again.
+    fn restore_state(&mut self, data: &[u8]) {}
+}
+
+/// Copies the bytes of `config` at `offset` into `data`, used when
+/// reading the device specific configuration.
+pub fn read_config_bytes(config: &[u8], offset: u64, data: &mut [u8]) {
+    let offset = offset as usize;
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte = config.get(offset + i).cloned().unwrap_or(0);
+    }
+}
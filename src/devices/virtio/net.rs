@@ -0,0 +1,294 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use ::memory::GuestMemory;
+use ::net::{NetBackend, NetConfig, PcapWriter, MAX_FRAME_SIZE};
+use super::*;
+
+const QUEUE_SIZE: u16 = 256;
+
+// Feature bits.
+const VIRTIO_NET_F_MAC: u32 = 5;
+const VIRTIO_NET_F_STATUS: u32 = 16;
+const VIRTIO_NET_F_CTRL_VQ: u32 = 17;
+const VIRTIO_NET_F_MQ: u32 = 22;
+
+const VIRTIO_NET_S_LINK_UP: u16 = 1;
+
+// Control queue commands.
+const VIRTIO_NET_CTRL_MQ: u8 = 4;
+const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const VIRTIO_NET_OK: u8 = 0;
+const VIRTIO_NET_ERR: u8 = 1;
+
+// struct virtio_net_hdr_v1, no offloads are negotiated.
+const NET_HEADER_SIZE: usize = 12;
+const NUM_BUFFERS_OFFSET: usize = 10;
+
+// class, command and the largest command data handled.
+const MAX_CTRL_SIZE: usize = 64;
+
+const CONFIG_SIZE: usize = 10;
+
+// How often the receive threads check whether they should stop.
+const RX_POLL_TIMEOUT_MS: i32 = 100;
+
+struct NetState {
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    tx_queues: Vec<Queue>,
+    ctrl_queue: Option<Queue>,
+    stop: Arc<AtomicBool>,
+    rx_threads: Vec<JoinHandle<()>>,
+}
+
+/// Virtio network device. Each queue pair uses its own backend, frames
+/// are received by a thread per queue pair and sent synchronously.
+pub struct Net {
+    backends: Vec<Arc<NetBackend>>,
+    pcap: Option<Arc<Mutex<PcapWriter>>>,
+    config: [u8; CONFIG_SIZE],
+    state: Option<NetState>,
+}
+
+impl Net {
+    pub fn new(net_config: NetConfig) -> Self {
+        let mut config = [0u8; CONFIG_SIZE];
+        config[..6].copy_from_slice(&net_config.mac);
+        LittleEndian::write_u16(&mut config[6..], VIRTIO_NET_S_LINK_UP);
+        LittleEndian::write_u16(&mut config[8..],
+                                net_config.backends.len() as u16);
+
+        Net {
+            backends: net_config.backends,
+            pcap: net_config.pcap,
+            config: config,
+            state: None,
+        }
+    }
+
+    fn multi_queue(&self) -> bool {
+        self.backends.len() > 1
+    }
+
+    fn process_tx(&mut self, pair: usize) {
+        let state = match self.state {
+            Some(ref mut state) => state,
+            None => return,
+        };
+        let backend = &self.backends[pair];
+        let queue = &mut state.tx_queues[pair];
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(&state.mem) {
+            if let Some(data) = chain.read_all(
+                    &state.mem, NET_HEADER_SIZE + MAX_FRAME_SIZE) {
+                if data.len() > NET_HEADER_SIZE {
+                    let frame = &data[NET_HEADER_SIZE..];
+                    if let Some(ref pcap) = self.pcap {
+                        pcap.lock().unwrap().write_frame(frame);
+                    }
+                    backend.send(frame);
+                }
+            }
+            queue.add_used(&state.mem, chain.head_index, 0);
+            used = true;
+        }
+        if used {
+            state.interrupt.signal_used_queue();
+        }
+    }
+
+    fn set_queue_pairs(&self, pairs: usize) -> u8 {
+        if pairs < 1 || pairs > self.backends.len() {
+            return VIRTIO_NET_ERR;
+        }
+        for (i, backend) in self.backends.iter().enumerate() {
+            backend.set_enabled(i < pairs);
+        }
+        VIRTIO_NET_OK
+    }
+
+    fn process_ctrl(&mut self) {
+        let (mem, interrupt, mut queue) = match self.state {
+            Some(ref mut state) => match state.ctrl_queue.take() {
+                Some(queue) => (state.mem.clone(), state.interrupt.clone(), queue),
+                None => return,
+            },
+            None => return,
+        };
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(&mem) {
+            let ack = match chain.read_all(&mem, MAX_CTRL_SIZE) {
+                Some(ref cmd) if cmd.len() >= 4 &&
+                        cmd[0] == VIRTIO_NET_CTRL_MQ &&
+                        cmd[1] == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET =>
+                    self.set_queue_pairs(LittleEndian::read_u16(&cmd[2..]) as usize),
+                _ => VIRTIO_NET_ERR,
+            };
+            let len = chain.write_all(&mem, &[ack]);
+            queue.add_used(&mem, chain.head_index, len as u32);
+            used = true;
+        }
+        if used {
+            interrupt.signal_used_queue();
+        }
+
+        if let Some(ref mut state) = self.state {
+            state.ctrl_queue = Some(queue);
+        }
+    }
+
+    fn spawn_rx_thread(&self, pair: usize, mem: GuestMemory, interrupt: Interrupt,
+                       queue: Queue, stop: Arc<AtomicBool>) -> JoinHandle<()> {
+        let backend = self.backends[pair].clone();
+        let pcap = self.pcap.clone();
+
+        thread::Builder::new()
+            .name(format!("virtio-net-rx{}", pair))
+            .spawn(move || rx_loop(backend, pcap, mem, interrupt, queue, stop))
+            .unwrap()
+    }
+
+    fn stop(&mut self) {
+        if let Some(state) = self.state.take() {
+            state.stop.store(true, Ordering::SeqCst);
+            for rx_thread in state.rx_threads {
+                rx_thread.join().unwrap();
+            }
+        }
+    }
+}
+
+fn rx_loop(backend: Arc<NetBackend>, pcap: Option<Arc<Mutex<PcapWriter>>>,
+           mem: GuestMemory, interrupt: Interrupt, mut queue: Queue,
+           stop: Arc<AtomicBool>) {
+    let mut buf = vec![0u8; NET_HEADER_SIZE + MAX_FRAME_SIZE];
+    // Single buffer frames.
+    LittleEndian::write_u16(&mut buf[NUM_BUFFERS_OFFSET..], 1);
+
+    while !stop.load(Ordering::SeqCst) {
+        let len = match backend.recv(&mut buf[NET_HEADER_SIZE..],
+                                     RX_POLL_TIMEOUT_MS) {
+            Ok(0) => continue,
+            Ok(len) => len,
+            Err(e) => {
+                println!("virtio-net receive failed: {}", e);
+                return;
+            },
+        };
+        if let Some(ref pcap) = pcap {
+            pcap.lock().unwrap().write_frame(&buf[NET_HEADER_SIZE..NET_HEADER_SIZE + len]);
+        }
+
+        // Wait for the driver to provide buffers.
+        let chain = loop {
+            if stop.load(Ordering::SeqCst) {
+                return;
+            }
+            match queue.pop(&mem) {
+                Some(chain) => break chain,
+                None => thread::sleep(Duration::from_millis(1)),
+            }
+        };
+
+        let frame = &buf[..NET_HEADER_SIZE + len];
+        let written = if chain.writable().iter()
+                              .map(|d| d.len as usize).sum::<usize>() >= frame.len() {
+            chain.write_all(&mem, frame)
+        } else {
+            // The frame doesn't fit, drop it.
+            0
+        };
+        queue.add_used(&mem, chain.head_index, written as u32);
+        interrupt.signal_used_queue();
+    }
+}
+
+impl VirtioDevice for Net {
+    fn debug_label(&self) -> String {
+        "virtio-net".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_NET
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        // rx/tx pairs, followed by the control queue.
+        let mut queues = self.backends.len() * 2;
+        if self.multi_queue() {
+            queues += 1;
+        }
+        vec![QUEUE_SIZE; queues]
+    }
+
+    fn features(&self) -> u64 {
+        let mut features = 1 << VIRTIO_F_VERSION_1 |
+                           1 << VIRTIO_NET_F_MAC |
+                           1 << VIRTIO_NET_F_STATUS;
+        if self.multi_queue() {
+            features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+        }
+        features
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_bytes(&self.config, offset, data);
+    }
+
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                queues: Vec<Queue>) {
+        let pairs = self.backends.len();
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let mut rx_threads = Vec::new();
+        let mut tx_queues = Vec::new();
+        let mut queues = queues.into_iter();
+        for pair in 0..pairs {
+            let rx_queue = queues.next().unwrap();
+            tx_queues.push(queues.next().unwrap());
+            rx_threads.push(self.spawn_rx_thread(
+                pair, mem.clone(), interrupt.clone(), rx_queue, stop.clone()));
+        }
+        // Until the driver says otherwise, only the first queue pair is used.
+        if self.multi_queue() {
+            self.set_queue_pairs(1);
+        }
+
+        self.state = Some(NetState {
+            mem: mem,
+            interrupt: interrupt,
+            tx_queues: tx_queues,
+            ctrl_queue: queues.next(),
+            stop: stop,
+            rx_threads: rx_threads,
+        });
+    }
+
+    fn queue_notify(&mut self, queue_index: usize) {
+        let pairs = self.backends.len();
+        if queue_index == pairs * 2 {
+            self.process_ctrl();
+        } else if queue_index % 2 == 1 {
+            self.process_tx(queue_index / 2);
+        }
+        // The receive threads poll the rx queues.
+    }
+
+    fn reset(&mut self) {
+        self.stop();
+    }
+
+    // The queues are all there is to save.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+}
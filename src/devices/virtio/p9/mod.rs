@@ -0,0 +1,166 @@
+//! Virtio 9P transport, sharing a host directory with the guest through
+//! the 9P2000.L protocol.
+
+extern crate byteorder;
+
+mod server;
+mod wire;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use ::memory::GuestMemory;
+use ::utils::options::{parse_bool, parse_key_values};
+use super::*;
+
+const QUEUE_SIZE: u16 = 128;
+
+// Feature bits.
+const VIRTIO_9P_MOUNT_TAG: u32 = 0;
+
+const MAX_TAG_LEN: usize = 255;
+
+/// A shared directory, as passed through the --share option.
+pub struct ShareConfig {
+    pub path: String,
+    /// Used by the guest to mount the share.
+    pub tag: String,
+    pub read_only: bool,
+    /// Lets the guest create set-user-ID and set-group-ID files.
+    pub trusted: bool,
+    /// (guest, host) user ID pairs. Unmapped IDs are passed through.
+    pub uid_map: Vec<(u32, u32)>,
+    /// (guest, host) group ID pairs.
+    pub gid_map: Vec<(u32, u32)>,
+}
+
+fn parse_id_mapping(key: &str, value: &str) -> (u32, u32) {
+    let ids = value.split(':')
+        .map(|id| id.parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .filter(|ids| ids.len() == 2)
+        .unwrap_or_else(|| panic!("Invalid {}: {}", key, value));
+    (ids[0], ids[1])
+}
+
+impl ShareConfig {
+    /// Parses a path=<path>,tag=<tag>[,readonly=on|off][,trusted=on|off]
+    /// [,uid-map=<guest uid>:<host uid>]...[,gid-map=<guest gid>:<host gid>]...
+    /// spec.
+    pub fn parse(spec: &str) -> Self {
+        let mut path = None;
+        let mut tag = None;
+        let mut config = ShareConfig {
+            path: String::new(),
+            tag: String::new(),
+            read_only: false,
+            trusted: false,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+        };
+        for (key, value) in parse_key_values(spec) {
+            match key.as_str() {
+                "path" => path = Some(value),
+                "tag" => tag = Some(value),
+                "readonly" => config.read_only = parse_bool(&key, &value),
+                "trusted" => config.trusted = parse_bool(&key, &value),
+                "uid-map" => config.uid_map.push(parse_id_mapping(&key, &value)),
+                "gid-map" => config.gid_map.push(parse_id_mapping(&key, &value)),
+                _ => panic!("Unknown share option: {}", key),
+            }
+        }
+
+        config.path = path.expect("Missing shared directory path.");
+        config.tag = tag.expect("Missing share tag.");
+        if config.tag.is_empty() || config.tag.len() > MAX_TAG_LEN {
+            panic!("Invalid share tag: {}", config.tag);
+        }
+        config
+    }
+}
+
+/// Virtio 9P device. Requests are processed synchronously.
+pub struct P9 {
+    server: server::Server,
+    config: Vec<u8>,
+    state: Option<(GuestMemory, Interrupt, Queue)>,
+}
+
+impl P9 {
+    pub fn new(share: &ShareConfig) -> Self {
+        let mut config = vec![0u8; 2];
+        LittleEndian::write_u16(&mut config, share.tag.len() as u16);
+        config.extend_from_slice(share.tag.as_bytes());
+
+        P9 {
+            server: server::Server::new(share),
+            config: config,
+            state: None,
+        }
+    }
+
+    fn process_request(&mut self, mem: &GuestMemory, chain: &DescriptorChain) -> u32 {
+        let request = match chain.read_all(mem, self.server.msize()) {
+            Some(request) => request,
+            None => return 0,
+        };
+        let max_reply = chain.writable().iter().map(|d| d.len as usize).sum();
+        let reply = self.server.handle_message(&request, max_reply);
+        if reply.len() > max_reply {
+            println!("virtio-9p: reply doesn't fit the guest buffers.");
+            return 0;
+        }
+        chain.write_all(mem, &reply) as u32
+    }
+}
+
+impl VirtioDevice for P9 {
+    fn debug_label(&self) -> String {
+        "virtio-9p".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_9P
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn features(&self) -> u64 {
+        1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_9P_MOUNT_TAG
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        read_config_bytes(&self.config, offset, data);
+    }
+
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                mut queues: Vec<Queue>) {
+        self.state = Some((mem, interrupt, queues.remove(0)));
+    }
+
+    fn queue_notify(&mut self, _queue_index: usize) {
+        let (mem, interrupt, mut queue) = match self.state.take() {
+            Some(state) => state,
+            None => return,
+        };
+
+        let mut used = false;
+        while let Some(chain) = queue.pop(&mem) {
+            let len = self.process_request(&mem, &chain);
+            queue.add_used(&mem, chain.head_index, len);
+            used = true;
+        }
+        if used {
+            interrupt.signal_used_queue();
+        }
+
+        self.state = Some((mem, interrupt, queue));
+    }
+
+    fn reset(&mut self) {
+        self.state = None;
+        self.server.reset();
+    }
+}
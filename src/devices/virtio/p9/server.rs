@@ -0,0 +1,1043 @@
+extern crate byteorder;
+extern crate libc;
+
+use self::byteorder::{ByteOrder, NativeEndian};
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+
+use super::ShareConfig;
+use super::wire::*;
+
+// Message types, the replies using the request type + 1.
+const P9_RLERROR: u8 = 7;
+const P9_TSTATFS: u8 = 8;
+const P9_TLOPEN: u8 = 12;
+const P9_TLCREATE: u8 = 14;
+const P9_TSYMLINK: u8 = 16;
+const P9_TMKNOD: u8 = 18;
+const P9_TRENAME: u8 = 20;
+const P9_TREADLINK: u8 = 22;
+const P9_TGETATTR: u8 = 24;
+const P9_TSETATTR: u8 = 26;
+const P9_TXATTRWALK: u8 = 30;
+const P9_TXATTRCREATE: u8 = 32;
+const P9_TREADDIR: u8 = 40;
+const P9_TFSYNC: u8 = 50;
+const P9_TLOCK: u8 = 52;
+const P9_TGETLOCK: u8 = 54;
+const P9_TLINK: u8 = 70;
+const P9_TMKDIR: u8 = 72;
+const P9_TRENAMEAT: u8 = 74;
+const P9_TUNLINKAT: u8 = 76;
+const P9_TVERSION: u8 = 100;
+const P9_TATTACH: u8 = 104;
+const P9_TFLUSH: u8 = 108;
+const P9_TWALK: u8 = 110;
+const P9_TREAD: u8 = 116;
+const P9_TWRITE: u8 = 118;
+const P9_TCLUNK: u8 = 120;
+const P9_TREMOVE: u8 = 122;
+
+const PROTOCOL_VERSION: &str = "9P2000.L";
+// size, type and tag
+const HEADER_SIZE: usize = 7;
+// Room for the header and count fields of Rread and Rreaddir.
+const IO_HEADER_SIZE: usize = HEADER_SIZE + 4;
+const MAX_MSIZE: u32 = 512 * 1024;
+
+// Rgetattr fields, up to data_version.
+const P9_GETATTR_BASIC: u64 = 0x7ff;
+
+// Tsetattr valid bits.
+const P9_SETATTR_MODE: u32 = 0x1;
+const P9_SETATTR_UID: u32 = 0x2;
+const P9_SETATTR_GID: u32 = 0x4;
+const P9_SETATTR_SIZE: u32 = 0x8;
+const P9_SETATTR_ATIME: u32 = 0x10;
+const P9_SETATTR_MTIME: u32 = 0x20;
+const P9_SETATTR_ATIME_SET: u32 = 0x80;
+const P9_SETATTR_MTIME_SET: u32 = 0x100;
+
+const P9_LOCK_SUCCESS: u8 = 0;
+const P9_LOCK_TYPE_UNLCK: u8 = 2;
+
+// Linux open flags forwarded to the host. The 9P2000.L values match the
+// x86 ones.
+const OPEN_FLAGS_MASK: i32 = libc::O_ACCMODE | libc::O_TRUNC | libc::O_APPEND |
+                             libc::O_DIRECTORY | libc::O_DSYNC | libc::O_SYNC;
+
+// Directory entry types.
+const DT_DIR: u8 = 4;
+const DT_LNK: u8 = 10;
+
+// struct linux_dirent64, up to d_name.
+const DIRENT_NAME_OFFSET: usize = 19;
+
+fn error(errno: i32) -> io::Error {
+    io::Error::from_raw_os_error(errno)
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| error(libc::EINVAL))
+}
+
+fn check_ret(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn qid(meta: &Metadata) -> Qid {
+    let file_type = meta.file_type();
+    Qid {
+        type_: if file_type.is_dir() {
+            QID_TYPE_DIR
+        } else if file_type.is_symlink() {
+            QID_TYPE_SYMLINK
+        } else {
+            QID_TYPE_FILE
+        },
+        version: 0,
+        path: meta.ino(),
+    }
+}
+
+fn map_id(map: &[(u32, u32)], id: u32, to_host: bool) -> u32 {
+    for &(guest, host) in map {
+        if to_host && guest == id {
+            return host;
+        }
+        if !to_host && host == id {
+            return guest;
+        }
+    }
+    id
+}
+
+struct Fid {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Fid {
+    fn new(path: PathBuf) -> Self {
+        Fid {
+            path: path,
+            file: None,
+        }
+    }
+}
+
+/// A 9P2000.L file server, exporting a host directory. Fids track host
+/// paths, which are kept from leaving the shared directory.
+pub struct Server {
+    root: PathBuf,
+    read_only: bool,
+    trusted: bool,
+    uid_map: Vec<(u32, u32)>,
+    gid_map: Vec<(u32, u32)>,
+    msize: u32,
+    fids: HashMap<u32, Fid>,
+}
+
+impl Server {
+    pub fn new(config: &ShareConfig) -> Self {
+        let root = fs::canonicalize(&config.path).unwrap_or_else(
+            |_| panic!("Cannot access shared directory \"{}\".", config.path));
+        if !root.is_dir() {
+            panic!("\"{}\" is not a directory.", config.path);
+        }
+
+        Server {
+            root: root,
+            read_only: config.read_only,
+            trusted: config.trusted,
+            uid_map: config.uid_map.clone(),
+            gid_map: config.gid_map.clone(),
+            msize: MAX_MSIZE,
+            fids: HashMap::new(),
+        }
+    }
+
+    /// Forgets about the fids, as when the driver resets the device.
+    pub fn reset(&mut self) {
+        self.fids.clear();
+        self.msize = MAX_MSIZE;
+    }
+
+    /// The largest message size, as negotiated by Tversion.
+    pub fn msize(&self) -> usize {
+        self.msize as usize
+    }
+
+    /// Handles a T-message, returning the R-message. Replies are kept
+    /// within `max_reply` bytes.
+    pub fn handle_message(&mut self, request: &[u8], max_reply: usize) -> Vec<u8> {
+        let mut r = Reader::new(request);
+        let (msg_type, tag) = match (r.u32(), r.u8(), r.u16()) {
+            (Ok(_), Ok(msg_type), Ok(tag)) => (msg_type, tag),
+            _ => return Vec::new(),
+        };
+
+        let mut w = Writer::new();
+        w.bytes(&[0u8; HEADER_SIZE]);
+        let max_reply = max_reply.min(self.msize as usize);
+        let result = match msg_type {
+            P9_TVERSION => self.version(&mut r, &mut w),
+            P9_TATTACH => self.attach(&mut r, &mut w),
+            P9_TFLUSH => Ok(()),
+            P9_TWALK => self.walk(&mut r, &mut w),
+            P9_TCLUNK => self.clunk(&mut r),
+            P9_TREMOVE => self.remove(&mut r),
+            P9_TSTATFS => self.statfs(&mut r, &mut w),
+            P9_TLOPEN => self.lopen(&mut r, &mut w),
+            P9_TLCREATE => self.lcreate(&mut r, &mut w),
+            P9_TSYMLINK => self.symlink(&mut r, &mut w),
+            P9_TMKNOD => self.mknod(&mut r, &mut w),
+            P9_TRENAME => self.rename(&mut r),
+            P9_TREADLINK => self.readlink(&mut r, &mut w),
+            P9_TGETATTR => self.getattr(&mut r, &mut w),
+            P9_TSETATTR => self.setattr(&mut r),
+            P9_TXATTRWALK | P9_TXATTRCREATE => Err(error(libc::EOPNOTSUPP)),
+            P9_TREADDIR => self.readdir(&mut r, &mut w, max_reply),
+            P9_TFSYNC => self.fsync(&mut r),
+            P9_TLOCK => self.lock(&mut r, &mut w),
+            P9_TGETLOCK => self.getlock(&mut r, &mut w),
+            P9_TLINK => self.link(&mut r),
+            P9_TMKDIR => self.mkdir(&mut r, &mut w),
+            P9_TRENAMEAT => self.renameat(&mut r),
+            P9_TUNLINKAT => self.unlinkat(&mut r),
+            P9_TREAD => self.read(&mut r, &mut w, max_reply),
+            P9_TWRITE => self.write(&mut r, &mut w),
+            _ => Err(error(libc::EOPNOTSUPP)),
+        };
+
+        let reply_type = match result {
+            Ok(()) => msg_type + 1,
+            Err(e) => {
+                w.data.truncate(HEADER_SIZE);
+                w.u32(e.raw_os_error().unwrap_or(libc::EIO) as u32);
+                P9_RLERROR
+            },
+        };
+        let size = w.data.len() as u32;
+        let mut header = Writer::new();
+        header.u32(size);
+        header.u8(reply_type);
+        header.u16(tag);
+        w.data[..HEADER_SIZE].copy_from_slice(&header.data);
+        w.data
+    }
+
+    /// Keeps the permission bits of a guest mode. The set-user-ID and
+    /// set-group-ID bits are dropped unless the guest is trusted, as they
+    /// would let it create host programs running with the host IDs.
+    fn file_mode(&self, mode: u32) -> u32 {
+        if self.trusted {
+            mode & 0o7777
+        } else {
+            mode & 0o1777
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            return Err(error(libc::EROFS));
+        }
+        Ok(())
+    }
+
+    /// Returns the path of a fid, making sure that it is still within the
+    /// shared directory, as directories may have been replaced with
+    /// symbolic links since the fid was walked.
+    fn fid_path(&self, fid: u32) -> io::Result<PathBuf> {
+        let path = self.fids.get(&fid).ok_or(error(libc::EBADF))?.path.clone();
+        if path != self.root {
+            let parent = fs::canonicalize(path.parent().unwrap())?;
+            if !parent.starts_with(&self.root) {
+                return Err(error(libc::EACCES));
+            }
+        }
+        Ok(path)
+    }
+
+    /// Returns the path of `name` within the directory of `dir_fid`.
+    fn child_path(&self, dir_fid: u32, name: &str) -> io::Result<PathBuf> {
+        if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+            return Err(error(libc::EINVAL));
+        }
+        Ok(self.fid_path(dir_fid)?.join(name))
+    }
+
+    fn file(&self, fid: u32) -> io::Result<&File> {
+        self.fids.get(&fid)
+            .ok_or(error(libc::EBADF))?
+            .file.as_ref().ok_or(error(libc::EBADF))
+    }
+
+    /// Gives new files the group asked by the guest. Failures are ignored
+    /// as unprivileged instances can't do that. The set-user-ID and
+    /// set-group-ID bits of `mode`, cleared by the change, are set again.
+    fn set_group(&self, path: &Path, gid: u32, mode: u32) {
+        let cpath = match cstring(path) {
+            Ok(cpath) => cpath,
+            Err(_) => return,
+        };
+        unsafe {
+            libc::lchown(cpath.as_ptr(), u32::max_value(),
+                         map_id(&self.gid_map, gid, true));
+        }
+
+        let setid_bits = self.file_mode(mode) & 0o6000;
+        if setid_bits != 0 {
+            if let Ok(meta) = fs::symlink_metadata(path) {
+                unsafe {
+                    libc::chmod(cpath.as_ptr(),
+                                (meta.mode() & 0o1777 | setid_bits) as libc::mode_t);
+                }
+            }
+        }
+    }
+
+    fn version(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let msize = r.u32()?;
+        let version = r.string()?;
+
+        self.reset();
+        self.msize = msize.min(MAX_MSIZE);
+        w.u32(self.msize);
+        w.string(if version == PROTOCOL_VERSION { PROTOCOL_VERSION } else { "unknown" });
+        Ok(())
+    }
+
+    fn attach(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _afid = r.u32()?;
+        let _uname = r.string()?;
+        let _aname = r.string()?;
+        let _n_uname = r.u32()?;
+
+        let meta = fs::symlink_metadata(&self.root)?;
+        self.fids.insert(fid, Fid::new(self.root.clone()));
+        w.qid(&qid(&meta));
+        Ok(())
+    }
+
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let newfid = r.u32()?;
+        let nwname = r.u16()?;
+        let mut names = Vec::new();
+        for _ in 0..nwname {
+            names.push(r.string()?);
+        }
+
+        let mut path = self.fid_path(fid)?;
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(error(libc::EEXIST));
+        }
+        let mut qids = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            let next = match name.as_str() {
+                "" | "." => path.clone(),
+                ".." if path == self.root => path.clone(),
+                ".." => path.parent().unwrap().to_path_buf(),
+                name if name.contains('/') => break,
+                name => path.join(name),
+            };
+            let meta = match fs::symlink_metadata(&next) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    if i == 0 {
+                        return Err(e);
+                    }
+                    break;
+                },
+            };
+            // Symbolic links are resolved by the guest.
+            if meta.file_type().is_symlink() && i + 1 < names.len() {
+                if i == 0 {
+                    return Err(error(libc::ELOOP));
+                }
+                break;
+            }
+            qids.push(qid(&meta));
+            path = next;
+        }
+
+        if qids.len() == names.len() {
+            self.fids.insert(newfid, Fid::new(path));
+        }
+        w.u16(qids.len() as u16);
+        for qid in qids.iter() {
+            w.qid(qid);
+        }
+        Ok(())
+    }
+
+    fn clunk(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        self.fids.remove(&fid).ok_or(error(libc::EBADF))?;
+        Ok(())
+    }
+
+    fn remove(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let path = self.fid_path(fid);
+        // The fid is clunked even when the removal fails.
+        self.fids.remove(&fid);
+        let path = path?;
+
+        self.check_writable()?;
+        if path == self.root {
+            return Err(error(libc::EBUSY));
+        }
+        if fs::symlink_metadata(&path)?.is_dir() {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+    }
+
+    fn statfs(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let path = cstring(&self.fid_path(r.u32()?)?)?;
+        let mut st: libc::statvfs = unsafe { mem::zeroed() };
+        check_ret(unsafe { libc::statvfs(path.as_ptr(), &mut st) })?;
+
+        // The type is left for the guest to pick.
+        w.u32(0);
+        w.u32(st.f_bsize as u32);
+        w.u64(st.f_blocks as u64);
+        w.u64(st.f_bfree as u64);
+        w.u64(st.f_bavail as u64);
+        w.u64(st.f_files as u64);
+        w.u64(st.f_ffree as u64);
+        w.u64(st.f_fsid as u64);
+        w.u32(st.f_namemax as u32);
+        Ok(())
+    }
+
+    /// Opens a regular file or a directory. Other files are refused, as
+    /// the requests are handled synchronously and FIFOs or devices could
+    /// block them.
+    fn open(&self, path: &Path, flags: i32, mode: u32) -> io::Result<File> {
+        if self.read_only && (flags & libc::O_ACCMODE != libc::O_RDONLY ||
+                              flags & (libc::O_TRUNC | libc::O_CREAT) != 0) {
+            return Err(error(libc::EROFS));
+        }
+        let path = cstring(path)?;
+        let fd = unsafe {
+            libc::open(path.as_ptr(),
+                       flags | libc::O_CLOEXEC | libc::O_NOFOLLOW | libc::O_NONBLOCK,
+                       mode as libc::c_uint)
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let file = unsafe { File::from_raw_fd(fd) };
+        let file_type = file.metadata()?.file_type();
+        if !file_type.is_file() && !file_type.is_dir() {
+            return Err(error(libc::EPERM));
+        }
+        Ok(file)
+    }
+
+    fn lopen(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let flags = r.u32()? as i32;
+
+        let path = self.fid_path(fid)?;
+        let file = self.open(&path, flags & OPEN_FLAGS_MASK, 0)?;
+        let meta = file.metadata()?;
+
+        self.fids.get_mut(&fid).unwrap().file = Some(file);
+        w.qid(&qid(&meta));
+        // Let the guest pick the I/O size based on msize.
+        w.u32(0);
+        Ok(())
+    }
+
+    fn lcreate(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()? as i32;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+
+        self.check_writable()?;
+        let path = self.child_path(fid, &name)?;
+        let file = self.open(&path, flags & OPEN_FLAGS_MASK | libc::O_CREAT | libc::O_EXCL,
+                             self.file_mode(mode))?;
+        self.set_group(&path, gid, mode);
+        let meta = file.metadata()?;
+
+        // The fid now refers to the new file.
+        let fid = self.fids.get_mut(&fid).unwrap();
+        fid.path = path;
+        fid.file = Some(file);
+        w.qid(&qid(&meta));
+        w.u32(0);
+        Ok(())
+    }
+
+    fn symlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let target = r.string()?;
+        let gid = r.u32()?;
+
+        self.check_writable()?;
+        let path = self.child_path(fid, &name)?;
+        ::std::os::unix::fs::symlink(&target, &path)?;
+        self.set_group(&path, gid, 0);
+        w.qid(&qid(&fs::symlink_metadata(&path)?));
+        Ok(())
+    }
+
+    fn mknod(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let _major = r.u32()?;
+        let _minor = r.u32()?;
+        let gid = r.u32()?;
+
+        self.check_writable()?;
+        // Device nodes would give the guest access to host devices.
+        let file_type = mode & libc::S_IFMT as u32;
+        let dev = match file_type as libc::mode_t {
+            0 | libc::S_IFREG | libc::S_IFIFO | libc::S_IFSOCK => 0,
+            _ => return Err(error(libc::EPERM)),
+        };
+        let path = self.child_path(fid, &name)?;
+        let cpath = cstring(&path)?;
+        check_ret(unsafe {
+            libc::mknod(cpath.as_ptr(), (file_type | self.file_mode(mode)) as libc::mode_t,
+                        dev)
+        })?;
+        self.set_group(&path, gid, mode);
+        w.qid(&qid(&fs::symlink_metadata(&path)?));
+        Ok(())
+    }
+
+    /// Moves a file, updating the fids referring to it or its children.
+    fn rename_path(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        self.check_writable()?;
+        if from == self.root {
+            return Err(error(libc::EBUSY));
+        }
+        fs::rename(from, to)?;
+
+        for fid in self.fids.values_mut() {
+            let new_path = match fid.path.strip_prefix(from) {
+                Ok(rest) if rest.as_os_str().is_empty() => to.to_path_buf(),
+                Ok(rest) => to.join(rest),
+                Err(_) => continue,
+            };
+            fid.path = new_path;
+        }
+        Ok(())
+    }
+
+    fn rename(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let dir_fid = r.u32()?;
+        let name = r.string()?;
+
+        let from = self.fid_path(fid)?;
+        let to = self.child_path(dir_fid, &name)?;
+        self.rename_path(&from, &to)
+    }
+
+    fn renameat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let old_dir_fid = r.u32()?;
+        let old_name = r.string()?;
+        let new_dir_fid = r.u32()?;
+        let new_name = r.string()?;
+
+        let from = self.child_path(old_dir_fid, &old_name)?;
+        let to = self.child_path(new_dir_fid, &new_name)?;
+        self.rename_path(&from, &to)
+    }
+
+    fn readlink(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let path = self.fid_path(r.u32()?)?;
+        let target = fs::read_link(&path)?;
+        w.string(&target.to_string_lossy());
+        Ok(())
+    }
+
+    fn getattr(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let _request_mask = r.u64()?;
+
+        let meta = fs::symlink_metadata(&self.fid_path(fid)?)?;
+        w.u64(P9_GETATTR_BASIC);
+        w.qid(&qid(&meta));
+        w.u32(meta.mode());
+        w.u32(map_id(&self.uid_map, meta.uid(), false));
+        w.u32(map_id(&self.gid_map, meta.gid(), false));
+        w.u64(meta.nlink());
+        w.u64(meta.rdev());
+        w.u64(meta.size());
+        w.u64(meta.blksize());
+        w.u64(meta.blocks());
+        w.u64(meta.atime() as u64);
+        w.u64(meta.atime_nsec() as u64);
+        w.u64(meta.mtime() as u64);
+        w.u64(meta.mtime_nsec() as u64);
+        w.u64(meta.ctime() as u64);
+        w.u64(meta.ctime_nsec() as u64);
+        // btime, gen and data_version aren't reported.
+        w.bytes(&[0u8; 32]);
+        Ok(())
+    }
+
+    fn setattr(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let valid = r.u32()?;
+        let mode = r.u32()?;
+        let uid = r.u32()?;
+        let gid = r.u32()?;
+        let size = r.u64()?;
+        let atime = (r.u64()?, r.u64()?);
+        let mtime = (r.u64()?, r.u64()?);
+
+        self.check_writable()?;
+        let path = self.fid_path(fid)?;
+        let cpath = cstring(&path)?;
+        let is_symlink = fs::symlink_metadata(&path)?.file_type().is_symlink();
+
+        if valid & P9_SETATTR_MODE != 0 {
+            // chmod would follow the link.
+            if is_symlink {
+                return Err(error(libc::EOPNOTSUPP));
+            }
+            check_ret(unsafe {
+                libc::chmod(cpath.as_ptr(), self.file_mode(mode) as libc::mode_t)
+            })?;
+        }
+
+        if valid & (P9_SETATTR_UID | P9_SETATTR_GID) != 0 {
+            let uid = if valid & P9_SETATTR_UID != 0 {
+                map_id(&self.uid_map, uid, true)
+            } else {
+                u32::max_value()
+            };
+            let gid = if valid & P9_SETATTR_GID != 0 {
+                map_id(&self.gid_map, gid, true)
+            } else {
+                u32::max_value()
+            };
+            check_ret(unsafe { libc::lchown(cpath.as_ptr(), uid, gid) })?;
+        }
+
+        if valid & P9_SETATTR_SIZE != 0 {
+            match self.file(fid) {
+                Ok(file) => file.set_len(size)?,
+                Err(_) if is_symlink => return Err(error(libc::EINVAL)),
+                Err(_) => check_ret(unsafe {
+                    libc::truncate(cpath.as_ptr(), size as libc::off_t)
+                })?,
+            }
+        }
+
+        if valid & (P9_SETATTR_ATIME | P9_SETATTR_MTIME) != 0 {
+            let timespec = |set: bool, explicit: bool, time: (u64, u64)| {
+                libc::timespec {
+                    tv_sec: time.0 as libc::time_t,
+                    tv_nsec: if !set {
+                        libc::UTIME_OMIT
+                    } else if !explicit {
+                        libc::UTIME_NOW
+                    } else {
+                        time.1 as libc::c_long
+                    },
+                }
+            };
+            let times = [
+                timespec(valid & P9_SETATTR_ATIME != 0,
+                         valid & P9_SETATTR_ATIME_SET != 0, atime),
+                timespec(valid & P9_SETATTR_MTIME != 0,
+                         valid & P9_SETATTR_MTIME_SET != 0, mtime),
+            ];
+            check_ret(unsafe {
+                libc::utimensat(libc::AT_FDCWD, cpath.as_ptr(), times.as_ptr(),
+                                libc::AT_SYMLINK_NOFOLLOW)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Reads the entries of an open directory, starting at `offset`, as
+    /// returned for a previous entry. The offsets are the host ones, so
+    /// that the position is kept across calls.
+    fn readdir(&mut self, r: &mut Reader, w: &mut Writer,
+               max_reply: usize) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = (r.u32()? as usize).min(max_reply.saturating_sub(IO_HEADER_SIZE));
+
+        let fd = self.file(fid)?.as_raw_fd();
+        if unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_SET) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Entries that don't fit in the reply are read again next time.
+        let mut buf = vec![0u8; count.max(4096)];
+        let len = unsafe {
+            libc::syscall(libc::SYS_getdents64, fd, buf.as_mut_ptr(), buf.len())
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut data = Writer::new();
+        let mut entries = &buf[..len as usize];
+        while entries.len() >= DIRENT_NAME_OFFSET {
+            let reclen = NativeEndian::read_u16(&entries[16..]) as usize;
+            if reclen < DIRENT_NAME_OFFSET || reclen > entries.len() {
+                return Err(error(libc::EIO));
+            }
+            let name = &entries[DIRENT_NAME_OFFSET..reclen];
+            let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+            let name = String::from_utf8_lossy(name);
+            let entry_size = QID_SIZE + 8 + 1 + 2 + name.len();
+            if data.data.len() + entry_size > count {
+                break;
+            }
+
+            let type_ = entries[18];
+            data.qid(&Qid {
+                type_: match type_ {
+                    DT_DIR => QID_TYPE_DIR,
+                    DT_LNK => QID_TYPE_SYMLINK,
+                    _ => QID_TYPE_FILE,
+                },
+                version: 0,
+                path: NativeEndian::read_u64(&entries[0..]),
+            });
+            data.u64(NativeEndian::read_u64(&entries[8..]));
+            data.u8(type_);
+            data.string(&name);
+            entries = &entries[reclen..];
+        }
+
+        w.u32(data.data.len() as u32);
+        w.bytes(&data.data);
+        Ok(())
+    }
+
+    fn fsync(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let datasync = r.u32()?;
+        let file = self.file(fid)?;
+        if datasync != 0 {
+            file.sync_data()
+        } else {
+            file.sync_all()
+        }
+    }
+
+    /// Locks are handled by the guest, host applications aren't expected
+    /// to use the shared files concurrently.
+    fn lock(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        self.file(r.u32()?)?;
+        w.u8(P9_LOCK_SUCCESS);
+        Ok(())
+    }
+
+    fn getlock(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        self.file(r.u32()?)?;
+        let _type = r.u8()?;
+        let start = r.u64()?;
+        let length = r.u64()?;
+        let proc_id = r.u32()?;
+        let client_id = r.string()?;
+
+        w.u8(P9_LOCK_TYPE_UNLCK);
+        w.u64(start);
+        w.u64(length);
+        w.u32(proc_id);
+        w.string(&client_id);
+        Ok(())
+    }
+
+    fn link(&mut self, r: &mut Reader) -> io::Result<()> {
+        let dir_fid = r.u32()?;
+        let fid = r.u32()?;
+        let name = r.string()?;
+
+        self.check_writable()?;
+        let target = self.fid_path(fid)?;
+        let path = self.child_path(dir_fid, &name)?;
+        fs::hard_link(&target, &path)
+    }
+
+    fn mkdir(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let mode = r.u32()?;
+        let gid = r.u32()?;
+
+        self.check_writable()?;
+        let path = self.child_path(fid, &name)?;
+        let cpath = cstring(&path)?;
+        check_ret(unsafe {
+            libc::mkdir(cpath.as_ptr(), self.file_mode(mode) as libc::mode_t)
+        })?;
+        self.set_group(&path, gid, mode);
+        w.qid(&qid(&fs::symlink_metadata(&path)?));
+        Ok(())
+    }
+
+    fn unlinkat(&mut self, r: &mut Reader) -> io::Result<()> {
+        let fid = r.u32()?;
+        let name = r.string()?;
+        let flags = r.u32()? as i32;
+
+        self.check_writable()?;
+        let path = self.child_path(fid, &name)?;
+        if flags & libc::AT_REMOVEDIR != 0 {
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        }
+    }
+
+    fn read(&mut self, r: &mut Reader, w: &mut Writer, max_reply: usize) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = (r.u32()? as usize).min(max_reply.saturating_sub(IO_HEADER_SIZE));
+
+        let mut buf = vec![0u8; count];
+        let file = self.file(fid)?;
+        let mut read = 0;
+        while read < count {
+            match file.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        w.u32(read as u32);
+        w.bytes(&buf[..read]);
+        Ok(())
+    }
+
+    fn write(&mut self, r: &mut Reader, w: &mut Writer) -> io::Result<()> {
+        let fid = r.u32()?;
+        let offset = r.u64()?;
+        let count = r.u32()? as usize;
+        let data = r.bytes(count)?;
+
+        self.check_writable()?;
+        let written = self.file(fid)?.write_at(data, offset)?;
+        w.u32(written as u32);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use std::os::unix::fs::FileTypeExt;
+    use ::utils::test_dir::TestDir;
+
+    fn server(dir: &TestDir, trusted: bool) -> Server {
+        let mut server = Server::new(&ShareConfig {
+            path: dir.path.to_string_lossy().into_owned(),
+            tag: "test".to_string(),
+            read_only: false,
+            trusted: trusted,
+            uid_map: Vec::new(),
+            gid_map: Vec::new(),
+        });
+        let mut w = Writer::new();
+        w.u32(0);
+        w.u32(u32::max_value());
+        w.string("");
+        w.string("");
+        w.u32(0);
+        call(&mut server, P9_TATTACH, w).unwrap();
+        server
+    }
+
+    /// Sends a request, returning the reply body or the error number.
+    fn call(server: &mut Server, msg_type: u8, body: Writer) -> Result<Vec<u8>, u32> {
+        let mut request = Writer::new();
+        request.u32((HEADER_SIZE + body.data.len()) as u32);
+        request.u8(msg_type);
+        request.u16(1);
+        request.bytes(&body.data);
+        let reply = server.handle_message(&request.data, MAX_MSIZE as usize);
+        if reply[4] == P9_RLERROR {
+            return Err(Reader::new(&reply[HEADER_SIZE..]).u32().unwrap());
+        }
+        assert_eq!(reply[4], msg_type + 1);
+        Ok(reply[HEADER_SIZE..].to_vec())
+    }
+
+    fn readdir(server: &mut Server, offset: u64, count: u32) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.u32(0);
+        w.u64(offset);
+        w.u32(count);
+        call(server, P9_TREADDIR, w).unwrap()
+    }
+
+    fn walk(server: &mut Server, newfid: u32, names: &[&str]) -> Result<Vec<u8>, u32> {
+        let mut w = Writer::new();
+        w.u32(0);
+        w.u32(newfid);
+        w.u16(names.len() as u16);
+        for name in names {
+            w.string(name);
+        }
+        call(server, P9_TWALK, w)
+    }
+
+    fn mknod(server: &mut Server, name: &str, mode: u32) -> Result<Vec<u8>, u32> {
+        let mut w = Writer::new();
+        w.u32(0);
+        w.string(name);
+        w.u32(mode);
+        w.u32(1);
+        w.u32(3);
+        w.u32(0);
+        call(server, P9_TMKNOD, w)
+    }
+
+    #[test]
+    fn readdir_resumes_at_offset() {
+        let dir = TestDir::new("p9-readdir");
+        let mut names = (0..50).map(|i| format!("file{}", i)).collect::<BTreeSet<_>>();
+        for name in names.iter() {
+            File::create(dir.path.join(name)).unwrap();
+        }
+        let mut server = server(&dir, false);
+        let mut w = Writer::new();
+        w.u32(0);
+        w.u32(libc::O_RDONLY as u32 | libc::O_DIRECTORY as u32);
+        call(&mut server, P9_TLOPEN, w).unwrap();
+
+        names.insert(".".to_string());
+        names.insert("..".to_string());
+        let mut seen = BTreeSet::new();
+        let mut offset = 0;
+        loop {
+            // Room for a few entries at a time.
+            let reply = readdir(&mut server, offset, 100);
+            let mut r = Reader::new(&reply);
+            let count = r.u32().unwrap() as usize;
+            if count == 0 {
+                break;
+            }
+            let mut r = Reader::new(&reply[4..4 + count]);
+            while let Ok(_) = r.bytes(QID_SIZE) {
+                offset = r.u64().unwrap();
+                r.u8().unwrap();
+                assert!(seen.insert(r.string().unwrap()));
+            }
+        }
+        assert_eq!(seen, names);
+    }
+
+    #[test]
+    fn mknod_only_creates_files() {
+        let dir = TestDir::new("p9-mknod");
+        let mut server = server(&dir, false);
+
+        for &mode in [libc::S_IFCHR, libc::S_IFBLK, libc::S_IFDIR].iter() {
+            assert_eq!(mknod(&mut server, "dev", mode as u32 | 0o666),
+                       Err(libc::EPERM as u32));
+        }
+        assert!(fs::symlink_metadata(dir.path.join("dev")).is_err());
+
+        mknod(&mut server, "fifo", libc::S_IFIFO as u32 | 0o644).unwrap();
+        assert!(fs::symlink_metadata(dir.path.join("fifo")).unwrap()
+                    .file_type().is_fifo());
+        mknod(&mut server, "file", libc::S_IFREG as u32 | 0o6755).unwrap();
+        assert_eq!(fs::metadata(dir.path.join("file")).unwrap().mode() & 0o7777,
+                   0o755);
+    }
+
+    #[test]
+    fn setuid_needs_trust() {
+        let dir = TestDir::new("p9-setuid");
+        for &trusted in [false, true].iter() {
+            let mut server = server(&dir, trusted);
+            let name = format!("file-{}", trusted);
+            let mut w = Writer::new();
+            w.u32(0);
+            w.string(&name);
+            w.u32(libc::O_RDWR as u32);
+            w.u32(0o4755);
+            w.u32(0);
+            call(&mut server, P9_TLCREATE, w).unwrap();
+            let path = dir.path.join(&name);
+            let expected = if trusted { 0o4755 } else { 0o755 };
+            assert_eq!(fs::metadata(&path).unwrap().mode() & 0o7777, expected);
+
+            // The fid now refers to the new file.
+            let mut w = Writer::new();
+            w.u32(0);
+            w.u32(P9_SETATTR_MODE);
+            w.u32(0o4750);
+            w.bytes(&[0u8; 8 + 8 + 32]);
+            call(&mut server, P9_TSETATTR, w).unwrap();
+            let expected = if trusted { 0o4750 } else { 0o750 };
+            assert_eq!(fs::metadata(&path).unwrap().mode() & 0o7777, expected);
+        }
+    }
+
+    #[test]
+    fn walk_keeps_used_fids() {
+        let dir = TestDir::new("p9-walk");
+        File::create(dir.path.join("file")).unwrap();
+        let mut server = server(&dir, false);
+
+        walk(&mut server, 1, &["file"]).unwrap();
+        assert_eq!(walk(&mut server, 1, &[]), Err(libc::EEXIST as u32));
+        // Walking a fid to itself is allowed.
+        walk(&mut server, 0, &[]).unwrap();
+    }
+
+    #[test]
+    fn special_files_are_not_opened() {
+        let dir = TestDir::new("p9-fifo");
+        let mut server = server(&dir, false);
+        mknod(&mut server, "fifo", libc::S_IFIFO as u32 | 0o644).unwrap();
+
+        walk(&mut server, 1, &["fifo"]).unwrap();
+        let mut w = Writer::new();
+        w.u32(1);
+        w.u32(libc::O_RDONLY as u32);
+        assert_eq!(call(&mut server, P9_TLOPEN, w), Err(libc::EPERM as u32));
+    }
+
+    #[test]
+    fn mkdir_drops_setid_bits() {
+        let dir = TestDir::new("p9-mkdir");
+        let mut server = server(&dir, false);
+        let mut w = Writer::new();
+        w.u32(0);
+        w.string("dir");
+        w.u32(0o6755);
+        w.u32(0);
+        call(&mut server, P9_TMKDIR, w).unwrap();
+        assert_eq!(fs::metadata(dir.path.join("dir")).unwrap().mode() & 0o7777,
+                   0o755);
+    }
+}
@@ -0,0 +1,111 @@
+extern crate byteorder;
+extern crate libc;
+
+use self::byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use std::io;
+
+// struct p9_qid
+pub const QID_SIZE: usize = 13;
+
+pub const QID_TYPE_DIR: u8 = 0x80;
+pub const QID_TYPE_SYMLINK: u8 = 0x02;
+pub const QID_TYPE_FILE: u8 = 0x00;
+
+#[derive(Copy, Clone)]
+pub struct Qid {
+    pub type_: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+fn invalid() -> io::Error {
+    io::Error::from_raw_os_error(libc::EINVAL)
+}
+
+/// Decodes the fields of a 9P message.
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader {
+            data: data,
+        }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(LittleEndian::read_u16(self.bytes(2)?))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(LittleEndian::read_u32(self.bytes(4)?))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(LittleEndian::read_u64(self.bytes(8)?))
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        let len = self.u16()? as usize;
+        String::from_utf8(self.bytes(len)?.to_vec()).map_err(|_| invalid())
+    }
+}
+
+/// Encodes the fields of a 9P message.
+pub struct Writer {
+    pub data: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer {
+            data: Vec::new(),
+        }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.write_u16::<LittleEndian>(value).unwrap();
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.write_u32::<LittleEndian>(value).unwrap();
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.write_u64::<LittleEndian>(value).unwrap();
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.data.extend_from_slice(value);
+    }
+
+    pub fn string(&mut self, value: &str) {
+        self.u16(value.len() as u16);
+        self.bytes(value.as_bytes());
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.type_);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+}
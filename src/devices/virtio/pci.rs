@@ -0,0 +1,481 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use ::devices::irq::IrqLine;
+use ::devices::pci::configuration::*;
+use ::devices::pci::device::PciDevice;
+use ::memory::GuestMemory;
+use ::snapshot::state::{StateReader, StateWriter};
+use super::*;
+
+const VENDOR_ID_VIRTIO: u16 = 0x1af4;
+// Modern devices use 0x1040 + the virtio device type.
+const DEVICE_ID_VIRTIO_BASE: u16 = 0x1040;
+const VIRTIO_PCI_REVISION: u8 = 1;
+
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+// Virtio PCI capability types.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+const VIRTIO_PCI_CAP_SIZE: usize = 16;
+const VIRTIO_PCI_NOTIFY_CAP_SIZE: usize = 20;
+
+// The layout of the BAR holding the virtio structures.
+const COMMON_CFG_OFFSET: u64 = 0x0000;
+const COMMON_CFG_SIZE: u64 = 0x38;
+const ISR_CFG_OFFSET: u64 = 0x1000;
+const ISR_CFG_SIZE: u64 = 1;
+const DEVICE_CFG_OFFSET: u64 = 0x2000;
+const DEVICE_CFG_SIZE: u64 = 0x1000;
+const NOTIFY_OFFSET: u64 = 0x3000;
+const NOTIFY_OFF_MULTIPLIER: u32 = 4;
+const NOTIFY_SIZE: u64 = 0x1000;
+const BAR_SIZE: u64 = 0x4000;
+
+// Common configuration registers.
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const MSIX_CONFIG: u64 = 0x10;
+const NUM_QUEUES: u64 = 0x12;
+const DEVICE_STATUS: u64 = 0x14;
+const CONFIG_GENERATION: u64 = 0x15;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC_LO: u64 = 0x20;
+const QUEUE_DESC_HI: u64 = 0x24;
+const QUEUE_DRIVER_LO: u64 = 0x28;
+const QUEUE_DRIVER_HI: u64 = 0x2c;
+const QUEUE_DEVICE_LO: u64 = 0x30;
+const QUEUE_DEVICE_HI: u64 = 0x34;
+
+// MSI-X is not supported.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+/// Exposes a virtio device through the virtio 1.x PCI transport, using
+/// legacy interrupts.
+pub struct VirtioPciDevice {
+    config: PciConfiguration,
+    device: Box<VirtioDevice>,
+    mem: GuestMemory,
+    interrupt: Option<Interrupt>,
+    queues: Vec<Queue>,
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: u64,
+    device_status: u8,
+    queue_select: u16,
+    activated: bool,
+    // The device state while stopped for a snapshot.
+    saved_state: Option<Vec<u8>>,
+}
+
+impl VirtioPciDevice {
+    pub fn new(device: Box<VirtioDevice>, mem: GuestMemory) -> Self {
+        let (class, subclass) = match device.device_type() {
+            TYPE_NET => (PCI_CLASS_NETWORK, 0x00),
+            TYPE_BLOCK => (PCI_CLASS_STORAGE, 0x00),
+            TYPE_CONSOLE => (PCI_CLASS_COMMUNICATION, 0x80),
+            _ => (PCI_CLASS_OTHER, 0x00),
+        };
+        let device_type = device.device_type() as u16;
+
+        let mut config = PciConfiguration::new(
+            VENDOR_ID_VIRTIO,
+            DEVICE_ID_VIRTIO_BASE + device_type,
+            class,
+            subclass,
+            0,
+            HEADER_TYPE_DEVICE,
+            VENDOR_ID_VIRTIO,
+            // The subsystem ID must be at least 0x40 for modern devices.
+            0x40 + device_type,
+            VIRTIO_PCI_REVISION);
+        let bar_idx = config.add_bar(PciBarConfiguration {
+            size: BAR_SIZE,
+            region_type: PciBarRegionType::Memory64BitRegion,
+            prefetchable: false,
+        });
+        config.set_interrupt_pin(PciInterruptPin::IntA);
+
+        let regions = [
+            (VIRTIO_PCI_CAP_COMMON_CFG, COMMON_CFG_OFFSET, COMMON_CFG_SIZE),
+            (VIRTIO_PCI_CAP_ISR_CFG, ISR_CFG_OFFSET, ISR_CFG_SIZE),
+            (VIRTIO_PCI_CAP_DEVICE_CFG, DEVICE_CFG_OFFSET, DEVICE_CFG_SIZE),
+        ];
+        for &(cfg_type, offset, length) in regions.iter() {
+            let mut cap = [0u8; VIRTIO_PCI_CAP_SIZE];
+            VirtioPciDevice::fill_cap(&mut cap, cfg_type, bar_idx, offset, length);
+            config.add_capability(&cap);
+        }
+        let mut cap = [0u8; VIRTIO_PCI_NOTIFY_CAP_SIZE];
+        VirtioPciDevice::fill_cap(&mut cap, VIRTIO_PCI_CAP_NOTIFY_CFG, bar_idx,
+                                  NOTIFY_OFFSET, NOTIFY_SIZE);
+        LittleEndian::write_u32(&mut cap[16..], NOTIFY_OFF_MULTIPLIER);
+        config.add_capability(&cap);
+
+        let queues = device.queue_max_sizes().into_iter()
+                           .map(Queue::new).collect();
+
+        VirtioPciDevice {
+            config: config,
+            device: device,
+            mem: mem,
+            interrupt: None,
+            queues: queues,
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: 0,
+            device_status: 0,
+            queue_select: 0,
+            activated: false,
+            saved_state: None,
+        }
+    }
+
+    fn fill_cap(cap: &mut [u8], cfg_type: u8, bar_idx: usize,
+                offset: u64, length: u64) {
+        cap[0] = PCI_CAP_ID_VNDR;
+        cap[2] = cap.len() as u8;
+        cap[3] = cfg_type;
+        cap[4] = bar_idx as u8;
+        LittleEndian::write_u32(&mut cap[8..], offset as u32);
+        LittleEndian::write_u32(&mut cap[12..], length as u32);
+    }
+
+    fn selected_queue(&mut self) -> Option<&mut Queue> {
+        self.queues.get_mut(self.queue_select as usize)
+    }
+
+    fn reset(&mut self) {
+        if self.activated {
+            self.device.reset();
+            self.activated = false;
+        }
+        self.queues = self.device.queue_max_sizes().into_iter()
+                          .map(Queue::new).collect();
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = 0;
+        self.device_status = 0;
+        self.queue_select = 0;
+        if let Some(ref interrupt) = self.interrupt {
+            interrupt.read_and_clear_status();
+        }
+    }
+
+    fn write_device_status(&mut self, status: u8) {
+        if status == 0 {
+            self.reset();
+            return;
+        }
+
+        if status & STATUS_FEATURES_OK != 0 &&
+                self.device_status & STATUS_FEATURES_OK == 0 {
+            // Legacy drivers are not supported.
+            if self.driver_features & (1 << VIRTIO_F_VERSION_1) == 0 {
+                println!("{}: the driver did not accept VIRTIO_F_VERSION_1.",
+                         self.device.debug_label());
+                self.device_status = status & !STATUS_FEATURES_OK;
+                return;
+            }
+            self.device.ack_features(self.driver_features);
+        }
+        self.device_status = status;
+
+        if status & STATUS_DRIVER_OK != 0 && !self.activated {
+            self.activate();
+        }
+    }
+
+    fn activate(&mut self) {
+        let interrupt = match self.interrupt {
+            Some(ref interrupt) => interrupt.clone(),
+            None => {
+                println!("{}: no interrupt assigned.", self.device.debug_label());
+                self.device_status |= STATUS_DEVICE_NEEDS_RESET;
+                return;
+            },
+        };
+        if self.queues.iter().any(|q| q.ready && !q.is_valid()) {
+            println!("{}: invalid queue configuration.",
+                     self.device.debug_label());
+            self.device_status |= STATUS_DEVICE_NEEDS_RESET;
+            return;
+        }
+
+        self.device.activate(self.mem.clone(), interrupt, self.queues.clone());
+        self.activated = true;
+    }
+
+    /// Hands the queues over to the device again after a snapshot, along
+    /// with its saved state.
+    fn reactivate(&mut self, device_state: &[u8]) {
+        for queue in self.queues.iter_mut() {
+            queue.restore_indexes(&self.mem);
+        }
+        self.device.ack_features(self.driver_features);
+        self.activate();
+        if !self.activated {
+            return;
+        }
+        self.device.restore_state(device_state);
+        // Process the buffers made available in the meantime.
+        for queue_index in 0..self.queues.len() {
+            self.device.queue_notify(queue_index);
+        }
+    }
+
+    fn read_common_config(&mut self, offset: u64) -> u32 {
+        match offset {
+            DEVICE_FEATURE_SELECT => self.device_feature_select,
+            DEVICE_FEATURE => match self.device_feature_select {
+                0 => self.device.features() as u32,
+                1 => (self.device.features() >> 32) as u32,
+                _ => 0,
+            },
+            DRIVER_FEATURE_SELECT => self.driver_feature_select,
+            DRIVER_FEATURE => match self.driver_feature_select {
+                0 => self.driver_features as u32,
+                1 => (self.driver_features >> 32) as u32,
+                _ => 0,
+            },
+            MSIX_CONFIG => VIRTIO_MSI_NO_VECTOR as u32,
+            NUM_QUEUES => self.queues.len() as u32,
+            DEVICE_STATUS => self.device_status as u32,
+            CONFIG_GENERATION => 0,
+            QUEUE_SELECT => self.queue_select as u32,
+            QUEUE_MSIX_VECTOR => VIRTIO_MSI_NO_VECTOR as u32,
+            // Each queue uses its own notification address.
+            QUEUE_NOTIFY_OFF => self.queue_select as u32,
+            _ => {
+                let queue = match self.selected_queue() {
+                    Some(queue) => queue,
+                    None => return 0,
+                };
+                match offset {
+                    QUEUE_SIZE => queue.size as u32,
+                    QUEUE_ENABLE => queue.ready as u32,
+                    QUEUE_DESC_LO => queue.desc_table as u32,
+                    QUEUE_DESC_HI => (queue.desc_table >> 32) as u32,
+                    QUEUE_DRIVER_LO => queue.avail_ring as u32,
+                    QUEUE_DRIVER_HI => (queue.avail_ring >> 32) as u32,
+                    QUEUE_DEVICE_LO => queue.used_ring as u32,
+                    QUEUE_DEVICE_HI => (queue.used_ring >> 32) as u32,
+                    _ => 0,
+                }
+            },
+        }
+    }
+
+    fn write_common_config(&mut self, offset: u64, value: u32) {
+        fn set_lo(reg: &mut u64, value: u32) {
+            *reg = (*reg & !0xffff_ffff) | value as u64;
+        }
+        fn set_hi(reg: &mut u64, value: u32) {
+            *reg = (*reg & 0xffff_ffff) | (value as u64) << 32;
+        }
+
+        match offset {
+            DEVICE_FEATURE_SELECT => self.device_feature_select = value,
+            DRIVER_FEATURE_SELECT => self.driver_feature_select = value,
+            DRIVER_FEATURE => {
+                // Only the offered features may be accepted.
+                let offered = self.device.features();
+                match self.driver_feature_select {
+                    0 => set_lo(&mut self.driver_features,
+                                value & offered as u32),
+                    1 => set_hi(&mut self.driver_features,
+                                value & (offered >> 32) as u32),
+                    _ => (),
+                }
+            },
+            DEVICE_STATUS => self.write_device_status(value as u8),
+            QUEUE_SELECT => self.queue_select = value as u16,
+            MSIX_CONFIG | QUEUE_MSIX_VECTOR => (),
+            _ => {
+                // The queue configuration can't change once activated.
+                if self.activated {
+                    return;
+                }
+                let queue = match self.selected_queue() {
+                    Some(queue) => queue,
+                    None => return,
+                };
+                match offset {
+                    QUEUE_SIZE => queue.size = value as u16,
+                    QUEUE_ENABLE => queue.ready = value == 1,
+                    QUEUE_DESC_LO => set_lo(&mut queue.desc_table, value),
+                    QUEUE_DESC_HI => set_hi(&mut queue.desc_table, value),
+                    QUEUE_DRIVER_LO => set_lo(&mut queue.avail_ring, value),
+                    QUEUE_DRIVER_HI => set_hi(&mut queue.avail_ring, value),
+                    QUEUE_DEVICE_LO => set_lo(&mut queue.used_ring, value),
+                    QUEUE_DEVICE_HI => set_hi(&mut queue.used_ring, value),
+                    _ => (),
+                }
+            },
+        }
+    }
+}
+
+impl PciDevice for VirtioPciDevice {
+    fn debug_label(&self) -> String {
+        format!("virtio-pci ({})", self.device.debug_label())
+    }
+
+    fn assign_irq(&mut self, irq: IrqLine, _pin: PciInterruptPin) {
+        self.interrupt = Some(Interrupt::new(irq));
+    }
+
+    fn config_registers(&self) -> &PciConfiguration {
+        &self.config
+    }
+
+    fn config_registers_mut(&mut self) -> &mut PciConfiguration {
+        &mut self.config
+    }
+
+    fn save_state(&mut self) -> Option<Vec<u8>> {
+        let device_state = self.device.save_state()?;
+        // The device stops using the queues until resumed.
+        if self.activated {
+            self.device.reset();
+        }
+
+        let mut state = StateWriter::new();
+        state.write_u32(self.device_feature_select);
+        state.write_u32(self.driver_feature_select);
+        state.write_u64(self.driver_features);
+        state.write_u8(self.device_status);
+        state.write_u16(self.queue_select);
+        state.write_bool(self.activated);
+        state.write_u32(self.queues.len() as u32);
+        for queue in &self.queues {
+            state.write_u16(queue.size);
+            state.write_bool(queue.ready);
+            state.write_u64(queue.desc_table);
+            state.write_u64(queue.avail_ring);
+            state.write_u64(queue.used_ring);
+        }
+        state.write_u8(self.interrupt.as_ref().map_or(0, |i| i.status()));
+        state.write_bytes(&device_state);
+
+        self.saved_state = Some(device_state);
+        Some(state.into_bytes())
+    }
+
+    fn restore_state(&mut self, data: &[u8]) {
+        let mut state = StateReader::new(data);
+        self.device_feature_select = state.read_u32();
+        self.driver_feature_select = state.read_u32();
+        self.driver_features = state.read_u64();
+        self.device_status = state.read_u8();
+        self.queue_select = state.read_u16();
+        let activated = state.read_bool();
+        if state.read_u32() as usize != self.queues.len() {
+            panic!("Invalid snapshot: {} queue count mismatch.",
+                   self.device.debug_label());
+        }
+        for queue in self.queues.iter_mut() {
+            queue.size = state.read_u16();
+            queue.ready = state.read_bool();
+            queue.desc_table = state.read_u64();
+            queue.avail_ring = state.read_u64();
+            queue.used_ring = state.read_u64();
+        }
+        let isr_status = state.read_u8();
+        if let Some(ref interrupt) = self.interrupt {
+            interrupt.restore_status(isr_status);
+        }
+
+        if activated {
+            self.reactivate(state.read_bytes());
+        } else if self.device_status & STATUS_FEATURES_OK != 0 {
+            self.device.ack_features(self.driver_features);
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Some(device_state) = self.saved_state.take() {
+            if self.activated {
+                self.activated = false;
+                self.reactivate(&device_state);
+            }
+        }
+    }
+
+    fn read_bar(&mut self, addr: u64, data: &mut [u8]) {
+        let offset = match self.config.bar_for_addr(addr) {
+            Some((_, offset)) => offset,
+            None => return,
+        };
+
+        match offset {
+            o if o < COMMON_CFG_OFFSET + COMMON_CFG_SIZE => {
+                // Registers are naturally aligned, smaller accesses are
+                // used for the 8 and 16 bit ones.
+                let value = self.read_common_config(o);
+                for (i, byte) in data.iter_mut().enumerate() {
+                    *byte = value.checked_shr(i as u32 * 8).unwrap_or(0) as u8;
+                }
+            },
+            o if o >= ISR_CFG_OFFSET && o < ISR_CFG_OFFSET + ISR_CFG_SIZE => {
+                let status = match self.interrupt {
+                    Some(ref interrupt) => interrupt.read_and_clear_status(),
+                    None => 0,
+                };
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+                if let Some(byte) = data.first_mut() {
+                    *byte = status;
+                }
+            },
+            o if o >= DEVICE_CFG_OFFSET && o < DEVICE_CFG_OFFSET + DEVICE_CFG_SIZE =>
+                self.device.read_config(o - DEVICE_CFG_OFFSET, data),
+            _ => {
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+            },
+        }
+    }
+
+    fn write_bar(&mut self, addr: u64, data: &[u8]) {
+        let offset = match self.config.bar_for_addr(addr) {
+            Some((_, offset)) => offset,
+            None => return,
+        };
+
+        match offset {
+            o if o < COMMON_CFG_OFFSET + COMMON_CFG_SIZE => {
+                let value = match data.len() {
+                    1 => data[0] as u32,
+                    2 => LittleEndian::read_u16(data) as u32,
+                    4 => LittleEndian::read_u32(data),
+                    _ => return,
+                };
+                self.write_common_config(o, value);
+            },
+            o if o >= DEVICE_CFG_OFFSET && o < DEVICE_CFG_OFFSET + DEVICE_CFG_SIZE =>
+                self.device.write_config(o - DEVICE_CFG_OFFSET, data),
+            o if o >= NOTIFY_OFFSET && o < NOTIFY_OFFSET + NOTIFY_SIZE => {
+                let queue_index = ((o - NOTIFY_OFFSET) /
+                                   NOTIFY_OFF_MULTIPLIER as u64) as usize;
+                if self.activated && queue_index < self.queues.len() {
+                    self.device.queue_notify(queue_index);
+                }
+            },
+            _ => (),
+        }
+    }
+}
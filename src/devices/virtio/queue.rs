@@ -0,0 +1,197 @@
+use std::cmp;
+use std::num::Wrapping;
+use std::sync::atomic::{fence, Ordering};
+
+use ::memory::GuestMemory;
+
+pub const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+pub const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+
+const DESCRIPTOR_SIZE: u64 = 16;
+// flags + idx
+const RING_HEADER_SIZE: u64 = 4;
+const USED_ELEMENT_SIZE: u64 = 8;
+
+/// A guest buffer, part of a descriptor chain.
+#[derive(Debug, Copy, Clone)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub write_only: bool,
+}
+
+/// A request made by the driver: the device readable buffers followed by
+/// the device writable ones.
+pub struct DescriptorChain {
+    pub head_index: u16,
+    pub descriptors: Vec<Descriptor>,
+}
+
+impl DescriptorChain {
+    pub fn readable(&self) -> Vec<Descriptor> {
+        self.descriptors.iter().cloned().filter(|d| !d.write_only).collect()
+    }
+
+    pub fn writable(&self) -> Vec<Descriptor> {
+        self.descriptors.iter().cloned().filter(|d| d.write_only).collect()
+    }
+
+    /// Gathers the device readable buffers. Returns None if they are not
+    /// in guest memory or if they hold more than `max_len` bytes.
+    pub fn read_all(&self, mem: &GuestMemory, max_len: usize)
+            -> Option<Vec<u8>> {
+        let readable = self.readable();
+        let total_len = readable.iter().map(|d| d.len as u64).sum::<u64>();
+        if total_len > max_len as u64 {
+            return None;
+        }
+
+        let mut data = Vec::with_capacity(total_len as usize);
+        for desc in readable {
+            let start = data.len();
+            data.resize(start + desc.len as usize, 0);
+            if !mem.read(desc.addr, &mut data[start..]) {
+                return None;
+            }
+        }
+        Some(data)
+    }
+
+    /// Scatters `data` over the device writable buffers, returning the
+    /// number of bytes written.
+    pub fn write_all(&self, mem: &GuestMemory, data: &[u8]) -> usize {
+        let mut written = 0;
+        for desc in self.writable() {
+            if written == data.len() {
+                break;
+            }
+            let count = cmp::min(desc.len as usize, data.len() - written);
+            if !mem.write(desc.addr, &data[written..written + count]) {
+                break;
+            }
+            written += count;
+        }
+        written
+    }
+}
+
+/// A split virtqueue, as configured by the driver.
+#[derive(Clone)]
+pub struct Queue {
+    pub max_size: u16,
+    pub size: u16,
+    pub ready: bool,
+    pub desc_table: u64,
+    pub avail_ring: u64,
+    pub used_ring: u64,
+    next_avail: Wrapping<u16>,
+    next_used: Wrapping<u16>,
+}
+
+impl Queue {
+    pub fn new(max_size: u16) -> Self {
+        Queue {
+            max_size: max_size,
+            size: max_size,
+            ready: false,
+            desc_table: 0,
+            avail_ring: 0,
+            used_ring: 0,
+            next_avail: Wrapping(0),
+            next_used: Wrapping(0),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.ready && self.size > 0 && self.size <= self.max_size &&
+            self.size.is_power_of_two()
+    }
+
+    fn avail_idx(&self, mem: &GuestMemory) -> Option<Wrapping<u16>> {
+        let idx = mem.read_obj::<u16>(self.avail_ring + 2)?;
+        // Make sure that the ring entries are read after the index.
+        fence(Ordering::Acquire);
+        Some(Wrapping(idx))
+    }
+
+    /// Returns the next descriptor chain made available by the driver.
+    pub fn pop(&mut self, mem: &GuestMemory) -> Option<DescriptorChain> {
+        if !self.is_valid() {
+            return None;
+        }
+
+        let avail_idx = self.avail_idx(mem)?;
+        if avail_idx == self.next_avail {
+            return None;
+        }
+        if (avail_idx - self.next_avail).0 > self.size {
+            println!("Invalid virtqueue available index: {}", avail_idx);
+            return None;
+        }
+
+        let ring_offset = (self.next_avail.0 % self.size) as u64 * 2;
+        let head_index = mem.read_obj::<u16>(
+            self.avail_ring + RING_HEADER_SIZE + ring_offset)?;
+        self.next_avail += Wrapping(1);
+
+        let mut descriptors = Vec::new();
+        let mut index = head_index;
+        loop {
+            // Guard against loops and out of range indexes.
+            if index >= self.size || descriptors.len() >= self.size as usize {
+                println!("Invalid virtqueue descriptor chain.");
+                return None;
+            }
+
+            let desc_addr = self.desc_table + index as u64 * DESCRIPTOR_SIZE;
+            let addr = mem.read_obj::<u64>(desc_addr)?;
+            let len = mem.read_obj::<u32>(desc_addr + 8)?;
+            let flags = mem.read_obj::<u16>(desc_addr + 12)?;
+            let next = mem.read_obj::<u16>(desc_addr + 14)?;
+
+            descriptors.push(Descriptor {
+                addr: addr,
+                len: len,
+                write_only: flags & VIRTQ_DESC_F_WRITE != 0,
+            });
+
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+
+        Some(DescriptorChain {
+            head_index: head_index,
+            descriptors: descriptors,
+        })
+    }
+
+    /// Resumes from the used ring index, once the queue was handed over
+    /// again after a snapshot. The buffers that were made available but not
+    /// used yet are popped again, which assumes that the device uses them
+    /// in order.
+    pub fn restore_indexes(&mut self, mem: &GuestMemory) {
+        if !self.is_valid() {
+            return;
+        }
+        let used_idx = Wrapping(mem.read_obj::<u16>(self.used_ring + 2)
+                                   .unwrap_or(0));
+        self.next_avail = used_idx;
+        self.next_used = used_idx;
+    }
+
+    /// Returns a descriptor chain to the driver, `len` being the number of
+    /// bytes written by the device.
+    pub fn add_used(&mut self, mem: &GuestMemory, head_index: u16, len: u32) {
+        let ring_offset = (self.next_used.0 % self.size) as u64 * USED_ELEMENT_SIZE;
+        let elem_addr = self.used_ring + RING_HEADER_SIZE + ring_offset;
+        mem.write_obj::<u32>(elem_addr, head_index as u32);
+        mem.write_obj::<u32>(elem_addr + 4, len);
+
+        self.next_used += Wrapping(1);
+        // The element must be visible before the index.
+        fence(Ordering::Release);
+        mem.write_obj::<u16>(self.used_ring + 2, self.next_used.0);
+    }
+}
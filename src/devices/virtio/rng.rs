@@ -0,0 +1,229 @@
+extern crate libc;
+
+use std::io;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ::memory::GuestMemory;
+use ::utils::options::parse_key_values;
+use super::*;
+
+const QUEUE_SIZE: u16 = 64;
+// Larger buffers are only partially filled, the driver asks again for
+// the rest.
+const MAX_CHUNK_SIZE: usize = 64 << 10;
+
+const DEFAULT_PERIOD_MS: u64 = 1000;
+
+/// Entropy rate limit, as passed through the --rng option.
+#[derive(Copy, Clone)]
+pub struct RngConfig {
+    /// Bytes handed out per period, unlimited if None.
+    pub max_bytes: Option<usize>,
+    pub period: Duration,
+}
+
+impl RngConfig {
+    /// Parses a [max-bytes=<n>][,period=<ms>] spec.
+    pub fn parse(spec: &str) -> Self {
+        let mut config = RngConfig {
+            max_bytes: None,
+            period: Duration::from_millis(DEFAULT_PERIOD_MS),
+        };
+        for (key, value) in parse_key_values(spec) {
+            match key.as_str() {
+                "max-bytes" => config.max_bytes = Some(
+                    value.parse::<usize>().unwrap_or_else(
+                        |_| panic!("Invalid rng max-bytes: {}", value))),
+                "period" => config.period = Duration::from_millis(
+                    value.parse::<u64>().unwrap_or_else(
+                        |_| panic!("Invalid rng period: {}", value))),
+                _ => panic!("Unknown rng option: {}", key),
+            }
+        }
+        if config.period == Duration::from_millis(0) {
+            panic!("Invalid rng period: 0");
+        }
+        config
+    }
+}
+
+fn getrandom(buf: &mut [u8]) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::syscall(libc::SYS_getrandom,
+                      buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+enum Event {
+    Notify,
+    Stop,
+}
+
+struct Worker {
+    config: RngConfig,
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    queue: Queue,
+    period_start: Instant,
+    // Bytes handed out during the current period.
+    period_bytes: usize,
+}
+
+impl Worker {
+    /// Returns the number of bytes the guest may still get during the
+    /// current period.
+    fn budget(&mut self) -> usize {
+        let max_bytes = match self.config.max_bytes {
+            Some(max_bytes) => max_bytes,
+            None => return usize::max_value(),
+        };
+        if self.period_start.elapsed() >= self.config.period {
+            self.period_start = Instant::now();
+            self.period_bytes = 0;
+        }
+        max_bytes - self.period_bytes
+    }
+
+    /// Fills the available buffers, returning false when the rate
+    /// limit was hit before all of them could be used.
+    fn process_queue(&mut self) -> bool {
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        let mut used = false;
+        let mut throttled = false;
+
+        loop {
+            let budget = self.budget();
+            if budget == 0 {
+                throttled = true;
+                break;
+            }
+            let chain = match self.queue.pop(&self.mem) {
+                Some(chain) => chain,
+                None => break,
+            };
+
+            let len = chain.writable().iter().map(|d| d.len as usize).sum::<usize>();
+            let count = len.min(budget).min(MAX_CHUNK_SIZE);
+            let written = match getrandom(&mut buf[..count]) {
+                Ok(count) => chain.write_all(&self.mem, &buf[..count]),
+                Err(e) => {
+                    println!("virtio-rng: getrandom failed: {}", e);
+                    0
+                },
+            };
+            self.period_bytes += written;
+            self.queue.add_used(&self.mem, chain.head_index, written as u32);
+            used = true;
+        }
+
+        if used {
+            self.interrupt.signal_used_queue();
+        }
+        !throttled
+    }
+
+    fn run(&mut self, events: mpsc::Receiver<Event>) {
+        let mut throttled = false;
+        loop {
+            let event = if throttled {
+                // Resume once the next period starts.
+                let timeout = self.config.period.checked_sub(self.period_start.elapsed())
+                                                .unwrap_or(Duration::from_millis(0));
+                match events.recv_timeout(timeout) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => Event::Notify,
+                    Err(RecvTimeoutError::Disconnected) => Event::Stop,
+                }
+            } else {
+                events.recv().unwrap_or(Event::Stop)
+            };
+
+            match event {
+                Event::Notify => throttled = !self.process_queue(),
+                Event::Stop => return,
+            }
+        }
+    }
+}
+
+/// Virtio entropy device, fed from the host getrandom(2). Requests are
+/// served by a worker thread so that rate limited ones can wait for the
+/// next period.
+pub struct Rng {
+    config: RngConfig,
+    events: Option<Sender<Event>>,
+    worker_thread: Option<JoinHandle<()>>,
+}
+
+impl Rng {
+    pub fn new(config: RngConfig) -> Self {
+        Rng {
+            config: config,
+            events: None,
+            worker_thread: None,
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(events) = self.events.take() {
+            let _ = events.send(Event::Stop);
+        }
+        if let Some(worker_thread) = self.worker_thread.take() {
+            worker_thread.join().unwrap();
+        }
+    }
+}
+
+impl VirtioDevice for Rng {
+    fn debug_label(&self) -> String {
+        "virtio-rng".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_RNG
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE]
+    }
+
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                mut queues: Vec<Queue>) {
+        let mut worker = Worker {
+            config: self.config,
+            mem: mem,
+            interrupt: interrupt,
+            queue: queues.remove(0),
+            period_start: Instant::now(),
+            period_bytes: 0,
+        };
+        let (events_tx, events_rx) = mpsc::channel();
+
+        self.events = Some(events_tx);
+        self.worker_thread = Some(thread::Builder::new()
+            .name("virtio-rng".to_string())
+            .spawn(move || worker.run(events_rx))
+            .unwrap());
+    }
+
+    fn queue_notify(&mut self, _queue_index: usize) {
+        if let Some(ref events) = self.events {
+            let _ = events.send(Event::Notify);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.stop();
+    }
+
+    // The queues are all there is to save.
+    fn save_state(&self) -> Option<Vec<u8>> {
+        Some(Vec::new())
+    }
+}
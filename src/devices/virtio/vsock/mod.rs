@@ -0,0 +1,160 @@
+//! Hybrid virtio-vsock device, bridging guest connections to host unix
+//! sockets without the vhost-vsock kernel module.
+//!
+//! Guest connections to the host CID on port P go to the unix socket
+//! <path>_P. Host clients connect to <path> and send "CONNECT <port>\n"
+//! to reach a guest port, getting "OK <local port>\n" back once the guest
+//! accepted the connection.
+
+extern crate byteorder;
+extern crate libc;
+
+mod muxer;
+mod packet;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::fs;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use ::memory::GuestMemory;
+use ::utils::options::parse_key_values;
+use super::*;
+
+const QUEUE_SIZE: u16 = 256;
+// rx, tx and event queues.
+const NUM_QUEUES: usize = 3;
+
+// Context IDs below 3 are reserved.
+const MIN_GUEST_CID: u64 = 3;
+
+/// A vsock device, as passed through the --vsock option.
+pub struct VsockConfig {
+    pub guest_cid: u64,
+    pub path: String,
+}
+
+impl VsockConfig {
+    /// Parses a cid=<cid>,path=<path> spec.
+    pub fn parse(spec: &str) -> Self {
+        let mut guest_cid = None;
+        let mut path = None;
+        for (key, value) in parse_key_values(spec) {
+            match key.as_str() {
+                "cid" => guest_cid = Some(value.parse::<u64>().unwrap_or_else(
+                    |_| panic!("Invalid vsock CID: {}", value))),
+                "path" => path = Some(value),
+                _ => panic!("Unknown vsock option: {}", key),
+            }
+        }
+
+        let guest_cid = guest_cid.expect("Missing vsock CID.");
+        if guest_cid < MIN_GUEST_CID || guest_cid > u32::max_value() as u64 {
+            panic!("Invalid vsock CID: {}", guest_cid);
+        }
+        VsockConfig {
+            guest_cid: guest_cid,
+            path: path.expect("Missing vsock socket path."),
+        }
+    }
+}
+
+/// Virtio socket device. Packets are processed by a muxer thread, which
+/// the device wakes up on queue notifications.
+pub struct Vsock {
+    config: VsockConfig,
+    listener: UnixListener,
+    wakeup_fd: RawFd,
+    stop: Arc<AtomicBool>,
+    muxer_thread: Option<JoinHandle<()>>,
+}
+
+impl Vsock {
+    pub fn new(config: VsockConfig) -> Self {
+        // Remove stale sockets left behind by previous runs.
+        let _ = fs::remove_file(&config.path);
+        let listener = UnixListener::bind(&config.path).unwrap_or_else(
+            |_| panic!("Cannot listen on \"{}\".", config.path));
+        listener.set_nonblocking(true).unwrap();
+
+        let wakeup_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wakeup_fd < 0 {
+            panic!("Cannot create eventfd: {}", io::Error::last_os_error());
+        }
+
+        Vsock {
+            config: config,
+            listener: listener,
+            wakeup_fd: wakeup_fd,
+            stop: Arc::new(AtomicBool::new(false)),
+            muxer_thread: None,
+        }
+    }
+
+    fn wake_up(&self) {
+        let value: u64 = 1;
+        unsafe {
+            libc::write(self.wakeup_fd, &value as *const u64 as *const libc::c_void, 8);
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(muxer_thread) = self.muxer_thread.take() {
+            self.stop.store(true, Ordering::SeqCst);
+            self.wake_up();
+            muxer_thread.join().unwrap();
+            self.stop.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+impl VirtioDevice for Vsock {
+    fn debug_label(&self) -> String {
+        "virtio-vsock".to_string()
+    }
+
+    fn device_type(&self) -> u32 {
+        TYPE_VSOCK
+    }
+
+    fn queue_max_sizes(&self) -> Vec<u16> {
+        vec![QUEUE_SIZE; NUM_QUEUES]
+    }
+
+    fn read_config(&self, offset: u64, data: &mut [u8]) {
+        let mut config = [0u8; 8];
+        LittleEndian::write_u64(&mut config, self.config.guest_cid);
+        read_config_bytes(&config, offset, data);
+    }
+
+    fn activate(&mut self, mem: GuestMemory, interrupt: Interrupt,
+                queues: Vec<Queue>) {
+        // The event queue is only used for transport resets, which
+        // never happen.
+        let mut queues = queues.into_iter();
+        let rx_queue = queues.next().unwrap();
+        let tx_queue = queues.next().unwrap();
+
+        let mut muxer = muxer::Muxer::new(
+            self.config.guest_cid, self.config.path.clone(),
+            self.listener.try_clone().unwrap(), mem, interrupt,
+            rx_queue, tx_queue, self.wakeup_fd, self.stop.clone());
+        self.muxer_thread = Some(thread::Builder::new()
+            .name("virtio-vsock".to_string())
+            .spawn(move || muxer.run())
+            .unwrap());
+    }
+
+    fn queue_notify(&mut self, _queue_index: usize) {
+        self.wake_up();
+    }
+
+    fn reset(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,507 @@
+extern crate libc;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use ::memory::GuestMemory;
+use super::super::{Interrupt, Queue};
+use super::packet::*;
+
+pub const HOST_CID: u64 = 2;
+
+// Receive buffer advertised to the guest for each connection.
+const CONN_BUF_SIZE: u32 = 256 * 1024;
+// Credit updates are sent once this much data was forwarded.
+const CREDIT_UPDATE_THRESHOLD: u32 = CONN_BUF_SIZE / 4;
+// Host data is read in chunks matching the guest receive buffers.
+const READ_CHUNK_SIZE: usize = 4096;
+// Stop reading from the host sockets when the guest is not keeping up.
+const MAX_PENDING_RX: usize = 256;
+// Control packets are dropped past this, when the guest doesn't provide
+// receive buffers.
+const MAX_QUEUED_RX: usize = MAX_PENDING_RX * 2;
+const MAX_HANDSHAKE_SIZE: usize = 32;
+// The largest packet sent by the guest, VIRTIO_VSOCK_MAX_PKT_BUF_SIZE.
+const MAX_TX_PACKET_SIZE: usize = HEADER_SIZE + (64 << 10);
+// Local ports of the host initiated connections.
+const FIRST_LOCAL_PORT: u32 = 1 << 30;
+const POLL_TIMEOUT_MS: i32 = 1000;
+
+#[derive(PartialEq)]
+enum ConnState {
+    /// Host initiated, waiting for the guest to accept.
+    Connecting,
+    Established,
+    /// The host closed its side, waiting for the guest reset.
+    Closing,
+}
+
+struct Connection {
+    stream: UnixStream,
+    state: ConnState,
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    // Bytes sent to the guest.
+    tx_cnt: u32,
+    // Bytes received from the guest and written to the host socket.
+    fwd_cnt: u32,
+    last_fwd_cnt_sent: u32,
+    // Guest data waiting for the host socket to become writable.
+    pending: Vec<u8>,
+    // The guest won't send anything else.
+    guest_shut_send: bool,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, state: ConnState) -> Self {
+        Connection {
+            stream: stream,
+            state: state,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            last_fwd_cnt_sent: 0,
+            pending: Vec::new(),
+            guest_shut_send: false,
+        }
+    }
+
+    /// Returns how much data the guest is able to receive.
+    fn peer_credit(&self) -> u32 {
+        self.peer_buf_alloc.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+}
+
+/// A host client that hasn't sent its "CONNECT <port>" line yet.
+struct Handshake {
+    stream: UnixStream,
+    buf: Vec<u8>,
+}
+
+// Connections are identified by (host port, guest port).
+type ConnKey = (u32, u32);
+
+/// Bridges the guest vsock connections to host unix sockets. Runs in its
+/// own thread, woken up by the device on queue notifications.
+pub struct Muxer {
+    guest_cid: u64,
+    path: String,
+    listener: UnixListener,
+    mem: GuestMemory,
+    interrupt: Interrupt,
+    rx_queue: Queue,
+    tx_queue: Queue,
+    wakeup_fd: RawFd,
+    stop: Arc<AtomicBool>,
+    conns: HashMap<ConnKey, Connection>,
+    handshakes: Vec<Handshake>,
+    rx_pending: VecDeque<Packet>,
+    next_local_port: u32,
+}
+
+impl Muxer {
+    pub fn new(guest_cid: u64, path: String, listener: UnixListener,
+               mem: GuestMemory, interrupt: Interrupt, rx_queue: Queue,
+               tx_queue: Queue, wakeup_fd: RawFd, stop: Arc<AtomicBool>) -> Self {
+        Muxer {
+            guest_cid: guest_cid,
+            path: path,
+            listener: listener,
+            mem: mem,
+            interrupt: interrupt,
+            rx_queue: rx_queue,
+            tx_queue: tx_queue,
+            wakeup_fd: wakeup_fd,
+            stop: stop,
+            conns: HashMap::new(),
+            handshakes: Vec::new(),
+            rx_pending: VecDeque::new(),
+            next_local_port: FIRST_LOCAL_PORT,
+        }
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            self.poll();
+            if self.stop.load(Ordering::SeqCst) {
+                return;
+            }
+
+            self.process_tx();
+            self.accept_clients();
+            self.read_handshakes();
+
+            let keys = self.conns.keys().cloned().collect::<Vec<_>>();
+            for key in keys {
+                self.write_to_host(key);
+                self.read_from_host(key);
+            }
+
+            self.flush_rx();
+        }
+    }
+
+    /// Waits for queue notifications or host socket events.
+    fn poll(&self) {
+        let pollfd = |fd: RawFd, events: libc::c_short| libc::pollfd {
+            fd: fd,
+            events: events,
+            revents: 0,
+        };
+
+        let mut pollfds = vec![pollfd(self.wakeup_fd, libc::POLLIN),
+                               pollfd(self.listener.as_raw_fd(), libc::POLLIN)];
+        for handshake in self.handshakes.iter() {
+            pollfds.push(pollfd(handshake.stream.as_raw_fd(), libc::POLLIN));
+        }
+        let rx_full = self.rx_pending.len() >= MAX_PENDING_RX;
+        for conn in self.conns.values() {
+            let mut events = 0;
+            if conn.state == ConnState::Established && conn.peer_credit() > 0 &&
+                    !rx_full {
+                events |= libc::POLLIN;
+            }
+            if !conn.pending.is_empty() {
+                events |= libc::POLLOUT;
+            }
+            pollfds.push(pollfd(conn.stream.as_raw_fd(), events));
+        }
+
+        unsafe {
+            libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t,
+                       POLL_TIMEOUT_MS);
+            let mut value: u64 = 0;
+            libc::read(self.wakeup_fd,
+                       &mut value as *mut u64 as *mut libc::c_void, 8);
+        }
+    }
+
+    fn process_tx(&mut self) {
+        let mut used = false;
+        while let Some(chain) = self.tx_queue.pop(&self.mem) {
+            let data = chain.read_all(&self.mem, MAX_TX_PACKET_SIZE);
+            self.tx_queue.add_used(&self.mem, chain.head_index, 0);
+            used = true;
+
+            if let Some(data) = data {
+                if let Some(header) = PacketHeader::parse(&data) {
+                    let payload_end = data.len().min(HEADER_SIZE + header.len as usize);
+                    self.handle_packet(&header, &data[HEADER_SIZE..payload_end]);
+                }
+            }
+        }
+        if used {
+            self.interrupt.signal_used_queue();
+        }
+    }
+
+    fn handle_packet(&mut self, header: &PacketHeader, data: &[u8]) {
+        if header.src_cid != self.guest_cid || header.dst_cid != HOST_CID {
+            return;
+        }
+        let key = (header.dst_port, header.src_port);
+        if header.type_ != VSOCK_TYPE_STREAM {
+            self.send_reset(key);
+            return;
+        }
+
+        if header.op == VSOCK_OP_REQUEST {
+            self.connect_to_host(key);
+            return;
+        }
+
+        let conn = match self.conns.get_mut(&key) {
+            Some(conn) => conn,
+            None => {
+                if header.op != VSOCK_OP_RST {
+                    self.send_reset(key);
+                }
+                return;
+            },
+        };
+        conn.peer_buf_alloc = header.buf_alloc;
+        conn.peer_fwd_cnt = header.fwd_cnt;
+
+        match header.op {
+            VSOCK_OP_RESPONSE if conn.state == ConnState::Connecting => {
+                conn.state = ConnState::Established;
+                let reply = format!("OK {}\n", key.0);
+                if conn.stream.write_all(reply.as_bytes()).is_err() {
+                    self.close(key, true);
+                }
+            },
+            VSOCK_OP_RW if conn.state == ConnState::Established => {
+                // Well behaved drivers don't exceed the advertised buffer.
+                let room = (CONN_BUF_SIZE as usize).saturating_sub(conn.pending.len());
+                conn.pending.extend_from_slice(&data[..data.len().min(room)]);
+            },
+            VSOCK_OP_SHUTDOWN => {
+                let both = VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND;
+                if header.flags & both == both {
+                    self.close(key, true);
+                } else if header.flags & VSOCK_SHUTDOWN_SEND != 0 {
+                    conn.guest_shut_send = true;
+                    if conn.pending.is_empty() {
+                        let _ = conn.stream.shutdown(Shutdown::Write);
+                    }
+                }
+            },
+            VSOCK_OP_RST => self.close(key, false),
+            VSOCK_OP_CREDIT_UPDATE => (),
+            VSOCK_OP_CREDIT_REQUEST => self.send_control(key, VSOCK_OP_CREDIT_UPDATE, 0),
+            _ => self.close(key, true),
+        }
+    }
+
+    /// Handles a guest connection request, connecting to <path>_<port>.
+    fn connect_to_host(&mut self, key: ConnKey) {
+        if self.conns.contains_key(&key) {
+            self.close(key, true);
+            return;
+        }
+
+        let path = format!("{}_{}", self.path, key.0);
+        match UnixStream::connect(&path) {
+            Ok(stream) => {
+                stream.set_nonblocking(true).unwrap();
+                self.conns.insert(key, Connection::new(stream, ConnState::Established));
+                self.send_control(key, VSOCK_OP_RESPONSE, 0);
+            },
+            Err(_) => self.send_reset(key),
+        }
+    }
+
+    fn accept_clients(&mut self) {
+        while let Ok((stream, _)) = self.listener.accept() {
+            stream.set_nonblocking(true).unwrap();
+            self.handshakes.push(Handshake {
+                stream: stream,
+                buf: Vec::new(),
+            });
+        }
+    }
+
+    /// Reads the "CONNECT <port>\n" lines of the host clients, asking the
+    /// guest to accept the connection.
+    fn read_handshakes(&mut self) {
+        let mut i = 0;
+        while i < self.handshakes.len() {
+            let mut buf = [0u8; MAX_HANDSHAKE_SIZE];
+            let done = match self.handshakes[i].stream.read(&mut buf) {
+                Ok(0) => true,
+                Ok(count) => {
+                    let handshake = &mut self.handshakes[i];
+                    handshake.buf.extend_from_slice(&buf[..count]);
+                    handshake.buf.contains(&b'\n') ||
+                        handshake.buf.len() > MAX_HANDSHAKE_SIZE
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                Err(_) => true,
+            };
+            if !done {
+                i += 1;
+                continue;
+            }
+
+            let mut handshake = self.handshakes.remove(i);
+            match parse_connect(&handshake.buf) {
+                Some(peer_port) => {
+                    let local_port = self.allocate_local_port();
+                    let key = (local_port, peer_port);
+                    self.conns.insert(key, Connection::new(handshake.stream,
+                                                           ConnState::Connecting));
+                    self.send_control(key, VSOCK_OP_REQUEST, 0);
+                },
+                None => {
+                    let _ = handshake.stream.write_all(b"ERROR\n");
+                },
+            }
+        }
+    }
+
+    fn allocate_local_port(&mut self) -> u32 {
+        loop {
+            let port = self.next_local_port;
+            self.next_local_port = match port {
+                0xffffffff => FIRST_LOCAL_PORT,
+                port => port + 1,
+            };
+            if !self.conns.keys().any(|&(local_port, _)| local_port == port) {
+                return port;
+            }
+        }
+    }
+
+    fn write_to_host(&mut self, key: ConnKey) {
+        let failed = {
+            let conn = match self.conns.get_mut(&key) {
+                Some(conn) => conn,
+                None => return,
+            };
+            if conn.pending.is_empty() {
+                return;
+            }
+
+            match conn.stream.write(&conn.pending) {
+                Ok(count) => {
+                    conn.pending.drain(..count);
+                    conn.fwd_cnt = conn.fwd_cnt.wrapping_add(count as u32);
+                    if conn.pending.is_empty() && conn.guest_shut_send {
+                        let _ = conn.stream.shutdown(Shutdown::Write);
+                    }
+                    false
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+                Err(_) => true,
+            }
+        };
+        if failed {
+            self.close(key, true);
+            return;
+        }
+
+        let conn = &self.conns[&key];
+        if conn.fwd_cnt.wrapping_sub(conn.last_fwd_cnt_sent) >= CREDIT_UPDATE_THRESHOLD {
+            self.send_control(key, VSOCK_OP_CREDIT_UPDATE, 0);
+        }
+    }
+
+    fn read_from_host(&mut self, key: ConnKey) {
+        if self.rx_pending.len() >= MAX_PENDING_RX {
+            return;
+        }
+
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        let result = {
+            let conn = match self.conns.get_mut(&key) {
+                Some(conn) => conn,
+                None => return,
+            };
+            let credit = conn.peer_credit() as usize;
+            if conn.state != ConnState::Established || credit == 0 {
+                return;
+            }
+            let len = buf.len().min(credit);
+            conn.stream.read(&mut buf[..len])
+        };
+
+        match result {
+            Ok(0) => {
+                // The host closed the connection.
+                self.conns.get_mut(&key).unwrap().state = ConnState::Closing;
+                self.send_control(key, VSOCK_OP_SHUTDOWN,
+                                  VSOCK_SHUTDOWN_RCV | VSOCK_SHUTDOWN_SEND);
+            },
+            Ok(count) => {
+                {
+                    let conn = self.conns.get_mut(&key).unwrap();
+                    conn.tx_cnt = conn.tx_cnt.wrapping_add(count as u32);
+                }
+                self.send_packet(key, VSOCK_OP_RW, 0, buf[..count].to_vec());
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(_) => self.close(key, true),
+        }
+    }
+
+    fn send_packet(&mut self, key: ConnKey, op: u16, flags: u32, data: Vec<u8>) {
+        let fwd_cnt = match self.conns.get_mut(&key) {
+            Some(conn) => {
+                conn.last_fwd_cnt_sent = conn.fwd_cnt;
+                conn.fwd_cnt
+            },
+            None => 0,
+        };
+
+        let header = PacketHeader {
+            src_cid: HOST_CID,
+            dst_cid: self.guest_cid,
+            src_port: key.0,
+            dst_port: key.1,
+            len: data.len() as u32,
+            type_: VSOCK_TYPE_STREAM,
+            op: op,
+            flags: flags,
+            buf_alloc: CONN_BUF_SIZE,
+            fwd_cnt: fwd_cnt,
+        };
+        self.rx_pending.push_back(Packet {
+            header: header,
+            data: data,
+        });
+    }
+
+    fn send_control(&mut self, key: ConnKey, op: u16, flags: u32) {
+        if self.rx_pending.len() >= MAX_QUEUED_RX {
+            return;
+        }
+        self.send_packet(key, op, flags, Vec::new());
+    }
+
+    fn send_reset(&mut self, key: ConnKey) {
+        self.send_control(key, VSOCK_OP_RST, 0);
+    }
+
+    /// Drops a connection, resetting the guest side if needed.
+    fn close(&mut self, key: ConnKey, reset: bool) {
+        if reset {
+            self.send_reset(key);
+        }
+        self.conns.remove(&key);
+    }
+
+    /// Moves the pending packets to the receive queue, splitting data
+    /// that doesn't fit the guest buffers.
+    fn flush_rx(&mut self) {
+        let mut used = false;
+        while !self.rx_pending.is_empty() {
+            let chain = match self.rx_queue.pop(&self.mem) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let capacity = chain.writable().iter().map(|d| d.len as usize).sum::<usize>();
+            if capacity < HEADER_SIZE {
+                self.rx_queue.add_used(&self.mem, chain.head_index, 0);
+                used = true;
+                continue;
+            }
+
+            let mut packet = self.rx_pending.pop_front().unwrap();
+            let room = capacity - HEADER_SIZE;
+            if packet.data.len() > room {
+                let rest = packet.data.split_off(room);
+                let mut rest_header = packet.header;
+                rest_header.len = rest.len() as u32;
+                self.rx_pending.push_front(Packet {
+                    header: rest_header,
+                    data: rest,
+                });
+                packet.header.len = room as u32;
+            }
+
+            let mut buf = packet.header.to_bytes().to_vec();
+            buf.extend_from_slice(&packet.data);
+            let len = chain.write_all(&self.mem, &buf);
+            self.rx_queue.add_used(&self.mem, chain.head_index, len as u32);
+            used = true;
+        }
+        if used {
+            self.interrupt.signal_used_queue();
+        }
+    }
+}
+
+fn parse_connect(line: &[u8]) -> Option<u32> {
+    let line = ::std::str::from_utf8(line).ok()?;
+    let mut fields = line.trim().split_whitespace();
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some("CONNECT"), Some(port), None) => port.parse::<u32>().ok(),
+        _ => None,
+    }
+}
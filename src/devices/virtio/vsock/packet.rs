@@ -0,0 +1,76 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+// struct virtio_vsock_hdr
+pub const HEADER_SIZE: usize = 44;
+
+pub const VSOCK_TYPE_STREAM: u16 = 1;
+
+// Operations.
+pub const VSOCK_OP_REQUEST: u16 = 1;
+pub const VSOCK_OP_RESPONSE: u16 = 2;
+pub const VSOCK_OP_RST: u16 = 3;
+pub const VSOCK_OP_SHUTDOWN: u16 = 4;
+pub const VSOCK_OP_RW: u16 = 5;
+pub const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+pub const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+// Shutdown flags.
+pub const VSOCK_SHUTDOWN_RCV: u32 = 1;
+pub const VSOCK_SHUTDOWN_SEND: u32 = 2;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct PacketHeader {
+    pub src_cid: u64,
+    pub dst_cid: u64,
+    pub src_port: u32,
+    pub dst_port: u32,
+    pub len: u32,
+    pub type_: u16,
+    pub op: u16,
+    pub flags: u32,
+    pub buf_alloc: u32,
+    pub fwd_cnt: u32,
+}
+
+impl PacketHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+        Some(PacketHeader {
+            src_cid: LittleEndian::read_u64(&data[0..]),
+            dst_cid: LittleEndian::read_u64(&data[8..]),
+            src_port: LittleEndian::read_u32(&data[16..]),
+            dst_port: LittleEndian::read_u32(&data[20..]),
+            len: LittleEndian::read_u32(&data[24..]),
+            type_: LittleEndian::read_u16(&data[28..]),
+            op: LittleEndian::read_u16(&data[30..]),
+            flags: LittleEndian::read_u32(&data[32..]),
+            buf_alloc: LittleEndian::read_u32(&data[36..]),
+            fwd_cnt: LittleEndian::read_u32(&data[40..]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut data = [0u8; HEADER_SIZE];
+        LittleEndian::write_u64(&mut data[0..], self.src_cid);
+        LittleEndian::write_u64(&mut data[8..], self.dst_cid);
+        LittleEndian::write_u32(&mut data[16..], self.src_port);
+        LittleEndian::write_u32(&mut data[20..], self.dst_port);
+        LittleEndian::write_u32(&mut data[24..], self.len);
+        LittleEndian::write_u16(&mut data[28..], self.type_);
+        LittleEndian::write_u16(&mut data[30..], self.op);
+        LittleEndian::write_u32(&mut data[32..], self.flags);
+        LittleEndian::write_u32(&mut data[36..], self.buf_alloc);
+        LittleEndian::write_u32(&mut data[40..], self.fwd_cnt);
+        data
+    }
+}
+
+/// A packet waiting for a receive buffer.
+pub struct Packet {
+    pub header: PacketHeader,
+    pub data: Vec<u8>,
+}
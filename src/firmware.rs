@@ -25,7 +25,7 @@ impl Firmware {
     }
 
     fn open(&self) -> File {
-        let f = File::open(&self.path).expect(&format!(
+        let f = File::open(&self.path).unwrap_or_else(|_| panic!(
             "Cannot find firmware image \"{}\".",
             self.path
         ));
@@ -0,0 +1,198 @@
+//! Guest physical memory layout: the RAM slots below and above the 32-bit
+//! PCI hole, the firmware ROM and the ranges reserved for the chipset.
+
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use libkvm::mem::MemorySlot;
+
+use ::accel::Accelerator;
+use ::devices::fw_cfg::FWCfgDev;
+use ::devices::fw_cfg::e820::{self, E820Table};
+use ::memory::{GuestMemory, MmapMemorySlot, RamBacking, RamConfig};
+
+const FOUR_GB: u64 = 1 << 32;
+
+// The low memory used by the EBDA, the VGA framebuffer and the BIOS.
+const LEGACY_AREA_START: u64 = 0x9fc00;
+const LEGACY_AREA_END: u64 = 0x100000;
+
+/// The real mode identity map page, followed by the three TSS pages
+/// required by KVM on Intel hosts.
+pub const KVM_IDENTITY_MAP_ADDR: u64 = 0xfeffc000;
+const KVM_IDENTITY_MAP_SIZE: u64 = 0x4000;
+
+const KVM_MEM_READONLY: u32 = 1 << 1;
+
+const E820_FILE: &str = "etc/e820";
+// Tells the firmware where to start placing the 64-bit PCI BARs.
+const RESERVED_MEMORY_END_FILE: &str = "etc/reserved-memory-end";
+const RESERVED_MEMORY_END_ALIGNMENT: u64 = 1 << 30;
+
+pub struct MemoryLayout {
+    ram_backing: RamBacking,
+    ram_slots: Vec<MmapMemorySlot>,
+    rom_slot: Option<MmapMemorySlot>,
+    /// (start, size) ranges which are not RAM but must not be used by
+    /// the guest either.
+    reserved: Vec<(u64, u64)>,
+    next_slot_id: u32,
+}
+
+fn slot_end(slot: &MmapMemorySlot) -> u64 {
+    slot.guest_address() + slot.memory_size() as u64
+}
+
+fn overlaps(start: u64, size: u64, other_start: u64, other_size: u64) -> bool {
+    start < other_start + other_size && other_start < start + size
+}
+
+impl MemoryLayout {
+    /// Places as much RAM as possible below `low_ram_max`, the rest being
+    /// mapped above 4GB.
+    pub fn new(ram_size: u64, low_ram_max: u64, ram_config: RamConfig)
+            -> Self {
+        let mut layout = MemoryLayout {
+            ram_backing: RamBacking::new(ram_config, ram_size),
+            ram_slots: Vec::new(),
+            rom_slot: None,
+            reserved: Vec::new(),
+            next_slot_id: 0,
+        };
+
+        let low_ram_size = ram_size.min(low_ram_max);
+        layout.add_ram(0, 0, low_ram_size);
+        if ram_size > low_ram_size {
+            layout.add_ram(low_ram_size, FOUR_GB, ram_size - low_ram_size);
+        }
+        layout.reserve(KVM_IDENTITY_MAP_ADDR, KVM_IDENTITY_MAP_SIZE);
+
+        layout
+    }
+
+    fn allocate_slot_id(&mut self) -> u32 {
+        let slot_id = self.next_slot_id;
+        self.next_slot_id += 1;
+        slot_id
+    }
+
+    /// Maps the part of the RAM backing found at `offset`.
+    fn add_ram(&mut self, offset: u64, start: u64, size: u64) {
+        let slot_id = self.allocate_slot_id();
+        self.ram_slots.push(MmapMemorySlot::new_ram(
+            &self.ram_backing, offset, size as usize, start, slot_id));
+    }
+
+    /// Maps a read-only ROM ending at 4GB, such as the firmware image.
+    pub fn add_rom(&mut self, size: usize) -> &mut MmapMemorySlot {
+        if self.rom_slot.is_some() {
+            panic!("A ROM is already mapped below 4GB.");
+        }
+
+        let start = FOUR_GB - size as u64;
+        if start < self.low_ram_end() ||
+                self.reserved.iter().any(|&(reserved_start, reserved_size)|
+                    overlaps(start, size as u64, reserved_start, reserved_size)) {
+            panic!("The ROM overlaps other guest memory. Size: {}B.", size);
+        }
+
+        let slot_id = self.allocate_slot_id();
+        self.rom_slot = Some(MmapMemorySlot::new(size, start, slot_id,
+                                                 KVM_MEM_READONLY));
+        self.rom_slot.as_mut().unwrap()
+    }
+
+    /// Marks a range used by the chipset as reserved in the e820 table.
+    pub fn reserve(&mut self, start: u64, size: u64) {
+        if self.ram_slots.iter().any(|slot| overlaps(
+                start, size, slot.guest_address(), slot.memory_size() as u64)) {
+            panic!("The reserved range {:x}-{:x} overlaps the guest RAM.",
+                   start, start + size);
+        }
+        self.reserved.push((start, size));
+    }
+
+    pub fn ram_backing(&self) -> &RamBacking {
+        &self.ram_backing
+    }
+
+    /// The slot holding the RAM below 1MB, used to load the kernel.
+    pub fn low_ram(&mut self) -> &mut MmapMemorySlot {
+        &mut self.ram_slots[0]
+    }
+
+    /// End of the RAM below 4GB.
+    pub fn low_ram_end(&self) -> u64 {
+        slot_end(&self.ram_slots[0])
+    }
+
+    /// Returns the (start, size) guest RAM ranges.
+    pub fn ram_ranges(&self) -> Vec<(u64, u64)> {
+        self.ram_slots.iter()
+            .map(|slot| (slot.guest_address(), slot.memory_size() as u64))
+            .collect()
+    }
+
+    /// The address above which the firmware may map the 64-bit PCI BARs.
+    pub fn reserved_memory_end(&self) -> u64 {
+        let end = self.ram_slots.iter().map(slot_end).max().unwrap()
+                      .max(FOUR_GB);
+        (end + RESERVED_MEMORY_END_ALIGNMENT - 1) &
+            !(RESERVED_MEMORY_END_ALIGNMENT - 1)
+    }
+
+    /// Registers the memory slots with the accelerator.
+    pub fn register(&self, accelerator: &Accelerator) {
+        for slot in self.ram_slots.iter().chain(self.rom_slot.iter()) {
+            accelerator.memory_region_add(slot);
+        }
+    }
+
+    /// Returns a view of the guest RAM. The layout must outlive it.
+    pub fn guest_memory(&self) -> GuestMemory {
+        GuestMemory::new(&self.ram_slots.iter().collect::<Vec<_>>())
+    }
+
+    pub fn e820_table(&self) -> E820Table {
+        let mut entries = self.reserved.iter()
+            .map(|&(start, size)| (start, size, e820::E820_RESERVED))
+            .collect::<Vec<_>>();
+
+        for (i, slot) in self.ram_slots.iter().enumerate() {
+            let (start, end) = (slot.guest_address(), slot_end(slot));
+            // The firmware reserves the legacy areas on its own, otherwise
+            // they are left out of the RAM.
+            if i == 0 && self.rom_slot.is_none() && end > LEGACY_AREA_END {
+                entries.push((start, LEGACY_AREA_START - start,
+                              e820::E820_RAM));
+                entries.push((LEGACY_AREA_START,
+                              LEGACY_AREA_END - LEGACY_AREA_START,
+                              e820::E820_RESERVED));
+                entries.push((LEGACY_AREA_END, end - LEGACY_AREA_END,
+                              e820::E820_RAM));
+            } else {
+                entries.push((start, end - start, e820::E820_RAM));
+            }
+        }
+        entries.sort_by_key(|&(start, _, _)| start);
+
+        let mut table = E820Table::new();
+        for (start, size, type_) in entries {
+            table.add_entry(start, size, type_);
+        }
+        table
+    }
+
+    pub fn add_to_fw_cfg(&self, fw_cfg: &mut FWCfgDev) {
+        let e820_table = self.e820_table();
+        let e820_buf = e820_table.to_slice();
+        fw_cfg.add_file(E820_FILE, e820_buf, e820_buf.len() as u32);
+
+        let mut reserved_memory_end = [0u8; 8];
+        LittleEndian::write_u64(&mut reserved_memory_end,
+                                self.reserved_memory_end());
+        fw_cfg.add_file(RESERVED_MEMORY_END_FILE, &reserved_memory_end,
+                        reserved_memory_end.len() as u32);
+    }
+}
@@ -0,0 +1,51 @@
+#![allow(unused)]
+
+// Offsets within the "zero page" (struct boot_params), as described
+// by Documentation/x86/boot.txt and Documentation/x86/zero-page.txt.
+
+pub const BOOT_PARAMS_SIZE: usize = 0x1000;
+
+pub const E820_ENTRIES_OFFSET: usize = 0x1e8;
+pub const SETUP_HEADER_OFFSET: usize = 0x1f1;
+pub const E820_TABLE_OFFSET: usize = 0x2d0;
+pub const E820_TABLE_MAX: usize = 128;
+
+// Setup header fields, relative to the beginning of the kernel image
+// (or of the zero page, since the header is copied at the same offset).
+pub const HDR_SETUP_SECTS: usize = 0x1f1;
+pub const HDR_SYSSIZE: usize = 0x1f4;
+pub const HDR_BOOT_FLAG: usize = 0x1fe;
+pub const HDR_JUMP: usize = 0x200;
+pub const HDR_HEADER: usize = 0x202;
+pub const HDR_VERSION: usize = 0x206;
+pub const HDR_TYPE_OF_LOADER: usize = 0x210;
+pub const HDR_LOADFLAGS: usize = 0x211;
+pub const HDR_CODE32_START: usize = 0x214;
+pub const HDR_RAMDISK_IMAGE: usize = 0x218;
+pub const HDR_RAMDISK_SIZE: usize = 0x21c;
+pub const HDR_HEAP_END_PTR: usize = 0x224;
+pub const HDR_CMD_LINE_PTR: usize = 0x228;
+pub const HDR_INITRD_ADDR_MAX: usize = 0x22c;
+pub const HDR_KERNEL_ALIGNMENT: usize = 0x230;
+pub const HDR_RELOCATABLE_KERNEL: usize = 0x234;
+pub const HDR_XLOADFLAGS: usize = 0x236;
+pub const HDR_CMDLINE_SIZE: usize = 0x238;
+pub const HDR_PREF_ADDRESS: usize = 0x258;
+pub const HDR_INIT_SIZE: usize = 0x260;
+
+pub const BOOT_FLAG_MAGIC: u16 = 0xaa55;
+pub const HEADER_MAGIC: &[u8] = b"HdrS";
+
+pub const LOADED_HIGH: u8 = 1 << 0;
+pub const KEEP_SEGMENTS: u8 = 1 << 6;
+pub const CAN_USE_HEAP: u8 = 1 << 7;
+
+pub const XLF_KERNEL_64: u16 = 1 << 0;
+pub const XLF_CAN_BE_LOADED_ABOVE_4G: u16 = 1 << 1;
+
+// "Undefined" boot loader ID.
+pub const LOADER_TYPE_UNDEFINED: u8 = 0xff;
+
+pub const SECTOR_SIZE: usize = 512;
+// Used when the setup_sects field is 0.
+pub const DEFAULT_SETUP_SECTS: usize = 4;
@@ -0,0 +1,331 @@
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::cmp::min;
+
+use libkvm::mem::MemorySlot;
+
+use ::cpu::boot::LongModeBootState;
+use ::devices::fw_cfg::e820::E820Table;
+use ::devices::fw_cfg::{self, FWCfgDev};
+use ::memory::MmapMemorySlot;
+use super::bootparam::*;
+use super::read_file;
+
+// Guest physical layout used when booting the kernel directly.
+const GDT_ADDR: u64 = 0x500;
+const BOOT_PARAMS_ADDR: u64 = 0x7000;
+const PML4_ADDR: u64 = 0x9000;
+const PDPT_ADDR: u64 = 0xa000;
+const PD_ADDR: u64 = 0xb000;
+const CMDLINE_ADDR: u64 = 0x20000;
+const CMDLINE_MAX_SIZE: usize = 0x10000;
+const KERNEL_ADDR: u64 = 0x100000;
+
+// The 64-bit entry point is located 0x200 bytes after the start of
+// the protected mode kernel.
+const KERNEL_64BIT_ENTRY_OFFSET: u64 = 0x200;
+
+// We identity map the first 4GB using 2MB pages.
+const IDENTITY_MAP_GB: u64 = 4;
+
+const BOOT_CS: u16 = 0x10;
+const BOOT_DS: u16 = 0x18;
+
+// The boot protocol version which introduced the 64-bit entry point.
+const MIN_BOOT_PROTOCOL: u16 = 0x20c;
+
+// Addresses used by the firmware when loading the kernel through fw_cfg.
+const FW_CFG_REAL_MODE_ADDR: u64 = 0x10000;
+const FW_CFG_MIN_BOOT_PROTOCOL: u16 = 0x202;
+
+// initrd_addr_max was added by the 2.03 boot protocol, older kernels
+// use this limit.
+const INITRD_ADDR_MAX_BOOT_PROTOCOL: u16 = 0x203;
+const DEFAULT_INITRD_ADDR_MAX: u64 = 0x37ff_ffff;
+
+pub struct LinuxKernel {
+    path: String,
+    image: Vec<u8>,
+    setup_size: usize,
+}
+
+impl LinuxKernel {
+    pub fn new(path: &str) -> Self {
+        let image = read_file(path, "kernel");
+
+        if image.len() < HDR_HEADER + HEADER_MAGIC.len() ||
+                LittleEndian::read_u16(&image[HDR_BOOT_FLAG..]) !=
+                    BOOT_FLAG_MAGIC ||
+                &image[HDR_HEADER..HDR_HEADER + 4] != HEADER_MAGIC {
+            panic!("\"{}\" is not a bzImage.", path);
+        }
+
+        let setup_sects = match image[HDR_SETUP_SECTS] as usize {
+            0 => DEFAULT_SETUP_SECTS,
+            sects => sects,
+        };
+        let setup_size = (setup_sects + 1) * SECTOR_SIZE;
+        if setup_size >= image.len() {
+            panic!("Invalid kernel image \"{}\", setup size: {}B.",
+                   path, setup_size);
+        }
+
+        LinuxKernel {
+            path: path.to_string(),
+            image: image,
+            setup_size: setup_size,
+        }
+    }
+
+    pub fn version(&self) -> u16 {
+        LittleEndian::read_u16(&self.image[HDR_VERSION..])
+    }
+
+    /// The real mode setup code, including the setup header.
+    pub fn setup_data(&self) -> &[u8] {
+        &self.image[..self.setup_size]
+    }
+
+    /// The protected mode kernel.
+    pub fn kernel_data(&self) -> &[u8] {
+        &self.image[self.setup_size..]
+    }
+
+    /// The setup header, as it should be copied in the zero page.
+    pub fn setup_header(&self) -> &[u8] {
+        // The byte following the jump instruction holds the header length.
+        let header_end = HDR_JUMP + 2 + self.image[HDR_JUMP + 1] as usize;
+        &self.image[HDR_SETUP_SECTS..header_end]
+    }
+
+    fn header_u32(&self, offset: usize) -> u32 {
+        LittleEndian::read_u32(&self.image[offset..])
+    }
+
+    /// The highest address the initrd may use.
+    fn initrd_addr_max(&self) -> u64 {
+        if self.version() < INITRD_ADDR_MAX_BOOT_PROTOCOL {
+            return DEFAULT_INITRD_ADDR_MAX;
+        }
+        self.header_u32(HDR_INITRD_ADDR_MAX) as u64
+    }
+
+    /// The end of the memory used by the kernel once decompressed.
+    fn kernel_end(&self) -> u64 {
+        let init_size = self.header_u32(HDR_INIT_SIZE) as u64;
+        KERNEL_ADDR + init_size.max(self.kernel_data().len() as u64)
+    }
+
+    /// Loads the kernel, the initrd and the command line in guest memory
+    /// and returns the vCPU state needed to jump to the 64-bit entry point.
+    pub fn load(&self, mem: &mut MmapMemorySlot, initrd_path: Option<&str>,
+                cmdline: &str, e820_table: &E820Table)
+        -> LongModeBootState
+    {
+        let version = self.version();
+        let xloadflags = LittleEndian::read_u16(
+            &self.image[HDR_XLOADFLAGS..]);
+        if version < MIN_BOOT_PROTOCOL || xloadflags & XLF_KERNEL_64 == 0 {
+            panic!("The kernel \"{}\" does not support the 64-bit boot \
+                    protocol. Boot protocol version: {:x}.",
+                   self.path, version);
+        }
+
+        let mem_end = mem.guest_address() + mem.memory_size() as u64;
+        let kernel_end = self.kernel_end();
+        if kernel_end > mem_end {
+            panic!("Not enough memory to load the kernel. Required: {}B.",
+                   kernel_end);
+        }
+
+        let mut boot_params = vec![0u8; BOOT_PARAMS_SIZE];
+        let header = self.setup_header();
+        boot_params[SETUP_HEADER_OFFSET..SETUP_HEADER_OFFSET + header.len()]
+            .copy_from_slice(header);
+
+        boot_params[HDR_TYPE_OF_LOADER] = LOADER_TYPE_UNDEFINED;
+        boot_params[HDR_LOADFLAGS] |= CAN_USE_HEAP;
+        LittleEndian::write_u16(&mut boot_params[HDR_HEAP_END_PTR..],
+                                0xfe00 - 0x200);
+
+        self.load_cmdline(mem, &mut boot_params, cmdline);
+        if let Some(path) = initrd_path {
+            self.load_initrd(mem, &mut boot_params, path,
+                             kernel_end, mem_end);
+        }
+
+        let e820_count = min(e820_table.count, E820_TABLE_MAX);
+        let e820_buf = e820_table.to_slice();
+        boot_params[E820_ENTRIES_OFFSET] = e820_count as u8;
+        boot_params[E820_TABLE_OFFSET..E820_TABLE_OFFSET + e820_buf.len()]
+            .copy_from_slice(e820_buf);
+
+        mem.write_at(KERNEL_ADDR, self.kernel_data());
+        mem.write_at(BOOT_PARAMS_ADDR, &boot_params);
+
+        setup_gdt(mem);
+        setup_page_tables(mem);
+
+        LongModeBootState {
+            entry_point: KERNEL_ADDR + KERNEL_64BIT_ENTRY_OFFSET,
+            boot_params_addr: BOOT_PARAMS_ADDR,
+            page_table_addr: PML4_ADDR,
+            gdt_addr: GDT_ADDR,
+            gdt_limit: (GDT_ENTRIES.len() * 8 - 1) as u16,
+            code_selector: BOOT_CS,
+            data_selector: BOOT_DS,
+        }
+    }
+
+    /// Exposes the kernel, the initrd and the command line through fw_cfg,
+    /// letting the firmware load them. The setup header is patched the
+    /// same way a boot loader would, using the addresses advertised to
+    /// the firmware.
+    ///
+    /// OVMF loads the kernel by itself. SeaBIOS relies on the linuxboot
+    /// option ROM provided by QEMU through "genroms/linuxboot_dma.bin",
+    /// which isn't provided here, so it can't boot these kernels.
+    pub fn add_to_fw_cfg(&self, fw_cfg_dev: &mut FWCfgDev,
+                         initrd_path: Option<&str>, cmdline: &str,
+                         ram_end: u64) {
+        let version = self.version();
+        if version < FW_CFG_MIN_BOOT_PROTOCOL {
+            panic!("The kernel \"{}\" uses an unsupported boot protocol \
+                    version: {:x}.", self.path, version);
+        }
+
+        let mut setup = self.setup_data().to_vec();
+        setup[HDR_TYPE_OF_LOADER] = LOADER_TYPE_UNDEFINED;
+        setup[HDR_LOADFLAGS] |= CAN_USE_HEAP;
+        LittleEndian::write_u16(
+            &mut setup[HDR_HEAP_END_PTR..],
+            (CMDLINE_ADDR - FW_CFG_REAL_MODE_ADDR - 0x200) as u16);
+        LittleEndian::write_u32(&mut setup[HDR_CMD_LINE_PTR..],
+                                CMDLINE_ADDR as u32);
+
+        let mut cmdline_buf = cmdline.as_bytes().to_vec();
+        cmdline_buf.push(0);
+
+        fw_cfg_dev.add_i32(fw_cfg::FW_CFG_CMDLINE_ADDR, CMDLINE_ADDR as i32);
+        fw_cfg_dev.add_i32(fw_cfg::FW_CFG_CMDLINE_SIZE,
+                           cmdline_buf.len() as i32);
+        fw_cfg_dev.add_bytes(fw_cfg::FW_CFG_CMDLINE_DATA, &cmdline_buf,
+                             cmdline_buf.len() as u32);
+
+        if let Some(path) = initrd_path {
+            let initrd = read_file(path, "initrd");
+            let initrd_size = initrd.len() as u64;
+
+            let addr_max = min(self.initrd_addr_max(), ram_end - 1);
+            let initrd_addr = match (addr_max + 1).checked_sub(initrd_size) {
+                Some(addr) if addr & !0xfff >= self.kernel_end() =>
+                    addr & !0xfff,
+                _ => panic!("Not enough memory to load the initrd. \
+                             Size: {}B.", initrd_size),
+            };
+
+            LittleEndian::write_u32(&mut setup[HDR_RAMDISK_IMAGE..],
+                                    initrd_addr as u32);
+            LittleEndian::write_u32(&mut setup[HDR_RAMDISK_SIZE..],
+                                    initrd_size as u32);
+
+            fw_cfg_dev.add_i32(fw_cfg::FW_CFG_INITRD_ADDR, initrd_addr as i32);
+            fw_cfg_dev.add_i32(fw_cfg::FW_CFG_INITRD_SIZE, initrd_size as i32);
+            fw_cfg_dev.add_bytes(fw_cfg::FW_CFG_INITRD_DATA, &initrd,
+                                 initrd_size as u32);
+        }
+
+        let kernel = self.kernel_data();
+        fw_cfg_dev.add_i32(fw_cfg::FW_CFG_KERNEL_ADDR, KERNEL_ADDR as i32);
+        fw_cfg_dev.add_i32(fw_cfg::FW_CFG_KERNEL_SIZE, kernel.len() as i32);
+        fw_cfg_dev.add_bytes(fw_cfg::FW_CFG_KERNEL_DATA, kernel,
+                             kernel.len() as u32);
+
+        fw_cfg_dev.add_i32(fw_cfg::FW_CFG_SETUP_ADDR,
+                           FW_CFG_REAL_MODE_ADDR as i32);
+        fw_cfg_dev.add_i32(fw_cfg::FW_CFG_SETUP_SIZE, setup.len() as i32);
+        fw_cfg_dev.add_bytes(fw_cfg::FW_CFG_SETUP_DATA, &setup,
+                             setup.len() as u32);
+    }
+
+    fn load_cmdline(&self, mem: &mut MmapMemorySlot,
+                    boot_params: &mut [u8], cmdline: &str) {
+        let max_size = min(self.header_u32(HDR_CMDLINE_SIZE) as usize,
+                           CMDLINE_MAX_SIZE - 1);
+        if cmdline.len() > max_size {
+            panic!("The kernel command line is too long. \
+                    Maximum size: {}B.", max_size);
+        }
+
+        let mut cmdline_buf = cmdline.as_bytes().to_vec();
+        cmdline_buf.push(0);
+
+        mem.write_at(CMDLINE_ADDR, &cmdline_buf);
+        LittleEndian::write_u32(&mut boot_params[HDR_CMD_LINE_PTR..],
+                                CMDLINE_ADDR as u32);
+    }
+
+    fn load_initrd(&self, mem: &mut MmapMemorySlot, boot_params: &mut [u8],
+                   path: &str, kernel_end: u64, mem_end: u64) {
+        let initrd = read_file(path, "initrd");
+
+        // The initrd is placed as high as possible, page aligned.
+        let addr_max = min(self.initrd_addr_max(), mem_end - 1);
+        let initrd_size = initrd.len() as u64;
+        let initrd_addr = match (addr_max + 1).checked_sub(initrd_size) {
+            Some(addr) if addr & !0xfff >= kernel_end => addr & !0xfff,
+            _ => panic!("Not enough memory to load the initrd. Size: {}B.",
+                        initrd_size),
+        };
+
+        mem.write_at(initrd_addr, &initrd);
+        LittleEndian::write_u32(&mut boot_params[HDR_RAMDISK_IMAGE..],
+                                initrd_addr as u32);
+        LittleEndian::write_u32(&mut boot_params[HDR_RAMDISK_SIZE..],
+                                initrd_size as u32);
+    }
+}
+
+const GDT_ENTRIES: [u64; 4] = [
+    0,                      // null
+    0,                      // unused
+    0x00af9a000000ffff,     // __BOOT_CS, 64-bit code
+    0x00cf92000000ffff,     // __BOOT_DS, data
+];
+
+fn setup_gdt(mem: &mut MmapMemorySlot) {
+    let mut gdt = [0u8; GDT_ENTRIES.len() * 8];
+    for (i, entry) in GDT_ENTRIES.iter().enumerate() {
+        LittleEndian::write_u64(&mut gdt[i * 8..], *entry);
+    }
+    mem.write_at(GDT_ADDR, &gdt);
+}
+
+fn setup_page_tables(mem: &mut MmapMemorySlot) {
+    const PAGE_PRESENT: u64 = 1 << 0;
+    const PAGE_RW: u64 = 1 << 1;
+    const PAGE_PS: u64 = 1 << 7;
+
+    let mut pml4 = [0u8; 0x1000];
+    LittleEndian::write_u64(&mut pml4, PDPT_ADDR | PAGE_PRESENT | PAGE_RW);
+    mem.write_at(PML4_ADDR, &pml4);
+
+    let mut pdpt = [0u8; 0x1000];
+    for i in 0..IDENTITY_MAP_GB {
+        let pd_addr = PD_ADDR + i * 0x1000;
+        LittleEndian::write_u64(&mut pdpt[i as usize * 8..],
+                                pd_addr | PAGE_PRESENT | PAGE_RW);
+
+        let mut pd = [0u8; 0x1000];
+        for j in 0..512u64 {
+            let page_addr = ((i << 9) + j) << 21;
+            LittleEndian::write_u64(
+                &mut pd[j as usize * 8..],
+                page_addr | PAGE_PRESENT | PAGE_RW | PAGE_PS);
+        }
+        mem.write_at(pd_addr, &pd);
+    }
+    mem.write_at(PDPT_ADDR, &pdpt);
+}
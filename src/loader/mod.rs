@@ -0,0 +1,15 @@
+pub mod bootparam;
+pub mod linux;
+
+use std::fs::File;
+use std::io::prelude::*;
+
+pub fn read_file(path: &str, description: &str) -> Vec<u8> {
+    let mut f = File::open(path).unwrap_or_else(|_| panic!(
+        "Cannot find {} image \"{}\".", description, path));
+
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf).unwrap_or_else(|_| panic!(
+        "Cannot read {} image \"{}\".", description, path));
+    buf
+}
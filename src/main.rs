@@ -7,95 +7,445 @@ extern crate libc;
 extern crate libkvm;
 
 mod accel;
+mod acpi;
 mod args;
+mod block;
+mod chardev;
+mod control;
 mod cpu;
 mod devices;
 mod ffi;
 mod firmware;
+mod layout;
+mod loader;
 mod memory;
+mod net;
+mod smbios;
+mod snapshot;
 mod utils;
 
+use std::collections::BTreeMap;
+use std::io::{self, Write};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
 
+use accel::{IrqChip, Vcpu};
 use args::parse_args;
 use cpu::exits::VcpuExit;
 use devices::bus::Bus;
-use devices::{qdbg, fw_cfg, post_code};
+use devices::{acpi_pm, qdbg, fw_cfg, pci, post_code, serial, virtio};
+use devices::irq::IrqPin;
 use devices::fw_cfg::defs::*;
-use devices::fw_cfg::e820;
-use memory::MmapMemorySlot;
+use loader::linux::LinuxKernel;
+use layout::MemoryLayout;
+use memory::{RamBackend, RamConfig};
+use snapshot::{Snapshotter, VcpuPauser};
+use utils::options::parse_u64;
 
+// Limited by the 8-bit xAPIC IDs.
+const MAX_VCPUS: usize = 255;
+
+// The RAM above these addresses is moved past 4GB, leaving room for the
+// 32-bit PCI BARs.
+const PC_LOW_RAM_MAX: u64 = 0xc000_0000;
+const Q35_LOW_RAM_MAX: u64 = 0x8000_0000;
 
 fn main() {
     let args = parse_args();
     check_architecture();
 
-    let fw_path = args.value_of("firmware").unwrap();
-    let mut fw = firmware::Firmware::new(fw_path);
-
     let mut accelerator = accel::new();
 
     let mem_size = args.value_of("memory_mb")
                        .unwrap().parse::<usize>().unwrap() << 20;
 
-    let fw_size = fw.get_size() as usize;
+    let vcpu_count = args.value_of("smp")
+                         .unwrap().parse::<usize>().unwrap();
+    if vcpu_count < 1 || vcpu_count > MAX_VCPUS {
+        panic!("The number of vCPUs must be between 1 and {}.", MAX_VCPUS);
+    }
+
+    let machine = args.value_of("machine").unwrap();
+    let pcie_root_ports = args.value_of("pcie_root_ports")
+                              .unwrap().parse::<usize>().unwrap();
+    if pcie_root_ports > 0 && machine != "q35" {
+        panic!("PCIe root ports require the q35 machine type.");
+    }
+
+    let mut ram_config = match args.value_of("memory_backend") {
+        Some(spec) => RamConfig::parse(spec),
+        None => RamConfig::new(),
+    };
+    let restore_ram = args.value_of("restore_ram").unwrap();
+    if let Some(snapshot_path) = args.value_of("restore") {
+        let ram_path = snapshot::ram_path(snapshot_path);
+        let backend = match restore_ram {
+            "copy" => None,
+            "map" => Some(RamBackend::Snapshot(ram_path)),
+            "uffd" => Some(RamBackend::SnapshotUffd(ram_path)),
+            _ => panic!("Invalid RAM restore mode: {}", restore_ram),
+        };
+        if let Some(backend) = backend {
+            match ram_config.backend {
+                RamBackend::Anonymous => (),
+                _ => panic!("Restoring the RAM lazily requires the anonymous \
+                             memory backend."),
+            }
+            // Touching or locking the pages would copy all of them.
+            if restore_ram == "map" &&
+               (ram_config.prealloc_threads > 0 || ram_config.mlock) {
+                panic!("Mapping the snapshot RAM can't be combined with \
+                        prealloc-threads or mlock.");
+            }
+            ram_config.backend = backend;
+        }
+    }
+    // The memory slots have to outlive the vCPUs.
+    let mut layout = match machine {
+        "q35" => {
+            let mut layout = MemoryLayout::new(mem_size as u64,
+                                               Q35_LOW_RAM_MAX, ram_config);
+            layout.reserve(pci::q35::PCIEXBAR_DEFAULT_BASE,
+                           pci::q35::PCIEXBAR_DEFAULT_SIZE);
+            layout
+        },
+        _ => MemoryLayout::new(mem_size as u64, PC_LOW_RAM_MAX, ram_config),
+    };
+
+    let has_firmware = match args.value_of("firmware") {
+        Some(fw_path) => {
+            let mut fw = firmware::Firmware::new(fw_path);
+            let fw_size = fw.get_size() as usize;
+            fw.load(layout.add_rom(fw_size));
+            true
+        },
+        None => false,
+    };
+    layout.register(&*accelerator);
 
-    let mem = MmapMemorySlot::new(mem_size, 0, 0, 0);
-    let mut bios_mem = MmapMemorySlot::new(fw_size, 0xffe00000, 1, 2);
+    let mut vcpus = (0..vcpu_count)
+        .map(|i| accelerator.create_vcpu(i))
+        .collect::<Vec<_>>();
 
-    accelerator.memory_region_add(&mem);
-    accelerator.memory_region_add(&bios_mem);
+    let kernel = args.value_of("kernel").map(LinuxKernel::new);
+    let initrd_path = args.value_of("initrd");
+    let cmdline = args.value_of("cmdline").unwrap_or("");
 
-    accelerator.init_vcpu();
+    // Restored VMs find the kernel and the tables in the snapshot RAM,
+    // they must not be loaded again.
+    let direct_boot = !has_firmware && !args.is_present("restore");
+    if direct_boot {
+        let e820_table = layout.e820_table();
+        let boot_state = kernel.as_ref().unwrap().load(
+            layout.low_ram(), initrd_path, cmdline, &e820_table);
+        vcpus[0].init_long_mode(&boot_state);
+    }
 
-    fw.load(&mut bios_mem);
+    let guest_mem = layout.guest_memory();
+    let mut snapshotter = Snapshotter::new(
+        Arc::new(VcpuPauser::new(vcpu_count)), accelerator.vm_state(),
+        vcpu_count, guest_mem.clone(), layout.ram_ranges());
 
-    let mut io_bus = Bus::new();
-    let mut mmio_bus = Bus::new();
+    let io_bus = Arc::new(Bus::new());
+    let mmio_bus = Arc::new(Bus::new());
 
-    let qdbg_dev = qdbg::QemuDebugConsole::new();
+    let qdbg_dev = qdbg::QemuDebugConsole::new(
+        chardev::new(args.value_of("debugcon").unwrap(), "debugcon"));
     io_bus.insert(
         Arc::new(Mutex::new(qdbg_dev)),
         0x402,
         1,
         false).unwrap();
 
-    let post_handler = post_code::PostCodeHandler::new();
+    let post_handler = post_code::PostCodeHandler::new(
+        chardev::new(args.value_of("postcode").unwrap(), "postcode"));
     io_bus.insert(
         Arc::new(Mutex::new(post_handler)),
         0x80,
         1,
         false).unwrap();
 
-    let mut e820_table = e820::E820Table::new();
-    // TODO: take into account reserved regions, if any.
-    e820_table.add_entry(0, mem_size as u64, e820::E820_RAM);
-    let e820_table_buf = e820_table.to_slice();
+    let irq_chip = accelerator.irq_chip();
+    let mut serial_count = 0;
+    if let Some(serial_args) = args.values_of("serial") {
+        // COM1/COM3 and COM2/COM4 share their interrupts.
+        let mut irq_pins = BTreeMap::new();
+        for (i, serial_arg) in serial_args.enumerate() {
+            let serial_dev = add_serial_port(&io_bus, &irq_chip, &mut irq_pins,
+                                             i, serial_arg);
+            snapshotter.add_device(&format!("serial{}", i), serial_dev);
+            serial_count += 1;
+        }
+    }
+
+    let pci_root = Arc::new(Mutex::new(
+        pci::PciRoot::new(io_bus.clone(), mmio_bus.clone(), irq_chip.clone())));
+    let pci_config_io = Arc::new(Mutex::new(
+        pci::PciConfigIo::new(pci_root.clone())));
+    io_bus.insert(
+        pci_config_io.clone(),
+        pci::root::PCI_CONFIG_IO_BASE,
+        pci::root::PCI_CONFIG_IO_SIZE,
+        false).unwrap();
+    snapshotter.add_device("pci-config-io", pci_config_io);
+
+    match machine {
+        "q35" => {
+            pci::q35::init(&pci_root, &io_bus, &mmio_bus);
+            let mut root = pci_root.lock().unwrap();
+            for _ in 0..pcie_root_ports {
+                root.add_root_port();
+            }
+        },
+        _ => {
+            pci_root.lock().unwrap().add_device_at_slot(
+                Arc::new(Mutex::new(pci::host_bridge::I440fxHostBridge::new())),
+                0);
+            // There is no PIIX4 device to program, the PM registers are
+            // always mapped.
+            let pm = Arc::new(Mutex::new(acpi_pm::AcpiPmDevice::new()));
+            io_bus.insert(
+                pm.clone(),
+                acpi_pm::PC_PM_BASE,
+                acpi_pm::PM_IO_SIZE,
+                false).unwrap();
+            snapshotter.add_device("acpi-pm", pm);
+        },
+    }
+
+    if let Some(drive_args) = args.values_of("drive") {
+        for drive_arg in drive_args {
+            let drive = block::DriveConfig::parse(drive_arg);
+            let disk = virtio::block::Block::new(
+                block::open_drive(&drive), drive.read_only, &drive.serial);
+            let device = virtio::VirtioPciDevice::new(
+                Box::new(disk), guest_mem.clone());
+            add_pci_device(&pci_root, device);
+        }
+    }
+    if let Some(netdev_args) = args.values_of("netdev") {
+        for (i, netdev_arg) in netdev_args.enumerate() {
+            let nic = virtio::net::Net::new(net::new(netdev_arg, i));
+            let device = virtio::VirtioPciDevice::new(
+                Box::new(nic), guest_mem.clone());
+            add_pci_device(&pci_root, device);
+        }
+    }
+
+    let mut control = control::ControlServer::new();
+    chardev::add_control_commands(&mut control);
+    if args.is_present("balloon") {
+        if !layout.ram_backing().is_anonymous() {
+            panic!("The balloon requires anonymous guest RAM.");
+        }
+        let balloon = virtio::balloon::Balloon::new(mem_size as u64);
+        balloon.add_control_commands(&mut control);
+        let device = virtio::VirtioPciDevice::new(
+            Box::new(balloon), guest_mem.clone());
+        add_pci_device(&pci_root, device);
+    }
+    if let Some(share_args) = args.values_of("share") {
+        for share_arg in share_args {
+            let share = virtio::p9::P9::new(
+                &virtio::p9::ShareConfig::parse(share_arg));
+            let device = virtio::VirtioPciDevice::new(
+                Box::new(share), guest_mem.clone());
+            add_pci_device(&pci_root, device);
+        }
+    }
+    if let Some(port_args) = args.values_of("console-port") {
+        let ports = port_args.map(virtio::console::PortConfig::parse).collect();
+        let console = virtio::console::Console::new(ports);
+        let device = virtio::VirtioPciDevice::new(
+            Box::new(console), guest_mem.clone());
+        add_pci_device(&pci_root, device);
+    }
+    if args.is_present("rng") {
+        let config = virtio::rng::RngConfig::parse(
+            args.value_of("rng").unwrap_or(""));
+        let device = virtio::VirtioPciDevice::new(
+            Box::new(virtio::rng::Rng::new(config)), guest_mem.clone());
+        add_pci_device(&pci_root, device);
+    }
+    if let Some(vsock_arg) = args.value_of("vsock") {
+        let vsock = virtio::vsock::Vsock::new(
+            virtio::vsock::VsockConfig::parse(vsock_arg));
+        let device = virtio::VirtioPciDevice::new(
+            Box::new(vsock), guest_mem.clone());
+        add_pci_device(&pci_root, device);
+    }
 
-    let mut fw_cfg_dev = fw_cfg::FWCfgDev::new();
-    fw_cfg_dev.add_i16(FW_CFG_NB_CPUS, 1);
-    fw_cfg_dev.add_i16(FW_CFG_MAX_CPUS, 1);
+    let fw_cfg_mmio = args.value_of("fw_cfg_mmio")
+        .map(|addr| parse_u64("fw-cfg-mmio", addr));
+    let fw_cfg_interface = match fw_cfg_mmio {
+        Some(_) => fw_cfg::FWCfgInterface::Mmio,
+        None => fw_cfg::FWCfgInterface::Io,
+    };
+    let mut fw_cfg_dev = fw_cfg::FWCfgDev::new(fw_cfg_interface,
+                                               guest_mem.clone());
+    fw_cfg_dev.add_i16(FW_CFG_NB_CPUS, vcpu_count as i16);
+    fw_cfg_dev.add_i16(FW_CFG_MAX_CPUS, vcpu_count as i16);
     fw_cfg_dev.add_i64(FW_CFG_RAM_SIZE, mem_size as i64);
-    fw_cfg_dev.add_file("etc/e820", e820_table_buf,
-                        e820_table_buf.len() as u32);
+    layout.add_to_fw_cfg(&mut fw_cfg_dev);
 
+    let smbios_config = match args.value_of("smbios") {
+        Some(spec) => smbios::SmbiosConfig::parse(spec),
+        None => smbios::SmbiosConfig::new(),
+    };
+    fw_cfg_dev.add_bytes(FW_CFG_UUID, &smbios_config.uuid,
+                         smbios_config.uuid.len() as u32);
+    let smbios_tables = smbios::SmbiosTables::new(
+        &smbios_config, vcpu_count, &layout.ram_ranges());
+    smbios_tables.add_to_fw_cfg(&mut fw_cfg_dev);
+    if direct_boot {
+        smbios_tables.install(&guest_mem);
+    }
+
+    if let (Some(kernel), true) = (&kernel, has_firmware) {
+        // Let the firmware load the kernel.
+        kernel.add_to_fw_cfg(&mut fw_cfg_dev, initrd_path, cmdline,
+                             layout.low_ram_end());
+    }
+
+    if !args.is_present("no_acpi") {
+        let (pm_base, ecam) = match machine {
+            "q35" => (pci::q35::ICH9_LPC_PMBASE_DEFAULT,
+                      Some((pci::q35::PCIEXBAR_DEFAULT_BASE,
+                            pci::q35::PCIEXBAR_DEFAULT_SIZE))),
+            _ => (acpi_pm::PC_PM_BASE as u16, None),
+        };
+        let acpi_tables = acpi::AcpiTables::new(&acpi::AcpiConfig {
+            vcpu_count: vcpu_count,
+            ram_end: layout.low_ram_end(),
+            pm_base: pm_base,
+            sci_irq: acpi_pm::SCI_IRQ as u8,
+            ecam: ecam,
+            pci_slots: pci_root.lock().unwrap().root_device_numbers(),
+            pci_irqs: pci::root::PCI_IRQS.to_vec(),
+            serial_ports: serial::COM_PORTS[..serial_count].to_vec(),
+            fw_cfg_mmio: fw_cfg_mmio,
+            vmgenid: args.is_present("vmgenid"),
+        });
+        acpi_tables.add_to_fw_cfg(&mut fw_cfg_dev, &guest_mem);
+        if direct_boot {
+            acpi_tables.install(&guest_mem, &mut fw_cfg_dev);
+        }
+    }
+
+    let fw_cfg_dev = Arc::new(Mutex::new(fw_cfg_dev));
+    match fw_cfg_mmio {
+        Some(addr) => mmio_bus.insert(fw_cfg_dev.clone(), addr,
+                                      fw_cfg::FW_CFG_MMIO_SIZE, false),
+        None => io_bus.insert(fw_cfg_dev.clone(), fw_cfg::FW_CFG_IO_BASE,
+                              fw_cfg::FW_CFG_IO_SIZE, false),
+    }.expect("Cannot map the fw_cfg registers.");
+    snapshotter.add_device("fw-cfg", fw_cfg_dev);
+
+    // The PCI devices are saved along with their configuration space.
+    snapshotter.add_device("pci", pci_root.clone());
+    if let Some(snapshot_path) = args.value_of("restore") {
+        snapshotter.restore(snapshot_path, &mut vcpus,
+                            restore_ram == "copy");
+    }
+    let snapshotter = Arc::new(snapshotter);
+    Snapshotter::add_control_commands(&snapshotter, &mut control);
+
+    if let Some(control_path) = args.value_of("control") {
+        control.start(control_path);
+    }
+
+    let (exit_tx, exit_rx) = mpsc::channel();
+    for vcpu in vcpus {
+        let io_bus = io_bus.clone();
+        let mmio_bus = mmio_bus.clone();
+        let exit_tx = exit_tx.clone();
+        let pauser = snapshotter.pauser();
+
+        thread::Builder::new()
+            .name(format!("vcpu{}", vcpu.index()))
+            .spawn(move || {
+                run_vcpu(vcpu, &io_bus, &mmio_bus, &pauser);
+                // Let the main thread know that the guest stopped.
+                exit_tx.send(()).unwrap();
+            })
+            .unwrap();
+    }
+
+    exit_rx.recv().unwrap();
+
+    dump_ring_buffers();
+}
+
+fn dump_ring_buffers() {
+    let mut stdout = io::stdout();
+    for (name, buffer) in chardev::ring_buffers() {
+        println!("{} output:", name);
+        stdout.write_all(&buffer.lock().unwrap().contents()).unwrap();
+        println!();
+    }
+}
+
+fn add_serial_port(io_bus: &Bus, irq_chip: &Arc<IrqChip>,
+                   irq_pins: &mut BTreeMap<u32, Arc<IrqPin>>,
+                   index: usize, serial_arg: &str) -> Arc<Mutex<serial::Serial>> {
+    let (base, irq) = serial::COM_PORTS[index];
+    let irq = IrqPin::new_line(irq_pins.entry(irq).or_insert_with(
+        || IrqPin::new(irq_chip.clone(), irq)));
+
+    let name = format!("serial{}", index);
+    let backend = chardev::new(serial_arg, &name);
+
+    let serial_dev = Arc::new(Mutex::new(
+        serial::Serial::new(irq, backend)));
     io_bus.insert(
-        Arc::new(Mutex::new(fw_cfg_dev)),
-        0x510,
-        8,
+        serial_dev.clone(),
+        base,
+        serial::COM_PORT_SIZE,
         false).unwrap();
 
+    serial::Serial::connect_input(&serial_dev);
+    serial_dev
+}
+
+/// Places PCIe devices behind the free root ports, if any.
+fn add_pci_device<T: pci::device::PciDevice + 'static>(
+        pci_root: &Mutex<pci::PciRoot>, device: T) {
+    let mut root = pci_root.lock().unwrap();
+    let device = Arc::new(Mutex::new(device));
+    match root.free_root_port() {
+        Some(port_dev_num) => root.add_device_to_port(device, port_dev_num),
+        None => {
+            root.add_device(device);
+        },
+    }
+}
+
+fn run_vcpu(mut vcpu: Box<Vcpu>, io_bus: &Bus, mmio_bus: &Bus,
+            pauser: &VcpuPauser) {
+    let vcpu_index = vcpu.index();
+    pauser.register_thread(vcpu_index);
+
     loop {
-        let vm_exit = accelerator.vcpu_run(0);
+        // Stops here while a snapshot is taken.
+        pauser.check(&mut *vcpu);
+
+        let vm_exit = vcpu.run();
         // todo: handle the exits and move this somewhere else.
         match vm_exit {
             VcpuExit::IoIn(port, data) => io_bus.read(port.into(), data),
             VcpuExit::IoOut(port, data) => io_bus.write(port.into(), data),
             VcpuExit::MmioRead(addr, data) => mmio_bus.read(addr, data),
             VcpuExit::MmioWrite(addr, data) => mmio_bus.write(addr, data),
-            VcpuExit::Hlt => { println!("vcpu halt."); break } ,
-            VcpuExit::Shutdown => { println!("vcpu shutdown exit."); break },
-            _ => panic!("Unsupported exit.")
+            VcpuExit::Hlt => {
+                println!("vcpu {} halt.", vcpu_index);
+                break
+            },
+            VcpuExit::Shutdown => {
+                println!("vcpu {} shutdown exit.", vcpu_index);
+                break
+            },
+            VcpuExit::Interrupted => continue,
         };
     }
 }
@@ -1,9 +1,317 @@
 extern crate std;
 extern crate libc;
 
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
 use libkvm::mem::MemorySlot;
 
 use ::utils::memory;
+use ::utils::posix::userfaultfd::UserFaultFd;
+use ::utils::options::{parse_bool, parse_key_values, parse_u64};
+
+const PAGE_SIZE: u64 = 4096;
+// The lazily restored RAM is read by chunks of this size, like the default
+// readahead window, rather than one page per fault.
+const FAULT_CHUNK_SIZE: u64 = 128 << 10;
+const HUGEPAGE_SIZES: [u64; 2] = [2 << 20, 1 << 30];
+
+/// How the guest RAM is backed on the host.
+pub enum RamBackend {
+    /// Private anonymous memory.
+    Anonymous,
+    /// Private memory taken from the huge page pool.
+    HugeTlb,
+    /// A sealed memfd. Handing it to out of process device backends isn't
+    /// supported yet.
+    Memfd,
+    /// A shared mapping of the given file, for example on hugetlbfs.
+    File(String),
+    /// A private mapping of a snapshot RAM file. The pages are read on
+    /// first access and copied on write, the unmodified ones being shared
+    /// by all the VMs restored from the same snapshot.
+    Snapshot(String),
+    /// Private anonymous memory, filled from a snapshot RAM file on first
+    /// access using userfaultfd.
+    SnapshotUffd(String),
+}
+
+pub struct RamConfig {
+    pub backend: RamBackend,
+    /// The huge page size used by the hugetlb and memfd backends.
+    pub hugepage_size: Option<u64>,
+    /// Touches all the pages upfront, using the given number of threads.
+    pub prealloc_threads: usize,
+    /// Locks the RAM into host memory.
+    pub mlock: bool,
+    /// Binds the RAM to the given host NUMA nodes.
+    pub host_nodes: Vec<u32>,
+}
+
+fn parse_hugepage_size(value: &str) -> u64 {
+    let size = match value {
+        "2M" => 2 << 20,
+        "1G" => 1 << 30,
+        _ => parse_u64("pagesize", value),
+    };
+    if !HUGEPAGE_SIZES.contains(&size) {
+        panic!("Unsupported huge page size: {}", value);
+    }
+    size
+}
+
+impl RamConfig {
+    pub fn new() -> Self {
+        RamConfig {
+            backend: RamBackend::Anonymous,
+            hugepage_size: None,
+            prealloc_threads: 0,
+            mlock: false,
+            host_nodes: Vec::new(),
+        }
+    }
+
+    /// Parses a "[backend=anon|hugetlb|memfd|file][,path=<path>]
+    /// [,pagesize=2M|1G][,prealloc-threads=<count>][,mlock=on|off]
+    /// [,host-nodes=<node>[:<node>...]]" spec.
+    pub fn parse(spec: &str) -> Self {
+        let mut config = RamConfig::new();
+        let mut backend = "anon".to_string();
+        let mut path = None;
+
+        for (key, value) in parse_key_values(spec) {
+            match key.as_str() {
+                "backend" => backend = value,
+                "path" => path = Some(value),
+                "pagesize" => config.hugepage_size = Some(
+                    parse_hugepage_size(&value)),
+                "prealloc-threads" => config.prealloc_threads =
+                    parse_u64(&key, &value) as usize,
+                "mlock" => config.mlock = parse_bool(&key, &value),
+                "host-nodes" => config.host_nodes = value.split(':')
+                    .map(|node| parse_u64(&key, node) as u32)
+                    .collect(),
+                _ => panic!("Unknown memory option: {}", key),
+            }
+        }
+
+        config.backend = match backend.as_str() {
+            "anon" => RamBackend::Anonymous,
+            "hugetlb" => {
+                if config.hugepage_size.is_none() {
+                    config.hugepage_size = Some(HUGEPAGE_SIZES[0]);
+                }
+                RamBackend::HugeTlb
+            },
+            "memfd" => RamBackend::Memfd,
+            "file" => RamBackend::File(path.take().expect(
+                "The file memory backend requires a path.")),
+            _ => panic!("Unknown memory backend: {}", backend),
+        };
+        if path.is_some() {
+            panic!("Only the file memory backend accepts a path.");
+        }
+        match config.backend {
+            RamBackend::HugeTlb | RamBackend::Memfd => (),
+            _ => if config.hugepage_size.is_some() {
+                panic!("Only the hugetlb and memfd memory backends accept a \
+                        page size.");
+            },
+        }
+        config
+    }
+}
+
+/// Resolves the page faults of the guest RAM by reading the pages from a
+/// snapshot, running in its own thread.
+struct LazyRamLoader {
+    uffd: UserFaultFd,
+    file: File,
+    /// The (host address, size, file offset) of the registered mappings.
+    ranges: Mutex<Vec<(u64, u64, u64)>>,
+}
+
+impl LazyRamLoader {
+    fn start(file: File) -> Arc<Self> {
+        let loader = Arc::new(LazyRamLoader {
+            uffd: UserFaultFd::new(),
+            file: file,
+            ranges: Mutex::new(Vec::new()),
+        });
+
+        let thread_loader = loader.clone();
+        thread::Builder::new()
+            .name("ram-loader".to_string())
+            .spawn(move || thread_loader.run())
+            .unwrap();
+        loader
+    }
+
+    fn register(&self, address: *mut libc::c_void, size: usize, offset: u64) {
+        self.ranges.lock().unwrap().push((address as u64, size as u64, offset));
+        self.uffd.register(address, size);
+    }
+
+    fn run(&self) {
+        let mut buf = vec![0u8; FAULT_CHUNK_SIZE as usize];
+        loop {
+            let fault = self.uffd.read_fault();
+            let (address, offset, len) =
+                fault_chunk(&self.ranges.lock().unwrap(), fault)
+                    .unwrap_or_else(|| panic!(
+                        "Unexpected guest RAM fault at {:x}.", fault));
+
+            let mut done = 0;
+            while done < len {
+                match self.file.read_at(&mut buf[done..len],
+                                        offset + done as u64) {
+                    Ok(0) => panic!("The snapshot RAM is truncated at {:x}.",
+                                    offset + done as u64),
+                    Ok(n) => done += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                    Err(e) => panic!("Cannot read the snapshot RAM: {}", e),
+                }
+            }
+            self.uffd.copy(address, &buf[..len]);
+        }
+    }
+}
+
+/// Returns the host address, file offset and length of the chunk holding
+/// `fault`, aligned to `FAULT_CHUNK_SIZE` and clamped to its mapping.
+fn fault_chunk(ranges: &[(u64, u64, u64)], fault: u64)
+               -> Option<(u64, u64, usize)> {
+    ranges.iter()
+        .find(|&&(start, size, _)| start <= fault && fault - start < size)
+        .map(|&(start, size, offset)| {
+            let aligned = fault & !(FAULT_CHUNK_SIZE - 1);
+            let chunk_start = aligned.max(start);
+            let chunk_end = (aligned + FAULT_CHUNK_SIZE).min(start + size);
+            (chunk_start, offset + chunk_start - start,
+             (chunk_end - chunk_start) as usize)
+        })
+}
+
+/// The host memory backing the guest RAM, shared by its memory slots.
+pub struct RamBacking {
+    config: RamConfig,
+    /// The file mapped by the slots, unless the RAM is private.
+    file: Option<File>,
+    /// The snapshot RAM mapped copy-on-write by the slots.
+    snapshot_file: Option<File>,
+    loader: Option<Arc<LazyRamLoader>>,
+}
+
+fn open_snapshot_ram(path: &str, ram_size: u64) -> File {
+    let file = File::open(path).unwrap_or_else(
+        |_| panic!("Cannot open the snapshot RAM \"{}\".", path));
+    if file.metadata().unwrap().len() != ram_size {
+        panic!("The snapshot RAM doesn't match the memory size.");
+    }
+    file
+}
+
+impl RamBacking {
+    pub fn new(config: RamConfig, ram_size: u64) -> Self {
+        if let Some(page_size) = config.hugepage_size {
+            if ram_size % page_size != 0 {
+                panic!("The RAM size must be a multiple of the huge page \
+                        size: {}B.", page_size);
+            }
+        }
+
+        let (mut snapshot_file, mut loader) = (None, None);
+        match config.backend {
+            RamBackend::Snapshot(ref path) => {
+                snapshot_file = Some(open_snapshot_ram(path, ram_size));
+            },
+            RamBackend::SnapshotUffd(ref path) => {
+                loader = Some(LazyRamLoader::start(
+                    open_snapshot_ram(path, ram_size)));
+            },
+            _ => (),
+        }
+
+        let file = match config.backend {
+            RamBackend::Anonymous | RamBackend::HugeTlb |
+            RamBackend::Snapshot(_) | RamBackend::SnapshotUffd(_) => None,
+            RamBackend::Memfd => Some(memory::memfd_create(
+                "insula-ram", ram_size, config.hugepage_size)),
+            RamBackend::File(ref path) => {
+                let file = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .open(path)
+                    .unwrap_or_else(|_| panic!(
+                        "Cannot open the memory file \"{}\".", path));
+                if file.metadata().unwrap().len() < ram_size {
+                    file.set_len(ram_size).unwrap_or_else(|_| panic!(
+                        "Cannot resize the memory file \"{}\".", path));
+                }
+                Some(file)
+            },
+        };
+
+        RamBacking {
+            config: config,
+            file: file,
+            snapshot_file: snapshot_file,
+            loader: loader,
+        }
+    }
+
+    /// Only private anonymous pages read back as zeros once discarded.
+    pub fn is_anonymous(&self) -> bool {
+        match self.config.backend {
+            RamBackend::Anonymous => true,
+            _ => false,
+        }
+    }
+
+    fn page_size(&self) -> u64 {
+        match self.file {
+            // hugetlbfs reports the huge page size as the block size.
+            Some(ref file) => self.config.hugepage_size.unwrap_or(
+                file.metadata().unwrap().blksize().max(PAGE_SIZE)),
+            None => self.config.hugepage_size.unwrap_or(PAGE_SIZE),
+        }
+    }
+
+    /// Maps `size` bytes of the RAM, starting at `offset`.
+    fn map(&self, offset: u64, size: usize) -> *mut libc::c_void {
+        let address = match (&self.config.backend, &self.file,
+                             &self.snapshot_file) {
+            (&RamBackend::HugeTlb, _, _) => memory::hugetlb_ram_alloc(
+                size, self.config.hugepage_size.unwrap()),
+            (_, &Some(ref file), _) => memory::file_ram_alloc(file, offset, size),
+            (_, _, &Some(ref file)) => memory::private_file_ram_alloc(
+                file, offset, size),
+            _ => memory::vm_memory_alloc(size),
+        };
+        // Must be done before touching the pages.
+        if let Some(ref loader) = self.loader {
+            loader.register(address, size, offset);
+        }
+
+        // The memory policy only applies to the pages allocated afterwards.
+        if !self.config.host_nodes.is_empty() {
+            memory::mbind(address, size, &self.config.host_nodes);
+        }
+        if self.config.prealloc_threads > 0 {
+            memory::prealloc(address, size, self.page_size() as usize,
+                             self.config.prealloc_threads);
+        }
+        if self.config.mlock {
+            memory::mlock(address, size);
+        }
+        address
+    }
+}
 
 pub struct MmapMemorySlot {
     memory_size: usize,
@@ -27,12 +335,39 @@ impl MmapMemorySlot {
         }
     }
 
+    /// Maps `memory_size` bytes of the guest RAM backing, starting at
+    /// `offset`.
+    pub fn new_ram(backing: &RamBacking, offset: u64, memory_size: usize,
+                   guest_address: u64, slot: u32) -> MmapMemorySlot {
+        MmapMemorySlot {
+            memory_size: memory_size,
+            guest_address: guest_address,
+            host_address: backing.map(offset, memory_size),
+            slot,
+            flags: 0,
+        }
+    }
+
     pub fn as_slice_mut(&mut self) -> &mut [u8] {
         unsafe {
             std::slice::from_raw_parts_mut(
                 self.host_address as *mut u8, self.memory_size)
         }
     }
+
+    /// Copies `data` at the given guest physical address.
+    pub fn write_at(&mut self, guest_address: u64, data: &[u8]) {
+        if guest_address < self.guest_address ||
+                guest_address + data.len() as u64 >
+                    self.guest_address + self.memory_size as u64 {
+            panic!("Guest address range {:x}-{:x} is outside the memory slot.",
+                   guest_address, guest_address + data.len() as u64);
+        }
+
+        let offset = (guest_address - self.guest_address) as usize;
+        self.as_slice_mut()[offset..offset + data.len()]
+            .copy_from_slice(data);
+    }
 }
 
 impl MemorySlot for MmapMemorySlot {
@@ -62,3 +397,148 @@ impl Drop for MmapMemorySlot {
         memory::anon_ram_free(self.host_address, self.memory_size);
     }
 }
+
+struct GuestRegion {
+    guest_address: u64,
+    size: u64,
+    host_address: u64,
+}
+
+/// A view of the guest RAM, used by the devices that access guest memory.
+/// The memory slots must outlive it.
+#[derive(Clone)]
+pub struct GuestMemory {
+    regions: Arc<Vec<GuestRegion>>,
+}
+
+// The guest memory is shared with the vCPUs anyway, accesses are not
+// synchronized.
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+impl GuestMemory {
+    pub fn new(slots: &[&MmapMemorySlot]) -> Self {
+        let regions = slots.iter().map(|slot| GuestRegion {
+            guest_address: slot.guest_address,
+            size: slot.memory_size as u64,
+            host_address: slot.host_address as u64,
+        }).collect();
+
+        GuestMemory {
+            regions: Arc::new(regions),
+        }
+    }
+
+    /// Returns the host address of the given guest range, which must not
+    /// cross memory slots.
+    fn host_address(&self, guest_address: u64, len: usize) -> Option<*mut u8> {
+        let end = guest_address.checked_add(len as u64)?;
+        self.regions.iter()
+            .find(|r| r.guest_address <= guest_address &&
+                      end <= r.guest_address + r.size)
+            .map(|r| (r.host_address + guest_address - r.guest_address)
+                     as *mut u8)
+    }
+
+    /// Returns false if the range is not backed by guest RAM.
+    pub fn read(&self, guest_address: u64, data: &mut [u8]) -> bool {
+        match self.host_address(guest_address, data.len()) {
+            Some(host_address) => {
+                unsafe {
+                    ptr::copy_nonoverlapping(host_address, data.as_mut_ptr(),
+                                             data.len());
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Returns false if the range is not backed by guest RAM.
+    pub fn write(&self, guest_address: u64, data: &[u8]) -> bool {
+        match self.host_address(guest_address, data.len()) {
+            Some(host_address) => {
+                unsafe {
+                    ptr::copy_nonoverlapping(data.as_ptr(), host_address,
+                                             data.len());
+                }
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Gives the backing of a page aligned range back to the host, the
+    /// guest reading zeros afterwards. Returns false if the range is not
+    /// backed by guest RAM.
+    pub fn discard(&self, guest_address: u64, len: usize) -> bool {
+        match self.host_address(guest_address, len) {
+            Some(host_address) => {
+                memory::madvise(host_address as *mut libc::c_void, len,
+                                libc::MADV_DONTNEED);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Reads a plain old data object, such as an integer or a `repr(C)`
+    /// structure.
+    pub fn read_obj<T: Copy>(&self, guest_address: u64) -> Option<T> {
+        let host_address = self.host_address(guest_address,
+                                             std::mem::size_of::<T>())?;
+        unsafe {
+            Some(ptr::read_unaligned(host_address as *const T))
+        }
+    }
+
+    pub fn write_obj<T: Copy>(&self, guest_address: u64, value: T) -> bool {
+        match self.host_address(guest_address, std::mem::size_of::<T>()) {
+            Some(host_address) => {
+                unsafe {
+                    ptr::write_unaligned(host_address as *mut T, value);
+                }
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_page_sizes() {
+        let config = RamConfig::parse("backend=hugetlb");
+        assert_eq!(config.hugepage_size, Some(HUGEPAGE_SIZES[0]));
+        let config = RamConfig::parse("backend=memfd,pagesize=1G");
+        assert_eq!(config.hugepage_size, Some(1 << 30));
+        let config = RamConfig::parse("backend=memfd");
+        assert_eq!(config.hugepage_size, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "page size")]
+    fn page_size_needs_huge_pages() {
+        RamConfig::parse("pagesize=2M");
+    }
+
+    #[test]
+    #[should_panic(expected = "page size")]
+    fn file_ignores_page_size() {
+        RamConfig::parse("backend=file,path=/dev/hugepages/ram,pagesize=2M");
+    }
+
+    #[test]
+    fn fault_chunks_stay_in_mappings() {
+        let ranges = [(0x10_0000, 0x8_0000, 0), (0x20_1000, 0x1000, 0x8_0000)];
+        assert_eq!(fault_chunk(&ranges, 0x12_3456),
+                   Some((0x12_0000, 0x2_0000, FAULT_CHUNK_SIZE as usize)));
+        assert_eq!(fault_chunk(&ranges, 0x20_1fff),
+                   Some((0x20_1000, 0x8_0000, 0x1000)));
+        assert_eq!(fault_chunk(&ranges, 0x18_0000), None);
+        assert_eq!(fault_chunk(&ranges, 0x20_0fff), None);
+    }
+}
@@ -0,0 +1,158 @@
+//! Host side endpoints of the guest network interfaces.
+
+mod pcap;
+mod tap;
+mod unix;
+mod user;
+
+extern crate libc;
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use ::utils::options::{parse_bool, parse_key_values};
+
+pub use self::pcap::PcapWriter;
+
+/// Large enough for any frame, including GSO ones.
+pub const MAX_FRAME_SIZE: usize = 65550;
+
+/// Host side endpoint of a network interface, exchanging Ethernet frames.
+#[allow(unused_variables)]
+pub trait NetBackend: Send + Sync {
+    /// Sends a frame to the network. Frames that can't be sent are
+    /// dropped, as a real network would do.
+    fn send(&self, frame: &[u8]);
+    /// Waits up to `timeout_ms` for a frame, returning its size or 0 if
+    /// none was received.
+    fn recv(&self, buf: &mut [u8], timeout_ms: i32) -> io::Result<usize>;
+    /// Called for multiqueue backends when the guest changes the number
+    /// of queues in use.
+    fn set_enabled(&self, enabled: bool) {}
+}
+
+/// A guest network interface, as passed through the --netdev option.
+pub struct NetConfig {
+    /// One backend per queue pair.
+    pub backends: Vec<Arc<NetBackend>>,
+    pub mac: [u8; 6],
+    pub pcap: Option<Arc<Mutex<PcapWriter>>>,
+}
+
+fn parse_mac(mac: &str) -> [u8; 6] {
+    let bytes = mac.split(':')
+        .map(|b| u8::from_str_radix(b, 16))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()
+        .filter(|bytes| bytes.len() == 6)
+        .unwrap_or_else(|| panic!("Invalid MAC address: {}", mac));
+
+    let mut addr = [0u8; 6];
+    addr.copy_from_slice(&bytes);
+    addr
+}
+
+/// Parses a network interface spec:
+/// tap,ifname=<name>[,queues=<n>] or
+/// unix,path=<path>[,type=dgram|seqpacket][,peer=<path>][,server=on|off] or
+/// user[,hostfwd=<rule>]...[,loopback=on|off]
+/// All accept [,mac=<addr>][,pcap=<path>]. `index` is used for the
+/// default MAC address.
+pub fn new(spec: &str, index: usize) -> NetConfig {
+    let (kind, options) = match spec.find(',') {
+        Some(idx) => (&spec[..idx], &spec[idx + 1..]),
+        None => (spec, ""),
+    };
+
+    let mut mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56 + index as u8];
+    let mut pcap = None;
+    let mut backend_options = Vec::new();
+    for (key, value) in parse_key_values(options) {
+        match key.as_str() {
+            "mac" => mac = parse_mac(&value),
+            "pcap" => pcap = Some(Arc::new(Mutex::new(PcapWriter::new(&value)))),
+            _ => backend_options.push((key, value)),
+        }
+    }
+    let get_option = |name: &str| backend_options.iter()
+        .find(|&&(ref key, _)| key == name)
+        .map(|&(_, ref value)| value.clone());
+    for &(ref key, _) in backend_options.iter() {
+        let known = match kind {
+            "tap" => ["ifname", "queues"].contains(&key.as_str()),
+            "unix" => ["path", "type", "peer", "server"].contains(&key.as_str()),
+            "user" => ["hostfwd", "loopback"].contains(&key.as_str()),
+            _ => true,
+        };
+        if !known {
+            panic!("Unknown {} network option: {}", kind, key);
+        }
+    }
+
+    let backends: Vec<Arc<NetBackend>> = match kind {
+        "tap" => {
+            let ifname = get_option("ifname").expect("Missing tap ifname.");
+            let queues = get_option("queues")
+                .map(|q| q.parse::<usize>().expect("Invalid tap queue count."))
+                .unwrap_or(1);
+            if queues < 1 {
+                panic!("Invalid tap queue count.");
+            }
+            (0..queues)
+                .map(|_| Arc::new(tap::TapBackend::new(&ifname, queues > 1))
+                         as Arc<NetBackend>)
+                .collect()
+        },
+        "unix" => {
+            let path = get_option("path").expect("Missing unix socket path.");
+            let server = get_option("server")
+                .map(|s| parse_bool("server", &s))
+                .unwrap_or(false);
+            let backend = match get_option("type").as_ref().map(|t| t.as_str()) {
+                None | Some("dgram") => unix::UnixBackend::new_datagram(
+                    &path, get_option("peer").as_ref().map(|p| p.as_str())),
+                Some("seqpacket") => unix::UnixBackend::new_seqpacket(
+                    &path, server),
+                Some(t) => panic!("Invalid unix socket type: {}", t),
+            };
+            vec![Arc::new(backend)]
+        },
+        "user" => {
+            let forwards = backend_options.iter()
+                .filter(|&&(ref key, _)| key == "hostfwd")
+                .map(|&(_, ref rule)| user::HostForward::parse(rule))
+                .collect();
+            let loopback = get_option("loopback")
+                .map(|l| parse_bool("loopback", &l))
+                .unwrap_or(false);
+            vec![Arc::new(user::UserNetBackend::new(forwards, loopback))]
+        },
+        _ => panic!("Invalid network backend: {}", spec),
+    };
+
+    NetConfig {
+        backends: backends,
+        mac: mac,
+        pcap: pcap,
+    }
+}
+
+/// Waits for the file descriptor to become readable, returning false
+/// on timeout.
+fn poll_readable(fd: RawFd, timeout_ms: i32) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd {
+        fd: fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(ret > 0)
+}
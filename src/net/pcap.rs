@@ -0,0 +1,57 @@
+extern crate byteorder;
+
+use self::byteorder::{LittleEndian, WriteBytesExt};
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// Captures the guest traffic in the pcap format.
+pub struct PcapWriter {
+    out: BufWriter<File>,
+}
+
+impl PcapWriter {
+    pub fn new(path: &str) -> Self {
+        let file = File::create(path).unwrap_or_else(
+            |_| panic!("Cannot create pcap file: {}", path));
+        let mut out = BufWriter::new(file);
+
+        out.write_u32::<LittleEndian>(PCAP_MAGIC).unwrap();
+        out.write_u16::<LittleEndian>(PCAP_VERSION_MAJOR).unwrap();
+        out.write_u16::<LittleEndian>(PCAP_VERSION_MINOR).unwrap();
+        // GMT offset and timestamp accuracy.
+        out.write_u32::<LittleEndian>(0).unwrap();
+        out.write_u32::<LittleEndian>(0).unwrap();
+        out.write_u32::<LittleEndian>(PCAP_SNAPLEN).unwrap();
+        out.write_u32::<LittleEndian>(LINKTYPE_ETHERNET).unwrap();
+        out.flush().unwrap();
+
+        PcapWriter {
+            out: out,
+        }
+    }
+
+    pub fn write_frame(&mut self, frame: &[u8]) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let captured = &frame[..frame.len().min(PCAP_SNAPLEN as usize)];
+
+        let result = self.out.write_u32::<LittleEndian>(now.as_secs() as u32)
+            .and_then(|_| self.out.write_u32::<LittleEndian>(
+                now.subsec_nanos() / 1000))
+            .and_then(|_| self.out.write_u32::<LittleEndian>(
+                captured.len() as u32))
+            .and_then(|_| self.out.write_u32::<LittleEndian>(frame.len() as u32))
+            .and_then(|_| self.out.write_all(captured))
+            .and_then(|_| self.out.flush());
+        if let Err(e) = result {
+            println!("Cannot write pcap record: {}", e);
+        }
+    }
+}
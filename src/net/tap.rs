@@ -0,0 +1,97 @@
+extern crate libc;
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use super::{poll_readable, NetBackend};
+
+const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETQUEUE: libc::c_ulong = 0x4004_54d9;
+
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_MULTI_QUEUE: libc::c_short = 0x0100;
+const IFF_ATTACH_QUEUE: libc::c_short = 0x0200;
+const IFF_DETACH_QUEUE: libc::c_short = 0x0400;
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    // The rest of the ifreq union.
+    padding: [u8; 22],
+}
+
+/// A Linux tap interface. Multiqueue interfaces use one backend per
+/// queue, each holding a separate file descriptor.
+pub struct TapBackend {
+    file: File,
+}
+
+impl TapBackend {
+    pub fn new(ifname: &str, multi_queue: bool) -> Self {
+        if ifname.len() >= libc::IFNAMSIZ {
+            panic!("Invalid tap interface name: {}", ifname);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")
+            .expect("Cannot open /dev/net/tun.");
+
+        let mut ifreq = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: IFF_TAP | IFF_NO_PI,
+            padding: [0; 22],
+        };
+        ifreq.name[..ifname.len()].copy_from_slice(ifname.as_bytes());
+        if multi_queue {
+            ifreq.flags |= IFF_MULTI_QUEUE;
+        }
+
+        let ret = unsafe {
+            libc::ioctl(file.as_raw_fd(), TUNSETIFF, &mut ifreq as *mut IfReq)
+        };
+        if ret < 0 {
+            panic!("Cannot attach to tap interface {}: {}",
+                   ifname, io::Error::last_os_error());
+        }
+
+        TapBackend {
+            file: file,
+        }
+    }
+}
+
+impl NetBackend for TapBackend {
+    fn send(&self, frame: &[u8]) {
+        // Frames are dropped when the interface is down.
+        let _ = (&self.file).write(frame);
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout_ms: i32) -> io::Result<usize> {
+        if !poll_readable(self.file.as_raw_fd(), timeout_ms)? {
+            return Ok(0);
+        }
+        (&self.file).read(buf)
+    }
+
+    fn set_enabled(&self, enabled: bool) {
+        let mut ifreq = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: if enabled { IFF_ATTACH_QUEUE } else { IFF_DETACH_QUEUE },
+            padding: [0; 22],
+        };
+        let ret = unsafe {
+            libc::ioctl(self.file.as_raw_fd(), TUNSETQUEUE,
+                        &mut ifreq as *mut IfReq)
+        };
+        if ret < 0 {
+            println!("Cannot change the tap queue state: {}",
+                     io::Error::last_os_error());
+        }
+    }
+}
@@ -0,0 +1,152 @@
+extern crate libc;
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+use super::{poll_readable, NetBackend};
+
+fn sockaddr_un(path: &str) -> (libc::sockaddr_un, libc::socklen_t) {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if path.len() >= addr.sun_path.len() {
+        panic!("Unix socket path too long: {}", path);
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path.as_bytes()) {
+        *dst = *src as libc::c_char;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len() + 1;
+    (addr, len as libc::socklen_t)
+}
+
+fn new_socket(sock_type: libc::c_int) -> RawFd {
+    let fd = unsafe {
+        libc::socket(libc::AF_UNIX, sock_type | libc::SOCK_CLOEXEC, 0)
+    };
+    if fd < 0 {
+        panic!("Cannot create unix socket: {}", io::Error::last_os_error());
+    }
+    fd
+}
+
+fn bind(fd: RawFd, path: &str) {
+    // Remove stale sockets left behind by previous runs.
+    let _ = fs::remove_file(path);
+    let (addr, len) = sockaddr_un(path);
+    let ret = unsafe {
+        libc::bind(fd, &addr as *const _ as *const libc::sockaddr, len)
+    };
+    if ret < 0 {
+        panic!("Cannot bind unix socket {}: {}",
+               path, io::Error::last_os_error());
+    }
+}
+
+/// Exchanges Ethernet frames over a unix socket, one frame per message.
+/// Datagram sockets send frames to the peer path, if any, while seqpacket
+/// sockets use a single connection.
+pub struct UnixBackend {
+    fd: RawFd,
+    peer: Option<(libc::sockaddr_un, libc::socklen_t)>,
+}
+
+// The socket is only used through thread safe system calls.
+unsafe impl Sync for UnixBackend {}
+unsafe impl Send for UnixBackend {}
+
+impl UnixBackend {
+    pub fn new_datagram(path: &str, peer: Option<&str>) -> Self {
+        let fd = new_socket(libc::SOCK_DGRAM);
+        bind(fd, path);
+
+        UnixBackend {
+            fd: fd,
+            peer: peer.map(sockaddr_un),
+        }
+    }
+
+    /// Either waits for a client to connect or connects to the given path.
+    pub fn new_seqpacket(path: &str, server: bool) -> Self {
+        let fd = new_socket(libc::SOCK_SEQPACKET);
+
+        let fd = if server {
+            bind(fd, path);
+            println!("Waiting for a connection on {}", path);
+            let client = unsafe {
+                if libc::listen(fd, 1) < 0 {
+                    panic!("Cannot listen on {}: {}",
+                           path, io::Error::last_os_error());
+                }
+                libc::accept4(fd, ::std::ptr::null_mut(), ::std::ptr::null_mut(),
+                              libc::SOCK_CLOEXEC)
+            };
+            if client < 0 {
+                panic!("Cannot accept connections on {}: {}",
+                       path, io::Error::last_os_error());
+            }
+            unsafe { libc::close(fd) };
+            client
+        } else {
+            let (addr, len) = sockaddr_un(path);
+            let ret = unsafe {
+                libc::connect(fd, &addr as *const _ as *const libc::sockaddr, len)
+            };
+            if ret < 0 {
+                panic!("Cannot connect to {}: {}",
+                       path, io::Error::last_os_error());
+            }
+            fd
+        };
+
+        UnixBackend {
+            fd: fd,
+            peer: None,
+        }
+    }
+}
+
+impl NetBackend for UnixBackend {
+    fn send(&self, frame: &[u8]) {
+        // Frames are dropped if the peer is gone.
+        unsafe {
+            match self.peer {
+                Some((ref addr, len)) => libc::sendto(
+                    self.fd, frame.as_ptr() as *const libc::c_void, frame.len(),
+                    libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL,
+                    addr as *const _ as *const libc::sockaddr, len),
+                None => libc::send(
+                    self.fd, frame.as_ptr() as *const libc::c_void, frame.len(),
+                    libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL),
+            };
+        }
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout_ms: i32) -> io::Result<usize> {
+        if !poll_readable(self.fd, timeout_ms)? {
+            return Ok(0);
+        }
+        let ret = unsafe {
+            libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void,
+                       buf.len(), libc::MSG_DONTWAIT)
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        if ret == 0 && self.peer.is_none() {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "the peer disconnected"));
+        }
+        Ok(ret as usize)
+    }
+}
+
+impl Drop for UnixBackend {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,177 @@
+//! A minimal DHCP server, always leasing the same address to the guest.
+
+extern crate byteorder;
+
+use self::byteorder::{BigEndian, ByteOrder};
+
+use std::net::Ipv4Addr;
+
+pub const DHCP_SERVER_PORT: u16 = 67;
+pub const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const DHCP_MAGIC: u32 = 0x6382_5363;
+// Fixed fields, followed by the magic cookie.
+const DHCP_OPTIONS_OFFSET: usize = 240;
+const CHADDR_OFFSET: usize = 28;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+
+const LEASE_TIME_SECS: u32 = 86400;
+
+/// The addresses handed out to the guest.
+pub struct DhcpConfig {
+    pub guest: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub dns: Ipv4Addr,
+}
+
+fn message_type(options: &[u8]) -> Option<u8> {
+    let mut i = 0;
+    while i < options.len() {
+        match options[i] {
+            OPT_PAD => i += 1,
+            OPT_END => break,
+            code => {
+                let len = *options.get(i + 1)? as usize;
+                if code == OPT_MESSAGE_TYPE && len == 1 {
+                    return options.get(i + 2).cloned();
+                }
+                i += 2 + len;
+            },
+        }
+    }
+    None
+}
+
+/// Returns the reply to a client message, if any.
+pub fn handle_message(config: &DhcpConfig, request: &[u8]) -> Option<Vec<u8>> {
+    if request.len() < DHCP_OPTIONS_OFFSET || request[0] != BOOTREQUEST ||
+            BigEndian::read_u32(&request[DHCP_OPTIONS_OFFSET - 4..]) != DHCP_MAGIC {
+        return None;
+    }
+
+    let reply_type = match message_type(&request[DHCP_OPTIONS_OFFSET..])? {
+        DHCPDISCOVER => DHCPOFFER,
+        DHCPREQUEST => DHCPACK,
+        _ => return None,
+    };
+
+    let mut reply = vec![0u8; DHCP_OPTIONS_OFFSET];
+    reply[0] = BOOTREPLY;
+    // htype, hlen, hops, xid, secs and flags are echoed back.
+    reply[1..12].copy_from_slice(&request[1..12]);
+    reply[16..20].copy_from_slice(&config.guest.octets());
+    reply[20..24].copy_from_slice(&config.gateway.octets());
+    reply[CHADDR_OFFSET..CHADDR_OFFSET + 16]
+        .copy_from_slice(&request[CHADDR_OFFSET..CHADDR_OFFSET + 16]);
+    BigEndian::write_u32(&mut reply[DHCP_OPTIONS_OFFSET - 4..], DHCP_MAGIC);
+
+    reply.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, reply_type]);
+    reply.extend_from_slice(&[OPT_SERVER_ID, 4]);
+    reply.extend_from_slice(&config.gateway.octets());
+    reply.extend_from_slice(&[OPT_LEASE_TIME, 4]);
+    let mut lease_time = [0u8; 4];
+    BigEndian::write_u32(&mut lease_time, LEASE_TIME_SECS);
+    reply.extend_from_slice(&lease_time);
+    reply.extend_from_slice(&[OPT_SUBNET_MASK, 4]);
+    reply.extend_from_slice(&config.netmask.octets());
+    reply.extend_from_slice(&[OPT_ROUTER, 4]);
+    reply.extend_from_slice(&config.gateway.octets());
+    reply.extend_from_slice(&[OPT_DNS, 4]);
+    reply.extend_from_slice(&config.dns.octets());
+    reply.push(OPT_END);
+
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DhcpConfig {
+        DhcpConfig {
+            guest: Ipv4Addr::new(10, 0, 2, 15),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::new(10, 0, 2, 2),
+            dns: Ipv4Addr::new(10, 0, 2, 3),
+        }
+    }
+
+    fn request(options: &[u8]) -> Vec<u8> {
+        let mut request = vec![0u8; DHCP_OPTIONS_OFFSET];
+        request[0] = BOOTREQUEST;
+        request[1] = 1;
+        request[2] = 6;
+        BigEndian::write_u32(&mut request[4..], 0x1234_5678);
+        request[CHADDR_OFFSET..CHADDR_OFFSET + 6].copy_from_slice(&[1, 2, 3, 4, 5, 6]);
+        BigEndian::write_u32(&mut request[DHCP_OPTIONS_OFFSET - 4..], DHCP_MAGIC);
+        request.extend_from_slice(options);
+        request
+    }
+
+    /// Returns the value of an option of the reply.
+    fn option(reply: &[u8], code: u8) -> Option<&[u8]> {
+        let mut options = &reply[DHCP_OPTIONS_OFFSET..];
+        while options[0] != OPT_END {
+            let len = options[1] as usize;
+            if options[0] == code {
+                return Some(&options[2..2 + len]);
+            }
+            options = &options[2 + len..];
+        }
+        None
+    }
+
+    #[test]
+    fn discover_gets_offer() {
+        let reply = handle_message(&config(), &request(
+            &[OPT_PAD, OPT_MESSAGE_TYPE, 1, DHCPDISCOVER, OPT_END])).unwrap();
+        assert_eq!(reply[0], BOOTREPLY);
+        assert_eq!(BigEndian::read_u32(&reply[4..]), 0x1234_5678);
+        assert_eq!(&reply[16..20], &[10, 0, 2, 15]);
+        assert_eq!(&reply[CHADDR_OFFSET..CHADDR_OFFSET + 6], &[1, 2, 3, 4, 5, 6]);
+        assert_eq!(option(&reply, OPT_MESSAGE_TYPE), Some(&[DHCPOFFER][..]));
+        assert_eq!(option(&reply, OPT_SUBNET_MASK), Some(&[255, 255, 255, 0][..]));
+        assert_eq!(option(&reply, OPT_ROUTER), Some(&[10, 0, 2, 2][..]));
+        assert_eq!(option(&reply, OPT_DNS), Some(&[10, 0, 2, 3][..]));
+    }
+
+    #[test]
+    fn request_gets_ack() {
+        let reply = handle_message(&config(), &request(
+            &[OPT_MESSAGE_TYPE, 1, DHCPREQUEST, OPT_END])).unwrap();
+        assert_eq!(option(&reply, OPT_MESSAGE_TYPE), Some(&[DHCPACK][..]));
+    }
+
+    #[test]
+    fn invalid_messages_are_ignored() {
+        let config = config();
+        let discover = request(&[OPT_MESSAGE_TYPE, 1, DHCPDISCOVER, OPT_END]);
+        assert!(handle_message(&config, &discover[..DHCP_OPTIONS_OFFSET - 1]).is_none());
+
+        let mut reply = discover.clone();
+        reply[0] = BOOTREPLY;
+        assert!(handle_message(&config, &reply).is_none());
+
+        // Options running past the end of the message.
+        assert!(handle_message(&config, &request(&[12, 200, 0])).is_none());
+        assert!(handle_message(&config, &request(&[OPT_MESSAGE_TYPE])).is_none());
+        // DHCPRELEASE isn't answered.
+        assert!(handle_message(&config, &request(&[OPT_MESSAGE_TYPE, 1, 7])).is_none());
+    }
+}
@@ -0,0 +1,181 @@
+//! User mode networking: a small TCP/IP stack that terminates the guest
+//! traffic and forwards it through ordinary host sockets, so neither root
+//! privileges nor host network setup are required.
+//!
+//! The guest network is 10.0.2.0/24, with the gateway at 10.0.2.2, the
+//! DNS forwarder at 10.0.2.3 and the guest at 10.0.2.15. The gateway maps
+//! to the host loopback only when enabled with `loopback=on`, as it gives
+//! the guest access to the services only listening there.
+
+mod dhcp;
+mod packet;
+mod stack;
+mod tcp;
+
+extern crate libc;
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::NetBackend;
+
+// Frames that the guest doesn't pick up are dropped past this limit.
+const MAX_QUEUED_FRAMES: usize = 4096;
+// Host sockets aren't read while this many frames are queued.
+const CONGESTED_FRAMES: usize = MAX_QUEUED_FRAMES / 2;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Forwards connections made to a host port to a guest port.
+#[derive(Debug, Copy, Clone)]
+pub struct HostForward {
+    pub protocol: Protocol,
+    pub host_addr: Ipv4Addr,
+    pub host_port: u16,
+    pub guest_addr: Ipv4Addr,
+    pub guest_port: u16,
+}
+
+impl HostForward {
+    /// Parses a "tcp|udp:[hostaddr]:hostport-[guestaddr]:guestport" rule.
+    /// The host address defaults to the loopback, the guest address to
+    /// the one assigned through DHCP.
+    pub fn parse(rule: &str) -> Self {
+        let (protocol, rest) = match rule.find(':') {
+            Some(idx) => (&rule[..idx], &rule[idx + 1..]),
+            None => invalid_rule(rule),
+        };
+        let protocol = match protocol {
+            "tcp" | "" => Protocol::Tcp,
+            "udp" => Protocol::Udp,
+            _ => invalid_rule(rule),
+        };
+        let (host, guest) = match rest.find('-') {
+            Some(idx) => (&rest[..idx], &rest[idx + 1..]),
+            None => invalid_rule(rule),
+        };
+
+        let parse_endpoint = |endpoint: &str, default_addr: Ipv4Addr| {
+            let idx = endpoint.rfind(':').unwrap_or_else(|| invalid_rule(rule));
+            let addr = match &endpoint[..idx] {
+                "" => default_addr,
+                addr => addr.parse::<Ipv4Addr>().unwrap_or_else(|_| invalid_rule(rule)),
+            };
+            let port = endpoint[idx + 1..].parse::<u16>()
+                                          .unwrap_or_else(|_| invalid_rule(rule));
+            (addr, port)
+        };
+        let (host_addr, host_port) = parse_endpoint(host, Ipv4Addr::new(127, 0, 0, 1));
+        let (guest_addr, guest_port) = parse_endpoint(guest, stack::GUEST_ADDR);
+
+        HostForward {
+            protocol: protocol,
+            host_addr: host_addr,
+            host_port: host_port,
+            guest_addr: guest_addr,
+            guest_port: guest_port,
+        }
+    }
+}
+
+fn invalid_rule(rule: &str) -> ! {
+    panic!("Invalid hostfwd rule: {}", rule)
+}
+
+/// Frames exchanged between the NIC and the stack thread.
+struct Shared {
+    from_guest: Mutex<VecDeque<Vec<u8>>>,
+    to_guest: Mutex<VecDeque<Vec<u8>>>,
+    to_guest_cond: Condvar,
+    // Wakes up the stack thread.
+    wakeup_fd: RawFd,
+}
+
+impl Shared {
+    fn wake_up(&self) {
+        let value: u64 = 1;
+        unsafe {
+            libc::write(self.wakeup_fd, &value as *const u64 as *const libc::c_void, 8);
+        }
+    }
+
+    fn push_to_guest(&self, frame: Vec<u8>) {
+        let mut to_guest = self.to_guest.lock().unwrap();
+        if to_guest.len() < MAX_QUEUED_FRAMES {
+            to_guest.push_back(frame);
+            self.to_guest_cond.notify_one();
+        }
+    }
+
+    /// Whether the guest is behind on receiving frames.
+    fn is_congested(&self) -> bool {
+        self.to_guest.lock().unwrap().len() >= CONGESTED_FRAMES
+    }
+}
+
+pub struct UserNetBackend {
+    shared: Arc<Shared>,
+}
+
+impl UserNetBackend {
+    /// `loopback` maps the gateway address to the host loopback.
+    pub fn new(forwards: Vec<HostForward>, loopback: bool) -> Self {
+        let wakeup_fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if wakeup_fd < 0 {
+            panic!("Cannot create eventfd: {}", io::Error::last_os_error());
+        }
+
+        let shared = Arc::new(Shared {
+            from_guest: Mutex::new(VecDeque::new()),
+            to_guest: Mutex::new(VecDeque::new()),
+            to_guest_cond: Condvar::new(),
+            wakeup_fd: wakeup_fd,
+        });
+
+        let mut stack = stack::UserStack::new(shared.clone(), &forwards, loopback);
+        thread::Builder::new()
+            .name("slirp".to_string())
+            .spawn(move || stack.run())
+            .unwrap();
+
+        UserNetBackend {
+            shared: shared,
+        }
+    }
+}
+
+impl NetBackend for UserNetBackend {
+    fn send(&self, frame: &[u8]) {
+        self.shared.from_guest.lock().unwrap().push_back(frame.to_vec());
+        self.shared.wake_up();
+    }
+
+    fn recv(&self, buf: &mut [u8], timeout_ms: i32) -> io::Result<usize> {
+        let mut to_guest = self.shared.to_guest.lock().unwrap();
+        if to_guest.is_empty() {
+            to_guest = self.shared.to_guest_cond.wait_timeout(
+                to_guest, Duration::from_millis(timeout_ms as u64)).unwrap().0;
+        }
+        match to_guest.pop_front() {
+            Some(frame) => {
+                if to_guest.len() + 1 == CONGESTED_FRAMES {
+                    // Let the stack read the host sockets again.
+                    self.shared.wake_up();
+                }
+                let count = frame.len().min(buf.len());
+                buf[..count].copy_from_slice(&frame[..count]);
+                Ok(count)
+            },
+            None => Ok(0),
+        }
+    }
+}
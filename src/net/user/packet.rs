@@ -0,0 +1,449 @@
+//! Ethernet, ARP, IPv4, UDP and TCP parsing and building.
+
+extern crate byteorder;
+
+use self::byteorder::{BigEndian, ByteOrder};
+
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+pub type MacAddr = [u8; 6];
+
+pub const BROADCAST_MAC: MacAddr = [0xff; 6];
+
+pub const ETH_HEADER_SIZE: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+pub const ARP_PACKET_SIZE: usize = 28;
+pub const ARP_OP_REQUEST: u16 = 1;
+pub const ARP_OP_REPLY: u16 = 2;
+
+pub const IPV4_HEADER_SIZE: usize = 20;
+pub const IP_PROTO_TCP: u8 = 6;
+pub const IP_PROTO_UDP: u8 = 17;
+const IP_FLAG_DF: u16 = 0x4000;
+const IP_FLAG_MF: u16 = 0x2000;
+const IP_FRAG_MASK: u16 = 0x3fff;
+const IP_TTL: u8 = 64;
+/// The MTU of the guest network, packets sent to the guest never exceed it.
+pub const IP_MTU: usize = 1500;
+
+pub const UDP_HEADER_SIZE: usize = 8;
+pub const MAX_UDP_PAYLOAD: usize = 65535 - IPV4_HEADER_SIZE - UDP_HEADER_SIZE;
+pub const TCP_HEADER_SIZE: usize = 20;
+
+pub const TCP_FIN: u8 = 0x01;
+pub const TCP_SYN: u8 = 0x02;
+pub const TCP_RST: u8 = 0x04;
+pub const TCP_PSH: u8 = 0x08;
+pub const TCP_ACK: u8 = 0x10;
+
+const TCP_OPT_END: u8 = 0;
+const TCP_OPT_NOP: u8 = 1;
+const TCP_OPT_MSS: u8 = 2;
+
+// Identifies the fragments of a datagram.
+static NEXT_IP_ID: AtomicUsize = AtomicUsize::new(0);
+
+fn checksum_add(mut sum: u32, data: &[u8]) -> u32 {
+    let mut chunks = data.chunks(2);
+    while let Some(chunk) = chunks.next() {
+        sum += if chunk.len() == 2 {
+            BigEndian::read_u16(chunk) as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+    }
+    sum
+}
+
+fn checksum_finish(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, len: usize) -> u32 {
+    let sum = checksum_add(0, &src.octets());
+    let sum = checksum_add(sum, &dst.octets());
+    sum + proto as u32 + len as u32
+}
+
+pub struct EthernetFrame<'a> {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        if frame.len() < ETH_HEADER_SIZE {
+            return None;
+        }
+        let mut dst = [0u8; 6];
+        let mut src = [0u8; 6];
+        dst.copy_from_slice(&frame[0..6]);
+        src.copy_from_slice(&frame[6..12]);
+        Some(EthernetFrame {
+            dst: dst,
+            src: src,
+            ethertype: BigEndian::read_u16(&frame[12..]),
+            payload: &frame[ETH_HEADER_SIZE..],
+        })
+    }
+}
+
+pub fn build_ethernet(dst: MacAddr, src: MacAddr, ethertype: u16,
+                      payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0u8; ETH_HEADER_SIZE];
+    frame[0..6].copy_from_slice(&dst);
+    frame[6..12].copy_from_slice(&src);
+    BigEndian::write_u16(&mut frame[12..], ethertype);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+pub struct ArpPacket {
+    pub op: u16,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Only Ethernet/IPv4 packets are accepted.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < ARP_PACKET_SIZE ||
+                BigEndian::read_u16(&data[0..]) != 1 ||
+                BigEndian::read_u16(&data[2..]) != ETHERTYPE_IPV4 {
+            return None;
+        }
+        let mut sender_mac = [0u8; 6];
+        sender_mac.copy_from_slice(&data[8..14]);
+        Some(ArpPacket {
+            op: BigEndian::read_u16(&data[6..]),
+            sender_mac: sender_mac,
+            sender_ip: Ipv4Addr::new(data[14], data[15], data[16], data[17]),
+            target_ip: Ipv4Addr::new(data[24], data[25], data[26], data[27]),
+        })
+    }
+}
+
+pub fn build_arp_reply(sender_mac: MacAddr, sender_ip: Ipv4Addr,
+                       target_mac: MacAddr, target_ip: Ipv4Addr) -> Vec<u8> {
+    let mut data = vec![0u8; ARP_PACKET_SIZE];
+    BigEndian::write_u16(&mut data[0..], 1);
+    BigEndian::write_u16(&mut data[2..], ETHERTYPE_IPV4);
+    data[4] = 6;
+    data[5] = 4;
+    BigEndian::write_u16(&mut data[6..], ARP_OP_REPLY);
+    data[8..14].copy_from_slice(&sender_mac);
+    data[14..18].copy_from_slice(&sender_ip.octets());
+    data[18..24].copy_from_slice(&target_mac);
+    data[24..28].copy_from_slice(&target_ip.octets());
+    data
+}
+
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub proto: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    /// Fragmented packets are not supported.
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < IPV4_HEADER_SIZE || data[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (data[0] & 0xf) as usize * 4;
+        let total_len = BigEndian::read_u16(&data[2..]) as usize;
+        if header_len < IPV4_HEADER_SIZE || total_len < header_len ||
+                total_len > data.len() ||
+                BigEndian::read_u16(&data[6..]) & IP_FRAG_MASK != 0 {
+            return None;
+        }
+        Some(Ipv4Packet {
+            src: Ipv4Addr::new(data[12], data[13], data[14], data[15]),
+            dst: Ipv4Addr::new(data[16], data[17], data[18], data[19]),
+            proto: data[9],
+            payload: &data[header_len..total_len],
+        })
+    }
+}
+
+pub fn build_ipv4(src: Ipv4Addr, dst: Ipv4Addr, proto: u8,
+                  payload: &[u8]) -> Vec<u8> {
+    build_ipv4_fragment(src, dst, proto, 0, IP_FLAG_DF, payload)
+}
+
+/// `fragment` holds the flags and the offset of the fragment.
+fn build_ipv4_fragment(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, id: u16,
+                       fragment: u16, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0u8; IPV4_HEADER_SIZE];
+    packet[0] = 0x45;
+    BigEndian::write_u16(&mut packet[2..],
+                         (IPV4_HEADER_SIZE + payload.len()) as u16);
+    BigEndian::write_u16(&mut packet[4..], id);
+    BigEndian::write_u16(&mut packet[6..], fragment);
+    packet[8] = IP_TTL;
+    packet[9] = proto;
+    packet[12..16].copy_from_slice(&src.octets());
+    packet[16..20].copy_from_slice(&dst.octets());
+    let checksum = checksum_finish(checksum_add(0, &packet));
+    BigEndian::write_u16(&mut packet[10..], checksum);
+    packet.extend_from_slice(payload);
+    packet
+}
+
+pub struct UdpDatagram<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < UDP_HEADER_SIZE {
+            return None;
+        }
+        let len = BigEndian::read_u16(&data[4..]) as usize;
+        if len < UDP_HEADER_SIZE || len > data.len() {
+            return None;
+        }
+        Some(UdpDatagram {
+            src_port: BigEndian::read_u16(&data[0..]),
+            dst_port: BigEndian::read_u16(&data[2..]),
+            payload: &data[UDP_HEADER_SIZE..len],
+        })
+    }
+}
+
+/// Returns the IPv4 packets holding the datagram, fragmented so that they
+/// fit in the MTU. Payloads too large for IPv4 give no packet at all.
+pub fn build_udp(src: Ipv4Addr, src_port: u16, dst: Ipv4Addr, dst_port: u16,
+                 payload: &[u8]) -> Vec<Vec<u8>> {
+    if payload.len() > MAX_UDP_PAYLOAD {
+        return Vec::new();
+    }
+    let len = UDP_HEADER_SIZE + payload.len();
+    let mut datagram = vec![0u8; UDP_HEADER_SIZE];
+    BigEndian::write_u16(&mut datagram[0..], src_port);
+    BigEndian::write_u16(&mut datagram[2..], dst_port);
+    BigEndian::write_u16(&mut datagram[4..], len as u16);
+    datagram.extend_from_slice(payload);
+
+    let sum = pseudo_header_sum(src, dst, IP_PROTO_UDP, len);
+    let checksum = match checksum_finish(checksum_add(sum, &datagram)) {
+        // Zero means that no checksum was computed.
+        0 => 0xffff,
+        checksum => checksum,
+    };
+    BigEndian::write_u16(&mut datagram[6..], checksum);
+
+    if IPV4_HEADER_SIZE + datagram.len() <= IP_MTU {
+        return vec![build_ipv4(src, dst, IP_PROTO_UDP, &datagram)];
+    }
+    // Fragment offsets are counted in 8 byte units.
+    let fragment_size = (IP_MTU - IPV4_HEADER_SIZE) & !7;
+    let id = NEXT_IP_ID.fetch_add(1, Ordering::Relaxed) as u16;
+    datagram.chunks(fragment_size)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let offset = i * fragment_size;
+            let mut fragment = (offset / 8) as u16;
+            if offset + chunk.len() < datagram.len() {
+                fragment |= IP_FLAG_MF;
+            }
+            build_ipv4_fragment(src, dst, IP_PROTO_UDP, id, fragment, chunk)
+        })
+        .collect()
+}
+
+pub struct TcpSegment<'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    pub flags: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> TcpSegment<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        if data.len() < TCP_HEADER_SIZE {
+            return None;
+        }
+        let header_len = (data[12] >> 4) as usize * 4;
+        if header_len < TCP_HEADER_SIZE || header_len > data.len() {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &data[TCP_HEADER_SIZE..header_len];
+        while !options.is_empty() {
+            match options[0] {
+                TCP_OPT_END => break,
+                TCP_OPT_NOP => options = &options[1..],
+                kind => {
+                    if options.len() < 2 || options[1] < 2 ||
+                            options[1] as usize > options.len() {
+                        break;
+                    }
+                    if kind == TCP_OPT_MSS && options[1] == 4 {
+                        mss = Some(BigEndian::read_u16(&options[2..]));
+                    }
+                    options = &options[options[1] as usize..];
+                },
+            }
+        }
+
+        Some(TcpSegment {
+            src_port: BigEndian::read_u16(&data[0..]),
+            dst_port: BigEndian::read_u16(&data[2..]),
+            seq: BigEndian::read_u32(&data[4..]),
+            ack: BigEndian::read_u32(&data[8..]),
+            flags: data[13],
+            window: BigEndian::read_u16(&data[14..]),
+            mss: mss,
+            payload: &data[header_len..],
+        })
+    }
+}
+
+/// Returns the IPv4 packet holding the segment. The MSS option is added
+/// to SYN segments.
+pub fn build_tcp(src: Ipv4Addr, src_port: u16, dst: Ipv4Addr, dst_port: u16,
+                 seq: u32, ack: u32, flags: u8, window: u16, mss: u16,
+                 payload: &[u8]) -> Vec<u8> {
+    let header_len = if flags & TCP_SYN != 0 {
+        TCP_HEADER_SIZE + 4
+    } else {
+        TCP_HEADER_SIZE
+    };
+    let mut segment = vec![0u8; header_len];
+    BigEndian::write_u16(&mut segment[0..], src_port);
+    BigEndian::write_u16(&mut segment[2..], dst_port);
+    BigEndian::write_u32(&mut segment[4..], seq);
+    BigEndian::write_u32(&mut segment[8..], ack);
+    segment[12] = ((header_len / 4) as u8) << 4;
+    segment[13] = flags;
+    BigEndian::write_u16(&mut segment[14..], window);
+    if flags & TCP_SYN != 0 {
+        segment[20] = TCP_OPT_MSS;
+        segment[21] = 4;
+        BigEndian::write_u16(&mut segment[22..], mss);
+    }
+    segment.extend_from_slice(payload);
+
+    let sum = pseudo_header_sum(src, dst, IP_PROTO_TCP, segment.len());
+    let checksum = checksum_finish(checksum_add(sum, &segment));
+    BigEndian::write_u16(&mut segment[16..], checksum);
+
+    build_ipv4(src, dst, IP_PROTO_TCP, &segment)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+    const DST: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+
+    fn header_checksum_ok(packet: &[u8]) -> bool {
+        checksum_finish(checksum_add(0, &packet[..IPV4_HEADER_SIZE])) == 0
+    }
+
+    #[test]
+    fn tcp_round_trip() {
+        let packet = build_tcp(SRC, 80, DST, 1234, 1000, 2000, TCP_SYN | TCP_ACK,
+                               4096, 1460, b"data");
+        assert!(header_checksum_ok(&packet));
+
+        let ip = Ipv4Packet::parse(&packet).unwrap();
+        assert_eq!((ip.src, ip.dst, ip.proto), (SRC, DST, IP_PROTO_TCP));
+        let sum = pseudo_header_sum(SRC, DST, IP_PROTO_TCP, ip.payload.len());
+        assert_eq!(checksum_finish(checksum_add(sum, ip.payload)), 0);
+
+        let segment = TcpSegment::parse(ip.payload).unwrap();
+        assert_eq!((segment.src_port, segment.dst_port), (80, 1234));
+        assert_eq!((segment.seq, segment.ack), (1000, 2000));
+        assert_eq!(segment.flags, TCP_SYN | TCP_ACK);
+        assert_eq!(segment.window, 4096);
+        assert_eq!(segment.mss, Some(1460));
+        assert_eq!(segment.payload, b"data");
+    }
+
+    #[test]
+    fn udp_round_trip() {
+        let packets = build_udp(SRC, 53, DST, 5353, b"reply");
+        assert_eq!(packets.len(), 1);
+
+        let ip = Ipv4Packet::parse(&packets[0]).unwrap();
+        let sum = pseudo_header_sum(SRC, DST, IP_PROTO_UDP, ip.payload.len());
+        assert_eq!(checksum_finish(checksum_add(sum, ip.payload)), 0);
+        let datagram = UdpDatagram::parse(ip.payload).unwrap();
+        assert_eq!((datagram.src_port, datagram.dst_port), (53, 5353));
+        assert_eq!(datagram.payload, b"reply");
+    }
+
+    #[test]
+    fn large_udp_is_fragmented() {
+        let payload = (0..4000).map(|i| i as u8).collect::<Vec<_>>();
+        let packets = build_udp(SRC, 53, DST, 5353, &payload);
+        assert_eq!(packets.len(), 3);
+
+        let mut datagram = Vec::new();
+        for (i, packet) in packets.iter().enumerate() {
+            assert!(packet.len() <= IP_MTU);
+            assert!(header_checksum_ok(packet));
+            assert_eq!(BigEndian::read_u16(&packet[4..]),
+                       BigEndian::read_u16(&packets[0][4..]));
+            let fragment = BigEndian::read_u16(&packet[6..]);
+            assert_eq!(fragment & IP_FLAG_MF != 0, i < packets.len() - 1);
+            assert_eq!((fragment & 0x1fff) as usize * 8, datagram.len());
+            // Fragments are rejected by the parser.
+            assert!(Ipv4Packet::parse(packet).is_none());
+            datagram.extend_from_slice(&packet[IPV4_HEADER_SIZE..]);
+        }
+        let datagram = UdpDatagram::parse(&datagram).unwrap();
+        assert_eq!(datagram.payload, &payload[..]);
+    }
+
+    #[test]
+    fn oversized_udp_is_dropped() {
+        assert!(build_udp(SRC, 53, DST, 5353, &vec![0; MAX_UDP_PAYLOAD + 1])
+                .is_empty());
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let packet = build_tcp(SRC, 80, DST, 1234, 0, 0, TCP_ACK, 0, 0, b"data");
+        assert!(Ipv4Packet::parse(&packet[..IPV4_HEADER_SIZE - 1]).is_none());
+        // The total length covers more than what was received.
+        assert!(Ipv4Packet::parse(&packet[..packet.len() - 1]).is_none());
+        assert!(TcpSegment::parse(&packet[IPV4_HEADER_SIZE..][..10]).is_none());
+
+        let packets = build_udp(SRC, 53, DST, 5353, b"reply");
+        let datagram = &packets[0][IPV4_HEADER_SIZE..];
+        assert!(UdpDatagram::parse(&datagram[..datagram.len() - 1]).is_none());
+        assert!(EthernetFrame::parse(&[0; ETH_HEADER_SIZE - 1]).is_none());
+    }
+
+    #[test]
+    fn arp_reply_parses() {
+        let mac = [1, 2, 3, 4, 5, 6];
+        let reply = build_arp_reply(mac, SRC, BROADCAST_MAC, DST);
+        let arp = ArpPacket::parse(&reply).unwrap();
+        assert_eq!(arp.op, ARP_OP_REPLY);
+        assert_eq!(arp.sender_mac, mac);
+        assert_eq!((arp.sender_ip, arp.target_ip), (SRC, DST));
+        assert!(ArpPacket::parse(&reply[..ARP_PACKET_SIZE - 1]).is_none());
+    }
+}
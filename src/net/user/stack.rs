@@ -0,0 +1,463 @@
+extern crate libc;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, UdpSocket};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::dhcp::{self, DhcpConfig};
+use super::packet::*;
+use super::tcp::TcpConn;
+use super::{HostForward, Protocol, Shared};
+
+pub const NETWORK_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 0);
+pub const NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+pub const GATEWAY_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 2);
+pub const DNS_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+pub const GUEST_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 15);
+const GATEWAY_MAC: MacAddr = [0x52, 0x55, 0x0a, 0x00, 0x02, 0x02];
+
+const DNS_PORT: u16 = 53;
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const POLL_TIMEOUT_MS: i32 = 1000;
+// Source ports used for forwarded connections, as seen by the guest.
+const FIRST_FORWARD_PORT: u16 = 49152;
+// Further connections are refused, each one holds a host socket and
+// pending ones a thread.
+const MAX_TCP_CONNS: usize = 1024;
+const MAX_PENDING_CONNECTS: usize = 64;
+// Each guest port sending UDP datagrams holds a host socket, the least
+// recently used one is closed past this.
+const MAX_UDP_NATS: usize = 256;
+
+/// Outbound UDP traffic of a guest port.
+struct UdpNat {
+    socket: UdpSocket,
+    guest_port: u16,
+    // Host destinations, along with the addresses used by the guest.
+    peers: HashMap<SocketAddrV4, SocketAddrV4>,
+    last_used: Instant,
+}
+
+struct UdpForward {
+    socket: UdpSocket,
+    forward: HostForward,
+    // Guest replies go to the last host peer.
+    last_peer: Option<SocketAddr>,
+}
+
+pub struct UserStack {
+    shared: Arc<Shared>,
+    guest_mac: Option<MacAddr>,
+    dns_server: Ipv4Addr,
+    // Whether the gateway address maps to the host loopback.
+    loopback: bool,
+    dhcp_config: DhcpConfig,
+    tcp_conns: Vec<TcpConn>,
+    tcp_listeners: Vec<(TcpListener, HostForward)>,
+    udp_nats: HashMap<u16, UdpNat>,
+    udp_forwards: Vec<UdpForward>,
+    next_forward_port: u16,
+    // IPv4 packets to be sent to the guest.
+    out: Vec<Vec<u8>>,
+}
+
+/// Uses the first IPv4 name server configured on the host.
+fn host_dns_server() -> Ipv4Addr {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+    resolv_conf.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            match (fields.next(), fields.next()) {
+                (Some("nameserver"), Some(addr)) => addr.parse::<Ipv4Addr>().ok(),
+                _ => None,
+            }
+        })
+        .next()
+        .unwrap_or(Ipv4Addr::new(127, 0, 0, 1))
+}
+
+fn in_guest_network(addr: Ipv4Addr) -> bool {
+    let mask = u32::from(NETMASK);
+    u32::from(addr) & mask == u32::from(NETWORK_ADDR) & mask
+}
+
+impl UserStack {
+    pub fn new(shared: Arc<Shared>, forwards: &[HostForward],
+               loopback: bool) -> Self {
+        let mut stack = UserStack {
+            shared: shared,
+            guest_mac: None,
+            dns_server: host_dns_server(),
+            loopback: loopback,
+            dhcp_config: DhcpConfig {
+                guest: GUEST_ADDR,
+                netmask: NETMASK,
+                gateway: GATEWAY_ADDR,
+                dns: DNS_ADDR,
+            },
+            tcp_conns: Vec::new(),
+            tcp_listeners: Vec::new(),
+            udp_nats: HashMap::new(),
+            udp_forwards: Vec::new(),
+            next_forward_port: FIRST_FORWARD_PORT,
+            out: Vec::new(),
+        };
+
+        for forward in forwards {
+            let host_addr = SocketAddrV4::new(forward.host_addr, forward.host_port);
+            match forward.protocol {
+                Protocol::Tcp => {
+                    let listener = TcpListener::bind(host_addr).unwrap_or_else(
+                        |_| panic!("Cannot listen on {}", host_addr));
+                    listener.set_nonblocking(true).unwrap();
+                    stack.tcp_listeners.push((listener, *forward));
+                },
+                Protocol::Udp => {
+                    let socket = UdpSocket::bind(host_addr).unwrap_or_else(
+                        |_| panic!("Cannot bind {}", host_addr));
+                    socket.set_nonblocking(true).unwrap();
+                    stack.udp_forwards.push(UdpForward {
+                        socket: socket,
+                        forward: *forward,
+                        last_peer: None,
+                    });
+                },
+            }
+        }
+
+        stack
+    }
+
+    pub fn run(&mut self) {
+        loop {
+            // Data stays in the host sockets while the guest lags behind.
+            let can_read = !self.shared.is_congested();
+            self.poll(can_read);
+
+            let frames = self.shared.from_guest.lock().unwrap()
+                             .drain(..).collect::<Vec<_>>();
+            for frame in frames {
+                self.handle_frame(&frame);
+            }
+
+            self.accept_connections();
+            for conn in self.tcp_conns.iter_mut() {
+                conn.pump(&mut self.out, can_read);
+            }
+            self.tcp_conns.retain(|conn| !conn.is_closed());
+            if can_read {
+                self.receive_udp();
+            }
+
+            self.flush_to_guest();
+        }
+    }
+
+    /// Waits for guest frames, host socket events or the next TCP
+    /// retransmission.
+    fn poll(&self, can_read: bool) {
+        let pollfd = |fd: RawFd, events: libc::c_short| libc::pollfd {
+            fd: fd,
+            events: events,
+            revents: 0,
+        };
+
+        let mut pollfds = vec![pollfd(self.shared.wakeup_fd, libc::POLLIN)];
+        for &(ref listener, _) in self.tcp_listeners.iter() {
+            pollfds.push(pollfd(listener.as_raw_fd(), libc::POLLIN));
+        }
+        if can_read {
+            for nat in self.udp_nats.values() {
+                pollfds.push(pollfd(nat.socket.as_raw_fd(), libc::POLLIN));
+            }
+            for forward in self.udp_forwards.iter() {
+                pollfds.push(pollfd(forward.socket.as_raw_fd(), libc::POLLIN));
+            }
+        }
+        for conn in self.tcp_conns.iter() {
+            if let Some(fd) = conn.host_fd() {
+                let (read, write) = conn.poll_events(can_read);
+                let mut events = 0;
+                if read {
+                    events |= libc::POLLIN;
+                }
+                if write {
+                    events |= libc::POLLOUT;
+                }
+                pollfds.push(pollfd(fd, events));
+            }
+        }
+
+        let now = Instant::now();
+        let timeout_ms = self.tcp_conns.iter()
+            .filter_map(|conn| conn.deadline())
+            .map(|deadline| {
+                let delay = if deadline > now {
+                    deadline - now
+                } else {
+                    Duration::from_secs(0)
+                };
+                // Rounded up, so that the deadline has passed on wake up.
+                delay.as_secs() as i32 * 1000 +
+                    ((delay.subsec_nanos() + 999_999) / 1_000_000) as i32
+            })
+            .fold(POLL_TIMEOUT_MS, |timeout, delay| timeout.min(delay));
+
+        unsafe {
+            libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t,
+                       timeout_ms);
+            let mut value: u64 = 0;
+            libc::read(self.shared.wakeup_fd,
+                       &mut value as *mut u64 as *mut libc::c_void, 8);
+        }
+    }
+
+    fn flush_to_guest(&mut self) {
+        let dst_mac = self.guest_mac.unwrap_or(BROADCAST_MAC);
+        for packet in self.out.drain(..) {
+            self.shared.push_to_guest(
+                build_ethernet(dst_mac, GATEWAY_MAC, ETHERTYPE_IPV4, &packet));
+        }
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        let frame = match EthernetFrame::parse(frame) {
+            Some(frame) => frame,
+            None => return,
+        };
+        if frame.dst != GATEWAY_MAC && frame.dst != BROADCAST_MAC {
+            return;
+        }
+        self.guest_mac = Some(frame.src);
+
+        match frame.ethertype {
+            ETHERTYPE_ARP => self.handle_arp(frame.payload),
+            ETHERTYPE_IPV4 => self.handle_ipv4(frame.payload),
+            _ => (),
+        }
+    }
+
+    fn handle_arp(&mut self, data: &[u8]) {
+        let arp = match ArpPacket::parse(data) {
+            Some(arp) => arp,
+            None => return,
+        };
+        // Every other address of the guest network belongs to the gateway.
+        if arp.op != ARP_OP_REQUEST || !in_guest_network(arp.target_ip) ||
+                arp.target_ip == arp.sender_ip {
+            return;
+        }
+
+        let reply = build_arp_reply(GATEWAY_MAC, arp.target_ip,
+                                    arp.sender_mac, arp.sender_ip);
+        self.shared.push_to_guest(
+            build_ethernet(arp.sender_mac, GATEWAY_MAC, ETHERTYPE_ARP, &reply));
+    }
+
+    fn handle_ipv4(&mut self, data: &[u8]) {
+        let packet = match Ipv4Packet::parse(data) {
+            Some(packet) => packet,
+            None => return,
+        };
+
+        match packet.proto {
+            IP_PROTO_UDP => {
+                if let Some(datagram) = UdpDatagram::parse(packet.payload) {
+                    self.handle_udp(&packet, &datagram);
+                }
+            },
+            IP_PROTO_TCP => {
+                if let Some(segment) = TcpSegment::parse(packet.payload) {
+                    self.handle_tcp(&packet, &segment);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Returns the host address used for a destination of the guest.
+    fn host_address(&self, dst: Ipv4Addr, port: u16) -> Option<SocketAddrV4> {
+        if dst == GATEWAY_ADDR && self.loopback {
+            Some(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port))
+        } else if dst == DNS_ADDR && port == DNS_PORT {
+            Some(SocketAddrV4::new(self.dns_server, DNS_PORT))
+        } else if in_guest_network(dst) || dst.is_broadcast() ||
+                  dst.is_multicast() || dst.is_unspecified() ||
+                  dst.is_loopback() {
+            None
+        } else {
+            Some(SocketAddrV4::new(dst, port))
+        }
+    }
+
+    fn handle_udp(&mut self, packet: &Ipv4Packet, datagram: &UdpDatagram) {
+        if datagram.dst_port == dhcp::DHCP_SERVER_PORT {
+            if let Some(reply) = dhcp::handle_message(&self.dhcp_config,
+                                                      datagram.payload) {
+                self.out.extend(build_udp(GATEWAY_ADDR, dhcp::DHCP_SERVER_PORT,
+                                        Ipv4Addr::new(255, 255, 255, 255),
+                                        dhcp::DHCP_CLIENT_PORT, &reply));
+            }
+            return;
+        }
+
+        // Replies to forwarded datagrams.
+        if packet.dst == GATEWAY_ADDR {
+            let forward = self.udp_forwards.iter().find(|f| {
+                f.forward.host_port == datagram.dst_port &&
+                f.forward.guest_port == datagram.src_port
+            });
+            if let Some(forward) = forward {
+                if let Some(peer) = forward.last_peer {
+                    let _ = forward.socket.send_to(datagram.payload, peer);
+                }
+                return;
+            }
+        }
+
+        let host_addr = match self.host_address(packet.dst, datagram.dst_port) {
+            Some(host_addr) => host_addr,
+            None => return,
+        };
+
+        if !self.udp_nats.contains_key(&datagram.src_port) {
+            if self.udp_nats.len() >= MAX_UDP_NATS {
+                let oldest = self.udp_nats.iter()
+                                          .min_by_key(|&(_, nat)| nat.last_used)
+                                          .map(|(port, _)| *port)
+                                          .unwrap();
+                self.udp_nats.remove(&oldest);
+            }
+            let socket = match UdpSocket::bind("0.0.0.0:0") {
+                Ok(socket) => socket,
+                Err(e) => {
+                    println!("slirp: cannot create UDP socket: {}", e);
+                    return;
+                },
+            };
+            socket.set_nonblocking(true).unwrap();
+            self.udp_nats.insert(datagram.src_port, UdpNat {
+                socket: socket,
+                guest_port: datagram.src_port,
+                peers: HashMap::new(),
+                last_used: Instant::now(),
+            });
+        }
+
+        let nat = self.udp_nats.get_mut(&datagram.src_port).unwrap();
+        nat.peers.insert(host_addr, SocketAddrV4::new(packet.dst, datagram.dst_port));
+        nat.last_used = Instant::now();
+        let _ = nat.socket.send_to(datagram.payload, host_addr);
+    }
+
+    fn receive_udp(&mut self) {
+        let mut buf = [0u8; 65536];
+
+        for nat in self.udp_nats.values_mut() {
+            while let Ok((count, src)) = nat.socket.recv_from(&mut buf) {
+                let src = match src {
+                    SocketAddr::V4(src) => src,
+                    SocketAddr::V6(_) => continue,
+                };
+                let virtual_src = match nat.peers.get(&src) {
+                    Some(virtual_src) => *virtual_src,
+                    None if src.ip().is_loopback() =>
+                        SocketAddrV4::new(GATEWAY_ADDR, src.port()),
+                    None => src,
+                };
+                nat.last_used = Instant::now();
+                self.out.extend(build_udp(*virtual_src.ip(), virtual_src.port(),
+                                        GUEST_ADDR, nat.guest_port,
+                                        &buf[..count]));
+            }
+        }
+        self.udp_nats.retain(|_, nat| nat.last_used.elapsed() < UDP_IDLE_TIMEOUT);
+
+        for forward in self.udp_forwards.iter_mut() {
+            while let Ok((count, src)) = forward.socket.recv_from(&mut buf) {
+                forward.last_peer = Some(src);
+                self.out.extend(build_udp(GATEWAY_ADDR, forward.forward.host_port,
+                                        forward.forward.guest_addr,
+                                        forward.forward.guest_port,
+                                        &buf[..count]));
+            }
+        }
+    }
+
+    fn handle_tcp(&mut self, packet: &Ipv4Packet, segment: &TcpSegment) {
+        let guest = SocketAddrV4::new(packet.src, segment.src_port);
+        let remote = SocketAddrV4::new(packet.dst, segment.dst_port);
+
+        if let Some(conn) = self.tcp_conns.iter_mut()
+                                .find(|c| c.guest == guest && c.remote == remote) {
+            conn.handle_segment(segment, &mut self.out);
+            return;
+        }
+
+        if segment.flags & TCP_RST != 0 {
+            return;
+        }
+        let connecting = self.tcp_conns.iter().filter(|c| c.is_connecting()).count();
+        let host_addr = match self.host_address(packet.dst, segment.dst_port) {
+            Some(host_addr) if segment.flags & (TCP_SYN | TCP_ACK) == TCP_SYN &&
+                               self.tcp_conns.len() < MAX_TCP_CONNS &&
+                               connecting < MAX_PENDING_CONNECTS => host_addr,
+            _ => {
+                self.send_reset(guest, remote, segment);
+                return;
+            },
+        };
+
+        let shared = self.shared.clone();
+        self.tcp_conns.push(TcpConn::outbound(guest, remote, host_addr, segment,
+                                              move || shared.wake_up()));
+    }
+
+    /// Refuses a segment that doesn't belong to any connection.
+    fn send_reset(&mut self, guest: SocketAddrV4, remote: SocketAddrV4,
+                  segment: &TcpSegment) {
+        let (seq, ack, flags) = if segment.flags & TCP_ACK != 0 {
+            (segment.ack, 0, TCP_RST)
+        } else {
+            let mut len = segment.payload.len() as u32;
+            if segment.flags & (TCP_SYN | TCP_FIN) != 0 {
+                len += 1;
+            }
+            (0, segment.seq.wrapping_add(len), TCP_RST | TCP_ACK)
+        };
+        self.out.push(build_tcp(*remote.ip(), remote.port(), *guest.ip(),
+                                guest.port(), seq, ack, flags, 0, 0, &[]));
+    }
+
+    fn allocate_forward_port(&mut self) -> u16 {
+        let port = self.next_forward_port;
+        self.next_forward_port = match port {
+            65535 => FIRST_FORWARD_PORT,
+            port => port + 1,
+        };
+        port
+    }
+
+    fn accept_connections(&mut self) {
+        let mut accepted = Vec::new();
+        for &(ref listener, ref forward) in self.tcp_listeners.iter() {
+            while let Ok((stream, _)) = listener.accept() {
+                accepted.push((stream, *forward));
+            }
+        }
+
+        for (stream, forward) in accepted {
+            if self.tcp_conns.len() >= MAX_TCP_CONNS {
+                // Closes the host connection.
+                continue;
+            }
+            let remote = SocketAddrV4::new(GATEWAY_ADDR, self.allocate_forward_port());
+            let guest = SocketAddrV4::new(forward.guest_addr, forward.guest_port);
+            let conn = TcpConn::inbound(guest, remote, stream, &mut self.out);
+            self.tcp_conns.push(conn);
+        }
+    }
+}
@@ -0,0 +1,572 @@
+//! TCP connections between the guest and host sockets. Frames sent to
+//! the guest may be dropped when it doesn't pick them up in time, so the
+//! data is kept until the guest acknowledges it and retransmitted after
+//! a timeout.
+
+use std::cmp;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, SocketAddrV4, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::packet::*;
+
+pub const TCP_MSS: usize = 1460;
+// Data from the guest that wasn't written to the host yet.
+const MAX_PENDING_TO_HOST: usize = 65535;
+// Data from the host that the guest didn't acknowledge yet. The host
+// socket isn't read while it is full.
+const MAX_PENDING_TO_GUEST: usize = 65535;
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+const INITIAL_RTO_MS: u64 = 200;
+const MAX_RTO_MS: u64 = 10_000;
+// The connection is reset after this many retransmissions in a row.
+const MAX_RETRANSMITS: u32 = 8;
+
+enum TcpState {
+    /// Connecting to the host on behalf of the guest.
+    Connecting(Receiver<io::Result<TcpStream>>),
+    /// SYN sent to the guest, for forwarded host connections.
+    SynSent,
+    /// SYN-ACK sent to the guest.
+    SynReceived,
+    Established,
+    Closed,
+}
+
+fn initial_seq() -> u32 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (now.as_secs() as u32).wrapping_mul(1_000_000).wrapping_add(now.subsec_nanos())
+}
+
+/// Returns true if a <= b, handling sequence number wrap around.
+fn seq_le(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) >= 0
+}
+
+fn seq_lt(a: u32, b: u32) -> bool {
+    a != b && seq_le(a, b)
+}
+
+pub struct TcpConn {
+    /// The guest endpoint.
+    pub guest: SocketAddrV4,
+    /// The remote endpoint, as seen by the guest.
+    pub remote: SocketAddrV4,
+    state: TcpState,
+    host: Option<TcpStream>,
+    // Send sequence space, towards the guest. snd_nxt goes back to
+    // snd_una on retransmissions, snd_max is the highest one sent.
+    snd_una: u32,
+    snd_nxt: u32,
+    snd_max: u32,
+    snd_wnd: u32,
+    // The next sequence number expected from the guest.
+    rcv_nxt: u32,
+    mss: usize,
+    to_host: Vec<u8>,
+    // Data starting at snd_una, once the SYN is acknowledged.
+    to_guest: Vec<u8>,
+    rto: Duration,
+    rto_deadline: Option<Instant>,
+    retransmits: u32,
+    host_eof: bool,
+    fin_sent: bool,
+    guest_fin: bool,
+    host_shutdown: bool,
+}
+
+impl TcpConn {
+    fn new(guest: SocketAddrV4, remote: SocketAddrV4, state: TcpState,
+           host: Option<TcpStream>) -> Self {
+        let iss = initial_seq();
+        TcpConn {
+            guest: guest,
+            remote: remote,
+            state: state,
+            host: host,
+            snd_una: iss,
+            snd_nxt: iss,
+            snd_max: iss,
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            mss: TCP_MSS,
+            to_host: Vec::new(),
+            to_guest: Vec::new(),
+            rto: Duration::from_millis(INITIAL_RTO_MS),
+            rto_deadline: None,
+            retransmits: 0,
+            host_eof: false,
+            fin_sent: false,
+            guest_fin: false,
+            host_shutdown: false,
+        }
+    }
+
+    /// Handles a guest SYN, connecting to `host_addr` in the background.
+    /// `on_connect` is called once the connection attempt completes.
+    pub fn outbound<F>(guest: SocketAddrV4, remote: SocketAddrV4,
+                       host_addr: SocketAddrV4, syn: &TcpSegment,
+                       on_connect: F) -> Self
+        where F: FnOnce() + Send + 'static
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("slirp-connect".to_string())
+            .spawn(move || {
+                let _ = tx.send(TcpStream::connect_timeout(
+                    &SocketAddr::V4(host_addr),
+                    Duration::from_secs(CONNECT_TIMEOUT_SECS)));
+                on_connect();
+            })
+            .unwrap();
+
+        let mut conn = TcpConn::new(guest, remote, TcpState::Connecting(rx), None);
+        conn.rcv_nxt = syn.seq.wrapping_add(1);
+        conn.snd_wnd = syn.window as u32;
+        conn.mss = cmp::min(syn.mss.unwrap_or(536) as usize, TCP_MSS);
+        conn
+    }
+
+    /// Forwards a host connection to the guest.
+    pub fn inbound(guest: SocketAddrV4, remote: SocketAddrV4, stream: TcpStream,
+                   out: &mut Vec<Vec<u8>>) -> Self {
+        let mut conn = TcpConn::new(guest, remote, TcpState::SynSent, None);
+        if stream.set_nonblocking(true).is_err() {
+            conn.state = TcpState::Closed;
+            return conn;
+        }
+        conn.host = Some(stream);
+
+        conn.send_syn(out);
+        conn
+    }
+
+    pub fn is_closed(&self) -> bool {
+        match self.state {
+            TcpState::Closed => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        match self.state {
+            TcpState::Connecting(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn host_fd(&self) -> Option<RawFd> {
+        self.host.as_ref().map(|host| host.as_raw_fd())
+    }
+
+    /// The poll events the connection is waiting for. The host socket
+    /// isn't read when `can_read` is false.
+    pub fn poll_events(&self, can_read: bool) -> (bool, bool) {
+        match self.state {
+            TcpState::Established => {
+                (can_read && !self.host_eof &&
+                 self.to_guest.len() < MAX_PENDING_TO_GUEST,
+                 !self.to_host.is_empty())
+            },
+            _ => (false, false),
+        }
+    }
+
+    /// When the next retransmission is due, if any.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.rto_deadline
+    }
+
+    fn rcv_window(&self) -> u16 {
+        (MAX_PENDING_TO_HOST - self.to_host.len()) as u16
+    }
+
+    fn send(&self, out: &mut Vec<Vec<u8>>, seq: u32, flags: u8, payload: &[u8]) {
+        let flags = match self.state {
+            // Only the initial SYN doesn't acknowledge anything.
+            TcpState::SynSent => flags,
+            _ => flags | TCP_ACK,
+        };
+        out.push(build_tcp(*self.remote.ip(), self.remote.port(),
+                           *self.guest.ip(), self.guest.port(),
+                           seq, self.rcv_nxt, flags, self.rcv_window(),
+                           TCP_MSS as u16, payload));
+    }
+
+    fn send_syn(&mut self, out: &mut Vec<Vec<u8>>) {
+        let iss = self.snd_una;
+        self.send(out, iss, TCP_SYN, &[]);
+        self.snd_nxt = iss.wrapping_add(1);
+        self.snd_max = self.snd_nxt;
+        self.start_timer();
+    }
+
+    fn start_timer(&mut self) {
+        if self.rto_deadline.is_none() {
+            self.rto_deadline = Some(Instant::now() + self.rto);
+        }
+    }
+
+    fn send_ack(&self, out: &mut Vec<Vec<u8>>) {
+        let seq = self.snd_nxt;
+        self.send(out, seq, TCP_ACK, &[]);
+    }
+
+    fn reset(&mut self, out: &mut Vec<Vec<u8>>) {
+        let seq = self.snd_nxt;
+        self.send(out, seq, TCP_RST, &[]);
+        self.state = TcpState::Closed;
+    }
+
+    pub fn handle_segment(&mut self, seg: &TcpSegment, out: &mut Vec<Vec<u8>>) {
+        if seg.flags & TCP_RST != 0 {
+            self.state = TcpState::Closed;
+            return;
+        }
+
+        match self.state {
+            TcpState::Connecting(_) | TcpState::Closed => return,
+            TcpState::SynSent => {
+                if seg.flags & (TCP_SYN | TCP_ACK) != TCP_SYN | TCP_ACK ||
+                        seg.ack != self.snd_nxt {
+                    return;
+                }
+                self.rcv_nxt = seg.seq.wrapping_add(1);
+                self.snd_wnd = seg.window as u32;
+                self.mss = cmp::min(seg.mss.unwrap_or(536) as usize, TCP_MSS);
+                self.state = TcpState::Established;
+                self.syn_acked();
+                self.send_ack(out);
+                return;
+            },
+            TcpState::SynReceived => {
+                if seg.flags & TCP_SYN != 0 {
+                    // Our SYN-ACK got lost.
+                    let seq = self.snd_una;
+                    self.send(out, seq, TCP_SYN, &[]);
+                    return;
+                }
+                if seg.flags & TCP_ACK == 0 || seg.ack != self.snd_nxt {
+                    return;
+                }
+                self.state = TcpState::Established;
+                self.syn_acked();
+            },
+            TcpState::Established => (),
+        }
+
+        if seg.flags & TCP_ACK != 0 && seq_le(self.snd_una, seg.ack) &&
+                seq_le(seg.ack, self.snd_max) {
+            self.handle_ack(seg.ack);
+            self.snd_wnd = seg.window as u32;
+        }
+
+        let mut need_ack = false;
+        if !seg.payload.is_empty() {
+            if seg.seq == self.rcv_nxt && !self.guest_fin {
+                let count = cmp::min(seg.payload.len(), self.rcv_window() as usize);
+                self.to_host.extend_from_slice(&seg.payload[..count]);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(count as u32);
+            }
+            // Out of order or retransmitted data is acknowledged again.
+            need_ack = true;
+        }
+        if seg.flags & TCP_FIN != 0 && !self.guest_fin &&
+                seg.seq.wrapping_add(seg.payload.len() as u32) == self.rcv_nxt {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.guest_fin = true;
+            need_ack = true;
+        }
+        if need_ack {
+            self.send_ack(out);
+        }
+    }
+
+    // The SYN occupies the first sequence number, the data follows it.
+    fn syn_acked(&mut self) {
+        self.snd_una = self.snd_nxt;
+        self.rto = Duration::from_millis(INITIAL_RTO_MS);
+        self.rto_deadline = None;
+        self.retransmits = 0;
+    }
+
+    fn handle_ack(&mut self, ack: u32) {
+        let acked = ack.wrapping_sub(self.snd_una) as usize;
+        if acked == 0 {
+            return;
+        }
+        let data = cmp::min(acked, self.to_guest.len());
+        self.to_guest.drain(..data);
+        if acked > data {
+            // The FIN was received, even if it is being retransmitted.
+            self.fin_sent = true;
+        }
+        self.snd_una = ack;
+        if seq_lt(self.snd_nxt, ack) {
+            self.snd_nxt = ack;
+        }
+
+        self.rto = Duration::from_millis(INITIAL_RTO_MS);
+        self.retransmits = 0;
+        self.rto_deadline = None;
+        if self.snd_una != self.snd_max {
+            self.start_timer();
+        }
+    }
+
+    /// Moves data between the host socket and the guest. The host socket
+    /// isn't read when `can_read` is false.
+    pub fn pump(&mut self, out: &mut Vec<Vec<u8>>, can_read: bool) {
+        let connect_result = match self.state {
+            TcpState::Connecting(ref rx) => match rx.try_recv() {
+                Ok(result) => Some(result),
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => Some(Err(io::Error::new(
+                    io::ErrorKind::Other, "connection attempt aborted"))),
+            },
+            TcpState::SynSent | TcpState::SynReceived => {
+                self.check_timer(out);
+                return;
+            },
+            TcpState::Established => None,
+            TcpState::Closed => return,
+        };
+
+        if let Some(result) = connect_result {
+            match result.and_then(|s| s.set_nonblocking(true).map(|_| s)) {
+                Ok(stream) => {
+                    self.host = Some(stream);
+                    self.state = TcpState::SynReceived;
+                    self.send_syn(out);
+                },
+                Err(_) => self.reset(out),
+            }
+            return;
+        }
+
+        if let Err(_) = self.flush_to_host(out) {
+            self.reset(out);
+            return;
+        }
+        if can_read {
+            if let Err(_) = self.read_from_host() {
+                self.reset(out);
+                return;
+            }
+        }
+        self.check_timer(out);
+        if self.is_closed() {
+            return;
+        }
+        self.transmit(out);
+
+        if self.fin_sent && self.snd_una == self.snd_max && self.guest_fin &&
+                self.to_host.is_empty() {
+            self.state = TcpState::Closed;
+        }
+    }
+
+    /// Goes back to the oldest unacknowledged segment once the
+    /// retransmission timer expires.
+    fn check_timer(&mut self, out: &mut Vec<Vec<u8>>) {
+        match self.rto_deadline {
+            Some(deadline) if Instant::now() >= deadline => (),
+            _ => return,
+        }
+        self.retransmits += 1;
+        if self.retransmits > MAX_RETRANSMITS {
+            self.reset(out);
+            return;
+        }
+        self.rto = cmp::min(self.rto * 2, Duration::from_millis(MAX_RTO_MS));
+        self.rto_deadline = Some(Instant::now() + self.rto);
+
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => {
+                let iss = self.snd_una;
+                self.send(out, iss, TCP_SYN, &[]);
+            },
+            TcpState::Established => {
+                // The timer only runs while something is unacknowledged,
+                // so an outstanding FIN is sent again as well.
+                self.snd_nxt = self.snd_una;
+                self.fin_sent = false;
+            },
+            _ => (),
+        }
+    }
+
+    /// Sends the data that fits in the guest window, then the FIN.
+    fn transmit(&mut self, out: &mut Vec<Vec<u8>>) {
+        loop {
+            let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+            let available = (self.snd_wnd as usize).saturating_sub(in_flight);
+            let count = cmp::min(cmp::min(available, self.mss),
+                                 self.to_guest.len().saturating_sub(in_flight));
+            if count == 0 {
+                break;
+            }
+
+            let seq = self.snd_nxt;
+            self.send(out, seq, TCP_PSH, &self.to_guest[in_flight..in_flight + count]);
+            self.snd_nxt = seq.wrapping_add(count as u32);
+            self.start_timer();
+        }
+
+        let in_flight = self.snd_nxt.wrapping_sub(self.snd_una);
+        if self.host_eof && !self.fin_sent &&
+                in_flight as usize == self.to_guest.len() &&
+                in_flight < self.snd_wnd.max(1) {
+            let seq = self.snd_nxt;
+            self.send(out, seq, TCP_FIN, &[]);
+            self.snd_nxt = seq.wrapping_add(1);
+            self.fin_sent = true;
+            self.start_timer();
+        }
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+    }
+
+    fn flush_to_host(&mut self, out: &mut Vec<Vec<u8>>) -> io::Result<()> {
+        let host = match self.host {
+            Some(ref mut host) => host,
+            None => return Ok(()),
+        };
+
+        let old_window = MAX_PENDING_TO_HOST - self.to_host.len();
+        let mut written = 0;
+        while written < self.to_host.len() {
+            match host.write(&self.to_host[written..]) {
+                Ok(0) => break,
+                Ok(count) => written += count,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        self.to_host.drain(..written);
+
+        if self.guest_fin && self.to_host.is_empty() && !self.host_shutdown {
+            let _ = host.shutdown(Shutdown::Write);
+            self.host_shutdown = true;
+        }
+        // Let the guest know that the window opened.
+        if written > 0 && old_window < self.mss {
+            self.send_ack(out);
+        }
+        Ok(())
+    }
+
+    fn read_from_host(&mut self) -> io::Result<()> {
+        let mut buf = [0u8; 16384];
+        while !self.host_eof && self.to_guest.len() < MAX_PENDING_TO_GUEST {
+            let count = cmp::min(buf.len(),
+                                 MAX_PENDING_TO_GUEST - self.to_guest.len());
+            let result = self.host.as_mut().unwrap().read(&mut buf[..count]);
+            match result {
+                Ok(0) => self.host_eof = true,
+                Ok(count) => self.to_guest.extend_from_slice(&buf[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    const GUEST_ISS: u32 = 5000;
+
+    /// Returns the sequence number, flags and payload of each segment.
+    fn segments(out: &[Vec<u8>]) -> Vec<(u32, u8, Vec<u8>)> {
+        out.iter()
+            .map(|packet| {
+                let ip = Ipv4Packet::parse(packet).unwrap();
+                let segment = TcpSegment::parse(ip.payload).unwrap();
+                (segment.seq, segment.flags, segment.payload.to_vec())
+            })
+            .collect()
+    }
+
+    fn guest_segment(seq: u32, ack: u32, flags: u8) -> TcpSegment<'static> {
+        TcpSegment {
+            src_port: 22,
+            dst_port: 49152,
+            seq: seq,
+            ack: ack,
+            flags: flags,
+            window: 65535,
+            mss: Some(1460),
+            payload: &[],
+        }
+    }
+
+    /// Pumps the connection until it sends something.
+    fn pump_until_sent(conn: &mut TcpConn) -> Vec<Vec<u8>> {
+        let start = Instant::now();
+        let mut out = Vec::new();
+        while out.is_empty() && start.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(1));
+            conn.pump(&mut out, true);
+        }
+        out
+    }
+
+    #[test]
+    fn lost_segments_are_retransmitted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let guest = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 15), 22);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 49152);
+        let mut out = Vec::new();
+        let mut conn = TcpConn::inbound(guest, remote, stream, &mut out);
+        let iss = segments(&out)[0].0;
+        assert_eq!(segments(&out), vec![(iss, TCP_SYN, vec![])]);
+
+        // The SYN is lost.
+        let syn = out.clone();
+        out.clear();
+        thread::sleep(Duration::from_millis(INITIAL_RTO_MS));
+        conn.pump(&mut out, true);
+        assert_eq!(out, syn);
+
+        out.clear();
+        conn.handle_segment(&guest_segment(GUEST_ISS, iss + 1, TCP_SYN | TCP_ACK),
+                            &mut out);
+        assert!(conn.deadline().is_none());
+        client.write_all(b"hello").unwrap();
+        let data = pump_until_sent(&mut conn);
+        assert_eq!(segments(&data),
+                   vec![(iss + 1, TCP_PSH | TCP_ACK, b"hello".to_vec())]);
+
+        // The data is lost.
+        out.clear();
+        thread::sleep(Duration::from_millis(INITIAL_RTO_MS));
+        conn.pump(&mut out, true);
+        assert_eq!(out, data);
+
+        out.clear();
+        conn.handle_segment(&guest_segment(GUEST_ISS + 1, iss + 6, TCP_ACK), &mut out);
+        assert!(conn.deadline().is_none());
+        conn.pump(&mut out, true);
+        assert!(out.is_empty());
+
+        // The host isn't read while the guest is congested.
+        drop(client);
+        thread::sleep(Duration::from_millis(10));
+        conn.pump(&mut out, false);
+        assert!(out.is_empty());
+        let fin = pump_until_sent(&mut conn);
+        assert_eq!(segments(&fin), vec![(iss + 6, TCP_FIN | TCP_ACK, vec![])]);
+        assert!(conn.deadline().is_some());
+    }
+}
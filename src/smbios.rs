@@ -0,0 +1,625 @@
+//! SMBIOS tables describing the machine, published to the firmware through
+//! fw_cfg or placed in the BIOS area when the kernel is booted directly.
+
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use devices::fw_cfg::FWCfgDev;
+use memory::GuestMemory;
+use utils::options::parse_key_values;
+
+const ANCHOR_FILE: &str = "etc/smbios/smbios-anchor";
+const TABLES_FILE: &str = "etc/smbios/smbios-tables";
+
+// Where the tables are placed for direct kernel boot. The OSes scan
+// 0xf0000-0xfffff for the entry point.
+const ENTRY_POINT_ADDRESS: u64 = 0xf_0000;
+const BIOS_AREA_END: u64 = 0x10_0000;
+
+// 64-bit (SMBIOS 3.0) entry point.
+const ENTRY_POINT_SIZE: usize = 24;
+const ENTRY_POINT_CHECKSUM_OFFSET: usize = 5;
+const ENTRY_POINT_TABLES_ADDRESS_OFFSET: usize = 16;
+const SMBIOS_MAJOR: u8 = 3;
+const SMBIOS_MINOR: u8 = 0;
+
+// Structure types.
+const TYPE_BIOS_INFORMATION: u8 = 0;
+const TYPE_SYSTEM_INFORMATION: u8 = 1;
+const TYPE_CHASSIS: u8 = 3;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_OEM_STRINGS: u8 = 11;
+const TYPE_PHYSICAL_MEMORY_ARRAY: u8 = 16;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_MEMORY_ARRAY_MAPPED_ADDRESS: u8 = 19;
+const TYPE_SYSTEM_BOOT: u8 = 32;
+const TYPE_END_OF_TABLE: u8 = 127;
+
+const BIOS_CHARACTERISTICS_NOT_SUPPORTED: u64 = 1 << 3;
+const BIOS_CHARACTERISTICS_EXT2_VIRTUAL_MACHINE: u8 = 1 << 4;
+const BIOS_RELEASE_DATE: &str = "01/01/2018";
+
+const WAKE_UP_POWER_SWITCH: u8 = 0x06;
+const CHASSIS_TYPE_OTHER: u8 = 0x01;
+const CHASSIS_STATE_SAFE: u8 = 0x03;
+const CHASSIS_SECURITY_UNKNOWN: u8 = 0x02;
+const PROCESSOR_TYPE_CENTRAL: u8 = 0x03;
+const PROCESSOR_FAMILY_OTHER: u8 = 0x01;
+const PROCESSOR_STATUS_ENABLED: u8 = 0x41;
+const PROCESSOR_UPGRADE_OTHER: u8 = 0x01;
+const PROCESSOR_CHARACTERISTICS_UNKNOWN: u16 = 0x02;
+const PROCESSOR_SPEED_MHZ: u16 = 2000;
+const MEMORY_ARRAY_LOCATION_OTHER: u8 = 0x01;
+const MEMORY_ARRAY_USE_SYSTEM: u8 = 0x03;
+const MEMORY_ARRAY_ECC_NONE: u8 = 0x03;
+const MEMORY_FORM_FACTOR_DIMM: u8 = 0x09;
+const MEMORY_TYPE_RAM: u8 = 0x07;
+const MEMORY_TYPE_DETAIL_OTHER: u16 = 0x02;
+const NO_HANDLE: u16 = 0xffff;
+const NO_ERROR_INFORMATION: u16 = 0xfffe;
+// Sizes which don't fit the legacy fields use the extended ones.
+const MEMORY_SIZE_EXTENDED_MB: u16 = 0x7fff;
+const MEMORY_CAPACITY_EXTENDED_KB: u32 = 0x8000_0000;
+const MEMORY_ADDRESS_EXTENDED_KB: u32 = 0xffff_ffff;
+
+/// Values overriding the defaults, as passed through the --smbios option.
+pub struct SmbiosConfig {
+    pub manufacturer: String,
+    pub product: String,
+    pub version: String,
+    pub serial: String,
+    pub uuid: [u8; 16],
+    pub oem_strings: Vec<String>,
+}
+
+/// Parses a UUID in the "xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx" format,
+/// returning its bytes in big-endian order.
+fn parse_uuid(value: &str) -> [u8; 16] {
+    let digits = value.replace("-", "");
+    let groups = value.split('-').map(|g| g.len()).collect::<Vec<_>>();
+    if groups != [8, 4, 4, 4, 12] || !digits.chars().all(|c| c.is_digit(16)) {
+        panic!("Invalid UUID: {}", value);
+    }
+
+    let mut uuid = [0u8; 16];
+    for (i, byte) in uuid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    uuid
+}
+
+impl SmbiosConfig {
+    pub fn new() -> Self {
+        SmbiosConfig {
+            manufacturer: "insula".to_string(),
+            product: "insula".to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            serial: String::new(),
+            uuid: [0; 16],
+            oem_strings: Vec::new(),
+        }
+    }
+
+    /// Parses a [manufacturer=<name>][,product=<name>][,version=<version>]
+    /// [,serial=<serial>][,uuid=<uuid>][,oem-string=<string>]... spec.
+    pub fn parse(spec: &str) -> Self {
+        let mut config = SmbiosConfig::new();
+        for (key, value) in parse_key_values(spec) {
+            match key.as_str() {
+                "manufacturer" => config.manufacturer = value,
+                "product" => config.product = value,
+                "version" => config.version = value,
+                "serial" => config.serial = value,
+                "uuid" => config.uuid = parse_uuid(&value),
+                "oem-string" => config.oem_strings.push(value),
+                _ => panic!("Unknown SMBIOS option: {}", key),
+            }
+        }
+        config
+    }
+}
+
+/// A structure along with its string set. Strings are referenced by
+/// their 1-based index, 0 meaning no string.
+struct Structure {
+    data: Vec<u8>,
+    strings: Vec<String>,
+}
+
+impl Structure {
+    fn new(structure_type: u8, handle: u16) -> Self {
+        let mut data = vec![structure_type, 0, 0, 0];
+        LittleEndian::write_u16(&mut data[2..], handle);
+        Structure {
+            data: data,
+            strings: Vec::new(),
+        }
+    }
+
+    fn add_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn add_u16(&mut self, value: u16) {
+        let mut bytes = [0u8; 2];
+        LittleEndian::write_u16(&mut bytes, value);
+        self.data.extend_from_slice(&bytes);
+    }
+
+    fn add_u32(&mut self, value: u32) {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, value);
+        self.data.extend_from_slice(&bytes);
+    }
+
+    fn add_u64(&mut self, value: u64) {
+        let mut bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bytes, value);
+        self.data.extend_from_slice(&bytes);
+    }
+
+    /// Adds a string reference.
+    fn add_string(&mut self, value: &str) {
+        if value.is_empty() {
+            self.add_u8(0);
+        } else {
+            self.strings.push(value.to_string());
+            let index = self.strings.len() as u8;
+            self.add_u8(index);
+        }
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.data[1] = self.data.len() as u8;
+        for string in &self.strings {
+            self.data.extend_from_slice(string.as_bytes());
+            self.data.push(0);
+        }
+        // The string set ends with an additional null byte, or two of them
+        // if there are no strings.
+        if self.strings.is_empty() {
+            self.data.push(0);
+        }
+        self.data.push(0);
+        self.data
+    }
+}
+
+pub struct SmbiosTables {
+    anchor: Vec<u8>,
+    tables: Vec<u8>,
+}
+
+fn bios_information(handle: u16) -> Structure {
+    let mut s = Structure::new(TYPE_BIOS_INFORMATION, handle);
+    s.add_string("insula");
+    s.add_string(env!("CARGO_PKG_VERSION"));
+    // BIOS starting address segment.
+    s.add_u16(0xe800);
+    s.add_string(BIOS_RELEASE_DATE);
+    // ROM size.
+    s.add_u8(0);
+    s.add_u64(BIOS_CHARACTERISTICS_NOT_SUPPORTED);
+    s.add_u8(0);
+    s.add_u8(BIOS_CHARACTERISTICS_EXT2_VIRTUAL_MACHINE);
+    // System BIOS and embedded controller firmware releases.
+    s.add_u8(0);
+    s.add_u8(0);
+    s.add_u8(0xff);
+    s.add_u8(0xff);
+    s
+}
+
+fn system_information(handle: u16, config: &SmbiosConfig) -> Structure {
+    let mut s = Structure::new(TYPE_SYSTEM_INFORMATION, handle);
+    s.add_string(&config.manufacturer);
+    s.add_string(&config.product);
+    s.add_string(&config.version);
+    s.add_string(&config.serial);
+    // The first three UUID fields are little-endian.
+    let mut uuid = config.uuid;
+    uuid[0..4].reverse();
+    uuid[4..6].reverse();
+    uuid[6..8].reverse();
+    s.data.extend_from_slice(&uuid);
+    s.add_u8(WAKE_UP_POWER_SWITCH);
+    // SKU number and family.
+    s.add_string("");
+    s.add_string("");
+    s
+}
+
+fn chassis(handle: u16, config: &SmbiosConfig) -> Structure {
+    let mut s = Structure::new(TYPE_CHASSIS, handle);
+    s.add_string(&config.manufacturer);
+    s.add_u8(CHASSIS_TYPE_OTHER);
+    s.add_string(&config.version);
+    s.add_string(&config.serial);
+    // Asset tag.
+    s.add_string("");
+    // Boot-up, power supply and thermal states.
+    s.add_u8(CHASSIS_STATE_SAFE);
+    s.add_u8(CHASSIS_STATE_SAFE);
+    s.add_u8(CHASSIS_STATE_SAFE);
+    s.add_u8(CHASSIS_SECURITY_UNKNOWN);
+    // OEM defined, height, power cords and contained elements.
+    s.add_u32(0);
+    s.add_u8(0);
+    s.add_u8(0);
+    s.add_u8(0);
+    s.add_u8(0);
+    s
+}
+
+/// Each vCPU is described as a single core processor.
+fn processor(handle: u16, index: usize, config: &SmbiosConfig) -> Structure {
+    let mut s = Structure::new(TYPE_PROCESSOR, handle);
+    s.add_string(&format!("CPU {}", index));
+    s.add_u8(PROCESSOR_TYPE_CENTRAL);
+    s.add_u8(PROCESSOR_FAMILY_OTHER);
+    s.add_string(&config.manufacturer);
+    // Processor ID.
+    s.add_u64(0);
+    // Version and voltage.
+    s.add_string("");
+    s.add_u8(0);
+    // External clock, max and current speeds.
+    s.add_u16(0);
+    s.add_u16(PROCESSOR_SPEED_MHZ);
+    s.add_u16(PROCESSOR_SPEED_MHZ);
+    s.add_u8(PROCESSOR_STATUS_ENABLED);
+    s.add_u8(PROCESSOR_UPGRADE_OTHER);
+    // No cache information.
+    s.add_u16(NO_HANDLE);
+    s.add_u16(NO_HANDLE);
+    s.add_u16(NO_HANDLE);
+    // Serial number, asset tag and part number.
+    s.add_string("");
+    s.add_string("");
+    s.add_string("");
+    // Core count, enabled cores and thread count.
+    s.add_u8(1);
+    s.add_u8(1);
+    s.add_u8(1);
+    s.add_u16(PROCESSOR_CHARACTERISTICS_UNKNOWN);
+    s.add_u16(PROCESSOR_FAMILY_OTHER as u16);
+    s.add_u16(1);
+    s.add_u16(1);
+    s.add_u16(1);
+    s
+}
+
+fn oem_strings(handle: u16, config: &SmbiosConfig) -> Structure {
+    let mut s = Structure::new(TYPE_OEM_STRINGS, handle);
+    s.add_u8(config.oem_strings.len() as u8);
+    for string in &config.oem_strings {
+        s.strings.push(string.clone());
+    }
+    s
+}
+
+fn physical_memory_array(handle: u16, ram_size: u64) -> Structure {
+    let mut s = Structure::new(TYPE_PHYSICAL_MEMORY_ARRAY, handle);
+    s.add_u8(MEMORY_ARRAY_LOCATION_OTHER);
+    s.add_u8(MEMORY_ARRAY_USE_SYSTEM);
+    s.add_u8(MEMORY_ARRAY_ECC_NONE);
+    let capacity_kb = ram_size >> 10;
+    if capacity_kb < MEMORY_CAPACITY_EXTENDED_KB as u64 {
+        s.add_u32(capacity_kb as u32);
+    } else {
+        s.add_u32(MEMORY_CAPACITY_EXTENDED_KB);
+    }
+    s.add_u16(NO_ERROR_INFORMATION);
+    // A single memory device.
+    s.add_u16(1);
+    s.add_u64(ram_size);
+    s
+}
+
+fn memory_device(handle: u16, array_handle: u16, ram_size: u64,
+                 config: &SmbiosConfig) -> Structure {
+    let mut s = Structure::new(TYPE_MEMORY_DEVICE, handle);
+    s.add_u16(array_handle);
+    s.add_u16(NO_ERROR_INFORMATION);
+    // Unknown total and data widths.
+    s.add_u16(0xffff);
+    s.add_u16(0xffff);
+    let size_mb = ram_size >> 20;
+    if size_mb < MEMORY_SIZE_EXTENDED_MB as u64 {
+        s.add_u16(size_mb as u16);
+    } else {
+        s.add_u16(MEMORY_SIZE_EXTENDED_MB);
+    }
+    s.add_u8(MEMORY_FORM_FACTOR_DIMM);
+    // Device set.
+    s.add_u8(0);
+    s.add_string("DIMM 0");
+    // Bank locator.
+    s.add_string("");
+    s.add_u8(MEMORY_TYPE_RAM);
+    s.add_u16(MEMORY_TYPE_DETAIL_OTHER);
+    // Unknown speed.
+    s.add_u16(0);
+    s.add_string(&config.manufacturer);
+    // Serial number, asset tag and part number.
+    s.add_string("");
+    s.add_string("");
+    s.add_string("");
+    // Attributes.
+    s.add_u8(0);
+    s.add_u32(size_mb as u32);
+    // Configured speed and voltages.
+    s.add_u16(0);
+    s.add_u16(0);
+    s.add_u16(0);
+    s.add_u16(0);
+    s
+}
+
+fn memory_array_mapped_address(handle: u16, array_handle: u16,
+                               start: u64, size: u64) -> Structure {
+    let mut s = Structure::new(TYPE_MEMORY_ARRAY_MAPPED_ADDRESS, handle);
+    let start_kb = start >> 10;
+    let end_kb = ((start + size) >> 10) - 1;
+    if end_kb < MEMORY_ADDRESS_EXTENDED_KB as u64 {
+        s.add_u32(start_kb as u32);
+        s.add_u32(end_kb as u32);
+    } else {
+        s.add_u32(MEMORY_ADDRESS_EXTENDED_KB);
+        s.add_u32(MEMORY_ADDRESS_EXTENDED_KB);
+    }
+    s.add_u16(array_handle);
+    // Partition width.
+    s.add_u8(1);
+    s.add_u64(start);
+    s.add_u64(start + size - 1);
+    s
+}
+
+fn system_boot(handle: u16) -> Structure {
+    let mut s = Structure::new(TYPE_SYSTEM_BOOT, handle);
+    s.data.extend_from_slice(&[0; 6]);
+    // No errors detected.
+    s.add_u8(0);
+    s
+}
+
+impl SmbiosTables {
+    /// `ram_ranges` lists the (start, size) guest RAM ranges.
+    pub fn new(config: &SmbiosConfig, vcpu_count: usize,
+               ram_ranges: &[(u64, u64)]) -> Self {
+        let ram_size = ram_ranges.iter().map(|&(_, size)| size).sum();
+
+        let mut structures = vec![
+            bios_information(0),
+            system_information(1, config),
+            chassis(2, config),
+        ];
+        for i in 0..vcpu_count {
+            let handle = structures.len() as u16;
+            structures.push(processor(handle, i, config));
+        }
+        if !config.oem_strings.is_empty() {
+            let handle = structures.len() as u16;
+            structures.push(oem_strings(handle, config));
+        }
+
+        let array_handle = structures.len() as u16;
+        structures.push(physical_memory_array(array_handle, ram_size));
+        structures.push(memory_device(array_handle + 1, array_handle,
+                                      ram_size, config));
+        for &(start, size) in ram_ranges {
+            let handle = structures.len() as u16;
+            structures.push(memory_array_mapped_address(handle, array_handle,
+                                                        start, size));
+        }
+
+        let handle = structures.len() as u16;
+        structures.push(system_boot(handle));
+        structures.push(Structure::new(TYPE_END_OF_TABLE, handle + 1));
+
+        let tables = structures.into_iter()
+                               .flat_map(|s| s.into_bytes())
+                               .collect::<Vec<_>>();
+
+        // The firmware fills in the tables address and the checksum.
+        let mut anchor = vec![0u8; ENTRY_POINT_SIZE];
+        anchor[0..5].copy_from_slice(b"_SM3_");
+        anchor[6] = ENTRY_POINT_SIZE as u8;
+        anchor[7] = SMBIOS_MAJOR;
+        anchor[8] = SMBIOS_MINOR;
+        // Entry point revision.
+        anchor[10] = 1;
+        LittleEndian::write_u32(&mut anchor[12..16], tables.len() as u32);
+
+        SmbiosTables {
+            anchor: anchor,
+            tables: tables,
+        }
+    }
+
+    /// Lets the firmware install the tables.
+    pub fn add_to_fw_cfg(&self, fw_cfg: &mut FWCfgDev) {
+        fw_cfg.add_file(ANCHOR_FILE, &self.anchor, self.anchor.len() as u32);
+        fw_cfg.add_file(TABLES_FILE, &self.tables, self.tables.len() as u32);
+    }
+
+    /// Places the tables in guest memory, for direct kernel boot.
+    pub fn install(&self, mem: &GuestMemory) {
+        let tables_address = ENTRY_POINT_ADDRESS + ENTRY_POINT_SIZE as u64;
+        if tables_address + self.tables.len() as u64 > BIOS_AREA_END {
+            panic!("The SMBIOS tables don't fit the BIOS area.");
+        }
+
+        let mut anchor = self.anchor.clone();
+        LittleEndian::write_u64(&mut anchor[ENTRY_POINT_TABLES_ADDRESS_OFFSET..],
+                                tables_address);
+        let sum = anchor.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        anchor[ENTRY_POINT_CHECKSUM_OFFSET] = 0u8.wrapping_sub(sum);
+
+        if !mem.write(ENTRY_POINT_ADDRESS, &anchor) ||
+                !mem.write(tables_address, &self.tables) {
+            panic!("Cannot write the SMBIOS tables.");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::MmapMemorySlot;
+
+    /// Splits the tables into (type, handle, formatted area, strings).
+    fn parse_tables(tables: &[u8]) -> Vec<(u8, u16, Vec<u8>, Vec<String>)> {
+        let mut structures = Vec::new();
+        let mut offset = 0;
+        while offset < tables.len() {
+            let len = tables[offset + 1] as usize;
+            let data = tables[offset..offset + len].to_vec();
+            offset += len;
+            let mut strings = Vec::new();
+            loop {
+                let end = offset + tables[offset..].iter()
+                                                   .position(|b| *b == 0)
+                                                   .unwrap();
+                if end == offset {
+                    break;
+                }
+                strings.push(String::from_utf8(
+                    tables[offset..end].to_vec()).unwrap());
+                offset = end + 1;
+            }
+            // The string set terminator, doubled when there are no strings.
+            offset += if strings.is_empty() { 2 } else { 1 };
+            structures.push((data[0], LittleEndian::read_u16(&data[2..4]),
+                             data, strings));
+        }
+        assert_eq!(offset, tables.len());
+        structures
+    }
+
+    #[test]
+    fn uuids() {
+        assert_eq!(parse_uuid("00112233-4455-6677-8899-aabbccddeeff"),
+                   [0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77,
+                    0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid UUID")]
+    fn uuid_bad_groups() {
+        parse_uuid("0011223-34455-6677-8899-aabbccddeeff");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid UUID")]
+    fn uuid_bad_digits() {
+        parse_uuid("0011223g-4455-6677-8899-aabbccddeeff");
+    }
+
+    #[test]
+    fn parse_options() {
+        let config = SmbiosConfig::parse(
+            "manufacturer=acme,serial=42,oem-string=a,oem-string=b");
+        assert_eq!(config.manufacturer, "acme");
+        assert_eq!(config.product, "insula");
+        assert_eq!(config.serial, "42");
+        assert_eq!(config.oem_strings, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn string_sets() {
+        let mut s = Structure::new(TYPE_SYSTEM_BOOT, 7);
+        s.add_string("");
+        assert_eq!(s.into_bytes(), vec![TYPE_SYSTEM_BOOT, 5, 7, 0, 0, 0, 0]);
+
+        let mut s = Structure::new(TYPE_CHASSIS, 0x102);
+        s.add_string("ab");
+        s.add_string("");
+        s.add_string("c");
+        assert_eq!(s.into_bytes(), vec![TYPE_CHASSIS, 7, 0x02, 0x01, 1, 0, 2,
+                                        b'a', b'b', 0, b'c', 0, 0]);
+    }
+
+    #[test]
+    fn structures() {
+        let mut config = SmbiosConfig::parse(
+            "serial=1234,uuid=00112233-4455-6677-8899-aabbccddeeff,\
+             oem-string=hello");
+        config.version = "1.0".to_string();
+        let ranges = [(0, 0xc000_0000), (0x1_0000_0000, 0x4000_0000)];
+        let smbios = SmbiosTables::new(&config, 2, &ranges);
+        let structures = parse_tables(&smbios.tables);
+
+        let types = structures.iter().map(|s| s.0).collect::<Vec<_>>();
+        assert_eq!(types, vec![TYPE_BIOS_INFORMATION, TYPE_SYSTEM_INFORMATION,
+                               TYPE_CHASSIS, TYPE_PROCESSOR, TYPE_PROCESSOR,
+                               TYPE_OEM_STRINGS, TYPE_PHYSICAL_MEMORY_ARRAY,
+                               TYPE_MEMORY_DEVICE,
+                               TYPE_MEMORY_ARRAY_MAPPED_ADDRESS,
+                               TYPE_MEMORY_ARRAY_MAPPED_ADDRESS,
+                               TYPE_SYSTEM_BOOT, TYPE_END_OF_TABLE]);
+        for (i, s) in structures.iter().enumerate() {
+            assert_eq!(s.1 as usize, i);
+        }
+
+        let system = &structures[1];
+        assert_eq!(system.3, vec!["insula", "insula", "1.0", "1234"]);
+        // The first three UUID fields are swapped.
+        assert_eq!(&system.2[8..24],
+                   &[0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66,
+                     0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert_eq!(structures[4].3, vec!["CPU 1", "insula"]);
+        assert_eq!(structures[5].2[4], 1);
+        assert_eq!(structures[5].3, vec!["hello"]);
+
+        // 4GB of RAM, in MB.
+        let memory_device = &structures[7];
+        assert_eq!(LittleEndian::read_u16(&memory_device.2[12..14]), 4096);
+        let mapped = &structures[9];
+        assert_eq!(LittleEndian::read_u32(&mapped.2[4..8]), 0x40_0000);
+        assert_eq!(LittleEndian::read_u32(&mapped.2[8..12]), 0x50_0000 - 1);
+        assert_eq!(LittleEndian::read_u64(&mapped.2[23..31]), 0x1_4000_0000 - 1);
+    }
+
+    #[test]
+    fn extended_sizes() {
+        let config = SmbiosConfig::new();
+        let ranges = [(0, 0xc000_0000), (0x1_0000_0000, 0x3ff_4000_0000)];
+        let smbios = SmbiosTables::new(&config, 1, &ranges);
+        let structures = parse_tables(&smbios.tables);
+
+        let array = &structures[4];
+        assert_eq!(array.0, TYPE_PHYSICAL_MEMORY_ARRAY);
+        assert_eq!(LittleEndian::read_u32(&array.2[7..11]),
+                   MEMORY_CAPACITY_EXTENDED_KB);
+        assert_eq!(LittleEndian::read_u64(&array.2[15..23]), 4 << 40);
+        let memory_device = &structures[5];
+        assert_eq!(LittleEndian::read_u16(&memory_device.2[12..14]),
+                   MEMORY_SIZE_EXTENDED_MB);
+        assert_eq!(LittleEndian::read_u32(&memory_device.2[28..32]), 4 << 20);
+        let mapped = &structures[7];
+        assert_eq!(LittleEndian::read_u32(&mapped.2[4..8]),
+                   MEMORY_ADDRESS_EXTENDED_KB);
+    }
+
+    #[test]
+    fn install() {
+        let slot = MmapMemorySlot::new(BIOS_AREA_END as usize, 0, 0, 0);
+        let mem = GuestMemory::new(&[&slot]);
+        let smbios = SmbiosTables::new(&SmbiosConfig::new(), 1,
+                                       &[(0, 64 << 20)]);
+        smbios.install(&mem);
+
+        let mut anchor = [0u8; ENTRY_POINT_SIZE];
+        assert!(mem.read(ENTRY_POINT_ADDRESS, &mut anchor));
+        assert_eq!(&anchor[0..5], b"_SM3_");
+        assert_eq!(anchor.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)), 0);
+        let tables_address = LittleEndian::read_u64(
+            &anchor[ENTRY_POINT_TABLES_ADDRESS_OFFSET..]);
+        let mut tables = vec![0u8; smbios.tables.len()];
+        assert!(mem.read(tables_address, &mut tables));
+        assert!(tables == smbios.tables);
+    }
+}
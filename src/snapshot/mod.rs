@@ -0,0 +1,344 @@
+//! VM snapshots: the vCPUs, the accelerator state and the devices are
+//! saved to a file, the guest RAM to "<path>.ram". Restoring requires
+//! the machine to be created using the same arguments.
+
+mod pause;
+pub mod state;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+
+use ::accel::{Vcpu, VmState};
+use ::control::ControlServer;
+use ::devices::bus::DeviceState;
+use ::memory::GuestMemory;
+use self::state::{StateReader, StateWriter};
+
+pub use self::pause::VcpuPauser;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"INSULASN";
+const SNAPSHOT_VERSION: u32 = 1;
+
+const MACHINE_SECTION: &str = "machine";
+const VM_SECTION: &str = "vm";
+
+// The guest RAM is copied using chunks of this size.
+const RAM_CHUNK_SIZE: usize = 1 << 20;
+
+/// Returns the path of the file holding the guest RAM.
+pub fn ram_path(path: &str) -> String {
+    format!("{}.ram", path)
+}
+
+fn tmp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+/// Named blobs, in the order they were saved.
+struct SnapshotFile {
+    sections: Vec<(String, Vec<u8>)>,
+}
+
+impl SnapshotFile {
+    fn new() -> Self {
+        SnapshotFile {
+            sections: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, name: &str, data: Vec<u8>) {
+        self.sections.push((name.to_string(), data));
+    }
+
+    fn section(&self, name: &str) -> &[u8] {
+        self.sections.iter()
+            .find(|&&(ref section_name, _)| section_name == name)
+            .map(|&(_, ref data)| &data[..])
+            .unwrap_or_else(
+                || panic!("Invalid snapshot: missing section: {}", name))
+    }
+
+    fn write(&self, path: &str) -> io::Result<()> {
+        let mut state = StateWriter::new();
+        state.write_u32(SNAPSHOT_VERSION);
+        state.write_u32(self.sections.len() as u32);
+        for &(ref name, ref data) in &self.sections {
+            state.write_str(name);
+            state.write_bytes(data);
+        }
+
+        let mut file = File::create(path)?;
+        file.write_all(SNAPSHOT_MAGIC)?;
+        file.write_all(&state.into_bytes())?;
+        file.sync_all()
+    }
+
+    fn read(path: &str) -> Self {
+        let mut data = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut data))
+            .unwrap_or_else(
+                |_| panic!("Cannot read the snapshot \"{}\".", path));
+        if !data.starts_with(SNAPSHOT_MAGIC) {
+            panic!("\"{}\" is not a snapshot.", path);
+        }
+
+        let mut state = StateReader::new(&data[SNAPSHOT_MAGIC.len()..]);
+        let version = state.read_u32();
+        if version != SNAPSHOT_VERSION {
+            panic!("Unsupported snapshot version: {}", version);
+        }
+        let mut snapshot = SnapshotFile::new();
+        for _ in 0..state.read_u32() {
+            let name = state.read_str();
+            snapshot.add(&name, state.read_bytes().to_vec());
+        }
+        snapshot
+    }
+}
+
+/// Describes the machine, making sure that snapshots are restored using
+/// a matching configuration.
+fn machine_state(vcpu_count: usize, ram_ranges: &[(u64, u64)]) -> Vec<u8> {
+    let mut state = StateWriter::new();
+    state.write_u32(vcpu_count as u32);
+    state.write_u32(ram_ranges.len() as u32);
+    for &(start, size) in ram_ranges {
+        state.write_u64(start);
+        state.write_u64(size);
+    }
+    state.into_bytes()
+}
+
+pub struct Snapshotter {
+    pauser: Arc<VcpuPauser>,
+    vm_state: Arc<VmState>,
+    vcpu_count: usize,
+    mem: GuestMemory,
+    ram_ranges: Vec<(u64, u64)>,
+    devices: Vec<(String, Arc<Mutex<DeviceState>>)>,
+    // Only one snapshot may be taken at a time.
+    lock: Mutex<()>,
+}
+
+impl Snapshotter {
+    pub fn new(pauser: Arc<VcpuPauser>, vm_state: Arc<VmState>,
+               vcpu_count: usize, mem: GuestMemory,
+               ram_ranges: Vec<(u64, u64)>) -> Self {
+        Snapshotter {
+            pauser: pauser,
+            vm_state: vm_state,
+            vcpu_count: vcpu_count,
+            mem: mem,
+            ram_ranges: ram_ranges,
+            devices: Vec::new(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    pub fn pauser(&self) -> Arc<VcpuPauser> {
+        self.pauser.clone()
+    }
+
+    /// Adds a device to the snapshots. The name identifies its state.
+    pub fn add_device(&mut self, name: &str, device: Arc<Mutex<DeviceState>>) {
+        if self.devices.iter().any(|&(ref n, _)| n == name) {
+            panic!("Duplicate snapshot device name: {}", name);
+        }
+        self.devices.push((name.to_string(), device));
+    }
+
+    pub fn add_control_commands(snapshotter: &Arc<Snapshotter>,
+                                control: &mut ControlServer) {
+        let snapshotter = snapshotter.clone();
+        control.add_command("snapshot", "<path>", Box::new(move |args| {
+            match args {
+                [path] => snapshotter.save(path).map(|_| String::new()),
+                _ => Err("usage: snapshot <path>".to_string()),
+            }
+        }));
+    }
+
+    /// Pauses the VM while saving it, the VM keeps running afterwards.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let _guard = self.lock.lock().unwrap();
+
+        self.pauser.pause();
+        let result = self.save_paused(path);
+        for &(_, ref device) in &self.devices {
+            device.lock().unwrap().resume();
+        }
+        self.pauser.resume();
+
+        result
+    }
+
+    fn save_paused(&self, path: &str) -> Result<(), String> {
+        let mut snapshot = SnapshotFile::new();
+        snapshot.add(MACHINE_SECTION,
+                     machine_state(self.vcpu_count, &self.ram_ranges));
+
+        // Saving the devices stops them. The vCPUs and the interrupt
+        // controllers are saved afterwards, once the device threads can't
+        // raise interrupts anymore.
+        let mut device_states = Vec::new();
+        for &(ref name, ref device) in &self.devices {
+            let device_state = device.lock().unwrap().save_state()
+                .map_err(|e| format!("{}: {}", name, e))?;
+            device_states.push((format!("device:{}", name), device_state));
+        }
+        for (i, vcpu_state) in self.pauser.save_states().into_iter()
+                                                        .enumerate() {
+            snapshot.add(&format!("vcpu{}", i), vcpu_state);
+        }
+        for (name, device_state) in device_states {
+            snapshot.add(&name, device_state);
+        }
+        snapshot.add(VM_SECTION, self.vm_state.save_state());
+
+        // Both files are written aside, then the previous state file is
+        // removed before the RAM is renamed and the new state file last:
+        // a save interrupted in between leaves no state file rather than
+        // a state file paired with another RAM file. VMs restored lazily
+        // keep the previous RAM file mapped, it is replaced rather than
+        // overwritten.
+        let ram_path = ram_path(path);
+        let ram_tmp_path = tmp_path(&ram_path);
+        let tmp_path = tmp_path(path);
+        self.save_ram(&ram_tmp_path)
+            .map_err(|e| format!("cannot save the guest RAM: {}", e))?;
+        snapshot.write(&tmp_path)
+            .map_err(|e| format!("cannot write the snapshot: {}", e))?;
+        match fs::remove_file(path) {
+            Err(ref e) if e.kind() != io::ErrorKind::NotFound => {
+                return Err(format!("cannot replace the snapshot: {}", e));
+            },
+            _ => {},
+        }
+        fs::rename(&ram_tmp_path, &ram_path)
+            .and_then(|_| fs::rename(&tmp_path, path))
+            .map_err(|e| format!("cannot replace the snapshot: {}", e))
+    }
+
+    fn save_ram(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        let mut buf = vec![0u8; RAM_CHUNK_SIZE];
+        for &(start, size) in &self.ram_ranges {
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(RAM_CHUNK_SIZE as u64) as usize;
+                if !self.mem.read(start + offset, &mut buf[..len]) {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              "invalid guest RAM range"));
+                }
+                file.write_all(&buf[..len])?;
+                offset += len as u64;
+            }
+        }
+        file.sync_all()
+    }
+
+    fn restore_ram(&self, path: &str) {
+        let mut file = File::open(path).unwrap_or_else(
+            |_| panic!("Cannot open the snapshot RAM \"{}\".", path));
+        let mut buf = vec![0u8; RAM_CHUNK_SIZE];
+        for &(start, size) in &self.ram_ranges {
+            let mut offset = 0;
+            while offset < size {
+                let len = (size - offset).min(RAM_CHUNK_SIZE as u64) as usize;
+                file.read_exact(&mut buf[..len]).expect(
+                    "Invalid snapshot: the guest RAM is truncated.");
+                self.mem.write(start + offset, &buf[..len]);
+                offset += len as u64;
+            }
+        }
+    }
+
+    /// Loads a snapshot before the vCPUs start running. The guest RAM is
+    /// copied unless `load_ram` is false, when it is mapped from the
+    /// snapshot by the RAM backend.
+    pub fn restore(&self, path: &str, vcpus: &mut [Box<Vcpu>],
+                   load_ram: bool) {
+        let snapshot = SnapshotFile::read(path);
+        if snapshot.section(MACHINE_SECTION) !=
+                &machine_state(self.vcpu_count, &self.ram_ranges)[..] {
+            panic!("The snapshot was taken using a different number of \
+                    vCPUs or memory size.");
+        }
+
+        // The devices find their queues in the guest RAM. The interrupt
+        // controllers come before the LAPICs and the devices, which may
+        // raise interrupts.
+        if load_ram {
+            self.restore_ram(&ram_path(path));
+        }
+        self.vm_state.restore_state(snapshot.section(VM_SECTION));
+        for vcpu in vcpus.iter_mut() {
+            vcpu.restore_state(
+                snapshot.section(&format!("vcpu{}", vcpu.index())));
+        }
+        for &(ref name, ref device) in &self.devices {
+            device.lock().unwrap().restore_state(
+                snapshot.section(&format!("device:{}", name)));
+        }
+        println!("Restored snapshot \"{}\".", path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::utils::test_dir::TestDir;
+
+    fn write_snapshot(path: &str) {
+        let mut snapshot = SnapshotFile::new();
+        snapshot.add(MACHINE_SECTION, machine_state(2, &[(0, 1 << 20)]));
+        snapshot.add("vcpu0", vec![1, 2, 3]);
+        snapshot.add("empty", Vec::new());
+        snapshot.write(path).unwrap();
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = TestDir::new("snapshot-round-trip");
+        let path = dir.file("vm");
+        write_snapshot(&path);
+
+        let snapshot = SnapshotFile::read(&path);
+        assert_eq!(snapshot.section(MACHINE_SECTION),
+                   &machine_state(2, &[(0, 1 << 20)])[..]);
+        assert_eq!(snapshot.section("vcpu0"), &[1, 2, 3]);
+        assert_eq!(snapshot.section("empty"), &[] as &[u8]);
+    }
+
+    #[test]
+    #[should_panic(expected = "missing section")]
+    fn missing_section() {
+        let dir = TestDir::new("snapshot-missing-section");
+        let path = dir.file("vm");
+        write_snapshot(&path);
+        SnapshotFile::read(&path).section("vcpu1");
+    }
+
+    #[test]
+    #[should_panic(expected = "truncated state")]
+    fn truncated_file() {
+        let dir = TestDir::new("snapshot-truncated");
+        let path = dir.file("vm");
+        write_snapshot(&path);
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap()
+            .set_len(len - 1).unwrap();
+        SnapshotFile::read(&path);
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a snapshot")]
+    fn bad_magic() {
+        let dir = TestDir::new("snapshot-bad-magic");
+        let path = dir.file("vm");
+        fs::write(&path, b"INSULA\0\0\x01\0\0\0\0\0\0\0").unwrap();
+        SnapshotFile::read(&path);
+    }
+}
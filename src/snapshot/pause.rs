@@ -0,0 +1,111 @@
+extern crate libc;
+
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use ::accel::Vcpu;
+use ::utils::posix::os;
+
+// How often the vCPUs are kicked while waiting for them to stop, in case
+// the signal arrived right before they entered the guest.
+const KICK_INTERVAL_MS: u64 = 10;
+
+struct PauseState {
+    paused: bool,
+    // How many vCPUs are waiting in `check`.
+    parked: usize,
+    // Set while the parked vCPUs are asked to save their state.
+    saving: bool,
+    // The state saved by each vCPU.
+    vcpu_states: Vec<Option<Vec<u8>>>,
+}
+
+/// Stops the vCPU threads. Their states are saved separately, once the
+/// devices which may raise interrupts are stopped too.
+pub struct VcpuPauser {
+    state: Mutex<PauseState>,
+    cond: Condvar,
+    threads: Mutex<Vec<Option<libc::pthread_t>>>,
+}
+
+impl VcpuPauser {
+    pub fn new(vcpu_count: usize) -> Self {
+        os::register_kick_handler();
+
+        VcpuPauser {
+            state: Mutex::new(PauseState {
+                paused: false,
+                parked: 0,
+                saving: false,
+                vcpu_states: vec![None; vcpu_count],
+            }),
+            cond: Condvar::new(),
+            threads: Mutex::new(vec![None; vcpu_count]),
+        }
+    }
+
+    /// Records the calling thread as the one running the given vCPU.
+    pub fn register_thread(&self, vcpu_index: usize) {
+        self.threads.lock().unwrap()[vcpu_index] = Some(os::current_thread());
+    }
+
+    /// Called by the vCPU threads between exits. Blocks while the vCPUs
+    /// are paused, saving the vCPU state when asked to.
+    pub fn check(&self, vcpu: &mut Vcpu) {
+        let mut state = self.state.lock().unwrap();
+        if !state.paused {
+            return;
+        }
+
+        state.parked += 1;
+        self.cond.notify_all();
+        while state.paused {
+            if state.saving && state.vcpu_states[vcpu.index()].is_none() {
+                state.vcpu_states[vcpu.index()] = Some(vcpu.save_state());
+                self.cond.notify_all();
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+        state.parked -= 1;
+    }
+
+    fn kick_all(&self) {
+        for thread in self.threads.lock().unwrap().iter() {
+            if let Some(thread) = *thread {
+                os::kick_thread(thread);
+            }
+        }
+    }
+
+    /// Stops all the vCPUs.
+    pub fn pause(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = true;
+
+        while state.parked < state.vcpu_states.len() {
+            self.kick_all();
+            state = self.cond.wait_timeout(
+                state, Duration::from_millis(KICK_INTERVAL_MS)).unwrap().0;
+        }
+    }
+
+    /// Has the paused vCPUs save their states, returning them.
+    pub fn save_states(&self) -> Vec<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.saving = true;
+        self.cond.notify_all();
+
+        while state.vcpu_states.iter().any(|s| s.is_none()) {
+            state = self.cond.wait(state).unwrap();
+        }
+
+        state.saving = false;
+        state.vcpu_states.iter_mut().map(|s| s.take().unwrap()).collect()
+    }
+
+    pub fn resume(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = false;
+        self.cond.notify_all();
+    }
+}
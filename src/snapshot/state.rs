@@ -0,0 +1,209 @@
+//! Encoding of the saved state, using little-endian integers and length
+//! prefixed byte strings.
+
+extern crate byteorder;
+
+use self::byteorder::{ByteOrder, LittleEndian};
+
+use std::mem::{self, size_of};
+use std::slice;
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter {
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        let mut bytes = [0u8; 2];
+        LittleEndian::write_u16(&mut bytes, value);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        let mut bytes = [0u8; 4];
+        LittleEndian::write_u32(&mut bytes, value);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        let mut bytes = [0u8; 8];
+        LittleEndian::write_u64(&mut bytes, value);
+        self.buf.extend_from_slice(&bytes);
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_u64(data.len() as u64);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn write_str(&mut self, value: &str) {
+        self.write_bytes(value.as_bytes());
+    }
+
+    /// Writes the raw bytes of a plain C structure, such as the ones
+    /// returned by KVM.
+    pub fn write_pod<T>(&mut self, value: &T) {
+        let bytes = unsafe {
+            slice::from_raw_parts(value as *const T as *const u8,
+                                  size_of::<T>())
+        };
+        self.write_bytes(bytes);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads back the values in the order they were written. Truncated
+/// states are fatal, as the snapshot can't be used anymore.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader {
+            data: data,
+            pos: 0,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> &'a [u8] {
+        if self.data.len() - self.pos < len {
+            panic!("Invalid snapshot: truncated state.");
+        }
+        let data = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        data
+    }
+
+    pub fn read_u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    pub fn read_bool(&mut self) -> bool {
+        self.read_u8() != 0
+    }
+
+    pub fn read_u16(&mut self) -> u16 {
+        LittleEndian::read_u16(self.take(2))
+    }
+
+    pub fn read_u32(&mut self) -> u32 {
+        LittleEndian::read_u32(self.take(4))
+    }
+
+    pub fn read_u64(&mut self) -> u64 {
+        LittleEndian::read_u64(self.take(8))
+    }
+
+    pub fn read_bytes(&mut self) -> &'a [u8] {
+        let len = self.read_u64() as usize;
+        self.take(len)
+    }
+
+    pub fn read_str(&mut self) -> String {
+        String::from_utf8(self.read_bytes().to_vec())
+            .expect("Invalid snapshot: malformed string.")
+    }
+
+    /// Reads a structure written by `write_pod`.
+    pub fn read_pod<T>(&mut self) -> T {
+        let bytes = self.read_bytes();
+        if bytes.len() != size_of::<T>() {
+            panic!("Invalid snapshot: unexpected structure size: {}.",
+                   bytes.len());
+        }
+        unsafe {
+            let mut value: T = mem::zeroed();
+            slice::from_raw_parts_mut(&mut value as *mut T as *mut u8,
+                                      size_of::<T>())
+                .copy_from_slice(bytes);
+            value
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[repr(C)]
+    struct Pod {
+        a: u32,
+        b: u16,
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x12);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u32(0x1234_5678);
+        writer.write_u64(0x1234_5678_9abc_def0);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_str("insula");
+        writer.write_pod(&Pod { a: 7, b: 8 });
+        let data = writer.into_bytes();
+        assert_eq!(&data[..4], &[0x12, 1, 0x34, 0x12]);
+
+        let mut reader = StateReader::new(&data);
+        assert_eq!(reader.read_u8(), 0x12);
+        assert!(reader.read_bool());
+        assert_eq!(reader.read_u16(), 0x1234);
+        assert_eq!(reader.read_u32(), 0x1234_5678);
+        assert_eq!(reader.read_u64(), 0x1234_5678_9abc_def0);
+        assert_eq!(reader.read_bytes(), &[1, 2, 3]);
+        assert_eq!(reader.read_str(), "insula");
+        assert_eq!(reader.read_pod::<Pod>(), Pod { a: 7, b: 8 });
+    }
+
+    #[test]
+    #[should_panic(expected = "truncated state")]
+    fn truncated() {
+        let mut writer = StateWriter::new();
+        writer.write_u32(1);
+        let data = writer.into_bytes();
+        StateReader::new(&data[..3]).read_u32();
+    }
+
+    #[test]
+    #[should_panic(expected = "truncated state")]
+    fn oversized_bytes() {
+        let mut writer = StateWriter::new();
+        writer.write_bytes(&[0; 4]);
+        let mut data = writer.into_bytes();
+        data[7] = 0xff;
+        StateReader::new(&data).read_bytes();
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected structure size")]
+    fn pod_size_mismatch() {
+        let mut writer = StateWriter::new();
+        writer.write_u32(0);
+        writer.write_pod(&0u32);
+        let data = writer.into_bytes();
+        let mut reader = StateReader::new(&data);
+        reader.read_u32();
+        reader.read_pod::<Pod>();
+    }
+}
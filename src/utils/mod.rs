@@ -1,6 +1,9 @@
 #[cfg(target_family = "unix")]
-mod posix;
+pub mod posix;
 #[cfg(target_family = "windows")]
-mod win32;
+pub mod win32;
 
 pub mod memory;
+pub mod options;
+#[cfg(test)]
+pub mod test_dir;
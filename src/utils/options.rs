@@ -0,0 +1,30 @@
+/// Splits a "key=value,key2=value2" command line option. Keys without
+/// a value are returned with an empty value.
+pub fn parse_key_values(spec: &str) -> Vec<(String, String)> {
+    spec.split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.find('=') {
+            Some(idx) => (s[..idx].to_string(), s[idx + 1..].to_string()),
+            None => (s.to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// Parses on/off style boolean options.
+pub fn parse_bool(key: &str, value: &str) -> bool {
+    match value {
+        "on" | "yes" | "true" | "" => true,
+        "off" | "no" | "false" => false,
+        _ => panic!("Invalid value for {}: {}", key, value),
+    }
+}
+
+/// Parses a decimal or "0x" prefixed hexadecimal integer.
+pub fn parse_u64(key: &str, value: &str) -> u64 {
+    let result = if value.starts_with("0x") || value.starts_with("0X") {
+        u64::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u64>()
+    };
+    result.unwrap_or_else(|_| panic!("Invalid value for {}: {}", key, value))
+}
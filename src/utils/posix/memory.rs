@@ -1,26 +1,162 @@
 extern crate libc;
 extern crate std;
 
+use std::ffi::CString;
+use std::fs::File;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
+
 use super::os::errno;
 
+// The huge page size is encoded as log2(size) in the mmap and memfd flags.
+const MAP_HUGE_SHIFT: u32 = 26;
+const MFD_HUGE_SHIFT: u32 = 26;
+
+const MFD_CLOEXEC: u32 = 0x1;
+const MFD_ALLOW_SEALING: u32 = 0x2;
+const MFD_HUGETLB: u32 = 0x4;
+
+const F_ADD_SEALS: i32 = 1033;
+const F_SEAL_SEAL: i32 = 0x1;
+const F_SEAL_SHRINK: i32 = 0x2;
+const F_SEAL_GROW: i32 = 0x4;
+
+const MPOL_BIND: i32 = 2;
+const MPOL_MF_STRICT: u32 = 1 << 0;
+const MPOL_MF_MOVE: u32 = 1 << 1;
+
 pub fn anon_ram_alloc(size: usize) -> *mut libc::c_void {
     // todo: handle flags, alignment
+    map_ram(size,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1, 0)
+}
+
+fn map_ram(size: usize, flags: i32, fd: i32, offset: u64) -> *mut libc::c_void {
     let addr = unsafe {
         libc::mmap(
             std::ptr::null_mut(),
             size,
             libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
-            -1,
-            0)
+            flags,
+            fd,
+            offset as libc::off_t)
     };
 
     if addr == libc::MAP_FAILED {
-        panic!("mmapp failed with: {}", errno());
+        panic!("mmap failed with: {}", errno());
     }
     addr
 }
 
+/// Allocates private memory out of the huge page pool. The pages are
+/// reserved upfront, so that the guest doesn't crash if the pool runs out.
+pub fn hugetlb_ram_alloc(size: usize, page_size: u64) -> *mut libc::c_void {
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_HUGETLB |
+                (page_size.trailing_zeros() << MAP_HUGE_SHIFT) as i32;
+    map_ram(size, flags, -1, 0)
+}
+
+/// Maps a part of a file, the changes being visible to the other
+/// processes mapping it.
+pub fn file_ram_alloc(file: &File, offset: u64, size: usize)
+        -> *mut libc::c_void {
+    map_ram(size, libc::MAP_SHARED, file.as_raw_fd(), offset)
+}
+
+/// Maps a part of a file copy-on-write, the changes staying private.
+pub fn private_file_ram_alloc(file: &File, offset: u64, size: usize)
+        -> *mut libc::c_void {
+    map_ram(size, libc::MAP_PRIVATE | libc::MAP_NORESERVE, file.as_raw_fd(),
+            offset)
+}
+
+/// Creates an anonymous file which may be passed to other processes.
+/// Its size is sealed, so that they cannot truncate it under our feet.
+pub fn memfd_create(name: &str, size: u64, hugepage_size: Option<u64>) -> File {
+    let mut flags = MFD_CLOEXEC | MFD_ALLOW_SEALING;
+    if let Some(page_size) = hugepage_size {
+        flags |= MFD_HUGETLB | page_size.trailing_zeros() << MFD_HUGE_SHIFT;
+    }
+
+    let name = CString::new(name).unwrap();
+    let fd = unsafe {
+        libc::syscall(libc::SYS_memfd_create, name.as_ptr(), flags)
+    };
+    if fd < 0 {
+        panic!("memfd_create failed with: {}", errno());
+    }
+    let file = unsafe { File::from_raw_fd(fd as i32) };
+
+    file.set_len(size).expect("Cannot resize the memfd.");
+    let result = unsafe {
+        libc::fcntl(file.as_raw_fd(), F_ADD_SEALS,
+                    F_SEAL_SHRINK | F_SEAL_GROW | F_SEAL_SEAL)
+    };
+    if result != 0 {
+        panic!("Cannot seal the memfd: {}", errno());
+    }
+    file
+}
+
+/// Touches every page of the range using the given number of threads, so
+/// that the guest doesn't take the allocation faults.
+pub fn prealloc(address: *mut libc::c_void, size: usize, page_size: usize,
+                thread_count: usize) {
+    let page_count = (size + page_size - 1) / page_size;
+    let pages_per_thread = (page_count + thread_count - 1) / thread_count;
+    // Raw pointers cannot be sent to other threads.
+    let address = address as usize;
+
+    let threads = (0..thread_count).map(|i| {
+        let first = (i * pages_per_thread).min(page_count);
+        let last = ((i + 1) * pages_per_thread).min(page_count);
+        thread::spawn(move || {
+            for page in first..last {
+                // Writing the value back keeps the contents of file backed
+                // memory.
+                let ptr = (address + page * page_size) as *mut u8;
+                unsafe {
+                    std::ptr::write_volatile(ptr, std::ptr::read_volatile(ptr));
+                }
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+/// Prevents the range from being swapped out.
+pub fn mlock(address: *mut libc::c_void, size: usize) {
+    let result = unsafe { libc::mlock(address, size) };
+
+    if result != 0 {
+        panic!("mlock failed with: {}", errno());
+    }
+}
+
+/// Binds the range to the given host NUMA nodes. Must be called before
+/// the pages are touched.
+pub fn mbind(address: *mut libc::c_void, size: usize, nodes: &[u32]) {
+    let max_node = nodes.iter().max().map_or(0, |node| *node as usize + 1);
+    let mut node_mask = vec![0u64; (max_node + 63) / 64];
+    for node in nodes {
+        node_mask[*node as usize / 64] |= 1 << (*node % 64);
+    }
+
+    // The kernel ignores the last bit of the mask.
+    let result = unsafe {
+        libc::syscall(libc::SYS_mbind, address, size, MPOL_BIND,
+                      node_mask.as_ptr(), node_mask.len() * 64 + 1,
+                      MPOL_MF_STRICT | MPOL_MF_MOVE)
+    };
+    if result != 0 {
+        panic!("mbind failed with: {}", errno());
+    }
+}
+
 pub fn anon_ram_free(address: *mut libc::c_void, size: usize) {
     let result = unsafe { libc::munmap(address, size) };
 
@@ -5,3 +5,35 @@ pub fn errno() -> i32 {
         *libc::__errno_location()
     }
 }
+
+/// The signal used to kick threads out of blocking system calls, such as
+/// a vCPU running guest code.
+pub const KICK_SIGNAL: libc::c_int = libc::SIGUSR1;
+
+extern "C" fn handle_kick_signal(_signum: libc::c_int) {}
+
+/// Installs a handler which does nothing for the kick signal. Without
+/// SA_RESTART, the interrupted system calls fail with EINTR.
+pub fn register_kick_handler() {
+    unsafe {
+        let mut action: libc::sigaction = ::std::mem::zeroed();
+        action.sa_sigaction = handle_kick_signal as extern "C" fn(libc::c_int)
+                              as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(KICK_SIGNAL, &action, ::std::ptr::null_mut()) < 0 {
+            panic!("Cannot install the kick signal handler: {}", errno());
+        }
+    }
+}
+
+pub fn kick_thread(thread: libc::pthread_t) {
+    unsafe {
+        libc::pthread_kill(thread, KICK_SIGNAL);
+    }
+}
+
+pub fn current_thread() -> libc::pthread_t {
+    unsafe {
+        libc::pthread_self()
+    }
+}
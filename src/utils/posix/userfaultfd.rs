@@ -0,0 +1,145 @@
+//! Minimal userfaultfd support, letting a thread resolve the missing page
+//! faults of registered ranges.
+
+extern crate byteorder;
+extern crate libc;
+extern crate std;
+
+use self::byteorder::{ByteOrder, NativeEndian};
+
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+
+use super::os::errno;
+
+// Only anonymous memory using small pages is registered.
+const PAGE_SIZE: usize = 4096;
+
+const UFFD_API: u64 = 0xaa;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+// _IOWR(0xaa, nr, struct)
+const UFFDIO_API: u64 = 0xc018_aa3f;
+const UFFDIO_REGISTER: u64 = 0xc020_aa00;
+const UFFDIO_COPY: u64 = 0xc028_aa03;
+
+#[repr(C)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioRegister {
+    start: u64,
+    len: u64,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+// struct uffd_msg, only the page fault event is used.
+const UFFD_MSG_SIZE: usize = 32;
+const UFFD_MSG_ADDRESS_OFFSET: usize = 16;
+
+pub struct UserFaultFd {
+    file: File,
+}
+
+impl UserFaultFd {
+    pub fn new() -> Self {
+        let fd = unsafe {
+            libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC)
+        };
+        if fd < 0 {
+            panic!("userfaultfd failed with: {}", errno());
+        }
+        let uffd = UserFaultFd {
+            file: unsafe { File::from_raw_fd(fd as i32) },
+        };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: 0,
+            ioctls: 0,
+        };
+        uffd.ioctl(UFFDIO_API, &mut api as *mut _ as *mut libc::c_void)
+            .expect("UFFDIO_API failed");
+        uffd
+    }
+
+    fn ioctl(&self, request: u64, arg: *mut libc::c_void) -> Result<(), i32> {
+        let result = unsafe {
+            libc::ioctl(self.file.as_raw_fd(), request as _, arg)
+        };
+        if result < 0 {
+            return Err(errno());
+        }
+        Ok(())
+    }
+
+    /// Reports the faults on pages of the range that were never populated.
+    pub fn register(&self, address: *mut libc::c_void, size: usize) {
+        let mut register = UffdioRegister {
+            start: address as u64,
+            len: size as u64,
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        if let Err(e) = self.ioctl(UFFDIO_REGISTER,
+                                   &mut register as *mut _ as *mut libc::c_void) {
+            panic!("UFFDIO_REGISTER failed with: {}", e);
+        }
+    }
+
+    /// Blocks until a page fault occurs, returning the faulting address.
+    pub fn read_fault(&self) -> u64 {
+        let mut msg = [0u8; UFFD_MSG_SIZE];
+        loop {
+            (&self.file).read_exact(&mut msg)
+                .expect("Cannot read from the userfaultfd.");
+            if msg[0] == UFFD_EVENT_PAGEFAULT {
+                return NativeEndian::read_u64(&msg[UFFD_MSG_ADDRESS_OFFSET..]);
+            }
+        }
+    }
+
+    /// Populates the pages at `address` with `data`, waking up the threads
+    /// waiting for them. The pages already populated are left as they are.
+    pub fn copy(&self, address: u64, data: &[u8]) {
+        let mut done = 0;
+        while done < data.len() {
+            let mut copy = UffdioCopy {
+                dst: address + done as u64,
+                src: data[done..].as_ptr() as u64,
+                len: (data.len() - done) as u64,
+                mode: 0,
+                copy: 0,
+            };
+            match self.ioctl(UFFDIO_COPY,
+                             &mut copy as *mut _ as *mut libc::c_void) {
+                Ok(()) => break,
+                // Stopped by a populated page, or by a change of the
+                // mappings when nothing was copied.
+                Err(libc::EAGAIN) if copy.copy > 0 => {
+                    done += copy.copy as usize;
+                },
+                Err(libc::EAGAIN) => (),
+                // Populated while resolving an earlier fault.
+                Err(libc::EEXIST) => done += PAGE_SIZE,
+                Err(e) => panic!("UFFDIO_COPY failed with: {}", e),
+            }
+        }
+    }
+}
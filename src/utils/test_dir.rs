@@ -0,0 +1,34 @@
+//! Temporary directories for the tests using files.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+
+/// Directory created empty and removed with its content once dropped.
+/// The names must be unique across the tests, which run in parallel.
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(
+            format!("insula-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TestDir {
+            path: path,
+        }
+    }
+
+    pub fn file(&self, name: &str) -> String {
+        self.path.join(name).to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}